@@ -0,0 +1,199 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use hdrhistogram::Histogram;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use rudis_client::{Client, Reconnect};
+
+mod workload;
+use crate::workload::{Op, Workload, ALL_OPS};
+
+/// Load generator for rudis. Runs each test with N concurrent clients and
+/// reports throughput and latency percentiles
+#[derive(Debug, Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// Server hostname
+    #[arg(long, default_value = "127.0.0.1")]
+    host: String,
+    /// Server port
+    #[arg(short, long, default_value_t = 6378)]
+    port: u16,
+    /// Number of parallel connections
+    #[arg(short, long, default_value_t = 50)]
+    clients: usize,
+    /// Total number of requests per test
+    #[arg(short = 'n', long, default_value_t = 100000)]
+    requests: u64,
+    /// Number of requests sent before waiting for the replies
+    #[arg(short = 'P', long, default_value_t = 1)]
+    pipeline: usize,
+    /// Number of distinct keys the requests pick from at random
+    #[arg(short = 'r', long, default_value_t = 10000)]
+    keyspace: u64,
+    /// Size in bytes of the values of SET and the list pushes
+    #[arg(short, long, default_value_t = 3)]
+    data_size: usize,
+    /// Comma separated tests to run one after the other
+    #[arg(short, long, value_delimiter = ',', default_value = "ping,set,get,incr,lpush,rpush,lpop,rpop,lrange_100")]
+    tests: Vec<String>,
+    /// Run a single test mixing commands instead, e.g. get:80,set:20
+    #[arg(long, conflicts_with = "tests")]
+    mix: Option<String>,
+    /// Keep connections open, otherwise every request opens a new one to
+    /// measure the cost of connecting
+    #[arg(short, long, default_value_t = true, action = clap::ArgAction::Set)]
+    keepalive: bool,
+    /// Only print the throughput and median latency of each test
+    #[arg(short, long)]
+    quiet: bool,
+}
+
+struct Report {
+    elapsed: Duration,
+    errors: u64,
+    // microseconds
+    latency: Histogram<u64>,
+}
+
+// One connection sending requests until the shared budget runs out.
+async fn client_loop(
+    args: Arc<Cli>,
+    workload: Arc<Workload>,
+    remaining: Arc<AtomicU64>,
+) -> Result<(Histogram<u64>, u64), rudis_client::Error> {
+    let addr = format!("{}:{}", args.host, args.port);
+    let mut latency = Histogram::new_with_bounds(1, 60_000_000, 3).unwrap();
+    let mut errors = 0;
+    let value = vec![b'x'; args.data_size];
+    let mut rng = StdRng::from_entropy();
+    let reconnect = Reconnect { attempts: 1, ..Reconnect::default() };
+    let mut client = None;
+    loop {
+        let batch = args.pipeline as u64;
+        let taken = remaining.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+            (left > 0).then(|| left - left.min(batch))
+        });
+        let depth = match taken {
+            Ok(left) => left.min(batch) as usize,
+            Err(_) => return Ok((latency, errors)),
+        };
+        let pipeline = workload.pipeline(&mut rng, depth, args.keyspace, &value);
+        let start = Instant::now();
+        if client.is_none() || !args.keepalive {
+            client = Some(Client::with_reconnect(addr.clone(), reconnect).await?);
+        }
+        let replies = client.as_mut().unwrap().pipeline(&pipeline).await?;
+        // every request of a pipeline waited for the whole pipeline
+        let micros = start.elapsed().as_micros().max(1) as u64;
+        latency.record_n(micros.min(latency.high()), depth as u64).unwrap();
+        errors += replies.iter().filter(|reply| reply.is_error()).count() as u64;
+    }
+}
+
+async fn run(args: Arc<Cli>, workload: Workload) -> Result<Report, rudis_client::Error> {
+    let workload = Arc::new(workload);
+    let remaining = Arc::new(AtomicU64::new(args.requests));
+    let start = Instant::now();
+    let tasks = (0..args.clients)
+        .map(|_| tokio::spawn(client_loop(args.clone(), workload.clone(), remaining.clone())))
+        .collect::<Vec<_>>();
+    let mut latency = Histogram::new_with_bounds(1, 60_000_000, 3).unwrap();
+    let mut errors = 0;
+    for task in tasks {
+        let (client_latency, client_errors) = task.await.expect("client task panicked")?;
+        latency.add(client_latency).unwrap();
+        errors += client_errors;
+    }
+    Ok(Report { elapsed: start.elapsed(), errors, latency })
+}
+
+fn msec(micros: u64) -> f64 {
+    micros as f64 / 1000.0
+}
+
+fn print_report(args: &Cli, name: &str, report: &Report) {
+    let rps = report.latency.len() as f64 / report.elapsed.as_secs_f64();
+    if args.quiet {
+        println!(
+            "{}: {:.2} requests per second, p50={:.3} msec",
+            name, rps, msec(report.latency.value_at_quantile(0.5))
+        );
+        return;
+    }
+    println!("====== {} ======", name);
+    println!(
+        "  {} requests completed in {:.2} seconds",
+        report.latency.len(), report.elapsed.as_secs_f64()
+    );
+    println!(
+        "  {} parallel clients, pipeline {}, {} bytes payload, keyspace {}, keep alive: {}",
+        args.clients, args.pipeline, args.data_size, args.keyspace, args.keepalive
+    );
+    if report.errors > 0 {
+        println!("  {} requests got an error reply", report.errors);
+    }
+    println!();
+    println!("Latency by percentile distribution (msec):");
+    for quantile in [0.5, 0.99, 0.999, 1.0] {
+        println!(
+            "{:8.3}% <= {:.3}",
+            quantile * 100.0, msec(report.latency.value_at_quantile(quantile))
+        );
+    }
+    println!();
+    println!("Latency histogram (msec):");
+    let mut seen = 0;
+    for bucket in report.latency.iter_log(100, 2.0) {
+        seen += bucket.count_since_last_iteration();
+        if bucket.count_since_last_iteration() == 0 && seen < report.latency.len() {
+            continue;
+        }
+        println!(
+            "  <= {:8.3}  {:7.3}%",
+            msec(bucket.value_iterated_to()),
+            seen as f64 * 100.0 / report.latency.len() as f64
+        );
+        if seen == report.latency.len() {
+            break;
+        }
+    }
+    println!();
+    println!("Summary:");
+    println!("  throughput: {:.2} requests per second", rps);
+    println!(
+        "  latency (msec): avg={:.3} p50={:.3} p99={:.3} p999={:.3} max={:.3}",
+        report.latency.mean() / 1000.0,
+        msec(report.latency.value_at_quantile(0.5)),
+        msec(report.latency.value_at_quantile(0.99)),
+        msec(report.latency.value_at_quantile(0.999)),
+        msec(report.latency.max()),
+    );
+    println!();
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Cli::parse();
+    if args.clients == 0 || args.pipeline == 0 {
+        return Err("--clients and --pipeline must be at least 1".into());
+    }
+    let workloads = match &args.mix {
+        Some(mix) => vec![Workload::mix(mix)?],
+        None => args.tests.iter()
+            .map(|name| Op::parse(name).map(Workload::single))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("{}, the tests are: {}", e, ALL_OPS.iter()
+                .map(Op::name).collect::<Vec<_>>().join(",")))?,
+    };
+    let args = Arc::new(args);
+    for workload in workloads {
+        let name = workload.name.clone();
+        let report = run(args.clone(), workload).await?;
+        print_report(&args, &name, &report);
+    }
+    Ok(())
+}
@@ -0,0 +1,115 @@
+// What the benchmark sends: a weighted mix of commands on random keys.
+
+use rand::Rng;
+use rudis_client::{Frame, Pipeline};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Ping,
+    Set,
+    Get,
+    Incr,
+    Lpush,
+    Rpush,
+    Lpop,
+    Rpop,
+    Lrange100,
+}
+
+pub const ALL_OPS: &[Op] = &[
+    Op::Ping, Op::Set, Op::Get, Op::Incr, Op::Lpush,
+    Op::Rpush, Op::Lpop, Op::Rpop, Op::Lrange100,
+];
+
+impl Op {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Op::Ping => "ping",
+            Op::Set => "set",
+            Op::Get => "get",
+            Op::Incr => "incr",
+            Op::Lpush => "lpush",
+            Op::Rpush => "rpush",
+            Op::Lpop => "lpop",
+            Op::Rpop => "rpop",
+            Op::Lrange100 => "lrange_100",
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<Op, String> {
+        ALL_OPS.iter()
+            .find(|op| op.name().eq_ignore_ascii_case(name))
+            .copied()
+            .ok_or_else(|| format!("unknown test '{}'", name))
+    }
+
+    // Each kind of value gets its own keys, so the mix never runs into
+    // WRONGTYPE errors.
+    fn command(&self, key: u64, value: &[u8]) -> Frame {
+        let counter = format!("counter:{}", key);
+        let list = format!("mylist:{}", key);
+        let key = format!("key:{}", key);
+        match self {
+            Op::Ping => Frame::command(["PING"]),
+            Op::Set => Frame::command([b"SET".to_vec(), key.into_bytes(), value.to_vec()]),
+            Op::Get => Frame::command(["GET".to_string(), key]),
+            Op::Incr => Frame::command(["INCR".to_string(), counter]),
+            Op::Lpush => Frame::command([b"LPUSH".to_vec(), list.into_bytes(), value.to_vec()]),
+            Op::Rpush => Frame::command([b"RPUSH".to_vec(), list.into_bytes(), value.to_vec()]),
+            Op::Lpop => Frame::command(["LPOP".to_string(), list]),
+            Op::Rpop => Frame::command(["RPOP".to_string(), list]),
+            Op::Lrange100 => Frame::command(["LRANGE", &list, "0", "99"]),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Workload {
+    pub name: String,
+    ops: Vec<(Op, u32)>,
+}
+
+impl Workload {
+    pub fn single(op: Op) -> Workload {
+        Workload { name: op.name().to_uppercase(), ops: vec![(op, 1)] }
+    }
+
+    /// Parses a mix like `get:80,set:20`, weights are relative to each other.
+    pub fn mix(spec: &str) -> Result<Workload, String> {
+        let ops = spec.split(',')
+            .map(|part| {
+                let (name, weight) = part.split_once(':')
+                    .ok_or_else(|| format!("expected name:weight, got '{}'", part))?;
+                let weight = weight.parse::<u32>()
+                    .map_err(|_| format!("invalid weight '{}'", weight))?;
+                Ok((Op::parse(name)?, weight))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        if ops.iter().map(|(_, w)| w).sum::<u32>() == 0 {
+            return Err("the mix needs a positive weight".to_string());
+        }
+        Ok(Workload { name: format!("MIX {}", spec), ops })
+    }
+
+    fn pick(&self, rng: &mut impl Rng) -> Op {
+        let total: u32 = self.ops.iter().map(|(_, w)| w).sum();
+        let mut n = rng.gen_range(0..total);
+        for (op, weight) in &self.ops {
+            if n < *weight {
+                return *op;
+            }
+            n -= weight;
+        }
+        unreachable!("n is below the total weight")
+    }
+
+    /// The next `depth` commands for one client.
+    pub fn pipeline(&self, rng: &mut impl Rng, depth: usize, keyspace: u64, value: &[u8]) -> Pipeline {
+        let mut pipeline = Pipeline::new();
+        for _ in 0..depth {
+            let key = rng.gen_range(0..keyspace.max(1));
+            pipeline.push(self.pick(rng).command(key, value));
+        }
+        pipeline
+    }
+}
@@ -0,0 +1,39 @@
+use std::io::{self, BufReader, Write};
+use std::net::TcpStream;
+
+use rudis_core::{Frame, FrameReader};
+
+/// A blocking connection to the server.
+pub struct Connection {
+    reader: FrameReader<BufReader<TcpStream>>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    pub fn connect(addr: &str) -> io::Result<Connection> {
+        let writer = TcpStream::connect(addr)?;
+        let reader = FrameReader::new(BufReader::new(writer.try_clone()?));
+        Ok(Connection { reader, writer })
+    }
+
+    /// Another handle to the socket, for writing while this one reads.
+    pub fn writer(&self) -> io::Result<TcpStream> {
+        self.writer.try_clone()
+    }
+
+    pub fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.writer.write_all(bytes)
+    }
+
+    pub fn read(&mut self) -> io::Result<Frame> {
+        self.reader.read_frame()?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "server closed the connection"))
+    }
+
+    pub fn request<I, A>(&mut self, args: I) -> io::Result<Frame>
+        where I: IntoIterator<Item = A>, A: Into<Vec<u8>>
+    {
+        self.write_all(&Frame::command(args).to_bytes())?;
+        self.read()
+    }
+}
@@ -0,0 +1,142 @@
+// Turning typed lines into commands and replies into text, the way
+// redis-cli does.
+
+use rudis_core::Frame;
+
+fn push_char(arg: &mut Vec<u8>, c: char) {
+    arg.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+}
+
+/// Splits a line into arguments. Arguments are separated by whitespace and
+/// can be quoted, "double quotes" understand \n, \r, \t, \" , \\ and \xHH
+/// escapes, 'single quotes' only \'.
+pub fn split_args(line: &str) -> Result<Vec<Vec<u8>>, String> {
+    let mut args = vec![];
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let quote = match chars.peek() {
+            None => return Ok(args),
+            Some('"') | Some('\'') => chars.next(),
+            Some(_) => None,
+        };
+        let mut arg = vec![];
+        loop {
+            match (quote, chars.next()) {
+                (Some(_), None) => return Err("unbalanced quotes".to_string()),
+                (None, None) => break,
+                (None, Some(c)) if c.is_whitespace() => break,
+                (Some(q), Some(c)) if c == q => {
+                    if chars.peek().is_some_and(|c| !c.is_whitespace()) {
+                        return Err("closing quote must be followed by a space".to_string());
+                    }
+                    break;
+                }
+                (Some('"'), Some('\\')) => match chars.next() {
+                    Some('n') => arg.push(b'\n'),
+                    Some('r') => arg.push(b'\r'),
+                    Some('t') => arg.push(b'\t'),
+                    Some('x') => {
+                        let hex: String = chars.by_ref().take(2).collect();
+                        let byte = u8::from_str_radix(&hex, 16)
+                            .map_err(|_| format!("invalid escape \\x{}", hex))?;
+                        arg.push(byte);
+                    }
+                    Some(c) => push_char(&mut arg, c),
+                    None => return Err("unbalanced quotes".to_string()),
+                },
+                (Some('\''), Some('\\')) if chars.peek() == Some(&'\'') => {
+                    chars.next();
+                    arg.push(b'\'');
+                }
+                (_, Some(c)) => push_char(&mut arg, c),
+            }
+        }
+        args.push(arg);
+    }
+}
+
+// Bulk strings are quoted with anything unprintable escaped.
+fn quote(bytes: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for b in bytes {
+        match b {
+            b'\\' => out.push_str("\\\\"),
+            b'"' => out.push_str("\\\""),
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            0x20..=0x7e => out.push(*b as char),
+            _ => out.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Formats a reply for a human, arrays as numbered, indented lists.
+pub fn pretty(frame: &Frame) -> String {
+    let mut out = String::new();
+    write_pretty(frame, 0, &mut out);
+    out
+}
+
+fn write_pretty(frame: &Frame, indent: usize, out: &mut String) {
+    match frame {
+        Frame::Simple(s) => out.push_str(s),
+        Frame::Error(e) => out.push_str(&format!("(error) {}", e)),
+        Frame::Integer(n) => out.push_str(&format!("(integer) {}", n)),
+        Frame::Bulk(b) => out.push_str(&quote(b)),
+        Frame::Null | Frame::NullArray => out.push_str("(nil)"),
+        Frame::Array(items) if items.is_empty() => out.push_str("(empty array)"),
+        Frame::Push(items) | Frame::Array(items) => {
+            let width = items.len().to_string().len();
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push('\n');
+                    out.push_str(&" ".repeat(indent));
+                }
+                let prefix = format!("{:>width$}) ", i + 1, width = width);
+                out.push_str(&prefix);
+                write_pretty(item, indent + prefix.len(), out);
+            }
+        }
+    }
+}
+
+/// Formats a reply the way it goes into a pipe: one line per value, no
+/// decoration.
+pub fn raw(frame: &Frame) -> String {
+    match frame {
+        Frame::Simple(s) => s.clone(),
+        Frame::Error(e) => e.clone(),
+        Frame::Integer(n) => n.to_string(),
+        Frame::Bulk(b) => String::from_utf8_lossy(b).into_owned(),
+        Frame::Null | Frame::NullArray => String::new(),
+        Frame::Array(items) | Frame::Push(items) => {
+            items.iter().map(raw).collect::<Vec<_>>().join("\n")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_quoted_args() {
+        let args = split_args(r#"set "a key" 'it\'s' "\x00\n""#).unwrap();
+        assert_eq!(args, vec![b"set".to_vec(), b"a key".to_vec(), b"it's".to_vec(), b"\0\n".to_vec()]);
+        assert!(split_args(r#"get "open"#).is_err());
+        assert!(split_args(r#"get "a"b"#).is_err());
+    }
+
+    #[test]
+    fn pretty_prints_nested_arrays() {
+        let reply = Frame::Array(vec![
+            Frame::bulk("a"),
+            Frame::Array(vec![Frame::Integer(1), Frame::Null]),
+        ]);
+        assert_eq!(pretty(&reply), "1) \"a\"\n2) 1) (integer) 1\n   2) (nil)");
+    }
+}
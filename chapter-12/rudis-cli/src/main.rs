@@ -0,0 +1,158 @@
+use std::error::Error;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::process;
+use std::thread;
+use std::time::SystemTime;
+
+use clap::Parser;
+use rudis_core::Frame;
+
+mod connection;
+use crate::connection::Connection;
+
+mod format;
+use crate::format::{pretty, raw};
+
+mod repl;
+
+/// Command line interface to rudis. Runs the given command, or starts an
+/// interactive shell when there is none
+#[derive(Debug, Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// Server hostname
+    #[arg(long, default_value = "127.0.0.1")]
+    host: String,
+    /// Server port
+    #[arg(short, long, default_value_t = 6378)]
+    port: u16,
+    /// Print replies without type annotations, as in a pipe
+    #[arg(long)]
+    raw: bool,
+    /// Mass insert: send the commands read from stdin, in RESP or as
+    /// inline commands, and report how many of them failed
+    #[arg(long, conflicts_with_all = ["scan", "command"])]
+    pipe: bool,
+    /// List the keys of the server with SCAN
+    #[arg(long, conflicts_with = "command")]
+    scan: bool,
+    /// Only list keys matching this glob-style pattern, with --scan
+    #[arg(long, requires = "scan")]
+    pattern: Option<String>,
+    /// The command to run, followed by its arguments
+    command: Vec<String>,
+}
+
+// Streams the commands to the server from one thread while reading the
+// replies on another, so the server never waits for its replies to be read
+// and the input never has to fit in memory.
+fn pipe(conn: &mut Connection) -> Result<(), Box<dyn Error>> {
+    // a reply only this run can get, to know when all of them are in
+    let marker = format!("rudis-cli-pipe-{}-{:?}", process::id(), SystemTime::now());
+    let mut writer = conn.writer()?;
+    let sender = {
+        let marker = marker.clone();
+        thread::spawn(move || {
+            let sent = send_commands(&mut writer, &marker);
+            if sent.is_err() {
+                // the replies will never end with the marker
+                let _ = writer.shutdown(Shutdown::Both);
+            }
+            sent
+        })
+    };
+    let mut replies = 0;
+    let mut errors = 0;
+    let received = loop {
+        match conn.read() {
+            Ok(Frame::Bulk(reply)) if reply == marker.as_bytes() => break Ok(()),
+            Ok(Frame::Error(e)) => {
+                eprintln!("{}", e);
+                errors += 1;
+            }
+            Ok(_) => (),
+            Err(e) => break Err(e),
+        }
+        replies += 1;
+    };
+    // a stalled stdin must not keep a failed connection open
+    if received.is_ok() || sender.is_finished() {
+        sender.join().map_err(|_| "the thread sending commands panicked")??;
+    }
+    received?;
+    println!("All data transferred. errors: {}, replies: {}", errors, replies);
+    if errors > 0 {
+        return Err(format!("{} commands failed", errors).into());
+    }
+    Ok(())
+}
+
+// Writes the whole commands read from stdin, then an ECHO of `marker`.
+fn send_commands(writer: &mut TcpStream, marker: &str) -> Result<(), String> {
+    let mut input = io::stdin().lock();
+    let mut buf = vec![];
+    let mut chunk = [0; 64 * 1024];
+    loop {
+        let n = input.read(&mut chunk).map_err(|e| format!("can't read stdin: {}", e))?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        let mut complete = 0;
+        while let Some((_, used)) = Frame::parse(&buf[complete..]).map_err(|e| e.to_string())? {
+            complete += used;
+        }
+        writer.write_all(&buf[..complete]).map_err(|e| e.to_string())?;
+        buf.drain(..complete);
+    }
+    if !buf.iter().all(u8::is_ascii_whitespace) {
+        return Err("the input ends with an incomplete command".into());
+    }
+    writer.write_all(&Frame::command(["ECHO", marker]).to_bytes()).map_err(|e| e.to_string())
+}
+
+fn scan(conn: &mut Connection, pattern: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let mut cursor = "0".to_string();
+    loop {
+        let mut args = vec!["SCAN", &cursor, "COUNT", "100"];
+        if let Some(pattern) = pattern {
+            args.extend(["MATCH", pattern]);
+        }
+        let (next, keys) = match conn.request(args)? {
+            Frame::Array(reply) => match <[Frame; 2]>::try_from(reply) {
+                Ok([Frame::Bulk(next), Frame::Array(keys)]) => (next, keys),
+                Ok(reply) => return Err(format!("unexpected reply {:?}", reply).into()),
+                Err(reply) => return Err(format!("unexpected reply {:?}", reply).into()),
+            },
+            Frame::Error(e) => return Err(e.into()),
+            reply => return Err(format!("unexpected reply {:?}", reply).into()),
+        };
+        for key in keys {
+            println!("{}", raw(&key));
+        }
+        cursor = String::from_utf8(next)?;
+        if cursor == "0" {
+            return Ok(());
+        }
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = Cli::parse();
+    let addr = format!("{}:{}", args.host, args.port);
+    let mut conn = Connection::connect(&addr)
+        .map_err(|e| format!("Could not connect to rudis at {}: {}", addr, e))?;
+    if args.pipe {
+        pipe(&mut conn)
+    } else if args.scan {
+        scan(&mut conn, args.pattern.as_deref())
+    } else if args.command.is_empty() {
+        repl::run(&mut conn, &format!("{}> ", addr))
+    } else {
+        let reply = conn.request(args.command)?;
+        let text = if args.raw { raw(&reply) } else { pretty(&reply) };
+        println!("{}", text);
+        Ok(())
+    }
+}
@@ -0,0 +1,121 @@
+// The interactive shell: line editing, history and completion of command
+// names as listed by the server's COMMAND.
+
+use std::env;
+use std::io;
+use std::path::PathBuf;
+
+use rudis_core::Frame;
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+
+use crate::connection::Connection;
+use crate::format::{pretty, split_args};
+
+struct Commands {
+    names: Vec<String>,
+}
+
+// Completion is all the shell needs, the rest keeps rustyline's defaults.
+impl Helper for Commands {}
+impl Highlighter for Commands {}
+impl Validator for Commands {}
+impl Hinter for Commands {
+    type Hint = String;
+}
+
+impl Completer for Commands {
+    type Candidate = Pair;
+
+    // Only the command name, the first word of the line, is completed.
+    fn complete(&self, line: &str, pos: usize, _: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let word = &line[..pos];
+        if word.contains(char::is_whitespace) {
+            return Ok((pos, vec![]));
+        }
+        let start = word.len() - word.trim_start().len();
+        let typed = word.trim_start();
+        // complete in the case the user started typing in
+        let lower = typed.chars().next().is_some_and(|c| c.is_lowercase());
+        let candidates = self.names.iter()
+            .filter(|name| name.starts_with(&typed.to_uppercase()))
+            .map(|name| {
+                let name = if lower { name.to_lowercase() } else { name.clone() };
+                Pair { display: name.clone(), replacement: name }
+            })
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+// The names of the commands the server knows, for completion.
+fn command_names(conn: &mut Connection) -> io::Result<Vec<String>> {
+    let mut names = match conn.request(["COMMAND"])? {
+        Frame::Array(commands) => commands.into_iter()
+            .filter_map(|info| match info {
+                Frame::Array(fields) => match fields.into_iter().next() {
+                    Some(Frame::Bulk(name)) => Some(String::from_utf8_lossy(&name).to_uppercase()),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect(),
+        _ => vec![],
+    };
+    names.sort();
+    Ok(names)
+}
+
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".rudiscli_history"))
+}
+
+/// Commands after which the server keeps sending, until the user hits
+/// Ctrl-C.
+fn is_streaming(args: &[Vec<u8>]) -> bool {
+    ["MONITOR", "SUBSCRIBE"].iter().any(|c| args[0].eq_ignore_ascii_case(c.as_bytes()))
+}
+
+pub fn run(conn: &mut Connection, prompt: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut editor = Editor::new()?;
+    editor.set_helper(Some(Commands { names: command_names(conn)? }));
+    let history = history_path();
+    if let Some(path) = &history {
+        let _ = editor.load_history(path);
+    }
+    loop {
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let args = match split_args(&line) {
+            Ok(args) if args.is_empty() => continue,
+            Ok(args) => args,
+            Err(e) => {
+                println!("Invalid argument(s): {}", e);
+                continue;
+            }
+        };
+        editor.add_history_entry(line.as_str())?;
+        if args[0].eq_ignore_ascii_case(b"quit") || args[0].eq_ignore_ascii_case(b"exit") {
+            break;
+        }
+        let streaming = is_streaming(&args);
+        println!("{}", pretty(&conn.request(args)?));
+        if streaming {
+            loop {
+                println!("{}", pretty(&conn.read()?));
+            }
+        }
+    }
+    if let Some(path) = &history {
+        let _ = editor.save_history(path);
+    }
+    Ok(())
+}
@@ -0,0 +1,502 @@
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use rudis_core::{Frame, RespCodec, SaveMode};
+use tokio::net::TcpStream;
+use tokio::time;
+use tokio_util::codec::{Decoder, Framed};
+
+use crate::error::{Error, Result};
+use crate::pubsub::PubSub;
+
+pub(crate) type Connection = Framed<TcpStream, RespCodec>;
+
+/// How hard a client tries to get its connection back once it broke.
+#[derive(Debug, Clone, Copy)]
+pub struct Reconnect {
+    pub attempts: u32,
+    /// Delay before the second attempt, doubled for every attempt after it.
+    pub delay: Duration,
+}
+
+impl Default for Reconnect {
+    fn default() -> Self {
+        Reconnect { attempts: 5, delay: Duration::from_millis(50) }
+    }
+}
+
+impl Reconnect {
+    pub(crate) async fn connect(&self, addr: &str) -> Result<Connection> {
+        let mut delay = self.delay;
+        let mut attempt = 1;
+        loop {
+            match TcpStream::connect(addr).await {
+                Ok(stream) => {
+                    stream.set_nodelay(true)?;
+                    return Ok(RespCodec.framed(stream));
+                }
+                Err(e) if attempt >= self.attempts => return Err(e.into()),
+                Err(_) => {
+                    time::sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Commands to send in one go, without waiting for each reply.
+#[derive(Debug, Default, Clone)]
+pub struct Pipeline {
+    commands: Vec<Frame>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Pipeline::default()
+    }
+
+    pub fn cmd<I, A>(&mut self, args: I) -> &mut Self
+        where I: IntoIterator<Item = A>, A: Into<Vec<u8>>
+    {
+        self.push(Frame::command(args))
+    }
+
+    /// Adds a command that is already a frame.
+    pub fn push(&mut self, command: Frame) -> &mut Self {
+        self.commands.push(command);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+}
+
+/// A connection to a rudis server.
+///
+/// When the connection breaks, the command that was running fails and the
+/// next command connects again. Commands are never resent, since the server
+/// may have run them before the connection broke.
+pub struct Client {
+    addr: String,
+    reconnect: Reconnect,
+    conn: Option<Connection>,
+}
+
+fn bytes(arg: impl AsRef<[u8]>) -> Vec<u8> {
+    arg.as_ref().to_vec()
+}
+
+// Turns error replies into errors.
+fn check(reply: Frame) -> Result<Frame> {
+    match reply {
+        Frame::Error(e) => Err(Error::Server(e)),
+        reply => Ok(reply),
+    }
+}
+
+fn expect_ok(reply: Frame) -> Result<()> {
+    match check(reply)? {
+        Frame::Simple(_) => Ok(()),
+        reply => Err(Error::UnexpectedReply(reply)),
+    }
+}
+
+fn expect_integer(reply: Frame) -> Result<i64> {
+    match check(reply)? {
+        Frame::Integer(n) => Ok(n),
+        reply => Err(Error::UnexpectedReply(reply)),
+    }
+}
+
+fn expect_bulks(reply: Frame) -> Result<Vec<Vec<u8>>> {
+    match check(reply)? {
+        Frame::Array(items) => items.into_iter()
+            .map(|item| match item {
+                Frame::Bulk(item) => Ok(item),
+                reply => Err(Error::UnexpectedReply(reply)),
+            })
+            .collect(),
+        reply => Err(Error::UnexpectedReply(reply)),
+    }
+}
+
+fn expect_string(reply: Frame) -> Result<String> {
+    match check(reply)? {
+        Frame::Simple(s) => Ok(s),
+        Frame::Bulk(b) => String::from_utf8(b)
+            .map_err(|e| Error::UnexpectedReply(Frame::Bulk(e.into_bytes()))),
+        reply => Err(Error::UnexpectedReply(reply)),
+    }
+}
+
+impl Client {
+    pub async fn connect(addr: impl Into<String>) -> Result<Client> {
+        Client::with_reconnect(addr, Reconnect::default()).await
+    }
+
+    pub async fn with_reconnect(addr: impl Into<String>, reconnect: Reconnect) -> Result<Client> {
+        let addr = addr.into();
+        let conn = reconnect.connect(&addr).await?;
+        Ok(Client { addr, reconnect, conn: Some(conn) })
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    pub(crate) fn into_parts(self) -> (String, Reconnect, Option<Connection>) {
+        (self.addr, self.reconnect, self.conn)
+    }
+
+    async fn take_connection(&mut self) -> Result<Connection> {
+        match self.conn.take() {
+            Some(conn) => Ok(conn),
+            None => self.reconnect.connect(&self.addr).await,
+        }
+    }
+
+    /// Sends `commands` and reads as many replies. A broken connection is
+    /// dropped, to be replaced by the next call.
+    async fn roundtrip(&mut self, commands: Vec<Frame>) -> Result<Vec<Frame>> {
+        let mut conn = self.take_connection().await?;
+        let count = commands.len();
+        for command in commands {
+            conn.feed(command).await?;
+        }
+        conn.flush().await?;
+        let mut replies = Vec::with_capacity(count);
+        while replies.len() < count {
+            match conn.next().await {
+                Some(reply) => replies.push(reply?),
+                None => return Err(Error::ConnectionClosed),
+            }
+        }
+        self.conn = Some(conn);
+        Ok(replies)
+    }
+
+    /// Sends any command and returns its reply as is, error replies
+    /// included.
+    pub async fn execute(&mut self, command: Frame) -> Result<Frame> {
+        let mut replies = self.roundtrip(vec![command]).await?;
+        Ok(replies.remove(0))
+    }
+
+    /// Runs a command built from `args`, an error reply is an `Error::Server`.
+    pub async fn cmd<I, A>(&mut self, args: I) -> Result<Frame>
+        where I: IntoIterator<Item = A>, A: Into<Vec<u8>>
+    {
+        check(self.execute(Frame::command(args)).await?)
+    }
+
+    /// Sends every command of the pipeline before reading any reply. The
+    /// replies come back in order, error replies included.
+    pub async fn pipeline(&mut self, pipeline: &Pipeline) -> Result<Vec<Frame>> {
+        if pipeline.is_empty() {
+            return Ok(vec![]);
+        }
+        self.roundtrip(pipeline.commands.clone()).await
+    }
+
+    pub async fn ping(&mut self) -> Result<String> {
+        expect_string(self.execute(Frame::command(["PING"])).await?)
+    }
+
+    pub async fn get(&mut self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        match self.cmd([bytes("GET"), bytes(key)]).await? {
+            Frame::Bulk(value) => Ok(Some(value)),
+            Frame::Null => Ok(None),
+            reply => Err(Error::UnexpectedReply(reply)),
+        }
+    }
+
+    pub async fn set(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()> {
+        expect_ok(self.execute(Frame::command([bytes("SET"), bytes(key), bytes(value)])).await?)
+    }
+
+    pub async fn incr(&mut self, key: impl AsRef<[u8]>) -> Result<i64> {
+        self.incr_by(key, 1).await
+    }
+
+    pub async fn incr_by(&mut self, key: impl AsRef<[u8]>, by: i64) -> Result<i64> {
+        let command = Frame::command([bytes("INCRBY"), bytes(key), bytes(by.to_string())]);
+        expect_integer(self.execute(command).await?)
+    }
+
+    /// Returns the number of keys that existed.
+    pub async fn del<I, A>(&mut self, keys: I) -> Result<i64>
+        where I: IntoIterator<Item = A>, A: AsRef<[u8]>
+    {
+        let args = std::iter::once(bytes("DEL")).chain(keys.into_iter().map(bytes));
+        expect_integer(self.execute(Frame::command(args)).await?)
+    }
+
+    /// Like `del`, large values are freed in the background by the server.
+    pub async fn unlink<I, A>(&mut self, keys: I) -> Result<i64>
+        where I: IntoIterator<Item = A>, A: AsRef<[u8]>
+    {
+        let args = std::iter::once(bytes("UNLINK")).chain(keys.into_iter().map(bytes));
+        expect_integer(self.execute(Frame::command(args)).await?)
+    }
+
+    /// Removes every key, freeing them in the background when `lazy` is set.
+    pub async fn flushall(&mut self, lazy: bool) -> Result<()> {
+        let mode = if lazy { "ASYNC" } else { "SYNC" };
+        expect_ok(self.execute(Frame::command(["FLUSHALL", mode])).await?)
+    }
+
+    pub async fn exists(&mut self, key: impl AsRef<[u8]>) -> Result<bool> {
+        Ok(expect_integer(self.execute(Frame::command([bytes("EXISTS"), bytes(key)])).await?)? == 1)
+    }
+
+    /// Returns false if the key does not exist.
+    pub async fn expire(&mut self, key: impl AsRef<[u8]>, ttl: Duration) -> Result<bool> {
+        let ttl = ttl.as_millis().to_string();
+        let command = Frame::command([bytes("PEXPIRE"), bytes(key), bytes(ttl)]);
+        Ok(expect_integer(self.execute(command).await?)? == 1)
+    }
+
+    /// How long until the key expires, `None` if it exists but never does.
+    /// A missing key is `Ok(None)` as well, check with `exists` if needed.
+    pub async fn ttl(&mut self, key: impl AsRef<[u8]>) -> Result<Option<Duration>> {
+        match expect_integer(self.execute(Frame::command([bytes("PTTL"), bytes(key)])).await?)? {
+            ms if ms < 0 => Ok(None),
+            ms => Ok(Some(Duration::from_millis(ms as u64))),
+        }
+    }
+
+    pub async fn persist(&mut self, key: impl AsRef<[u8]>) -> Result<bool> {
+        Ok(expect_integer(self.execute(Frame::command([bytes("PERSIST"), bytes(key)])).await?)? == 1)
+    }
+
+    /// The key's value and expiry in the server's own format, for `restore`.
+    pub async fn dump(&mut self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        match self.cmd([bytes("DUMP"), bytes(key)]).await? {
+            Frame::Bulk(payload) => Ok(Some(payload)),
+            Frame::Null => Ok(None),
+            reply => Err(Error::UnexpectedReply(reply)),
+        }
+    }
+
+    /// Creates a key from a `dump` payload. Without a `ttl` the key gets the
+    /// expiry recorded in the payload.
+    pub async fn restore(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        ttl: Option<Duration>,
+        payload: impl AsRef<[u8]>,
+        replace: bool,
+    ) -> Result<()> {
+        let ttl = ttl.map_or(0, |ttl| ttl.as_millis().max(1)).to_string();
+        let mut args = vec![bytes("RESTORE"), bytes(key), bytes(ttl), bytes(payload)];
+        if replace {
+            args.push(bytes("REPLACE"));
+        }
+        expect_ok(self.execute(Frame::command(args)).await?)
+    }
+
+    /// Moves `keys` to another server. Returns false if none of the keys
+    /// existed.
+    pub async fn migrate<I, A>(
+        &mut self,
+        host: &str,
+        port: u16,
+        keys: I,
+        timeout: Duration,
+        replace: bool,
+    ) -> Result<bool>
+        where I: IntoIterator<Item = A>, A: AsRef<[u8]>
+    {
+        let mut args = vec![
+            bytes("MIGRATE"), bytes(host), bytes(port.to_string()), bytes(""), bytes("0"),
+            bytes(timeout.as_millis().to_string()),
+        ];
+        if replace {
+            args.push(bytes("REPLACE"));
+        }
+        args.push(bytes("KEYS"));
+        args.extend(keys.into_iter().map(bytes));
+        match self.cmd(args).await? {
+            Frame::Simple(s) if s == "OK" => Ok(true),
+            Frame::Simple(s) if s == "NOKEY" => Ok(false),
+            reply => Err(Error::UnexpectedReply(reply)),
+        }
+    }
+
+    /// Returns the length of the list after the push.
+    pub async fn lpush<I, A>(&mut self, key: impl AsRef<[u8]>, elements: I) -> Result<i64>
+        where I: IntoIterator<Item = A>, A: AsRef<[u8]>
+    {
+        self.push("LPUSH", key, elements).await
+    }
+
+    pub async fn rpush<I, A>(&mut self, key: impl AsRef<[u8]>, elements: I) -> Result<i64>
+        where I: IntoIterator<Item = A>, A: AsRef<[u8]>
+    {
+        self.push("RPUSH", key, elements).await
+    }
+
+    async fn push<I, A>(&mut self, command: &str, key: impl AsRef<[u8]>, elements: I) -> Result<i64>
+        where I: IntoIterator<Item = A>, A: AsRef<[u8]>
+    {
+        let args = [bytes(command), bytes(key)].into_iter().chain(elements.into_iter().map(bytes));
+        expect_integer(self.execute(Frame::command(args)).await?)
+    }
+
+    pub async fn lpop(&mut self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        self.pop("LPOP", key).await
+    }
+
+    pub async fn rpop(&mut self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        self.pop("RPOP", key).await
+    }
+
+    async fn pop(&mut self, command: &str, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        match self.cmd([bytes(command), bytes(key)]).await? {
+            Frame::Bulk(element) => Ok(Some(element)),
+            Frame::Null => Ok(None),
+            reply => Err(Error::UnexpectedReply(reply)),
+        }
+    }
+
+    pub async fn llen(&mut self, key: impl AsRef<[u8]>) -> Result<i64> {
+        expect_integer(self.execute(Frame::command([bytes("LLEN"), bytes(key)])).await?)
+    }
+
+    pub async fn lrange(&mut self, key: impl AsRef<[u8]>, start: i64, stop: i64) -> Result<Vec<Vec<u8>>> {
+        let args = [bytes("LRANGE"), bytes(key), bytes(start.to_string()), bytes(stop.to_string())];
+        expect_bulks(self.execute(Frame::command(args)).await?)
+    }
+
+    /// Returns the number of fields that were new.
+    pub async fn hset<I, F, V>(&mut self, key: impl AsRef<[u8]>, fields: I) -> Result<i64>
+        where I: IntoIterator<Item = (F, V)>, F: AsRef<[u8]>, V: AsRef<[u8]>
+    {
+        let mut args = vec![bytes("HSET"), bytes(key)];
+        for (field, value) in fields {
+            args.extend([bytes(field), bytes(value)]);
+        }
+        expect_integer(self.execute(Frame::command(args)).await?)
+    }
+
+    pub async fn hget(&mut self, key: impl AsRef<[u8]>, field: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        match self.cmd([bytes("HGET"), bytes(key), bytes(field)]).await? {
+            Frame::Bulk(value) => Ok(Some(value)),
+            Frame::Null => Ok(None),
+            reply => Err(Error::UnexpectedReply(reply)),
+        }
+    }
+
+    pub async fn hgetall(&mut self, key: impl AsRef<[u8]>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let items = expect_bulks(self.execute(Frame::command([bytes("HGETALL"), bytes(key)])).await?)?;
+        let mut items = items.into_iter();
+        Ok(std::iter::from_fn(|| Some((items.next()?, items.next()?))).collect())
+    }
+
+    /// Returns the number of members that were new.
+    pub async fn sadd<I, A>(&mut self, key: impl AsRef<[u8]>, members: I) -> Result<i64>
+        where I: IntoIterator<Item = A>, A: AsRef<[u8]>
+    {
+        self.push("SADD", key, members).await
+    }
+
+    pub async fn smembers(&mut self, key: impl AsRef<[u8]>) -> Result<Vec<Vec<u8>>> {
+        expect_bulks(self.execute(Frame::command([bytes("SMEMBERS"), bytes(key)])).await?)
+    }
+
+    /// Returns the number of members that were new.
+    pub async fn zadd<I, A>(&mut self, key: impl AsRef<[u8]>, members: I) -> Result<i64>
+        where I: IntoIterator<Item = (f64, A)>, A: AsRef<[u8]>
+    {
+        let mut args = vec![bytes("ZADD"), bytes(key)];
+        for (score, member) in members {
+            args.extend([bytes(score.to_string()), bytes(member)]);
+        }
+        expect_integer(self.execute(Frame::command(args)).await?)
+    }
+
+    /// Members by rank, lowest score first.
+    pub async fn zrange(&mut self, key: impl AsRef<[u8]>, start: i64, stop: i64) -> Result<Vec<Vec<u8>>> {
+        let args = [bytes("ZRANGE"), bytes(key), bytes(start.to_string()), bytes(stop.to_string())];
+        expect_bulks(self.execute(Frame::command(args)).await?)
+    }
+
+    /// How the server stores the key, e.g. "listpack" or "hashtable".
+    pub async fn object_encoding(&mut self, key: impl AsRef<[u8]>) -> Result<Option<String>> {
+        match self.cmd([bytes("OBJECT"), bytes("ENCODING"), bytes(key)]).await? {
+            Frame::Null => Ok(None),
+            reply => expect_string(reply).map(Some),
+        }
+    }
+
+    /// Returns the number of clients that received the message.
+    pub async fn publish(
+        &mut self,
+        channel: impl AsRef<[u8]>,
+        message: impl AsRef<[u8]>,
+    ) -> Result<i64> {
+        let command = Frame::command([bytes("PUBLISH"), bytes(channel), bytes(message)]);
+        expect_integer(self.execute(command).await?)
+    }
+
+    pub async fn config_get(&mut self, pattern: &str) -> Result<Vec<(String, String)>> {
+        let params = match self.cmd(["CONFIG", "GET", pattern]).await? {
+            Frame::Array(params) => params,
+            reply => return Err(Error::UnexpectedReply(reply)),
+        };
+        params.chunks(2)
+            .map(|kv| Ok((expect_string(kv[0].clone())?, expect_string(kv[1].clone())?)))
+            .collect()
+    }
+
+    pub async fn config_set(&mut self, name: &str, value: &str) -> Result<()> {
+        expect_ok(self.execute(Frame::command(["CONFIG", "SET", name, value])).await?)
+    }
+
+    pub async fn save(&mut self) -> Result<()> {
+        expect_ok(self.execute(Frame::command(["SAVE"])).await?)
+    }
+
+    pub async fn slowlog_len(&mut self) -> Result<i64> {
+        expect_integer(self.execute(Frame::command(["SLOWLOG", "LEN"])).await?)
+    }
+
+    pub async fn slowlog_reset(&mut self) -> Result<()> {
+        expect_ok(self.execute(Frame::command(["SLOWLOG", "RESET"])).await?)
+    }
+
+    pub async fn cluster_keyslot(&mut self, key: impl AsRef<[u8]>) -> Result<i64> {
+        let command = Frame::command([bytes("CLUSTER"), bytes("KEYSLOT"), bytes(key)]);
+        expect_integer(self.execute(command).await?)
+    }
+
+    /// Stops the server. The server answers every client, this one
+    /// included, that it is shutting down, which is not an error here.
+    pub async fn shutdown(mut self, mode: SaveMode) -> Result<()> {
+        let command = match mode {
+            SaveMode::Default => Frame::command(["SHUTDOWN"]),
+            SaveMode::Save => Frame::command(["SHUTDOWN", "SAVE"]),
+            SaveMode::NoSave => Frame::command(["SHUTDOWN", "NOSAVE"]),
+        };
+        match self.execute(command).await? {
+            Frame::Error(e) if e.contains("shutting down") => Ok(()),
+            reply => Err(Error::UnexpectedReply(reply)),
+        }
+    }
+
+    /// Turns the connection into a subscriber of `channels`.
+    pub async fn subscribe<I, A>(self, channels: I) -> Result<PubSub>
+        where I: IntoIterator<Item = A>, A: Into<Vec<u8>>
+    {
+        let mut pubsub = PubSub::new(self);
+        pubsub.subscribe(channels).await?;
+        Ok(pubsub)
+    }
+}
@@ -0,0 +1,18 @@
+use std::io;
+
+use rudis_core::Frame;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    /// The server answered with an error reply.
+    #[error("{0}")]
+    Server(String),
+    #[error("unexpected reply: {0:?}")]
+    UnexpectedReply(Frame),
+    #[error("connection closed by the server")]
+    ConnectionClosed,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
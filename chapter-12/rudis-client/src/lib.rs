@@ -0,0 +1,23 @@
+//! An async client for rudis.
+//!
+//! ```no_run
+//! # async fn example() -> rudis_client::Result<()> {
+//! use rudis_client::Client;
+//!
+//! let mut client = Client::connect("127.0.0.1:6378").await?;
+//! client.set("key", "value").await?;
+//! assert_eq!(client.get("key").await?, Some(b"value".to_vec()));
+//! # Ok(())
+//! # }
+//! ```
+
+mod client;
+mod error;
+mod pool;
+mod pubsub;
+
+pub use crate::client::{Client, Pipeline, Reconnect};
+pub use crate::error::{Error, Result};
+pub use crate::pool::{Pool, PooledClient};
+pub use crate::pubsub::{Message, PubSub};
+pub use rudis_core::{Frame, SaveMode};
@@ -0,0 +1,87 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::client::{Client, Reconnect};
+use crate::error::Result;
+
+struct Inner {
+    addr: String,
+    reconnect: Reconnect,
+    idle: Mutex<Vec<Client>>,
+    permits: Arc<Semaphore>,
+}
+
+/// At most `size` connections to one server, shared between tasks.
+/// Cloning a pool gives another handle to the same connections.
+#[derive(Clone)]
+pub struct Pool {
+    inner: Arc<Inner>,
+}
+
+/// A client borrowed from a pool, it goes back to the pool when dropped.
+pub struct PooledClient {
+    client: Option<Client>,
+    inner: Arc<Inner>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Pool {
+    /// Connections are opened on demand, the first time they are needed.
+    pub fn new(addr: impl Into<String>, size: usize) -> Pool {
+        Pool::with_reconnect(addr, size, Reconnect::default())
+    }
+
+    pub fn with_reconnect(addr: impl Into<String>, size: usize, reconnect: Reconnect) -> Pool {
+        Pool {
+            inner: Arc::new(Inner {
+                addr: addr.into(),
+                reconnect,
+                idle: Mutex::new(Vec::with_capacity(size)),
+                permits: Arc::new(Semaphore::new(size)),
+            }),
+        }
+    }
+
+    /// Waits until a connection is free.
+    pub async fn get(&self) -> Result<PooledClient> {
+        let permit = Arc::clone(&self.inner.permits)
+            .acquire_owned()
+            .await
+            .expect("the pool semaphore is never closed");
+        let idle = self.inner.idle.lock().unwrap().pop();
+        let client = match idle {
+            Some(client) => client,
+            None => Client::with_reconnect(self.inner.addr.clone(), self.inner.reconnect).await?,
+        };
+        Ok(PooledClient { client: Some(client), inner: Arc::clone(&self.inner), _permit: permit })
+    }
+
+    /// Number of open connections nobody is using.
+    pub fn idle(&self) -> usize {
+        self.inner.idle.lock().unwrap().len()
+    }
+}
+
+impl Deref for PooledClient {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledClient {
+    fn deref_mut(&mut self) -> &mut Client {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledClient {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            self.inner.idle.lock().unwrap().push(client);
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::{BTreeSet, VecDeque};
+
+use futures::stream::BoxStream;
+use futures::{SinkExt, StreamExt};
+use rudis_core::Frame;
+
+use crate::client::{Client, Connection, Reconnect};
+use crate::error::{Error, Result};
+
+/// A message published to a channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub channel: Vec<u8>,
+    pub payload: Vec<u8>,
+}
+
+/// A connection subscribed to channels. When the connection breaks, it
+/// reconnects and subscribes again to the same channels, messages published
+/// in between are lost.
+pub struct PubSub {
+    addr: String,
+    reconnect: Reconnect,
+    conn: Option<Connection>,
+    channels: BTreeSet<Vec<u8>>,
+    // messages that arrived while waiting for a subscription to be confirmed
+    pending: VecDeque<Message>,
+}
+
+enum Push {
+    Message(Message),
+    Subscribed(Vec<u8>),
+    Other,
+}
+
+fn parse_push(frame: Frame) -> Result<Push> {
+    let items = match frame {
+        Frame::Error(e) => return Err(Error::Server(e)),
+        Frame::Array(items) => items,
+        frame => return Err(Error::UnexpectedReply(frame)),
+    };
+    match items.as_slice() {
+        [Frame::Bulk(kind), Frame::Bulk(channel), Frame::Bulk(payload)] if kind == b"message" => {
+            Ok(Push::Message(Message { channel: channel.clone(), payload: payload.clone() }))
+        }
+        [Frame::Bulk(kind), Frame::Bulk(channel), _] if kind == b"subscribe" => {
+            Ok(Push::Subscribed(channel.clone()))
+        }
+        _ => Ok(Push::Other),
+    }
+}
+
+impl PubSub {
+    pub(crate) fn new(client: Client) -> PubSub {
+        let (addr, reconnect, conn) = client.into_parts();
+        PubSub { addr, reconnect, conn, channels: BTreeSet::new(), pending: VecDeque::new() }
+    }
+
+    async fn connection(&mut self) -> Result<&mut Connection> {
+        if self.conn.is_none() {
+            let conn = self.reconnect.connect(&self.addr).await?;
+            self.conn = Some(conn);
+            let channels = self.channels.iter().cloned().collect::<Vec<_>>();
+            if !channels.is_empty() {
+                self.send_subscribe(channels).await?;
+            }
+        }
+        Ok(self.conn.as_mut().unwrap())
+    }
+
+    // Reads from the current connection, dropping it when it broke.
+    async fn read(&mut self) -> Result<Frame> {
+        let conn = self.conn.as_mut().ok_or(Error::ConnectionClosed)?;
+        let frame = match conn.next().await {
+            Some(Ok(frame)) => Ok(frame),
+            Some(Err(e)) => Err(e.into()),
+            None => Err(Error::ConnectionClosed),
+        };
+        if frame.is_err() {
+            self.conn = None;
+        }
+        frame
+    }
+
+    // Subscribes on the current connection and waits for every channel to
+    // be confirmed.
+    async fn send_subscribe(&mut self, channels: Vec<Vec<u8>>) -> Result<()> {
+        let mut waiting = channels.iter().cloned().collect::<BTreeSet<_>>();
+        let command = Frame::command(std::iter::once(b"SUBSCRIBE".to_vec()).chain(channels));
+        let sent = self.conn.as_mut().unwrap().send(command).await;
+        if let Err(e) = sent {
+            self.conn = None;
+            return Err(e.into());
+        }
+        while !waiting.is_empty() {
+            match parse_push(self.read().await?)? {
+                Push::Subscribed(channel) => {
+                    waiting.remove(&channel);
+                }
+                Push::Message(message) => self.pending.push_back(message),
+                Push::Other => (),
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn subscribe<I, A>(&mut self, channels: I) -> Result<()>
+        where I: IntoIterator<Item = A>, A: Into<Vec<u8>>
+    {
+        let channels = channels.into_iter().map(Into::into).collect::<Vec<_>>();
+        self.channels.extend(channels.iter().cloned());
+        self.connection().await?;
+        self.send_subscribe(channels).await
+    }
+
+    pub async fn unsubscribe<I, A>(&mut self, channels: I) -> Result<()>
+        where I: IntoIterator<Item = A>, A: Into<Vec<u8>>
+    {
+        let channels = channels.into_iter().map(Into::into).collect::<Vec<_>>();
+        for channel in &channels {
+            self.channels.remove(channel);
+        }
+        let command = Frame::command(std::iter::once(b"UNSUBSCRIBE".to_vec()).chain(channels));
+        let sent = self.connection().await?.send(command).await;
+        if let Err(e) = sent {
+            self.conn = None;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    pub fn channels(&self) -> impl Iterator<Item = &[u8]> {
+        self.channels.iter().map(|c| c.as_slice())
+    }
+
+    /// Waits for the next message published to any of the channels.
+    pub async fn next_message(&mut self) -> Result<Message> {
+        loop {
+            if let Some(message) = self.pending.pop_front() {
+                return Ok(message);
+            }
+            // reconnects if the connection broke on the last read
+            self.connection().await?;
+            if let Ok(frame) = self.read().await {
+                if let Push::Message(message) = parse_push(frame)? {
+                    return Ok(message);
+                }
+            }
+        }
+    }
+
+    /// The published messages as a stream.
+    pub fn messages(&mut self) -> BoxStream<'_, Result<Message>> {
+        futures::stream::unfold(self, |pubsub| async {
+            let message = pubsub.next_message().await;
+            Some((message, pubsub))
+        })
+        .boxed()
+    }
+}
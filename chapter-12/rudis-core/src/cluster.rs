@@ -0,0 +1,391 @@
+// Cluster mode: keys are spread over 16384 hash slots and each slot is
+// served by exactly one node. This is the cluster view of a single node,
+// keeping it in sync with the peers is up to the network frontend, which
+// feeds the CLUSTER NODES output of its peers into `merge`.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{BuildHasher, Hasher};
+use std::net::SocketAddr;
+
+use crate::frame::Frame;
+
+pub const CLUSTER_SLOTS: u16 = 16384;
+
+// CRC16 with the XMODEM polynomial, the same one Redis cluster uses.
+fn crc16(buf: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in buf {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Maps a key to its hash slot. When the key contains a non-empty `{tag}`
+/// only the tag is hashed, so related keys can be kept on one node.
+pub fn key_hash_slot(key: &[u8]) -> u16 {
+    let hashed = key.iter().position(|b| *b == b'{')
+        .and_then(|start| {
+            key[start + 1..].iter().position(|b| *b == b'}')
+                .filter(|len| *len > 0)
+                .map(|len| &key[start + 1..start + 1 + len])
+        })
+        .unwrap_or(key);
+    crc16(hashed) % CLUSTER_SLOTS
+}
+
+fn new_node_id() -> String {
+    let state = RandomState::new();
+    (0..3).map(|i| {
+        let mut hasher = state.build_hasher();
+        hasher.write_u8(i);
+        format!("{:016x}", hasher.finish())
+    }).collect::<String>()[..40].to_string()
+}
+
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub id: String,
+    pub addr: SocketAddr,
+}
+
+pub struct ClusterState {
+    enabled: bool,
+    myself: Node,
+    nodes: HashMap<String, Node>,
+    // peers we have been told to meet but whose id we do not know yet
+    handshakes: Vec<SocketAddr>,
+    slots: Vec<Option<String>>,
+    migrating: BTreeMap<u16, String>,
+    importing: BTreeMap<u16, String>,
+}
+
+/// What to do with a command for a given key.
+pub enum Route {
+    Serve,
+    Redirect(Frame),
+}
+
+impl Default for ClusterState {
+    fn default() -> Self {
+        ClusterState::new()
+    }
+}
+
+impl ClusterState {
+    pub fn new() -> Self {
+        let myself = Node {
+            id: new_node_id(),
+            addr: ([127, 0, 0, 1], 6378).into(),
+        };
+        ClusterState {
+            enabled: false,
+            myself,
+            nodes: HashMap::new(),
+            handshakes: vec![],
+            slots: vec![None; CLUSTER_SLOTS as usize],
+            migrating: BTreeMap::new(),
+            importing: BTreeMap::new(),
+        }
+    }
+
+    pub fn enable(&mut self, addr: SocketAddr) {
+        self.enabled = true;
+        self.myself.addr = addr;
+        self.nodes.insert(self.myself.id.clone(), self.myself.clone());
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn myid(&self) -> &str {
+        &self.myself.id
+    }
+
+    fn node_addr(&self, id: &str) -> Option<SocketAddr> {
+        self.nodes.get(id).map(|n| n.addr)
+    }
+
+    fn owns(&self, slot: u16) -> bool {
+        self.slots[slot as usize].as_deref() == Some(self.myid())
+    }
+
+    /// Decides whether this node serves a command for keys in `slot` or
+    /// answers with a `-MOVED`/`-ASK` redirect. `exists` tells if the keys are
+    /// present locally, which matters while the slot is being migrated away.
+    pub fn route(&self, slot: u16, exists: bool, asking: bool) -> Route {
+        let owner = match &self.slots[slot as usize] {
+            Some(owner) => owner,
+            None => return Route::Redirect(Frame::Error(
+                format!("CLUSTERDOWN Hash slot {} not served", slot)
+            )),
+        };
+        if owner == self.myid() {
+            match self.migrating.get(&slot).and_then(|t| self.node_addr(t)) {
+                Some(target) if !exists => Route::Redirect(Frame::Error(
+                    format!("ASK {} {}", slot, target)
+                )),
+                _ => Route::Serve,
+            }
+        } else if asking && self.importing.contains_key(&slot) {
+            Route::Serve
+        } else {
+            match self.node_addr(owner) {
+                Some(addr) => Route::Redirect(Frame::Error(
+                    format!("MOVED {} {}", slot, addr)
+                )),
+                None => Route::Redirect(Frame::Error(
+                    format!("CLUSTERDOWN Hash slot {} not served", slot)
+                )),
+            }
+        }
+    }
+
+    pub fn add_slots(&mut self, slots: &[u16]) -> Result<(), Frame> {
+        for slot in slots {
+            if self.slots[*slot as usize].is_some() {
+                return Err(Frame::Error(
+                    format!("ERR Slot {} is already busy", slot)
+                ));
+            }
+        }
+        for slot in slots {
+            self.slots[*slot as usize] = Some(self.myself.id.clone());
+        }
+        Ok(())
+    }
+
+    pub fn meet(&mut self, addr: SocketAddr) {
+        let known = self.nodes.values().any(|n| n.addr == addr);
+        if !known && !self.handshakes.contains(&addr) {
+            self.handshakes.push(addr);
+        }
+    }
+
+    pub fn set_slot(&mut self, slot: u16, action: &str, node_id: Option<&str>)
+        -> Result<(), Frame>
+    {
+        let node_id = match (action, node_id) {
+            ("STABLE", _) => {
+                self.migrating.remove(&slot);
+                self.importing.remove(&slot);
+                return Ok(());
+            }
+            (_, Some(id)) if self.nodes.contains_key(id) => id.to_string(),
+            (_, Some(id)) => return Err(Frame::Error(
+                format!("ERR I don't know about node {}", id)
+            )),
+            (_, None) => return Err(Frame::Error(
+                "ERR syntax error".to_string()
+            )),
+        };
+        match action {
+            "MIGRATING" if !self.owns(slot) => Err(Frame::Error(
+                format!("ERR I'm not the owner of hash slot {}", slot)
+            )),
+            "MIGRATING" => {
+                self.migrating.insert(slot, node_id);
+                Ok(())
+            }
+            "IMPORTING" if self.owns(slot) => Err(Frame::Error(
+                format!("ERR I'm already the owner of hash slot {}", slot)
+            )),
+            "IMPORTING" => {
+                self.importing.insert(slot, node_id);
+                Ok(())
+            }
+            "NODE" => {
+                self.migrating.remove(&slot);
+                self.importing.remove(&slot);
+                self.slots[slot as usize] = Some(node_id);
+                Ok(())
+            }
+            _ => Err(Frame::Error("ERR syntax error".to_string())),
+        }
+    }
+
+    // Contiguous slot ranges served by each node, in slot order.
+    fn slot_ranges(&self) -> Vec<(u16, u16, &str)> {
+        let mut ranges: Vec<(u16, u16, &str)> = vec![];
+        for (slot, owner) in self.slots.iter().enumerate() {
+            let slot = slot as u16;
+            match (owner, ranges.last_mut()) {
+                (Some(owner), Some((_, end, id)))
+                    if *id == owner.as_str() && *end + 1 == slot => *end = slot,
+                (Some(owner), _) => ranges.push((slot, slot, owner)),
+                (None, _) => (),
+            }
+        }
+        ranges
+    }
+
+    pub fn slots_reply(&self) -> Frame {
+        let ranges = self.slot_ranges().into_iter()
+            .filter_map(|(start, end, id)| {
+                let addr = self.node_addr(id)?;
+                Some(Frame::Array(vec![
+                    Frame::Integer(start as i64),
+                    Frame::Integer(end as i64),
+                    Frame::Array(vec![
+                        Frame::bulk(addr.ip().to_string()),
+                        Frame::Integer(addr.port() as i64),
+                        Frame::bulk(id),
+                    ]),
+                ]))
+            })
+            .collect();
+        Frame::Array(ranges)
+    }
+
+    /// Renders the cluster view in the `CLUSTER NODES` line format.
+    pub fn nodes_reply(&self) -> String {
+        let ranges = self.slot_ranges();
+        let mut nodes = self.nodes.values().collect::<Vec<_>>();
+        nodes.sort_by_key(|n| n.id != self.myself.id);
+        let mut out = String::new();
+        for node in nodes {
+            let flags = if node.id == self.myself.id {
+                "myself,master"
+            } else {
+                "master"
+            };
+            out.push_str(&format!(
+                "{} {}@{} {} - 0 0 0 connected",
+                node.id, node.addr, node.addr.port() as u32 + 10000, flags
+            ));
+            for (start, end, _) in ranges.iter().filter(|r| r.2 == node.id) {
+                if start == end {
+                    out.push_str(&format!(" {}", start));
+                } else {
+                    out.push_str(&format!(" {}-{}", start, end));
+                }
+            }
+            if node.id == self.myself.id {
+                for (slot, target) in &self.migrating {
+                    out.push_str(&format!(" [{}->-{}]", slot, target));
+                }
+                for (slot, source) in &self.importing {
+                    out.push_str(&format!(" [{}-<-{}]", slot, source));
+                }
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Merges the CLUSTER NODES output of the peer at `addr` into our view.
+    /// Peers are trusted for the slots they claim themselves, but never for
+    /// slots this node serves.
+    pub fn merge(&mut self, addr: SocketAddr, nodes: &str) {
+        for line in nodes.lines() {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            if fields.len() < 8 {
+                continue;
+            }
+            let id = fields[0].to_string();
+            let node_addr = match fields[1].split('@').next()
+                .and_then(|a| a.parse::<SocketAddr>().ok())
+            {
+                Some(a) => a,
+                None => continue,
+            };
+            if id == self.myself.id {
+                continue;
+            }
+            let is_peer = fields[2].split(',').any(|f| f == "myself");
+            let node_addr = if is_peer { addr } else { node_addr };
+            self.handshakes.retain(|a| *a != node_addr);
+            self.nodes.retain(|nid, n| *nid == id || n.addr != node_addr);
+            self.nodes.insert(id.clone(), Node { id: id.clone(), addr: node_addr });
+            if !is_peer {
+                continue;
+            }
+            for range in fields[8..].iter().filter(|r| !r.starts_with('[')) {
+                let mut bounds = range.splitn(2, '-')
+                    .filter_map(|s| s.parse::<u16>().ok());
+                let start = match bounds.next() {
+                    Some(s) => s,
+                    None => continue,
+                };
+                let end = bounds.next().unwrap_or(start).min(CLUSTER_SLOTS - 1);
+                for slot in start..=end {
+                    if !self.owns(slot) {
+                        self.slots[slot as usize] = Some(id.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn myself_addr(&self) -> SocketAddr {
+        self.myself.addr
+    }
+
+    /// Addresses of every other node we know, or have been told to meet.
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        self.nodes.values()
+            .filter(|n| n.id != self.myself.id)
+            .map(|n| n.addr)
+            .chain(self.handshakes.iter().cloned())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(port: u16, slots: &[u16]) -> ClusterState {
+        let mut state = ClusterState::new();
+        state.enable(([127, 0, 0, 1], port).into());
+        state.add_slots(slots).unwrap();
+        state
+    }
+
+    #[test]
+    fn merge_learns_peers_and_their_slots_but_keeps_ours() {
+        let mut a = node(7001, &[0, 1, 2]);
+        let b = node(7002, &[2, 3, 4, 10]);
+        a.meet(b.myself_addr());
+        assert_eq!(a.peers(), vec![b.myself_addr()]);
+
+        // the peer may list itself under another address than we reach it at
+        a.merge(([127, 0, 0, 1], 7002).into(), &b.nodes_reply().replace("127.0.0.1:7002@", "10.0.0.2:7002@"));
+        assert_eq!(a.peers(), vec![b.myself_addr()]);
+        assert_eq!(b.nodes_reply().lines().next().unwrap().split(' ').skip(8).collect::<Vec<_>>(), ["2-4", "10"]);
+        for slot in [0, 1, 2] {
+            assert!(a.owns(slot), "slot {}", slot);
+        }
+        for slot in [3, 4, 10] {
+            assert_eq!(a.slots[slot as usize].as_deref(), Some(b.myid()), "slot {}", slot);
+        }
+        assert!(a.nodes_reply().contains(&format!("{} 127.0.0.1:7001@17001 myself,master - 0 0 0 connected 0-2", a.myid())));
+
+        // nodes the peer knows are learned at the address it knows them by
+        let mut c = node(7003, &[]);
+        c.merge(a.myself_addr(), &a.nodes_reply());
+        let mut peers = c.peers();
+        peers.sort();
+        assert_eq!(peers, vec![a.myself_addr(), b.myself_addr()]);
+        // but only the peer itself is trusted with its slots
+        assert_eq!(c.slots[3], None);
+    }
+
+    #[test]
+    fn slots_are_claimed_once() {
+        let mut a = node(7001, &[5]);
+        assert!(a.add_slots(&[4, 5]).is_err());
+        assert_eq!(a.slots[4], None);
+        assert!(a.set_slot(5, "MIGRATING", Some("unknown")).is_err());
+        let myid = a.myid().to_owned();
+        assert!(a.set_slot(6, "MIGRATING", Some(&myid)).is_err());
+    }
+}
@@ -0,0 +1,37 @@
+use std::io;
+use bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::frame::Frame;
+
+/// Frames RESP over any async byte stream, for use with `tokio_util`'s
+/// `Framed`.
+pub struct RespCodec;
+
+impl Encoder<Frame> for RespCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: Frame, buf: &mut BytesMut) -> io::Result<()> {
+        let bytes = frame.to_bytes();
+        buf.reserve(bytes.len());
+        buf.extend(bytes);
+        Ok(())
+    }
+}
+
+impl Decoder for RespCodec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    // Decodes a single frame from the front of the buffer, leaving any
+    // pipelined frames that follow it for the next call.
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Frame>> {
+        match Frame::parse(buf)? {
+            Some((frame, used)) => {
+                buf.advance(used);
+                Ok(Some(frame))
+            }
+            None => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,631 @@
+// The command table and the command implementations.
+
+use crate::cluster::{key_hash_slot, CLUSTER_SLOTS};
+use crate::diagnostics::Diagnostics;
+use crate::dump;
+use crate::encoding::Limits;
+use crate::engine::Engine;
+use crate::frame::Frame;
+use crate::glob::glob_match;
+use crate::hashes;
+use crate::persistence::{parse_save_mode, shutting_down};
+use crate::keys::{self, expires_at};
+use crate::lazyfree::LazyFreeConfig;
+use crate::session::{Clients, Session};
+use crate::lists;
+use crate::sets;
+use crate::sort;
+use crate::store::{wrong_type, Value};
+use crate::tracking::Tracking;
+use crate::zsets;
+
+pub(crate) type Reply = Result<Frame, Frame>;
+
+type Handler = fn(&Engine, &mut Session, &[Vec<u8>]) -> Reply;
+
+pub(crate) struct CommandSpec {
+    pub(crate) name: &'static str,
+    // number of arguments including the command name, negative means at least
+    arity: i64,
+    // position of the first and last key argument and the step between
+    // keys, zero when the command takes no keys; a negative last key
+    // counts from the end
+    first_key: usize,
+    last_key: i64,
+    step: usize,
+    // as reported by COMMAND, e.g. "write" or "readonly"
+    flags: &'static [&'static str],
+    pub(crate) handler: Handler,
+}
+
+impl CommandSpec {
+    pub(crate) fn check_arity(&self, argc: usize) -> Result<(), Frame> {
+        let argc = argc as i64;
+        if (self.arity >= 0 && argc != self.arity) || argc < -self.arity {
+            return Err(Frame::error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                self.name.to_lowercase()
+            )));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn keys<'a>(&self, args: &'a [Vec<u8>]) -> Vec<&'a [u8]> {
+        if self.first_key == 0 {
+            return vec![];
+        }
+        let last = if self.last_key < 0 {
+            args.len() as i64 + self.last_key
+        } else {
+            self.last_key
+        };
+        (self.first_key..=last.max(0) as usize)
+            .step_by(self.step.max(1))
+            .filter_map(|i| args.get(i).map(|k| k.as_slice()))
+            .collect()
+    }
+
+    pub(crate) fn has_flag(&self, flag: &str) -> bool {
+        self.flags.contains(&flag)
+    }
+
+    // The entry describing the command in the reply to COMMAND.
+    fn info(&self) -> Frame {
+        Frame::Array(vec![
+            Frame::bulk(self.name.to_lowercase()),
+            Frame::Integer(self.arity),
+            Frame::Array(self.flags.iter().map(|f| Frame::Simple(f.to_string())).collect()),
+            Frame::Integer(self.first_key as i64),
+            Frame::Integer(self.last_key),
+            Frame::Integer(self.step as i64),
+        ])
+    }
+}
+
+macro_rules! command {
+    ($name:expr, $arity:expr, $first:expr, $last:expr, $step:expr, $flags:expr, $handler:expr) => {
+        CommandSpec {
+            name: $name,
+            arity: $arity,
+            first_key: $first,
+            last_key: $last,
+            step: $step,
+            flags: $flags,
+            handler: $handler,
+        }
+    };
+}
+
+static COMMANDS: &[CommandSpec] = &[
+    command!("GET", 2, 1, 1, 1, &["readonly", "fast"], get),
+    command!("SET", -3, 1, 1, 1, &["write"], set),
+    command!("INCR", 2, 1, 1, 1, &["write", "fast"], incr),
+    command!("DECR", 2, 1, 1, 1, &["write", "fast"], decr),
+    command!("INCRBY", 3, 1, 1, 1, &["write", "fast"], incrby),
+    command!("DECRBY", 3, 1, 1, 1, &["write", "fast"], decrby),
+    command!("DEL", -2, 1, -1, 1, &["write"], keys::del),
+    command!("UNLINK", -2, 1, -1, 1, &["write", "fast"], keys::unlink),
+    command!("FLUSHALL", -1, 0, 0, 0, &["write"], keys::flushall),
+    command!("FLUSHDB", -1, 0, 0, 0, &["write"], keys::flushall),
+    command!("EXISTS", -2, 1, -1, 1, &["readonly", "fast"], keys::exists),
+    command!("TYPE", 2, 1, 1, 1, &["readonly", "fast"], keys::key_type),
+    command!("EXPIRE", 3, 1, 1, 1, &["write", "fast"], keys::expire),
+    command!("PEXPIRE", 3, 1, 1, 1, &["write", "fast"], keys::pexpire),
+    command!("EXPIREAT", 3, 1, 1, 1, &["write", "fast"], keys::expireat),
+    command!("PEXPIREAT", 3, 1, 1, 1, &["write", "fast"], keys::pexpireat),
+    command!("TTL", 2, 1, 1, 1, &["readonly", "fast"], keys::ttl),
+    command!("PTTL", 2, 1, 1, 1, &["readonly", "fast"], keys::pttl),
+    command!("PERSIST", 2, 1, 1, 1, &["write", "fast"], keys::persist),
+    command!("DUMP", 2, 1, 1, 1, &["readonly"], dump::dump),
+    command!("RESTORE", -4, 1, 1, 1, &["write"], dump::restore),
+    // the keys are found by the command itself, MIGRATE is not routed
+    command!("MIGRATE", -6, 0, 0, 0, &["write"], dump::migrate),
+    command!("LPUSH", -3, 1, 1, 1, &["write", "fast"], lists::lpush),
+    command!("RPUSH", -3, 1, 1, 1, &["write", "fast"], lists::rpush),
+    command!("LPOP", -2, 1, 1, 1, &["write", "fast"], lists::lpop),
+    command!("RPOP", -2, 1, 1, 1, &["write", "fast"], lists::rpop),
+    command!("LLEN", 2, 1, 1, 1, &["readonly", "fast"], lists::llen),
+    command!("LRANGE", 4, 1, 1, 1, &["readonly"], lists::lrange),
+    command!("HSET", -4, 1, 1, 1, &["write", "fast"], hashes::hset),
+    command!("HGET", 3, 1, 1, 1, &["readonly", "fast"], hashes::hget),
+    command!("HMGET", -3, 1, 1, 1, &["readonly", "fast"], hashes::hmget),
+    command!("HDEL", -3, 1, 1, 1, &["write", "fast"], hashes::hdel),
+    command!("HLEN", 2, 1, 1, 1, &["readonly", "fast"], hashes::hlen),
+    command!("HEXISTS", 3, 1, 1, 1, &["readonly", "fast"], hashes::hexists),
+    command!("HGETALL", 2, 1, 1, 1, &["readonly"], hashes::hgetall),
+    command!("HKEYS", 2, 1, 1, 1, &["readonly"], hashes::hkeys),
+    command!("HVALS", 2, 1, 1, 1, &["readonly"], hashes::hvals),
+    command!("HINCRBY", 4, 1, 1, 1, &["write", "fast"], hashes::hincrby),
+    command!("SADD", -3, 1, 1, 1, &["write", "fast"], sets::sadd),
+    command!("SREM", -3, 1, 1, 1, &["write", "fast"], sets::srem),
+    command!("SISMEMBER", 3, 1, 1, 1, &["readonly", "fast"], sets::sismember),
+    command!("SMEMBERS", 2, 1, 1, 1, &["readonly"], sets::smembers),
+    command!("SCARD", 2, 1, 1, 1, &["readonly", "fast"], sets::scard),
+    command!("ZADD", -4, 1, 1, 1, &["write", "fast"], zsets::zadd),
+    command!("ZINCRBY", 4, 1, 1, 1, &["write", "fast"], zsets::zincrby),
+    command!("ZREM", -3, 1, 1, 1, &["write", "fast"], zsets::zrem),
+    command!("ZSCORE", 3, 1, 1, 1, &["readonly", "fast"], zsets::zscore),
+    command!("ZCARD", 2, 1, 1, 1, &["readonly", "fast"], zsets::zcard),
+    command!("ZRANK", 3, 1, 1, 1, &["readonly", "fast"], zsets::zrank),
+    command!("ZRANGE", -4, 1, 1, 1, &["readonly"], zsets::zrange),
+    command!("SORT", -2, 1, 1, 1, &["write", "movablekeys"], sort::sort),
+    command!("SORT_RO", -2, 1, 1, 1, &["readonly"], sort::sort_ro),
+    command!("OBJECT", -2, 2, 2, 1, &["readonly"], keys::object),
+    command!("MEMORY", -2, 2, 2, 1, &["readonly"], keys::memory),
+    command!("SCAN", -2, 0, 0, 0, &["readonly"], scan),
+    command!("COMMAND", -1, 0, 0, 0, &[], command),
+    command!("CLUSTER", -2, 0, 0, 0, &[], cluster),
+    command!("ASKING", 1, 0, 0, 0, &["fast"], asking),
+    command!("MONITOR", 1, 0, 0, 0, &["admin"], monitor),
+    command!("SLOWLOG", -2, 0, 0, 0, &["admin"], slowlog),
+    command!("LATENCY", -2, 0, 0, 0, &["admin"], latency),
+    command!("CONFIG", -2, 0, 0, 0, &["admin"], config),
+    command!("SAVE", 1, 0, 0, 0, &["admin"], save),
+    command!("SHUTDOWN", -1, 0, 0, 0, &["admin"], shutdown),
+    command!("PING", -1, 0, 0, 0, &["fast"], ping),
+    command!("HELLO", -1, 0, 0, 0, &["fast"], hello),
+    command!("CLIENT", -2, 0, 0, 0, &[], client),
+    command!("PUBLISH", 3, 0, 0, 0, &["pubsub", "fast"], publish),
+    command!("SUBSCRIBE", -2, 0, 0, 0, &["pubsub"], subscribe),
+    command!("UNSUBSCRIBE", -1, 0, 0, 0, &["pubsub"], unsubscribe),
+];
+
+// The only commands a client subscribed to a channel may send.
+pub(crate) const PUBSUB_COMMANDS: &[&str] = &["PING", "SUBSCRIBE", "UNSUBSCRIBE"];
+
+pub(crate) fn lookup(name: &[u8]) -> Option<&'static CommandSpec> {
+    COMMANDS.iter().find(|c| c.name.as_bytes().eq_ignore_ascii_case(name))
+}
+
+fn ok() -> Reply {
+    Ok(Frame::ok())
+}
+
+fn str_args(args: &[Vec<u8>]) -> Result<Vec<&str>, Frame> {
+    args.iter()
+        .map(|a| std::str::from_utf8(a).map_err(|_| Frame::error("ERR Invalid argument")))
+        .collect()
+}
+
+pub(crate) fn unknown_subcommand(subcommand: &str) -> Frame {
+    Frame::error(format!(
+        "ERR Unknown subcommand or wrong number of arguments for '{}'",
+        subcommand
+    ))
+}
+
+pub(crate) fn parse_int(arg: &[u8]) -> Result<i64, Frame> {
+    std::str::from_utf8(arg).ok()
+        .and_then(|n| n.parse().ok())
+        .ok_or_else(|| Frame::error("ERR value is not an integer or out of range"))
+}
+
+fn get(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let store = engine.store();
+    match store.get(&args[1]) {
+        Some(Value::Str(s)) => Ok(Frame::bulk(s.as_slice())),
+        Some(_) => Err(wrong_type()),
+        None => Ok(Frame::Null),
+    }
+}
+
+// SET key value [NX|XX] [GET] [EX s|PX ms|EXAT ts|PXAT ms-ts|KEEPTTL]
+fn set(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let syntax_error = || Frame::error("ERR syntax error");
+    let mut condition = None;
+    let mut get = false;
+    let mut expiry = None;
+    let mut keep_ttl = false;
+    let mut options = args[3..].iter();
+    while let Some(option) = options.next() {
+        let option = option.to_ascii_uppercase();
+        match option.as_slice() {
+            b"NX" | b"XX" if condition.is_none() => condition = Some(option),
+            b"GET" => get = true,
+            b"KEEPTTL" if expiry.is_none() => keep_ttl = true,
+            b"EX" | b"PX" | b"EXAT" | b"PXAT" if expiry.is_none() && !keep_ttl => {
+                let time = options.next().ok_or_else(syntax_error)?;
+                let unit = if option.starts_with(b"E") { 1000 } else { 1 };
+                if parse_int(time)? <= 0 {
+                    return Err(Frame::error("ERR invalid expire time in 'set' command"));
+                }
+                expiry = Some(expires_at(time, !option.ends_with(b"AT"), unit)? as u64);
+            }
+            _ => return Err(syntax_error()),
+        }
+    }
+    let mut store = engine.store();
+    let old = match store.get(&args[1]) {
+        Some(Value::Str(s)) => Frame::bulk(s.as_slice()),
+        Some(_) if get => return Err(wrong_type()),
+        Some(_) => Frame::Null,
+        None => Frame::Null,
+    };
+    let exists = store.contains(&args[1]);
+    let skip = match condition.as_deref() {
+        Some(b"NX") => exists,
+        Some(_) => !exists,
+        None => false,
+    };
+    if !skip {
+        if keep_ttl {
+            expiry = store.expiry(&args[1]);
+        }
+        store.set_with_expiry(args[1].to_vec(), Value::Str(args[2].to_vec()), expiry);
+    }
+    match (get, skip) {
+        (true, _) => Ok(old),
+        (false, true) => Ok(Frame::Null),
+        (false, false) => ok(),
+    }
+}
+
+// A missing key counts as 0, an existing one keeps its expiry.
+fn increment(engine: &Engine, key: &[u8], by: i64) -> Reply {
+    let mut store = engine.store();
+    let current = match store.get(key) {
+        Some(Value::Str(s)) => parse_int(s)?,
+        Some(_) => return Err(wrong_type()),
+        None => 0,
+    };
+    let value = current.checked_add(by)
+        .ok_or_else(|| Frame::error("ERR increment or decrement would overflow"))?;
+    let expires_at = store.expiry(key);
+    store.set_with_expiry(key.to_vec(), Value::Str(value.to_string().into_bytes()), expires_at);
+    Ok(Frame::Integer(value))
+}
+
+fn incr(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    increment(engine, &args[1], 1)
+}
+
+fn decr(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    increment(engine, &args[1], -1)
+}
+
+fn incrby(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    increment(engine, &args[1], parse_int(&args[2])?)
+}
+
+fn decrby(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let by = parse_int(&args[2])?.checked_neg()
+        .ok_or_else(|| Frame::error("ERR decrement would overflow"))?;
+    increment(engine, &args[1], by)
+}
+
+fn command(_: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let args = str_args(&args[1..])?;
+    let subcommand = args.first().map(|s| s.to_uppercase()).unwrap_or_default();
+    match (subcommand.as_str(), args.get(1..).unwrap_or(&[])) {
+        ("", _) => Ok(Frame::Array(COMMANDS.iter().map(CommandSpec::info).collect())),
+        ("COUNT", []) => Ok(Frame::Integer(COMMANDS.len() as i64)),
+        ("INFO", names) => Ok(Frame::Array(names.iter()
+            .map(|name| lookup(name.as_bytes()).map(CommandSpec::info).unwrap_or(Frame::NullArray))
+            .collect())),
+        _ => Err(unknown_subcommand(&subcommand)),
+    }
+}
+
+// The cursor is the place in the keyspace to continue from, see
+// `Store::scan`. A key that was not there for the whole scan may or may not
+// be returned.
+fn scan(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let invalid_cursor = || Frame::error("ERR invalid cursor");
+    let cursor = std::str::from_utf8(&args[1]).ok()
+        .and_then(|c| c.parse::<usize>().ok())
+        .ok_or_else(invalid_cursor)?;
+    let mut pattern = None;
+    let mut count = 10;
+    for option in args[2..].chunks(2) {
+        match option {
+            [name, value] if name.eq_ignore_ascii_case(b"MATCH") => pattern = Some(value),
+            [name, value] if name.eq_ignore_ascii_case(b"COUNT") => {
+                count = std::str::from_utf8(value).ok()
+                    .and_then(|c| c.parse::<usize>().ok())
+                    .filter(|c| *c > 0)
+                    .ok_or_else(|| Frame::error("ERR value is not an integer or out of range"))?;
+            }
+            _ => return Err(Frame::error("ERR syntax error")),
+        }
+    }
+    let store = engine.store();
+    let (next, keys) = store.scan(cursor, count);
+    let batch = keys.into_iter()
+        .filter(|k| pattern.is_none_or(|p| glob_match(p, k)))
+        .map(|k| Frame::bulk(k.as_slice()))
+        .collect();
+    Ok(Frame::Array(vec![Frame::bulk(next.to_string()), Frame::Array(batch)]))
+}
+
+fn asking(_: &Engine, session: &mut Session, _: &[Vec<u8>]) -> Reply {
+    session.asking = true;
+    ok()
+}
+
+fn monitor(engine: &Engine, session: &mut Session, _: &[Vec<u8>]) -> Reply {
+    if let Some(client) = engine.clients().get_mut(session.id) {
+        client.monitor = true;
+    }
+    ok()
+}
+
+fn slowlog(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    engine.diagnostics().slowlog(&str_args(&args[1..])?)
+}
+
+fn latency(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    engine.diagnostics().latency(&str_args(&args[1..])?)
+}
+
+fn config(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let args = str_args(&args[1..])?;
+    let subcommand = args[0].to_uppercase();
+    match (subcommand.as_str(), &args[1..]) {
+        ("GET", [pattern]) => {
+            let pattern = pattern.to_lowercase();
+            let params = Diagnostics::CONFIG_NAMES.iter()
+                .chain(Limits::CONFIG_NAMES)
+                .chain(LazyFreeConfig::CONFIG_NAMES)
+                .chain(["maxclients"].iter())
+                .filter(|n| pattern == "*" || **n == pattern)
+                .filter_map(|n| {
+                    let value = match *n {
+                        "maxclients" => Some(engine.clients().max_clients.to_string()),
+                        n if Limits::CONFIG_NAMES.contains(&n) => engine.store().limits().get_config(n),
+                        n if LazyFreeConfig::CONFIG_NAMES.contains(&n) => {
+                            engine.store().lazyfree_config().get_config(n)
+                        }
+                        _ => engine.diagnostics().get_config(n),
+                    }?;
+                    Some(vec![Frame::bulk(*n), Frame::bulk(value)])
+                })
+                .flatten()
+                .collect();
+            Ok(Frame::Array(params))
+        }
+        ("SET", [name, value]) => {
+            match name.to_lowercase().as_str() {
+                "maxclients" => {
+                    engine.clients().max_clients = value.parse().map_err(|_| {
+                        Frame::error(format!(
+                            "ERR Invalid argument '{}' for CONFIG SET 'maxclients'", value
+                        ))
+                    })?;
+                }
+                name if Limits::CONFIG_NAMES.contains(&name) => {
+                    engine.store().limits_mut().set_config(name, value)?;
+                }
+                name if LazyFreeConfig::CONFIG_NAMES.contains(&name) => {
+                    engine.store().lazyfree_config_mut().set_config(name, value)?;
+                }
+                name => engine.diagnostics().set_config(name, value)?,
+            }
+            ok()
+        }
+        _ => Err(unknown_subcommand(&subcommand)),
+    }
+}
+
+fn save(engine: &Engine, _: &mut Session, _: &[Vec<u8>]) -> Reply {
+    engine.save_snapshot()
+        .map(|_| Frame::ok())
+        .map_err(|e| Frame::error(format!("ERR {}", e)))
+}
+
+// The reply to SHUTDOWN is the error every other client gets as well.
+fn shutdown(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    engine.request_shutdown(parse_save_mode(&args[1..])?);
+    Err(shutting_down())
+}
+
+fn parse_slot(slot: &str) -> Result<u16, Frame> {
+    slot.parse::<u16>().ok()
+        .filter(|slot| *slot < CLUSTER_SLOTS)
+        .ok_or_else(|| Frame::error("ERR Invalid or out of range slot"))
+}
+
+fn cluster(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    if !engine.cluster().read().unwrap().is_enabled() {
+        return Err(Frame::error("ERR This instance has cluster support disabled"));
+    }
+    if args[1].eq_ignore_ascii_case(b"KEYSLOT") && args.len() == 3 {
+        return Ok(Frame::Integer(key_hash_slot(&args[2]) as i64));
+    }
+    let args = str_args(&args[1..])?;
+    let subcommand = args[0].to_uppercase();
+    match (subcommand.as_str(), &args[1..]) {
+        ("MYID", []) => {
+            Ok(Frame::bulk(engine.cluster().read().unwrap().myid()))
+        }
+        ("SLOTS", []) => Ok(engine.cluster().read().unwrap().slots_reply()),
+        ("NODES", []) => Ok(Frame::bulk(engine.cluster().read().unwrap().nodes_reply())),
+        ("ADDSLOTS", slots) if !slots.is_empty() => {
+            let slots = slots.iter().map(|s| parse_slot(s))
+                .collect::<Result<Vec<_>, _>>()?;
+            engine.cluster().write().unwrap().add_slots(&slots)?;
+            ok()
+        }
+        ("MEET", [ip, port]) => {
+            let addr = format!("{}:{}", ip, port).parse()
+                .map_err(|_| Frame::error(
+                    format!("ERR Invalid node address specified: {}:{}", ip, port)
+                ))?;
+            engine.cluster().write().unwrap().meet(addr);
+            ok()
+        }
+        ("SETSLOT", [slot, action, node_id @ ..]) if node_id.len() <= 1 => {
+            let slot = parse_slot(slot)?;
+            engine.cluster().write().unwrap()
+                .set_slot(slot, &action.to_uppercase(), node_id.first().copied())?;
+            ok()
+        }
+        ("COUNTKEYSINSLOT", [slot]) => {
+            let slot = parse_slot(slot)?;
+            let count = engine.store().keys()
+                .filter(|k| key_hash_slot(k) == slot)
+                .count();
+            Ok(Frame::Integer(count as i64))
+        }
+        ("GETKEYSINSLOT", [slot, count]) => {
+            let slot = parse_slot(slot)?;
+            let count = count.parse::<usize>()
+                .map_err(|_| Frame::error("ERR Invalid number of keys"))?;
+            let keys = engine.store().keys()
+                .filter(|k| key_hash_slot(k) == slot)
+                .take(count)
+                .map(|k| Frame::bulk(k.as_slice()))
+                .collect();
+            Ok(Frame::Array(keys))
+        }
+        _ => Err(unknown_subcommand(&subcommand)),
+    }
+}
+
+fn ping(_: &Engine, session: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let message = match args {
+        [_] => None,
+        [_, message] => Some(message),
+        _ => return Err(Frame::error("ERR wrong number of arguments for 'ping' command")),
+    };
+    // subscribed RESP2 clients get a reply that can't be mistaken for a message
+    if session.is_subscribed() && !session.is_resp3() {
+        let message = message.map(|m| m.as_slice()).unwrap_or(b"");
+        return Ok(Frame::Array(vec![Frame::bulk("pong"), Frame::bulk(message)]));
+    }
+    Ok(match message {
+        Some(message) => Frame::bulk(message.as_slice()),
+        None => Frame::Simple("PONG".to_string()),
+    })
+}
+
+fn hello(engine: &Engine, session: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let resp3 = match args.get(1).map(|v| v.as_slice()) {
+        None => session.is_resp3(),
+        Some(b"2") => false,
+        Some(b"3") => true,
+        Some(_) => return Err(Frame::error("NOPROTO unsupported protocol version")),
+    };
+    if args.len() > 2 {
+        return Err(Frame::error("ERR syntax error"));
+    }
+    if let Some(client) = engine.clients().get_mut(session.id) {
+        client.resp3 = resp3;
+    }
+    let mode = if engine.cluster().read().unwrap().is_enabled() { "cluster" } else { "standalone" };
+    Ok(Frame::Array(vec![
+        Frame::bulk("server"), Frame::bulk("rudis"),
+        Frame::bulk("version"), Frame::bulk(env!("CARGO_PKG_VERSION")),
+        Frame::bulk("proto"), Frame::Integer(if resp3 { 3 } else { 2 }),
+        Frame::bulk("id"), Frame::Integer(session.id as i64),
+        Frame::bulk("mode"), Frame::bulk(mode),
+        Frame::bulk("role"), Frame::bulk("master"),
+        Frame::bulk("modules"), Frame::Array(vec![]),
+    ]))
+}
+
+fn client(engine: &Engine, session: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let subcommand = String::from_utf8_lossy(&args[1]).to_uppercase();
+    let mut clients = engine.clients();
+    match (subcommand.as_str(), &args[2..]) {
+        ("ID", []) => Ok(Frame::Integer(session.id as i64)),
+        ("TRACKING", [on_off, options @ ..]) if on_off.eq_ignore_ascii_case(b"ON") => {
+            let tracking = Tracking::parse(options)?;
+            let redirect = tracking.redirect();
+            if redirect.is_some_and(|id| clients.get_mut(id).is_none()) {
+                return Err(Frame::error("ERR The client ID you want redirect to does not exist"));
+            }
+            let client = clients.get_mut(session.id).ok_or_else(shutting_down)?;
+            if !client.resp3 && redirect.is_none() {
+                return Err(Frame::error(
+                    "ERR Client tracking in RESP2 needs a REDIRECT to a client subscribed to __redis__:invalidate"
+                ));
+            }
+            clients.start_tracking(session.id, tracking);
+            ok()
+        }
+        ("TRACKING", [on_off]) if on_off.eq_ignore_ascii_case(b"OFF") => {
+            clients.untrack(session.id);
+            ok()
+        }
+        ("CACHING", [yes_no]) => {
+            let tracking = clients.get_mut(session.id).and_then(|c| c.tracking.as_ref());
+            let caching = match yes_no.to_ascii_uppercase().as_slice() {
+                b"YES" if tracking.is_some_and(|t| t.optin()) => true,
+                b"NO" if tracking.is_some_and(|t| t.optout()) => false,
+                b"YES" | b"NO" => return Err(Frame::error(
+                    "ERR CLIENT CACHING YES is only valid when tracking is enabled in OPTIN mode, NO in OPTOUT mode"
+                )),
+                _ => return Err(Frame::error("ERR syntax error")),
+            };
+            session.caching = Some(caching);
+            ok()
+        }
+        ("GETREDIR", []) => {
+            let redirect = match clients.get_mut(session.id).and_then(|c| c.tracking.as_ref()) {
+                Some(tracking) => tracking.redirect().map_or(0, |id| id as i64),
+                None => -1,
+            };
+            Ok(Frame::Integer(redirect))
+        }
+        _ => Err(unknown_subcommand(&subcommand)),
+    }
+}
+
+fn publish(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let receivers = engine.clients().publish(&args[1], &args[2]);
+    Ok(Frame::Integer(receivers as i64))
+}
+
+// (UN)SUBSCRIBE answers with one reply per channel. The first one is the
+// command's reply, the others are pushed right behind it, before any
+// message published to the channels since the registry is still locked.
+fn subscription_replies(clients: &mut Clients, session: &Session, replies: Vec<Vec<Frame>>) -> Reply {
+    let client = clients.get_mut(session.id).ok_or_else(shutting_down)?;
+    let mut replies = replies.into_iter().map(|reply| client.pubsub_frame(reply));
+    let first = replies.next().unwrap_or(Frame::Null);
+    for reply in replies {
+        client.push(reply);
+    }
+    Ok(first)
+}
+
+fn subscription_reply(kind: &str, channel: Option<&[u8]>, count: usize) -> Vec<Frame> {
+    vec![
+        Frame::bulk(kind),
+        channel.map(Frame::bulk).unwrap_or(Frame::Null),
+        Frame::Integer(count as i64),
+    ]
+}
+
+fn subscribe(engine: &Engine, session: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let mut clients = engine.clients();
+    let replies = args[1..].iter()
+        .map(|channel| {
+            let count = clients.subscribe(session.id, channel);
+            subscription_reply("subscribe", Some(channel), count)
+        })
+        .collect();
+    subscription_replies(&mut clients, session, replies)
+}
+
+fn unsubscribe(engine: &Engine, session: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let mut clients = engine.clients();
+    // no channels means every channel the client is subscribed to
+    let channels = match &args[1..] {
+        [] => clients.get_mut(session.id)
+            .map(|c| c.channels.iter().cloned().collect())
+            .unwrap_or_default(),
+        channels => channels.to_vec(),
+    };
+    let replies = if channels.is_empty() {
+        vec![subscription_reply("unsubscribe", None, 0)]
+    } else {
+        channels.iter()
+            .map(|channel| {
+                let count = clients.unsubscribe(session.id, channel);
+                subscription_reply("unsubscribe", Some(channel), count)
+            })
+            .collect()
+    };
+    subscription_replies(&mut clients, session, replies)
+}
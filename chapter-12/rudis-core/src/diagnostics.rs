@@ -0,0 +1,310 @@
+// Command timing: the slow log, the latency monitor, MONITOR feeds and
+// per command call counts and latency histograms.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::frame::Frame;
+
+// Limits borrowed from Redis, so a huge MSET does not blow up the slow log.
+const SLOWLOG_MAX_ARGC: usize = 32;
+const SLOWLOG_MAX_ARGLEN: usize = 128;
+const LATENCY_HISTORY_LEN: usize = 160;
+
+/// Upper bounds of the command latency histogram buckets, in microseconds.
+pub const LATENCY_BUCKETS: &[u64] = &[
+    100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 1_000_000,
+];
+
+/// The calls of one command since the server started.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandStats {
+    pub calls: u64,
+    /// Calls answered with an error reply.
+    pub failed_calls: u64,
+    pub duration: Duration,
+    /// Calls per bucket of `LATENCY_BUCKETS`, the last one counts the
+    /// calls slower than every bucket.
+    pub buckets: Vec<u64>,
+}
+
+impl Default for CommandStats {
+    fn default() -> Self {
+        CommandStats {
+            calls: 0,
+            failed_calls: 0,
+            duration: Duration::ZERO,
+            buckets: vec![0; LATENCY_BUCKETS.len() + 1],
+        }
+    }
+}
+
+struct SlowLogEntry {
+    id: u64,
+    timestamp: u64,
+    duration: Duration,
+    args: Vec<String>,
+    client: String,
+}
+
+#[derive(Default)]
+struct LatencyEvent {
+    // (unix time, latency in ms), at most one sample per second
+    history: VecDeque<(u64, u64)>,
+    max: u64,
+}
+
+pub struct Diagnostics {
+    slowlog: VecDeque<SlowLogEntry>,
+    slowlog_next_id: u64,
+    // in microseconds, negative disables the slow log
+    slowlog_slower_than: i64,
+    slowlog_max_len: usize,
+    // in milliseconds, zero disables the latency monitor
+    latency_threshold: u64,
+    latency: BTreeMap<String, LatencyEvent>,
+    commands: BTreeMap<&'static str, CommandStats>,
+}
+
+fn unix_time() -> Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default()
+}
+
+fn arg_str(arg: &[u8]) -> String {
+    String::from_utf8_lossy(arg).into_owned()
+}
+
+// Quotes an argument the way MONITOR shows it, escaping anything unprintable.
+fn quoted(arg: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for b in arg {
+        match b {
+            b'\\' => out.push_str("\\\\"),
+            b'"' => out.push_str("\\\""),
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            b if b.is_ascii_graphic() || *b == b' ' => out.push(*b as char),
+            b => out.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Formats a command the way MONITOR clients see it.
+pub fn monitor_line(args: &[Vec<u8>], client: &str) -> Frame {
+    let now = unix_time();
+    let args = args.iter()
+        .map(|a| quoted(a))
+        .collect::<Vec<_>>()
+        .join(" ");
+    Frame::Simple(format!(
+        "{}.{:06} [0 {}] {}", now.as_secs(), now.subsec_micros(), client, args
+    ))
+}
+
+impl Default for Diagnostics {
+    fn default() -> Self {
+        Diagnostics::new()
+    }
+}
+
+impl Diagnostics {
+    pub fn new() -> Self {
+        Diagnostics {
+            slowlog: VecDeque::new(),
+            slowlog_next_id: 0,
+            slowlog_slower_than: 10_000,
+            slowlog_max_len: 128,
+            latency_threshold: 0,
+            latency: BTreeMap::new(),
+            commands: BTreeMap::new(),
+        }
+    }
+
+    /// Records how long a command took in the slow log and latency monitor.
+    pub fn record(&mut self, args: &[Vec<u8>], client: &str, duration: Duration) {
+        let now = unix_time().as_secs();
+        if self.slowlog_slower_than >= 0
+            && duration.as_micros() >= self.slowlog_slower_than as u128
+        {
+            let mut logged = args.iter()
+                .take(SLOWLOG_MAX_ARGC)
+                .map(|a| {
+                    let a = arg_str(a);
+                    if a.len() > SLOWLOG_MAX_ARGLEN {
+                        let cut = (0..=SLOWLOG_MAX_ARGLEN).rev()
+                            .find(|i| a.is_char_boundary(*i))
+                            .unwrap_or(0);
+                        format!("{}... ({} more bytes)", &a[..cut], a.len() - cut)
+                    } else {
+                        a
+                    }
+                })
+                .collect::<Vec<_>>();
+            if args.len() > SLOWLOG_MAX_ARGC {
+                logged[SLOWLOG_MAX_ARGC - 1] = format!(
+                    "... ({} more arguments)", args.len() - SLOWLOG_MAX_ARGC + 1
+                );
+            }
+            self.slowlog.push_front(SlowLogEntry {
+                id: self.slowlog_next_id,
+                timestamp: now,
+                duration,
+                args: logged,
+                client: client.to_string(),
+            });
+            self.slowlog_next_id += 1;
+            self.slowlog.truncate(self.slowlog_max_len);
+        }
+        let ms = duration.as_millis() as u64;
+        if self.latency_threshold > 0 && ms >= self.latency_threshold {
+            let event = self.latency.entry("command".to_string()).or_default();
+            match event.history.back_mut() {
+                Some((ts, latency)) if *ts == now => *latency = ms.max(*latency),
+                _ => event.history.push_back((now, ms)),
+            }
+            if event.history.len() > LATENCY_HISTORY_LEN {
+                event.history.pop_front();
+            }
+            event.max = event.max.max(ms);
+        }
+    }
+
+    /// Counts a call of a known command, `name` as in the command table.
+    pub fn count_call(&mut self, name: &'static str, duration: Duration, failed: bool) {
+        let stats = self.commands.entry(name).or_default();
+        stats.calls += 1;
+        stats.failed_calls += failed as u64;
+        stats.duration += duration;
+        let us = duration.as_micros() as u64;
+        let bucket = LATENCY_BUCKETS.iter().position(|le| us <= *le).unwrap_or(LATENCY_BUCKETS.len());
+        stats.buckets[bucket] += 1;
+    }
+
+    /// The commands called at least once, by name.
+    pub fn command_stats(&self) -> Vec<(&'static str, CommandStats)> {
+        self.commands.iter().map(|(name, stats)| (*name, stats.clone())).collect()
+    }
+
+    pub fn slowlog(&mut self, args: &[&str]) -> Result<Frame, Frame> {
+        let subcommand = args.first().map(|s| s.to_uppercase()).unwrap_or_default();
+        match (subcommand.as_str(), &args[1.min(args.len())..]) {
+            ("GET", count) if count.len() <= 1 => {
+                let count = match count.first() {
+                    Some(c) => c.parse::<i64>().map_err(|_| Frame::Error(
+                        "ERR value is out of range, must be positive".to_string()
+                    ))?,
+                    None => 10,
+                };
+                let count = if count < 0 { self.slowlog.len() } else { count as usize };
+                let entries = self.slowlog.iter().take(count).map(|e| {
+                    Frame::Array(vec![
+                        Frame::Integer(e.id as i64),
+                        Frame::Integer(e.timestamp as i64),
+                        Frame::Integer(e.duration.as_micros() as i64),
+                        Frame::Array(
+                            e.args.iter().map(|a| Frame::bulk(a.as_str())).collect()
+                        ),
+                        Frame::bulk(e.client.as_str()),
+                        Frame::bulk(""),
+                    ])
+                }).collect();
+                Ok(Frame::Array(entries))
+            }
+            ("LEN", []) => Ok(Frame::Integer(self.slowlog.len() as i64)),
+            ("RESET", []) => {
+                self.slowlog.clear();
+                Ok(Frame::ok())
+            }
+            _ => Err(Frame::Error(format!(
+                "ERR Unknown subcommand or wrong number of arguments for '{}'",
+                subcommand
+            ))),
+        }
+    }
+
+    pub fn latency(&mut self, args: &[&str]) -> Result<Frame, Frame> {
+        let subcommand = args.first().map(|s| s.to_uppercase()).unwrap_or_default();
+        match (subcommand.as_str(), &args[1.min(args.len())..]) {
+            ("LATEST", []) => {
+                let latest = self.latency.iter()
+                    .filter_map(|(name, event)| {
+                        let (ts, ms) = event.history.back()?;
+                        Some(Frame::Array(vec![
+                            Frame::bulk(name.as_str()),
+                            Frame::Integer(*ts as i64),
+                            Frame::Integer(*ms as i64),
+                            Frame::Integer(event.max as i64),
+                        ]))
+                    })
+                    .collect();
+                Ok(Frame::Array(latest))
+            }
+            ("HISTORY", [name]) => {
+                let history = self.latency.get(*name)
+                    .map(|event| event.history.iter()
+                        .map(|(ts, ms)| Frame::Array(vec![
+                            Frame::Integer(*ts as i64),
+                            Frame::Integer(*ms as i64),
+                        ]))
+                        .collect())
+                    .unwrap_or_default();
+                Ok(Frame::Array(history))
+            }
+            ("RESET", names) => {
+                let reset = if names.is_empty() {
+                    let n = self.latency.len();
+                    self.latency.clear();
+                    n
+                } else {
+                    names.iter()
+                        .filter(|n| self.latency.remove(**n).is_some())
+                        .count()
+                };
+                Ok(Frame::Integer(reset as i64))
+            }
+            _ => Err(Frame::Error(format!(
+                "ERR Unknown subcommand or wrong number of arguments for '{}'",
+                subcommand
+            ))),
+        }
+    }
+
+    pub fn get_config(&self, name: &str) -> Option<String> {
+        match name {
+            "slowlog-log-slower-than" => Some(self.slowlog_slower_than.to_string()),
+            "slowlog-max-len" => Some(self.slowlog_max_len.to_string()),
+            "latency-monitor-threshold" => Some(self.latency_threshold.to_string()),
+            _ => None,
+        }
+    }
+
+    pub fn set_config(&mut self, name: &str, value: &str) -> Result<(), Frame> {
+        let invalid = || Frame::Error(format!(
+            "ERR Invalid argument '{}' for CONFIG SET '{}'", value, name
+        ));
+        match name {
+            "slowlog-log-slower-than" => {
+                self.slowlog_slower_than = value.parse().map_err(|_| invalid())?;
+            }
+            "slowlog-max-len" => {
+                self.slowlog_max_len = value.parse().map_err(|_| invalid())?;
+                self.slowlog.truncate(self.slowlog_max_len);
+            }
+            "latency-monitor-threshold" => {
+                self.latency_threshold = value.parse().map_err(|_| invalid())?;
+            }
+            _ => return Err(Frame::Error(format!(
+                "ERR Unknown option or number of arguments for CONFIG SET - '{}'",
+                name
+            ))),
+        }
+        Ok(())
+    }
+
+    pub const CONFIG_NAMES: &'static [&'static str] = &[
+        "slowlog-log-slower-than", "slowlog-max-len", "latency-monitor-threshold",
+    ];
+}
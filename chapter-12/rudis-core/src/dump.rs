@@ -0,0 +1,274 @@
+// DUMP, RESTORE and MIGRATE. A DUMP payload is the value in the snapshot
+// encoding, followed by the absolute expiry time (u64, 0 for none), the
+// payload version (u16) and a CRC64 of everything before it, all little
+// endian. Nothing but rudis is expected to read it.
+
+use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::commands::{parse_int, Reply};
+use crate::encoding::Limits;
+use crate::engine::Engine;
+use crate::frame::{Frame, FrameReader};
+use crate::session::Session;
+use crate::store::{now_ms, Value};
+
+const PAYLOAD_VERSION: u16 = 1;
+// the expiry, version and checksum
+const TRAILER_LEN: usize = 8 + 2 + 8;
+
+// CRC-64/Jones, the checksum Redis uses for its DUMP payloads as well.
+const CRC64_POLY: u64 = 0x95ac_9329_ac4b_c9b5;
+
+const CRC64_TABLE: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ CRC64_POLY } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc64(data: &[u8]) -> u64 {
+    data.iter().fold(0, |crc, b| CRC64_TABLE[((crc ^ *b as u64) & 0xff) as usize] ^ (crc >> 8))
+}
+
+pub(crate) fn serialize(value: &Value, expires_at: Option<u64>) -> Vec<u8> {
+    let mut payload = value.to_frame().to_bytes();
+    payload.extend_from_slice(&expires_at.unwrap_or(0).to_le_bytes());
+    payload.extend_from_slice(&PAYLOAD_VERSION.to_le_bytes());
+    let crc = crc64(&payload);
+    payload.extend_from_slice(&crc.to_le_bytes());
+    payload
+}
+
+pub(crate) fn deserialize(payload: &[u8], limits: &Limits) -> Result<(Value, Option<u64>), Frame> {
+    let invalid = || Frame::error("ERR DUMP payload version or checksum are wrong");
+    let body_len = payload.len().checked_sub(TRAILER_LEN).ok_or_else(invalid)?;
+    let (checked, crc) = payload.split_at(payload.len() - 8);
+    let version = u16::from_le_bytes(checked[checked.len() - 2..].try_into().unwrap());
+    if version != PAYLOAD_VERSION || crc64(checked) != u64::from_le_bytes(crc.try_into().unwrap()) {
+        return Err(invalid());
+    }
+    let bad_data = || Frame::error("ERR Bad data format");
+    let value = match Frame::parse_unlimited(&payload[..body_len]) {
+        Ok(Some((frame, used))) if used == body_len => Value::from_frame(frame, limits),
+        _ => None,
+    }.ok_or_else(bad_data)?;
+    let expires_at = u64::from_le_bytes(payload[body_len..body_len + 8].try_into().unwrap());
+    Ok((value, Some(expires_at).filter(|at| *at > 0)))
+}
+
+pub(crate) fn dump(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let store = engine.store();
+    Ok(match store.get(&args[1]) {
+        Some(value) => Frame::Bulk(serialize(value, store.expiry(&args[1]))),
+        None => Frame::Null,
+    })
+}
+
+// A TTL of 0 keeps the expiry recorded in the payload.
+pub(crate) fn restore(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let mut replace = false;
+    let mut absttl = false;
+    for option in &args[4..] {
+        match option.to_ascii_uppercase().as_slice() {
+            b"REPLACE" => replace = true,
+            b"ABSTTL" => absttl = true,
+            _ => return Err(Frame::error("ERR syntax error")),
+        }
+    }
+    let ttl = parse_int(&args[2])?;
+    if ttl < 0 {
+        return Err(Frame::error("ERR Invalid TTL value, must be >= 0"));
+    }
+    let mut store = engine.store();
+    let (value, recorded) = deserialize(&args[3], &store.limits())?;
+    let expires_at = match ttl as u64 {
+        0 => recorded,
+        at if absttl => Some(at),
+        ttl => Some(now_ms().saturating_add(ttl)),
+    };
+    if !replace && store.contains(&args[1]) {
+        return Err(Frame::error("BUSYKEY Target key name already exists."));
+    }
+    // a key that expired on the way is not created at all
+    if expires_at.is_some_and(|at| at <= now_ms()) {
+        store.remove(&args[1]);
+    } else {
+        store.set_with_expiry(args[1].to_vec(), value, expires_at);
+    }
+    Ok(Frame::ok())
+}
+
+struct MigrateOptions {
+    copy: bool,
+    replace: bool,
+    keys: Vec<Vec<u8>>,
+}
+
+fn parse_migrate(args: &[Vec<u8>]) -> Result<MigrateOptions, Frame> {
+    let mut options = MigrateOptions { copy: false, replace: false, keys: vec![] };
+    let mut rest = args[6..].iter();
+    while let Some(option) = rest.next() {
+        match option.to_ascii_uppercase().as_slice() {
+            b"COPY" => options.copy = true,
+            b"REPLACE" => options.replace = true,
+            b"KEYS" if args[3].is_empty() => {
+                options.keys = rest.by_ref().cloned().collect();
+                if options.keys.is_empty() {
+                    return Err(Frame::error("ERR syntax error"));
+                }
+            }
+            b"KEYS" => return Err(Frame::error(
+                "ERR When using MIGRATE KEYS option, the key argument must be set to the empty string"
+            )),
+            _ => return Err(Frame::error("ERR syntax error")),
+        }
+    }
+    if options.keys.is_empty() {
+        options.keys.push(args[3].to_vec());
+    }
+    Ok(options)
+}
+
+fn io_error(doing: &str) -> Frame {
+    Frame::error(format!("IOERR error or timeout {} target instance", doing))
+}
+
+// Sends the RESTORE commands in one pipeline and reads back one reply each.
+fn send_restores(addr: &str, timeout: Duration, asking: bool, restores: &[Frame]) -> Result<Vec<Frame>, Frame> {
+    let addr = addr.to_socket_addrs().ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| io_error("connecting to"))?;
+    let stream = TcpStream::connect_timeout(&addr, timeout)
+        .map_err(|_| io_error("connecting to"))?;
+    stream.set_read_timeout(Some(timeout)).and(stream.set_write_timeout(Some(timeout)))
+        .map_err(|_| io_error("connecting to"))?;
+    let mut request = vec![];
+    for restore in restores {
+        // the target is importing the slot while a cluster is resharded
+        if asking {
+            Frame::command(["ASKING"]).encode(&mut request);
+        }
+        restore.encode(&mut request);
+    }
+    let mut reader = FrameReader::new(stream);
+    reader.get_mut().write_all(&request).map_err(|_| io_error("writing to"))?;
+    let mut replies = Vec::with_capacity(restores.len());
+    for _ in restores {
+        if asking {
+            reader.read_frame().ok().flatten().ok_or_else(|| io_error("reading from"))?;
+        }
+        replies.push(reader.read_frame().ok().flatten().ok_or_else(|| io_error("reading from"))?);
+    }
+    Ok(replies)
+}
+
+// MIGRATE host port key|"" db timeout [COPY] [REPLACE] [KEYS key ...]
+//
+// The store stays locked until the target acknowledged the keys, so no
+// client sees them change half way through the move. Like in Redis the
+// whole server waits for the target, `timeout` bounds how long.
+pub(crate) fn migrate(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let options = parse_migrate(args)?;
+    if parse_int(&args[4])? != 0 {
+        return Err(Frame::error("ERR DB index is out of range"));
+    }
+    let timeout = match parse_int(&args[5])? {
+        ms if ms <= 0 => 1000,
+        ms => ms as u64,
+    };
+    let addr = format!("{}:{}", String::from_utf8_lossy(&args[1]), String::from_utf8_lossy(&args[2]));
+    let asking = engine.cluster().read().unwrap().is_enabled();
+
+    let mut store = engine.store();
+    let mut keys = vec![];
+    let mut restores = vec![];
+    for key in &options.keys {
+        let value = match store.get(key) {
+            Some(value) => value,
+            None => continue,
+        };
+        // what is left of the TTL, RESTORE wants 0 for none
+        let ttl = store.expiry(key).map_or(0, |at| at.saturating_sub(now_ms()).max(1));
+        let mut restore = vec![
+            b"RESTORE".to_vec(), key.to_vec(), ttl.to_string().into_bytes(), serialize(value, None),
+        ];
+        if options.replace {
+            restore.push(b"REPLACE".to_vec());
+        }
+        keys.push(key.as_slice());
+        restores.push(Frame::command(restore));
+    }
+    if keys.is_empty() {
+        return Ok(Frame::Simple("NOKEY".to_string()));
+    }
+    let replies = send_restores(&addr, Duration::from_millis(timeout), asking, &restores)?;
+
+    // keys the target refused stay here, the first error is reported
+    let mut error = None;
+    let mut moved = vec![];
+    for (key, reply) in keys.iter().zip(replies) {
+        match reply {
+            Frame::Error(e) => {
+                error.get_or_insert(e);
+            }
+            _ => moved.push(*key),
+        }
+    }
+    if !options.copy {
+        for key in &moved {
+            store.remove(key);
+        }
+        drop(store);
+        engine.clients().invalidate(&moved, None);
+    }
+    match error {
+        Some(e) => Err(Frame::error(format!("ERR Target instance replied with error: {}", e))),
+        None => Ok(Frame::ok()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc64_matches_redis() {
+        assert_eq!(crc64(b"123456789"), 0xe9c6_d914_c4b8_d9ca);
+    }
+
+    #[test]
+    fn payload_roundtrip() {
+        let value = Value::List(vec![b"a".to_vec(), b"b\r\n".to_vec()].into());
+        let payload = serialize(&value, Some(1234));
+        assert_eq!(deserialize(&payload, &Limits::default()), Ok((value, Some(1234))));
+        let payload = serialize(&Value::Str(b"v".to_vec()), None);
+        assert_eq!(deserialize(&payload, &Limits::default()), Ok((Value::Str(b"v".to_vec()), None)));
+    }
+
+    #[test]
+    fn payloads_bigger_than_a_request_roundtrip() {
+        let list = (0..=crate::frame::MAX_ARRAY_LEN).map(|i| i.to_string().into_bytes()).collect();
+        let value = Value::List(list);
+        let payload = serialize(&value, None);
+        assert_eq!(deserialize(&payload, &Limits::default()), Ok((value, None)));
+    }
+
+    #[test]
+    fn corrupt_payloads_are_refused() {
+        let mut payload = serialize(&Value::Str(b"value".to_vec()), None);
+        payload[5] ^= 1;
+        assert!(deserialize(&payload, &Limits::default()).is_err());
+        assert!(deserialize(b"short", &Limits::default()).is_err());
+    }
+}
@@ -0,0 +1,465 @@
+// The in-memory encodings of hashes, sets and sorted sets. Small values are
+// kept in flat vectors, the way Redis packs them into listpacks and intsets:
+// one allocation, no per-entry overhead, and scanning a handful of entries
+// is as fast as hashing. Past the configured limits a value is converted to
+// a full hash table or ordered index, and never back.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::frame::Frame;
+
+/// Sizes past which a compact encoding is converted, as set through
+/// CONFIG SET under the same names as in Redis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub hash_max_listpack_entries: usize,
+    pub hash_max_listpack_value: usize,
+    pub set_max_intset_entries: usize,
+    pub set_max_listpack_entries: usize,
+    pub set_max_listpack_value: usize,
+    pub zset_max_listpack_entries: usize,
+    pub zset_max_listpack_value: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            hash_max_listpack_entries: 128,
+            hash_max_listpack_value: 64,
+            set_max_intset_entries: 512,
+            set_max_listpack_entries: 128,
+            set_max_listpack_value: 64,
+            zset_max_listpack_entries: 128,
+            zset_max_listpack_value: 64,
+        }
+    }
+}
+
+impl Limits {
+    pub const CONFIG_NAMES: &'static [&'static str] = &[
+        "hash-max-listpack-entries", "hash-max-listpack-value",
+        "set-max-intset-entries", "set-max-listpack-entries", "set-max-listpack-value",
+        "zset-max-listpack-entries", "zset-max-listpack-value",
+    ];
+
+    fn field(&mut self, name: &str) -> Option<&mut usize> {
+        Some(match name {
+            "hash-max-listpack-entries" => &mut self.hash_max_listpack_entries,
+            "hash-max-listpack-value" => &mut self.hash_max_listpack_value,
+            "set-max-intset-entries" => &mut self.set_max_intset_entries,
+            "set-max-listpack-entries" => &mut self.set_max_listpack_entries,
+            "set-max-listpack-value" => &mut self.set_max_listpack_value,
+            "zset-max-listpack-entries" => &mut self.zset_max_listpack_entries,
+            "zset-max-listpack-value" => &mut self.zset_max_listpack_value,
+            _ => return None,
+        })
+    }
+
+    pub fn get_config(&self, name: &str) -> Option<String> {
+        let mut limits = *self;
+        limits.field(name).map(|value| value.to_string())
+    }
+
+    /// Values already converted stay as they are, like in Redis.
+    pub fn set_config(&mut self, name: &str, value: &str) -> Result<(), Frame> {
+        let invalid = || Frame::Error(format!(
+            "ERR Invalid argument '{}' for CONFIG SET '{}'", value, name
+        ));
+        let parsed = value.parse().map_err(|_| invalid())?;
+        *self.field(name).ok_or_else(invalid)? = parsed;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Hash {
+    // fields in insertion order
+    Listpack(Vec<(Vec<u8>, Vec<u8>)>),
+    Table(HashMap<Vec<u8>, Vec<u8>>),
+}
+
+impl Default for Hash {
+    fn default() -> Self {
+        Hash::Listpack(vec![])
+    }
+}
+
+impl Hash {
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Hash::Listpack(_) => "listpack",
+            Hash::Table(_) => "hashtable",
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Hash::Listpack(entries) => entries.len(),
+            Hash::Table(entries) => entries.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, field: &[u8]) -> Option<&[u8]> {
+        match self {
+            Hash::Listpack(entries) => entries.iter()
+                .find(|(f, _)| f == field)
+                .map(|(_, v)| v.as_slice()),
+            Hash::Table(entries) => entries.get(field).map(|v| v.as_slice()),
+        }
+    }
+
+    /// Returns true if the field is new.
+    pub fn insert(&mut self, field: Vec<u8>, value: Vec<u8>, limits: &Limits) -> bool {
+        if let Hash::Listpack(entries) = self {
+            let too_long = field.len().max(value.len()) > limits.hash_max_listpack_value;
+            if let Some(entry) = entries.iter_mut().find(|(f, _)| *f == field) {
+                if !too_long {
+                    entry.1 = value;
+                    return false;
+                }
+            } else if !too_long && entries.len() < limits.hash_max_listpack_entries {
+                entries.push((field, value));
+                return true;
+            }
+            *self = Hash::Table(std::mem::take(entries).into_iter().collect());
+        }
+        match self {
+            Hash::Table(entries) => entries.insert(field, value).is_none(),
+            Hash::Listpack(_) => unreachable!("converted above"),
+        }
+    }
+
+    pub fn remove(&mut self, field: &[u8]) -> bool {
+        match self {
+            Hash::Listpack(entries) => match entries.iter().position(|(f, _)| f == field) {
+                Some(i) => {
+                    entries.remove(i);
+                    true
+                }
+                None => false,
+            },
+            Hash::Table(entries) => entries.remove(field).is_some(),
+        }
+    }
+
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (&[u8], &[u8])> + '_> {
+        match self {
+            Hash::Listpack(entries) => Box::new(entries.iter().map(|(f, v)| (f.as_slice(), v.as_slice()))),
+            Hash::Table(entries) => Box::new(entries.iter().map(|(f, v)| (f.as_slice(), v.as_slice()))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Set {
+    // sorted, for sets of integers only
+    Intset(Vec<i64>),
+    Listpack(Vec<Vec<u8>>),
+    Table(HashSet<Vec<u8>>),
+}
+
+impl Default for Set {
+    fn default() -> Self {
+        Set::Intset(vec![])
+    }
+}
+
+// Only members that print back the same are integers, "007" is not.
+fn as_int(member: &[u8]) -> Option<i64> {
+    let n = std::str::from_utf8(member).ok()?.parse::<i64>().ok()?;
+    Some(n).filter(|n| n.to_string().as_bytes() == member)
+}
+
+impl Set {
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Set::Intset(_) => "intset",
+            Set::Listpack(_) => "listpack",
+            Set::Table(_) => "hashtable",
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Set::Intset(members) => members.len(),
+            Set::Listpack(members) => members.len(),
+            Set::Table(members) => members.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, member: &[u8]) -> bool {
+        match self {
+            Set::Intset(members) => as_int(member).is_some_and(|n| members.binary_search(&n).is_ok()),
+            Set::Listpack(members) => members.iter().any(|m| m == member),
+            Set::Table(members) => members.contains(member),
+        }
+    }
+
+    /// Returns true if the member is new.
+    pub fn insert(&mut self, member: Vec<u8>, limits: &Limits) -> bool {
+        if self.contains(&member) {
+            return false;
+        }
+        if let Set::Intset(members) = self {
+            match as_int(&member) {
+                Some(n) if members.len() < limits.set_max_intset_entries => {
+                    let at = members.binary_search(&n).unwrap_err();
+                    members.insert(at, n);
+                    return true;
+                }
+                _ => {
+                    let members = members.iter().map(|n| n.to_string().into_bytes()).collect();
+                    *self = Set::Listpack(members);
+                }
+            }
+        }
+        if let Set::Listpack(members) = self {
+            if members.len() < limits.set_max_listpack_entries
+                && member.len() <= limits.set_max_listpack_value
+            {
+                members.push(member);
+                return true;
+            }
+            *self = Set::Table(std::mem::take(members).into_iter().collect());
+        }
+        match self {
+            Set::Table(members) => members.insert(member),
+            _ => unreachable!("converted above"),
+        }
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self {
+            Set::Intset(members) => match as_int(member).map(|n| members.binary_search(&n)) {
+                Some(Ok(i)) => {
+                    members.remove(i);
+                    true
+                }
+                _ => false,
+            },
+            Set::Listpack(members) => match members.iter().position(|m| m == member) {
+                Some(i) => {
+                    members.swap_remove(i);
+                    true
+                }
+                None => false,
+            },
+            Set::Table(members) => members.remove(member),
+        }
+    }
+
+    pub fn members(&self) -> Vec<Vec<u8>> {
+        match self {
+            Set::Intset(members) => members.iter().map(|n| n.to_string().into_bytes()).collect(),
+            Set::Listpack(members) => members.clone(),
+            Set::Table(members) => members.iter().cloned().collect(),
+        }
+    }
+}
+
+/// A score that orders like Redis orders them. NaN never gets this far.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Score(pub f64);
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ZSet {
+    // ordered by score, then member
+    Listpack(Vec<(Score, Vec<u8>)>),
+    // the ordered index plays the part of Redis' skiplist
+    Skiplist {
+        scores: HashMap<Vec<u8>, Score>,
+        order: BTreeSet<(Score, Vec<u8>)>,
+    },
+}
+
+impl Default for ZSet {
+    fn default() -> Self {
+        ZSet::Listpack(vec![])
+    }
+}
+
+impl ZSet {
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            ZSet::Listpack(_) => "listpack",
+            ZSet::Skiplist { .. } => "skiplist",
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            ZSet::Listpack(entries) => entries.len(),
+            ZSet::Skiplist { scores, .. } => scores.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        match self {
+            ZSet::Listpack(entries) => entries.iter().find(|(_, m)| m == member).map(|(s, _)| s.0),
+            ZSet::Skiplist { scores, .. } => scores.get(member).map(|s| s.0),
+        }
+    }
+
+    /// Sets the member's score, returns true if the member is new.
+    pub fn insert(&mut self, member: Vec<u8>, score: f64, limits: &Limits) -> bool {
+        let added = self.remove(&member).is_none();
+        let score = Score(score);
+        if let ZSet::Listpack(entries) = self {
+            if entries.len() < limits.zset_max_listpack_entries
+                && member.len() <= limits.zset_max_listpack_value
+            {
+                let entry = (score, member);
+                let at = entries.binary_search(&entry).unwrap_or_else(|at| at);
+                entries.insert(at, entry);
+                return added;
+            }
+            let entries = std::mem::take(entries);
+            *self = ZSet::Skiplist {
+                scores: entries.iter().map(|(s, m)| (m.clone(), *s)).collect(),
+                order: entries.into_iter().collect(),
+            };
+        }
+        if let ZSet::Skiplist { scores, order } = self {
+            scores.insert(member.clone(), score);
+            order.insert((score, member));
+        }
+        added
+    }
+
+    /// Returns the score the member had.
+    pub fn remove(&mut self, member: &[u8]) -> Option<f64> {
+        match self {
+            ZSet::Listpack(entries) => {
+                let i = entries.iter().position(|(_, m)| m == member)?;
+                Some(entries.remove(i).0 .0)
+            }
+            ZSet::Skiplist { scores, order } => {
+                let score = scores.remove(member)?;
+                order.remove(&(score, member.to_vec()));
+                Some(score.0)
+            }
+        }
+    }
+
+    /// Members from lowest to highest score.
+    pub fn iter(&self) -> Box<dyn DoubleEndedIterator<Item = (&[u8], f64)> + '_> {
+        match self {
+            ZSet::Listpack(entries) => Box::new(entries.iter().map(|(s, m)| (m.as_slice(), s.0))),
+            ZSet::Skiplist { order, .. } => Box::new(order.iter().map(|(s, m)| (m.as_slice(), s.0))),
+        }
+    }
+
+    pub fn rank(&self, member: &[u8]) -> Option<usize> {
+        let score = Score(self.score(member)?);
+        match self {
+            ZSet::Listpack(entries) => entries.binary_search(&(score, member.to_vec())).ok(),
+            ZSet::Skiplist { order, .. } => Some(order.range(..(score, member.to_vec())).count()),
+        }
+    }
+}
+
+/// How Redis prints scores: integers without a fraction, "inf" for infinity.
+pub(crate) fn format_score(score: f64) -> String {
+    if score.is_infinite() {
+        return if score > 0.0 { "inf" } else { "-inf" }.to_string();
+    }
+    score.to_string()
+}
+
+pub(crate) fn parse_score(arg: &[u8]) -> Result<f64, Frame> {
+    std::str::from_utf8(arg).ok()
+        .and_then(|s| s.parse::<f64>().ok())
+        .filter(|s| !s.is_nan())
+        .ok_or_else(|| Frame::error("ERR value is not a valid float"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn small() -> Limits {
+        Limits {
+            hash_max_listpack_entries: 2,
+            hash_max_listpack_value: 4,
+            set_max_intset_entries: 2,
+            set_max_listpack_entries: 3,
+            set_max_listpack_value: 4,
+            zset_max_listpack_entries: 2,
+            zset_max_listpack_value: 4,
+        }
+    }
+
+    #[test]
+    fn hashes_convert_past_the_limits() {
+        let limits = small();
+        let mut hash = Hash::default();
+        assert!(hash.insert(b"a".to_vec(), b"1".to_vec(), &limits));
+        assert!(!hash.insert(b"a".to_vec(), b"2".to_vec(), &limits));
+        assert_eq!(hash.encoding(), "listpack");
+        hash.insert(b"b".to_vec(), b"long value".to_vec(), &limits);
+        assert_eq!(hash.encoding(), "hashtable");
+        assert_eq!(hash.get(b"a"), Some(&b"2"[..]));
+        assert_eq!(hash.len(), 2);
+    }
+
+    #[test]
+    fn sets_go_from_intset_to_listpack_to_hashtable() {
+        let limits = small();
+        let mut set = Set::default();
+        set.insert(b"3".to_vec(), &limits);
+        set.insert(b"-1".to_vec(), &limits);
+        assert_eq!(set.encoding(), "intset");
+        assert_eq!(set.members(), vec![b"-1".to_vec(), b"3".to_vec()]);
+        set.insert(b"007".to_vec(), &limits);
+        assert_eq!(set.encoding(), "listpack");
+        assert!(set.contains(b"3") && set.contains(b"007"));
+        set.insert(b"x".to_vec(), &limits);
+        assert_eq!(set.encoding(), "hashtable");
+        assert!(set.remove(b"-1"));
+        assert_eq!(set.len(), 3);
+    }
+
+    #[test]
+    fn zsets_stay_ordered_in_both_encodings() {
+        let limits = small();
+        let mut zset = ZSet::default();
+        zset.insert(b"b".to_vec(), 2.0, &limits);
+        zset.insert(b"a".to_vec(), 2.0, &limits);
+        assert_eq!(zset.encoding(), "listpack");
+        assert_eq!(zset.rank(b"b"), Some(1));
+        zset.insert(b"c".to_vec(), 1.0, &limits);
+        assert_eq!(zset.encoding(), "skiplist");
+        let members = zset.iter().map(|(m, _)| m.to_vec()).collect::<Vec<_>>();
+        assert_eq!(members, vec![b"c".to_vec(), b"a".to_vec(), b"b".to_vec()]);
+        assert!(!zset.insert(b"c".to_vec(), 3.0, &limits));
+        assert_eq!(zset.rank(b"c"), Some(2));
+        assert_eq!(zset.remove(b"a"), Some(2.0));
+    }
+}
@@ -0,0 +1,742 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::cluster::{key_hash_slot, ClusterState, Route};
+use crate::commands::{lookup, CommandSpec, Reply, PUBSUB_COMMANDS};
+use crate::diagnostics::{monitor_line, CommandStats, Diagnostics};
+use crate::frame::Frame;
+use crate::persistence::{Persistence, SaveMode};
+use crate::session::{Clients, Pushes, Session};
+use crate::store::{now_ms, Store};
+
+/// How often frontends should call `Engine::expire_keys`.
+pub const EXPIRE_INTERVAL: Duration = Duration::from_millis(100);
+// Keys with an expiry looked at per round of `Engine::expire_keys`
+const EXPIRE_SAMPLES: usize = 20;
+// The longest `Engine::expire_keys` holds the store
+const EXPIRE_BUDGET: Duration = Duration::from_millis(1);
+
+/// Server wide counters, see `Engine::stats`.
+#[derive(Debug, Clone)]
+pub struct Stats {
+    pub commands: Vec<(&'static str, CommandStats)>,
+    pub connected_clients: usize,
+    /// Keys in the one database, counting expired keys not removed yet.
+    pub keys: usize,
+    pub expired_keys: u64,
+    /// There is no maxmemory policy yet, so nothing is ever evicted.
+    pub evicted_keys: u64,
+    /// Values unlinked from the keyspace and waiting to be freed.
+    pub lazyfree_pending_objects: usize,
+    pub persistence_enabled: bool,
+    /// When the last snapshot was attempted and whether it was written.
+    pub last_save: Option<(SystemTime, bool)>,
+}
+
+struct Shared {
+    store: Mutex<Store>,
+    cluster: RwLock<ClusterState>,
+    diagnostics: Mutex<Diagnostics>,
+    persistence: Mutex<Persistence>,
+    clients: Arc<Mutex<Clients>>,
+    shutdown: Mutex<Option<SaveMode>>,
+}
+
+/// The command execution engine. It knows nothing about sockets: a network
+/// frontend connects a `Session` per client and feeds it the client's frames.
+/// Cloning an engine gives another handle to the same data.
+#[derive(Clone)]
+pub struct Engine {
+    shared: Arc<Shared>,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Engine::new()
+    }
+}
+
+// A command is an array of bulk strings, anything else is refused.
+fn command_args(frame: Frame) -> Result<Vec<Vec<u8>>, Frame> {
+    let invalid = || Frame::error("ERR Protocol error: expected an array of bulk strings");
+    match frame {
+        Frame::Array(items) if !items.is_empty() => items.into_iter()
+            .map(|item| match item {
+                Frame::Bulk(arg) => Ok(arg),
+                _ => Err(invalid()),
+            })
+            .collect(),
+        _ => Err(invalid()),
+    }
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        Engine {
+            shared: Arc::new(Shared {
+                store: Mutex::new(Store::new()),
+                cluster: RwLock::new(ClusterState::new()),
+                diagnostics: Mutex::new(Diagnostics::new()),
+                persistence: Mutex::new(Persistence::new()),
+                clients: Arc::new(Mutex::new(Clients::new())),
+                shutdown: Mutex::new(None),
+            }),
+        }
+    }
+
+    /// Registers a new client. Fails with the error reply for the client
+    /// when `maxclients` is reached or the server is shutting down.
+    pub fn connect(&self, peer: impl ToString) -> Result<(Session, Pushes), Frame> {
+        Session::connect(&self.shared.clients, peer.to_string())
+    }
+
+    /// Runs a single command for the client and returns the reply.
+    pub fn execute(&self, session: &mut Session, frame: Frame) -> Frame {
+        let args = match command_args(frame) {
+            Ok(args) => args,
+            Err(e) => return e,
+        };
+        self.feed_monitors(&args, session);
+        let spec = lookup(&args[0]);
+        let start = Instant::now();
+        let reply = self.dispatch(session, spec, &args);
+        self.invalidate_expired();
+        let elapsed = start.elapsed();
+        let mut diagnostics = self.diagnostics();
+        diagnostics.record(&args, &session.peer, elapsed);
+        if let Some(spec) = spec {
+            diagnostics.count_call(spec.name, elapsed, reply.is_err());
+        }
+        match reply {
+            Ok(r) | Err(r) => r,
+        }
+    }
+
+    fn dispatch(&self, session: &mut Session, spec: Option<&CommandSpec>, args: &[Vec<u8>]) -> Reply {
+        let asking = std::mem::take(&mut session.asking);
+        let caching = session.caching.take();
+        let spec = spec.ok_or_else(|| Frame::error(format!(
+            "ERR unknown command '{}'", String::from_utf8_lossy(&args[0])
+        )))?;
+        spec.check_arity(args.len())?;
+        let pubsub_only = session.is_subscribed() && !session.is_resp3();
+        if pubsub_only && !PUBSUB_COMMANDS.contains(&spec.name) {
+            return Err(Frame::error(format!(
+                "ERR Can't execute '{}': only (UN)SUBSCRIBE / PING are allowed in this context",
+                spec.name.to_lowercase()
+            )));
+        }
+        if let Route::Redirect(redirect) = self.route(spec, args, asking) {
+            return Err(redirect);
+        }
+        self.track_reads(spec, args, session, caching);
+        let reply = (spec.handler)(self, session, args)?;
+        self.invalidate_writes(spec, args, session);
+        Ok(reply)
+    }
+
+    // Client side caching: remembers the keys a command reads. That happens
+    // before the read, so that a write landing in between is reported; the
+    // invalidation then reaches the client after the reply.
+    fn track_reads(&self, spec: &CommandSpec, args: &[Vec<u8>], session: &Session, caching: Option<bool>) {
+        // which key such a command writes, if any, depends on its
+        // arguments, so it tells the clients caching it itself
+        let reads = spec.has_flag("movablekeys")
+            || (spec.has_flag("readonly") && !spec.has_flag("write"));
+        let keys = spec.keys(args);
+        if reads && !keys.is_empty() {
+            self.clients().track(session.id, &keys, caching);
+        }
+    }
+
+    // Tells the clients caching the keys a command wrote.
+    fn invalidate_writes(&self, spec: &CommandSpec, args: &[Vec<u8>], session: &Session) {
+        let keys = spec.keys(args);
+        if spec.has_flag("write") && !spec.has_flag("movablekeys") && !keys.is_empty() {
+            self.clients().invalidate(&keys, Some(session.id));
+        }
+    }
+
+    /// Removes keys that expired without anyone touching them, a little at a
+    /// time: rounds of EXPIRE_SAMPLES keys, for as long as a good part of
+    /// a round had expired and the time budget lasts. Frontends call it
+    /// every EXPIRE_INTERVAL.
+    pub fn expire_keys(&self) {
+        let start = Instant::now();
+        {
+            let mut store = self.store();
+            let now = now_ms();
+            while store.expire_some(now, EXPIRE_SAMPLES) > EXPIRE_SAMPLES / 4
+                && start.elapsed() < EXPIRE_BUDGET {}
+        }
+        self.invalidate_expired();
+    }
+
+    // Keys found expired while running the command are gone for the
+    // clients caching them too.
+    fn invalidate_expired(&self) {
+        let expired = self.store().take_expired();
+        if !expired.is_empty() {
+            let keys = expired.iter().map(|k| k.as_slice()).collect::<Vec<_>>();
+            self.clients().invalidate(&keys, None);
+        }
+    }
+
+    // In cluster mode commands with keys have to be served by the node
+    // owning the keys' slot.
+    fn route(&self, spec: &CommandSpec, args: &[Vec<u8>], asking: bool) -> Route {
+        let cluster = self.cluster().read().unwrap();
+        if !cluster.is_enabled() {
+            return Route::Serve;
+        }
+        let keys = spec.keys(args);
+        let slot = match keys.first() {
+            Some(key) => key_hash_slot(key),
+            None => return Route::Serve,
+        };
+        if keys.iter().any(|k| key_hash_slot(k) != slot) {
+            return Route::Redirect(Frame::error(
+                "CROSSSLOT Keys in request don't hash to the same slot"
+            ));
+        }
+        let exists = {
+            let store = self.store();
+            keys.iter().all(|k| store.contains(k))
+        };
+        cluster.route(slot, exists, asking)
+    }
+
+    fn feed_monitors(&self, args: &[Vec<u8>], session: &Session) {
+        let clients = self.clients();
+        let mut monitors = clients.iter().filter(|c| c.monitor).peekable();
+        if monitors.peek().is_none() {
+            return;
+        }
+        let line = monitor_line(args, &session.peer);
+        for monitor in monitors {
+            monitor.push(line.clone());
+        }
+    }
+
+    pub(crate) fn store(&self) -> MutexGuard<'_, Store> {
+        self.shared.store.lock().unwrap()
+    }
+
+    pub(crate) fn diagnostics(&self) -> MutexGuard<'_, Diagnostics> {
+        self.shared.diagnostics.lock().unwrap()
+    }
+
+    pub(crate) fn clients(&self) -> MutexGuard<'_, Clients> {
+        self.shared.clients.lock().unwrap()
+    }
+
+    /// The cluster view of this node, for the frontend to keep in sync
+    /// with the other nodes.
+    pub fn cluster(&self) -> &RwLock<ClusterState> {
+        &self.shared.cluster
+    }
+
+    pub fn enable_cluster(&self, addr: SocketAddr) {
+        self.cluster().write().unwrap().enable(addr);
+    }
+
+    pub fn set_max_clients(&self, max_clients: usize) {
+        self.clients().max_clients = max_clients;
+    }
+
+    /// Turns on snapshots to `path` and loads the existing snapshot, if any.
+    pub fn enable_persistence(&self, path: &str) -> io::Result<()> {
+        let mut persistence = self.shared.persistence.lock().unwrap();
+        persistence.enable(path);
+        let limits = self.store().limits();
+        *self.store() = persistence.load(limits)?;
+        Ok(())
+    }
+
+    pub fn save_snapshot(&self) -> io::Result<()> {
+        let mut persistence = self.shared.persistence.lock().unwrap();
+        persistence.save(&self.store())
+    }
+
+    /// A snapshot of the counters a metrics exporter reports.
+    pub fn stats(&self) -> Stats {
+        let (keys, expired_keys, lazyfree_pending_objects) = {
+            let store = self.store();
+            (store.len(), store.expired_total(), store.lazyfree_pending())
+        };
+        let persistence = self.shared.persistence.lock().unwrap();
+        Stats {
+            commands: self.diagnostics().command_stats(),
+            connected_clients: self.clients().len(),
+            keys,
+            expired_keys,
+            evicted_keys: 0,
+            lazyfree_pending_objects,
+            persistence_enabled: persistence.is_enabled(),
+            last_save: persistence.last_save(),
+        }
+    }
+
+    pub fn request_shutdown(&self, mode: SaveMode) {
+        let mut shutdown = self.shared.shutdown.lock().unwrap();
+        shutdown.get_or_insert(mode);
+    }
+
+    pub fn shutdown_requested(&self) -> Option<SaveMode> {
+        *self.shared.shutdown.lock().unwrap()
+    }
+
+    /// Refuses new clients and ends the push streams of connected ones.
+    pub fn close_clients(&self) {
+        self.clients().close();
+    }
+
+    /// Saves the snapshot if the requested shutdown asks for it. Returns
+    /// whether a snapshot was written.
+    pub fn finish_shutdown(&self) -> io::Result<bool> {
+        let mode = self.shutdown_requested().unwrap_or(SaveMode::Default);
+        let mut persistence = self.shared.persistence.lock().unwrap();
+        if !persistence.should_save(mode) {
+            return Ok(false);
+        }
+        persistence.save(&self.store())?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(engine: &Engine, session: &mut Session, args: &[&str]) -> Frame {
+        engine.execute(session, Frame::command(args.iter().copied()))
+    }
+
+    #[test]
+    fn set_then_get() {
+        let engine = Engine::new();
+        let (mut session, _) = engine.connect("test").unwrap();
+        assert_eq!(run(&engine, &mut session, &["GET", "k"]), Frame::Null);
+        assert_eq!(run(&engine, &mut session, &["set", "k", "v"]), Frame::ok());
+        assert_eq!(run(&engine, &mut session, &["GET", "k"]), Frame::bulk("v"));
+    }
+
+    #[test]
+    fn counters_and_lists() {
+        let engine = Engine::new();
+        let (mut session, _) = engine.connect("test").unwrap();
+        assert_eq!(run(&engine, &mut session, &["INCR", "n"]), Frame::Integer(1));
+        assert_eq!(run(&engine, &mut session, &["INCRBY", "n", "41"]), Frame::Integer(42));
+        assert_eq!(run(&engine, &mut session, &["RPUSH", "l", "b", "c"]), Frame::Integer(2));
+        assert_eq!(run(&engine, &mut session, &["LPUSH", "l", "a"]), Frame::Integer(3));
+        assert_eq!(
+            run(&engine, &mut session, &["LRANGE", "l", "0", "-2"]),
+            Frame::Array(vec![Frame::bulk("a"), Frame::bulk("b")])
+        );
+        assert!(run(&engine, &mut session, &["INCR", "l"]).is_error());
+        assert!(run(&engine, &mut session, &["GET", "l"]).is_error());
+        assert_eq!(
+            run(&engine, &mut session, &["RPOP", "l", "5"]),
+            Frame::Array(vec![Frame::bulk("c"), Frame::bulk("b"), Frame::bulk("a")])
+        );
+        assert_eq!(run(&engine, &mut session, &["LLEN", "l"]), Frame::Integer(0));
+        assert_eq!(run(&engine, &mut session, &["LPOP", "l"]), Frame::Null);
+    }
+
+    #[test]
+    fn unlink_and_flush_free_in_the_background() {
+        let engine = Engine::new();
+        let (mut session, _) = engine.connect("test").unwrap();
+        let items = (0..1000).map(|i| i.to_string()).collect::<Vec<_>>();
+        let push = |session: &mut Session, key: &str| {
+            let args = ["RPUSH", key].into_iter().map(String::from).chain(items.iter().cloned());
+            engine.execute(session, Frame::command(args))
+        };
+        push(&mut session, "big");
+        run(&engine, &mut session, &["SET", "small", "1"]);
+        assert_eq!(run(&engine, &mut session, &["UNLINK", "big", "small", "missing"]), Frame::Integer(2));
+        assert_eq!(run(&engine, &mut session, &["EXISTS", "big", "small"]), Frame::Integer(0));
+
+        assert_eq!(
+            run(&engine, &mut session, &["CONFIG", "GET", "lazyfree-lazy-server-del"]),
+            Frame::Array(vec![Frame::bulk("lazyfree-lazy-server-del"), Frame::bulk("no")])
+        );
+        assert_eq!(run(&engine, &mut session, &["CONFIG", "SET", "lazyfree-lazy-server-del", "yes"]), Frame::ok());
+        assert!(run(&engine, &mut session, &["CONFIG", "SET", "lazyfree-lazy-expire", "maybe"]).is_error());
+        push(&mut session, "big");
+        assert_eq!(run(&engine, &mut session, &["SET", "big", "replaced"]), Frame::ok());
+
+        push(&mut session, "big");
+        assert!(run(&engine, &mut session, &["FLUSHALL", "LATER"]).is_error());
+        assert_eq!(run(&engine, &mut session, &["FLUSHDB", "ASYNC"]), Frame::ok());
+        assert_eq!(run(&engine, &mut session, &["EXISTS", "big"]), Frame::Integer(0));
+        for _ in 0..100 {
+            if engine.stats().lazyfree_pending_objects == 0 {
+                return;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        panic!("the background thread never freed the values");
+    }
+
+    #[test]
+    fn scan_returns_keys_there_all_along_once() {
+        let engine = Engine::new();
+        let (mut session, _) = engine.connect("test").unwrap();
+        for n in 0..100 {
+            run(&engine, &mut session, &["SET", &format!("stays:{}", n), "x"]);
+            run(&engine, &mut session, &["SET", &format!("goes:{}", n), "x"]);
+        }
+        let mut cursor = "0".to_owned();
+        let mut seen = Vec::new();
+        for round in 0.. {
+            let reply = run(&engine, &mut session, &["SCAN", &cursor, "COUNT", "10"]);
+            let Frame::Array(reply) = reply else { panic!("bad SCAN reply") };
+            let [Frame::Bulk(next), Frame::Array(keys)] = &reply[..] else { panic!("bad SCAN reply") };
+            assert!(keys.len() <= 10);
+            seen.extend(keys.iter().cloned());
+            cursor = String::from_utf8(next.clone()).unwrap();
+            if cursor == "0" {
+                break;
+            }
+            // the keyspace changes under the scan
+            run(&engine, &mut session, &["DEL", &format!("goes:{}", round)]);
+            run(&engine, &mut session, &["SET", &format!("new:{}", round), "x"]);
+            run(&engine, &mut session, &["SET", &format!("stays:{}", 99 - round), "y"]);
+        }
+        for n in 0..100 {
+            let key = Frame::bulk(format!("stays:{}", n));
+            assert_eq!(seen.iter().filter(|k| **k == key).count(), 1, "stays:{}", n);
+        }
+    }
+
+    #[test]
+    fn keys_nobody_touches_expire_too() {
+        let engine = Engine::new();
+        let (mut session, _) = engine.connect("test").unwrap();
+        for i in 0..100 {
+            run(&engine, &mut session, &["SET", &format!("k{}", i), "v", "PX", "1"]);
+        }
+        run(&engine, &mut session, &["SET", "kept", "v", "PX", "100000"]);
+        run(&engine, &mut session, &["SET", "persisted", "v", "PX", "1"]);
+        run(&engine, &mut session, &["PERSIST", "persisted"]);
+        run(&engine, &mut session, &["SET", "deleted", "v", "PX", "1"]);
+        run(&engine, &mut session, &["DEL", "deleted"]);
+        std::thread::sleep(Duration::from_millis(10));
+        for _ in 0..10 {
+            engine.expire_keys();
+        }
+        let stats = engine.stats();
+        assert_eq!(stats.keys, 2);
+        assert_eq!(stats.expired_keys, 100);
+        assert_eq!(run(&engine, &mut session, &["TTL", "persisted"]), Frame::Integer(-1));
+        assert!(matches!(run(&engine, &mut session, &["TTL", "kept"]), Frame::Integer(ttl) if ttl > 0));
+    }
+
+    #[test]
+    fn keys_expire_and_survive_dump_restore() {
+        let engine = Engine::new();
+        let (mut session, _) = engine.connect("test").unwrap();
+        assert_eq!(run(&engine, &mut session, &["SET", "k", "1", "PX", "100000"]), Frame::ok());
+        assert_eq!(run(&engine, &mut session, &["INCR", "k"]), Frame::Integer(2));
+        assert!(matches!(run(&engine, &mut session, &["PTTL", "k"]), Frame::Integer(ms) if ms > 99_000));
+        let payload = match run(&engine, &mut session, &["DUMP", "k"]) {
+            Frame::Bulk(payload) => payload,
+            other => panic!("unexpected DUMP reply {:?}", other),
+        };
+        let mut restore = |key: &str| engine.execute(&mut session, Frame::command([
+            b"RESTORE".to_vec(), key.as_bytes().to_vec(), b"0".to_vec(), payload.clone(),
+        ]));
+        assert_eq!(restore("k"), Frame::error("BUSYKEY Target key name already exists."));
+        assert_eq!(restore("copy"), Frame::ok());
+        assert_eq!(run(&engine, &mut session, &["GET", "copy"]), Frame::bulk("2"));
+        assert!(matches!(run(&engine, &mut session, &["TTL", "copy"]), Frame::Integer(100)));
+        assert_eq!(run(&engine, &mut session, &["PERSIST", "copy"]), Frame::Integer(1));
+        assert_eq!(run(&engine, &mut session, &["TTL", "copy"]), Frame::Integer(-1));
+        assert_eq!(run(&engine, &mut session, &["PEXPIRE", "k", "0"]), Frame::Integer(1));
+        assert_eq!(run(&engine, &mut session, &["EXISTS", "k", "copy"]), Frame::Integer(1));
+        assert_eq!(run(&engine, &mut session, &["TTL", "k"]), Frame::Integer(-2));
+        assert_eq!(run(&engine, &mut session, &["SET", "copy", "x", "NX"]), Frame::Null);
+        assert_eq!(run(&engine, &mut session, &["DEL", "copy", "k"]), Frame::Integer(1));
+    }
+
+    #[test]
+    fn small_collections_use_compact_encodings() {
+        let engine = Engine::new();
+        let (mut session, _) = engine.connect("test").unwrap();
+        let encoding = |session: &mut Session, key: &str| {
+            engine.execute(session, Frame::command(["OBJECT", "ENCODING", key]))
+        };
+        run(&engine, &mut session, &["CONFIG", "SET", "hash-max-listpack-entries", "2"]);
+        assert_eq!(run(&engine, &mut session, &["HSET", "h", "a", "1", "b", "2"]), Frame::Integer(2));
+        assert_eq!(encoding(&mut session, "h"), Frame::bulk("listpack"));
+        let small = run(&engine, &mut session, &["MEMORY", "USAGE", "h"]);
+        run(&engine, &mut session, &["HSET", "h", "c", "3"]);
+        assert_eq!(encoding(&mut session, "h"), Frame::bulk("hashtable"));
+        assert!(matches!((small, run(&engine, &mut session, &["MEMORY", "USAGE", "h"])),
+            (Frame::Integer(small), Frame::Integer(large)) if small < large));
+        assert_eq!(run(&engine, &mut session, &["HINCRBY", "h", "a", "9"]), Frame::Integer(10));
+
+        run(&engine, &mut session, &["SADD", "s", "1", "2"]);
+        assert_eq!(encoding(&mut session, "s"), Frame::bulk("intset"));
+        run(&engine, &mut session, &["SADD", "s", "x"]);
+        assert_eq!(encoding(&mut session, "s"), Frame::bulk("listpack"));
+        assert_eq!(run(&engine, &mut session, &["SREM", "s", "1", "2", "x"]), Frame::Integer(3));
+        assert_eq!(run(&engine, &mut session, &["TYPE", "s"]), Frame::Simple("none".to_string()));
+
+        run(&engine, &mut session, &["ZADD", "z", "2", "b", "1", "a"]);
+        assert_eq!(encoding(&mut session, "z"), Frame::bulk("listpack"));
+        assert_eq!(
+            run(&engine, &mut session, &["ZRANGE", "z", "0", "-1", "WITHSCORES"]),
+            Frame::Array(vec![Frame::bulk("a"), Frame::bulk("1"), Frame::bulk("b"), Frame::bulk("2")])
+        );
+        assert_eq!(run(&engine, &mut session, &["ZINCRBY", "z", "1.5", "a"]), Frame::bulk("2.5"));
+        assert_eq!(run(&engine, &mut session, &["ZRANK", "z", "a"]), Frame::Integer(1));
+        assert!(run(&engine, &mut session, &["HGET", "z", "a"]).is_error());
+    }
+
+    #[test]
+    fn sort_by_external_weights() {
+        let engine = Engine::new();
+        let (mut session, _) = engine.connect("test").unwrap();
+        let bulks = |items: &[&str]| Frame::Array(items.iter().map(|i| Frame::bulk(*i)).collect());
+        run(&engine, &mut session, &["RPUSH", "ids", "3", "1", "2"]);
+        assert_eq!(run(&engine, &mut session, &["SORT", "ids"]), bulks(&["1", "2", "3"]));
+        assert_eq!(run(&engine, &mut session, &["SORT", "ids", "DESC", "LIMIT", "0", "2"]), bulks(&["3", "2"]));
+        run(&engine, &mut session, &["SET", "weight_1", "30"]);
+        run(&engine, &mut session, &["SET", "weight_2", "10"]);
+        run(&engine, &mut session, &["SET", "weight_3", "20"]);
+        run(&engine, &mut session, &["HSET", "user:1", "name", "ann"]);
+        run(&engine, &mut session, &["HSET", "user:2", "name", "bob"]);
+        assert_eq!(
+            run(&engine, &mut session, &["SORT", "ids", "BY", "weight_*", "GET", "#", "GET", "user:*->name"]),
+            Frame::Array(vec![
+                Frame::bulk("2"), Frame::bulk("bob"),
+                Frame::bulk("3"), Frame::Null,
+                Frame::bulk("1"), Frame::bulk("ann"),
+            ])
+        );
+        assert_eq!(run(&engine, &mut session, &["SORT", "ids", "BY", "nosort"]), bulks(&["3", "1", "2"]));
+
+        run(&engine, &mut session, &["SADD", "names", "carol", "alice", "bob"]);
+        assert!(run(&engine, &mut session, &["SORT", "names"]).is_error());
+        assert_eq!(run(&engine, &mut session, &["SORT_RO", "names", "ALPHA"]), bulks(&["alice", "bob", "carol"]));
+        assert!(run(&engine, &mut session, &["SORT_RO", "names", "ALPHA", "STORE", "out"]).is_error());
+        assert_eq!(
+            run(&engine, &mut session, &["SORT", "names", "ALPHA", "DESC", "STORE", "out"]),
+            Frame::Integer(3)
+        );
+        assert_eq!(run(&engine, &mut session, &["LRANGE", "out", "0", "-1"]), bulks(&["carol", "bob", "alice"]));
+
+        run(&engine, &mut session, &["ZADD", "z", "1", "x", "2", "y"]);
+        assert_eq!(run(&engine, &mut session, &["SORT", "z", "BY", "nosort", "DESC"]), bulks(&["y", "x"]));
+        assert_eq!(run(&engine, &mut session, &["SORT", "missing", "STORE", "out"]), Frame::Integer(0));
+        assert_eq!(run(&engine, &mut session, &["EXISTS", "out"]), Frame::Integer(0));
+    }
+
+    #[test]
+    fn bad_commands_get_errors() {
+        let engine = Engine::new();
+        let (mut session, _) = engine.connect("test").unwrap();
+        assert!(run(&engine, &mut session, &["NOPE"]).is_error());
+        assert!(run(&engine, &mut session, &["GET"]).is_error());
+        assert!(engine.execute(&mut session, Frame::Integer(1)).is_error());
+    }
+
+    #[test]
+    fn published_messages_reach_subscribers() {
+        use futures::StreamExt;
+
+        let engine = Engine::new();
+        let (mut subscriber, mut pushes) = engine.connect("subscriber").unwrap();
+        let (mut publisher, _) = engine.connect("publisher").unwrap();
+        let reply = run(&engine, &mut subscriber, &["SUBSCRIBE", "news", "sport"]);
+        assert_eq!(reply, Frame::Array(vec![
+            Frame::bulk("subscribe"), Frame::bulk("news"), Frame::Integer(1)
+        ]));
+        assert!(run(&engine, &mut subscriber, &["GET", "k"]).is_error());
+        assert_eq!(run(&engine, &mut publisher, &["PUBLISH", "news", "hi"]), Frame::Integer(1));
+        let mut pushes = futures::executor::block_on_stream(pushes.by_ref());
+        assert_eq!(pushes.next(), Some(Frame::Array(vec![
+            Frame::bulk("subscribe"), Frame::bulk("sport"), Frame::Integer(2)
+        ])));
+        assert_eq!(pushes.next(), Some(Frame::Array(vec![
+            Frame::bulk("message"), Frame::bulk("news"), Frame::bulk("hi")
+        ])));
+        drop(subscriber);
+        assert_eq!(run(&engine, &mut publisher, &["PUBLISH", "news", "hi"]), Frame::Integer(0));
+    }
+
+    #[test]
+    fn tracking_invalidates_read_keys() {
+        let engine = Engine::new();
+        let (mut reader, pushes) = engine.connect("reader").unwrap();
+        let (mut writer, _) = engine.connect("writer").unwrap();
+        let mut pushes = futures::executor::block_on_stream(pushes);
+        run(&engine, &mut reader, &["HELLO", "3"]);
+        assert_eq!(run(&engine, &mut reader, &["CLIENT", "TRACKING", "ON"]), Frame::ok());
+        run(&engine, &mut reader, &["GET", "a"]);
+        run(&engine, &mut writer, &["SET", "b", "1"]);
+        run(&engine, &mut writer, &["SET", "a", "1"]);
+        assert_eq!(pushes.next(), Some(Frame::Push(vec![
+            Frame::bulk("invalidate"), Frame::Array(vec![Frame::bulk("a")])
+        ])));
+        // the key is only reported again once it was read again
+        run(&engine, &mut writer, &["SET", "a", "2"]);
+        run(&engine, &mut reader, &["CLIENT", "TRACKING", "ON", "BCAST", "PREFIX", "user:"]);
+        run(&engine, &mut writer, &["SET", "user:1", "x"]);
+        assert_eq!(pushes.next(), Some(Frame::Push(vec![
+            Frame::bulk("invalidate"), Frame::Array(vec![Frame::bulk("user:1")])
+        ])));
+    }
+
+    #[test]
+    fn tracking_forgets_clients_that_stop() {
+        let engine = Engine::new();
+        let (mut reader, _) = engine.connect("reader").unwrap();
+        run(&engine, &mut reader, &["HELLO", "3"]);
+        run(&engine, &mut reader, &["CLIENT", "TRACKING", "ON"]);
+        run(&engine, &mut reader, &["GET", "a"]);
+        assert_eq!(engine.clients().tracked.len(), 1);
+        run(&engine, &mut reader, &["CLIENT", "TRACKING", "OFF"]);
+        assert!(engine.clients().tracked.is_empty());
+        run(&engine, &mut reader, &["CLIENT", "TRACKING", "ON", "BCAST"]);
+        assert_eq!(engine.clients().bcast.len(), 1);
+        run(&engine, &mut reader, &["CLIENT", "TRACKING", "ON"]);
+        assert!(engine.clients().bcast.is_empty());
+        run(&engine, &mut reader, &["GET", "a"]);
+        run(&engine, &mut reader, &["CLIENT", "TRACKING", "ON", "BCAST"]);
+        drop(reader);
+        assert!(engine.clients().tracked.is_empty());
+        assert!(engine.clients().bcast.is_empty());
+    }
+
+    #[test]
+    fn tracking_sort_only_invalidates_its_destination() {
+        let engine = Engine::new();
+        let (mut reader, pushes) = engine.connect("reader").unwrap();
+        let (mut writer, _) = engine.connect("writer").unwrap();
+        let mut pushes = futures::executor::block_on_stream(pushes);
+        run(&engine, &mut writer, &["RPUSH", "list", "2", "1"]);
+        run(&engine, &mut reader, &["HELLO", "3"]);
+        run(&engine, &mut reader, &["CLIENT", "TRACKING", "ON"]);
+        run(&engine, &mut reader, &["LRANGE", "list", "0", "-1"]);
+        run(&engine, &mut reader, &["GET", "out"]);
+        run(&engine, &mut writer, &["SORT", "list"]);
+        run(&engine, &mut writer, &["SORT", "list", "STORE", "out"]);
+        assert_eq!(pushes.next(), Some(Frame::Push(vec![
+            Frame::bulk("invalidate"), Frame::Array(vec![Frame::bulk("out")])
+        ])));
+    }
+
+    #[test]
+    fn tracking_redirects_to_resp2_subscribers() {
+        let engine = Engine::new();
+        let (mut listener, pushes) = engine.connect("listener").unwrap();
+        let (mut reader, _) = engine.connect("reader").unwrap();
+        let mut pushes = futures::executor::block_on_stream(pushes);
+        run(&engine, &mut listener, &["SUBSCRIBE", "__redis__:invalidate"]);
+        assert!(run(&engine, &mut reader, &["CLIENT", "TRACKING", "ON"]).is_error());
+        let id = listener.id.to_string();
+        let on = ["CLIENT", "TRACKING", "ON", "REDIRECT", id.as_str(), "OPTIN"];
+        assert_eq!(run(&engine, &mut reader, &on), Frame::ok());
+        // OPTIN: only reads right after CLIENT CACHING YES are tracked
+        run(&engine, &mut reader, &["GET", "skipped"]);
+        run(&engine, &mut reader, &["CLIENT", "CACHING", "YES"]);
+        run(&engine, &mut reader, &["GET", "cached"]);
+        run(&engine, &mut reader, &["SET", "skipped", "1"]);
+        run(&engine, &mut reader, &["SET", "cached", "1"]);
+        assert_eq!(pushes.next(), Some(Frame::Array(vec![
+            Frame::bulk("message"),
+            Frame::bulk("__redis__:invalidate"),
+            Frame::Array(vec![Frame::bulk("cached")]),
+        ])));
+    }
+
+    #[test]
+    fn maxclients_is_enforced() {
+        let engine = Engine::new();
+        engine.set_max_clients(1);
+        let first = engine.connect("one").unwrap();
+        assert!(engine.connect("two").is_err());
+        drop(first);
+        assert!(engine.connect("three").is_ok());
+    }
+
+    // Two nodes that know each other: `a` serves the slot of "foo" and `b`
+    // the slot of "bar".
+    fn cluster_pair() -> (Engine, Engine) {
+        let (a, b) = (Engine::new(), Engine::new());
+        a.enable_cluster("127.0.0.1:7001".parse().unwrap());
+        b.enable_cluster("127.0.0.1:7002".parse().unwrap());
+        for (node, key) in [(&a, "foo"), (&b, "bar")] {
+            let (mut session, _) = node.connect("test").unwrap();
+            let slot = key_hash_slot(key.as_bytes()).to_string();
+            assert_eq!(run(node, &mut session, &["CLUSTER", "ADDSLOTS", &slot]), Frame::ok());
+        }
+        gossip(&a, &b);
+        gossip(&b, &a);
+        (a, b)
+    }
+
+    fn gossip(to: &Engine, from: &Engine) {
+        let from = from.cluster().read().unwrap();
+        to.cluster().write().unwrap().merge(from.myself_addr(), &from.nodes_reply());
+    }
+
+    #[test]
+    fn cluster_redirects_keys_owned_elsewhere() {
+        let (a, b) = cluster_pair();
+        let (mut sa, _) = a.connect("test").unwrap();
+        let (mut sb, _) = b.connect("test").unwrap();
+        assert_eq!(run(&a, &mut sa, &["SET", "foo", "1"]), Frame::ok());
+        assert_eq!(run(&b, &mut sb, &["GET", "foo"]), Frame::error("MOVED 12182 127.0.0.1:7001"));
+        assert_eq!(run(&a, &mut sa, &["GET", "bar"]), Frame::error("MOVED 5061 127.0.0.1:7002"));
+        assert_eq!(run(&a, &mut sa, &["GET", "foo"]), Frame::bulk("1"));
+        // keyless commands are served anywhere
+        assert_eq!(run(&b, &mut sb, &["PING"]), Frame::Simple("PONG".to_string()));
+        let unowned = run(&a, &mut sa, &["GET", "baz"]);
+        assert!(matches!(unowned, Frame::Error(e) if e.starts_with("CLUSTERDOWN")));
+    }
+
+    #[test]
+    fn cluster_asks_for_keys_of_a_migrating_slot() {
+        let (a, b) = cluster_pair();
+        let (mut sa, _) = a.connect("test").unwrap();
+        let (mut sb, _) = b.connect("test").unwrap();
+        let (a_id, b_id) = (a.cluster().read().unwrap().myid().to_owned(), b.cluster().read().unwrap().myid().to_owned());
+        assert_eq!(run(&a, &mut sa, &["SET", "foo", "1"]), Frame::ok());
+        assert_eq!(run(&a, &mut sa, &["CLUSTER", "SETSLOT", "12182", "MIGRATING", &b_id]), Frame::ok());
+        assert_eq!(run(&b, &mut sb, &["CLUSTER", "SETSLOT", "12182", "IMPORTING", &a_id]), Frame::ok());
+
+        // keys still here are served, the others are asked for at the target
+        assert_eq!(run(&a, &mut sa, &["GET", "foo"]), Frame::bulk("1"));
+        assert_eq!(run(&a, &mut sa, &["GET", "{foo}.moved"]), Frame::error("ASK 12182 127.0.0.1:7002"));
+        // the target only serves the slot to clients that were asked
+        assert_eq!(run(&b, &mut sb, &["GET", "{foo}.moved"]), Frame::error("MOVED 12182 127.0.0.1:7001"));
+        assert_eq!(run(&b, &mut sb, &["ASKING"]), Frame::ok());
+        assert_eq!(run(&b, &mut sb, &["SET", "{foo}.moved", "2"]), Frame::ok());
+        assert_eq!(run(&b, &mut sb, &["GET", "{foo}.moved"]), Frame::error("MOVED 12182 127.0.0.1:7001"));
+
+        // once the slot is handed over the old owner moves clients on
+        assert_eq!(run(&a, &mut sa, &["CLUSTER", "SETSLOT", "12182", "NODE", &b_id]), Frame::ok());
+        assert_eq!(run(&b, &mut sb, &["CLUSTER", "SETSLOT", "12182", "NODE", &b_id]), Frame::ok());
+        assert_eq!(run(&a, &mut sa, &["GET", "foo"]), Frame::error("MOVED 12182 127.0.0.1:7002"));
+        assert_eq!(run(&b, &mut sb, &["GET", "{foo}.moved"]), Frame::bulk("2"));
+    }
+
+    #[test]
+    fn cluster_refuses_keys_across_slots() {
+        let (a, _b) = cluster_pair();
+        let (mut session, _) = a.connect("test").unwrap();
+        assert_eq!(run(&a, &mut session, &["SET", "foo", "1"]), Frame::ok());
+        assert_eq!(
+            run(&a, &mut session, &["EXISTS", "foo", "bar"]),
+            Frame::error("CROSSSLOT Keys in request don't hash to the same slot")
+        );
+        assert_eq!(run(&a, &mut session, &["EXISTS", "foo", "{foo}.bar"]), Frame::Integer(1));
+        assert_eq!(run(&a, &mut session, &["DEL", "{foo}.bar", "foo"]), Frame::Integer(1));
+    }
+}
@@ -0,0 +1,340 @@
+// RESP frames and an incremental parser for them. The parser never consumes
+// a partial frame, so callers can keep appending bytes to a buffer and try
+// again until a whole frame is there.
+
+use std::io::{self, Read};
+
+// Same limits as Redis, a client can not make us allocate gigabytes.
+const MAX_BULK_LEN: i64 = 512 * 1024 * 1024;
+pub(crate) const MAX_ARRAY_LEN: i64 = 1024 * 1024;
+const MAX_INLINE_LEN: usize = 64 * 1024;
+// Arrays in arrays are parsed recursively, deeper nesting could overflow
+// the stack.
+const MAX_NESTING: usize = 128;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Null,
+    Array(Vec<Frame>),
+    NullArray,
+    /// Out of band data for RESP3 clients, like invalidation messages.
+    Push(Vec<Frame>),
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Protocol error: {}", msg))
+}
+
+fn find_crlf(buf: &[u8]) -> Option<usize> {
+    buf.windows(2).position(|w| w == b"\r\n")
+}
+
+// The length of an empty inline line at the start of `buf`, up to and
+// including its LF.
+fn empty_line(buf: &[u8]) -> Option<usize> {
+    if b"+-:$*>".contains(buf.first()?) {
+        return None;
+    }
+    let end = buf.iter().position(|b| *b == b'\n')?;
+    buf[..end].iter().all(u8::is_ascii_whitespace).then_some(end + 1)
+}
+
+fn parse_int(line: &[u8]) -> io::Result<i64> {
+    std::str::from_utf8(line).ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| invalid("invalid integer"))
+}
+
+impl Frame {
+    pub fn ok() -> Frame {
+        Frame::Simple("OK".to_string())
+    }
+
+    pub fn error(msg: impl Into<String>) -> Frame {
+        Frame::Error(msg.into())
+    }
+
+    pub fn bulk(data: impl Into<Vec<u8>>) -> Frame {
+        Frame::Bulk(data.into())
+    }
+
+    /// A command as clients send it: an array of bulk strings.
+    pub fn command<I, A>(args: I) -> Frame
+        where I: IntoIterator<Item = A>, A: Into<Vec<u8>>
+    {
+        Frame::Array(args.into_iter().map(|a| Frame::Bulk(a.into())).collect())
+    }
+
+    pub fn is_error(&self) -> bool {
+        matches!(self, Frame::Error(_))
+    }
+
+    /// Tries to parse one frame from the start of `buf`. Returns the frame
+    /// and the number of bytes it took, or `None` if more bytes are needed.
+    pub fn parse(buf: &[u8]) -> io::Result<Option<(Frame, usize)>> {
+        Frame::parse_nested(buf, 0, true)
+    }
+
+    /// Like `parse`, but for frames we wrote ourselves, like snapshots and
+    /// DUMP payloads: bulk strings and arrays may be longer than a client
+    /// could send.
+    pub(crate) fn parse_unlimited(buf: &[u8]) -> io::Result<Option<(Frame, usize)>> {
+        Frame::parse_nested(buf, 0, false)
+    }
+
+    fn parse_nested(buf: &[u8], depth: usize, limited: bool) -> io::Result<Option<(Frame, usize)>> {
+        // empty lines are skipped, like Redis does
+        let mut skipped = 0;
+        while let Some(len) = empty_line(&buf[skipped..]) {
+            skipped += len;
+        }
+        Ok(Frame::parse_frame(&buf[skipped..], depth, limited)?.map(|(frame, used)| (frame, skipped + used)))
+    }
+
+    fn parse_frame(buf: &[u8], depth: usize, limited: bool) -> io::Result<Option<(Frame, usize)>> {
+        let marker = match buf.first() {
+            Some(m) => *m,
+            None => return Ok(None),
+        };
+        if !b"+-:$*>".contains(&marker) {
+            return Frame::parse_inline(buf);
+        }
+        let end = match find_crlf(buf) {
+            Some(end) => end,
+            None => return Ok(None),
+        };
+        let line = &buf[1..end];
+        let rest = end + 2;
+        let frame = match marker {
+            b'+' => Frame::Simple(String::from_utf8_lossy(line).into_owned()),
+            b'-' => Frame::Error(String::from_utf8_lossy(line).into_owned()),
+            b':' => Frame::Integer(parse_int(line)?),
+            b'$' => {
+                let len = parse_int(line)?;
+                if len < 0 {
+                    return Ok(Some((Frame::Null, rest)));
+                }
+                if limited && len > MAX_BULK_LEN {
+                    return Err(invalid("invalid bulk length"));
+                }
+                let len = len as usize;
+                if buf.len() - rest < len + 2 {
+                    return Ok(None);
+                }
+                if &buf[rest + len..rest + len + 2] != b"\r\n" {
+                    return Err(invalid("expected CRLF after bulk string"));
+                }
+                return Ok(Some((Frame::Bulk(buf[rest..rest + len].to_vec()), rest + len + 2)));
+            }
+            b'*' | b'>' => {
+                let len = parse_int(line)?;
+                if len < 0 {
+                    return Ok(Some((Frame::NullArray, rest)));
+                }
+                if limited && len > MAX_ARRAY_LEN {
+                    return Err(invalid("invalid multibulk length"));
+                }
+                if depth >= MAX_NESTING {
+                    return Err(invalid("too deeply nested multibulk"));
+                }
+                // the length is only believed as far as the bytes go
+                let mut items = Vec::with_capacity((len as usize).min(buf.len() / 4));
+                let mut pos = rest;
+                for _ in 0..len {
+                    match Frame::parse_nested(&buf[pos..], depth + 1, limited)? {
+                        Some((item, used)) => {
+                            items.push(item);
+                            pos += used;
+                        }
+                        None => return Ok(None),
+                    }
+                }
+                let frame = if marker == b'>' { Frame::Push(items) } else { Frame::Array(items) };
+                return Ok(Some((frame, pos)));
+            }
+            _ => unreachable!("inline commands are parsed above"),
+        };
+        Ok(Some((frame, rest)))
+    }
+
+    // Inline commands, as typed into telnet. Lines may end in a bare LF.
+    fn parse_inline(buf: &[u8]) -> io::Result<Option<(Frame, usize)>> {
+        let end = match buf.iter().position(|b| *b == b'\n') {
+            Some(end) => end,
+            None if buf.len() > MAX_INLINE_LEN => return Err(invalid("too big inline request")),
+            None => return Ok(None),
+        };
+        let rest = end + 1;
+        let args = buf[..end].split(|b| b.is_ascii_whitespace())
+            .filter(|a| !a.is_empty())
+            .map(|a| Frame::Bulk(a.to_vec()))
+            .collect();
+        Ok(Some((Frame::Array(args), rest)))
+    }
+
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Frame::Simple(s) => {
+                out.push(b'+');
+                out.extend_from_slice(s.as_bytes());
+                out.extend_from_slice(b"\r\n");
+            }
+            Frame::Error(s) => {
+                out.push(b'-');
+                out.extend_from_slice(s.as_bytes());
+                out.extend_from_slice(b"\r\n");
+            }
+            Frame::Integer(i) => out.extend_from_slice(format!(":{}\r\n", i).as_bytes()),
+            Frame::Bulk(b) => {
+                out.extend_from_slice(format!("${}\r\n", b.len()).as_bytes());
+                out.extend_from_slice(b);
+                out.extend_from_slice(b"\r\n");
+            }
+            Frame::Null => out.extend_from_slice(b"$-1\r\n"),
+            Frame::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(out);
+                }
+            }
+            Frame::NullArray => out.extend_from_slice(b"*-1\r\n"),
+            Frame::Push(items) => {
+                out.extend_from_slice(format!(">{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(out);
+                }
+            }
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode(&mut out);
+        out
+    }
+}
+
+/// Reads frames from a blocking reader, buffering whatever arrives past
+/// the end of the current frame.
+pub struct FrameReader<R> {
+    reader: R,
+    buf: Vec<u8>,
+}
+
+impl<R: Read> FrameReader<R> {
+    pub fn new(reader: R) -> Self {
+        FrameReader { reader, buf: Vec::new() }
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    /// Returns the next frame, or `None` when the peer closed the stream.
+    pub fn read_frame(&mut self) -> io::Result<Option<Frame>> {
+        let mut chunk = [0; 4096];
+        loop {
+            if let Some((frame, used)) = Frame::parse(&self.buf)? {
+                self.buf.drain(..used);
+                return Ok(Some(frame));
+            }
+            let n = self.reader.read(&mut chunk)?;
+            if n == 0 {
+                return if self.buf.is_empty() {
+                    Ok(None)
+                } else {
+                    Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed mid frame"))
+                };
+            }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let frame = Frame::Array(vec![
+            Frame::Simple("OK".to_string()),
+            Frame::Error("ERR nope".to_string()),
+            Frame::Integer(-42),
+            Frame::bulk("with\r\nbinary\0"),
+            Frame::Null,
+            Frame::NullArray,
+            Frame::Array(vec![]),
+            Frame::Push(vec![Frame::bulk("invalidate")]),
+        ]);
+        let bytes = frame.to_bytes();
+        assert_eq!(Frame::parse(&bytes).unwrap(), Some((frame, bytes.len())));
+    }
+
+    #[test]
+    fn incomplete_frames_wait_for_more() {
+        let bytes = Frame::command(["SET", "key", "value"]).to_bytes();
+        for end in 0..bytes.len() {
+            assert_eq!(Frame::parse(&bytes[..end]).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn pipelined_frames_are_parsed_one_at_a_time() {
+        let mut bytes = Frame::command(["GET", "a"]).to_bytes();
+        bytes.extend(Frame::command(["GET", "b"]).to_bytes());
+        let mut reader = FrameReader::new(&bytes[..]);
+        assert_eq!(reader.read_frame().unwrap(), Some(Frame::command(["GET", "a"])));
+        assert_eq!(reader.read_frame().unwrap(), Some(Frame::command(["GET", "b"])));
+        assert_eq!(reader.read_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn inline_commands() {
+        let (frame, used) = Frame::parse(b"SET  key value\r\nrest").unwrap().unwrap();
+        assert_eq!(frame, Frame::command(["SET", "key", "value"]));
+        assert_eq!(used, 16);
+        let (frame, used) = Frame::parse(b"\nPING\n").unwrap().unwrap();
+        assert_eq!(frame, Frame::command(["PING"]));
+        assert_eq!(used, 6);
+    }
+
+    #[test]
+    fn malformed_frames_are_errors() {
+        assert!(Frame::parse(b"$abc\r\n").is_err());
+        assert!(Frame::parse(b"$3\r\nfooXX").is_err());
+    }
+
+    #[test]
+    fn our_own_frames_may_be_longer_than_requests() {
+        let mut bytes = format!("*{}\r\n", MAX_ARRAY_LEN + 1).into_bytes();
+        bytes.extend(b":1\r\n".repeat(MAX_ARRAY_LEN as usize + 1));
+        assert!(Frame::parse(&bytes).is_err());
+        let (frame, used) = Frame::parse_unlimited(&bytes).unwrap().unwrap();
+        assert!(matches!(frame, Frame::Array(items) if items.len() == MAX_ARRAY_LEN as usize + 1));
+        assert_eq!(used, bytes.len());
+        // a length the bytes don't back up waits for more
+        assert_eq!(Frame::parse_unlimited(b"*9223372036854775807\r\n").unwrap(), None);
+        assert_eq!(Frame::parse_unlimited(b"$9223372036854775807\r\n").unwrap(), None);
+    }
+
+    #[test]
+    fn deep_nesting_is_an_error_not_a_stack_overflow() {
+        let nested = |depth| {
+            let mut bytes = b"*1\r\n".repeat(depth);
+            bytes.extend_from_slice(b":1\r\n");
+            bytes
+        };
+        assert!(Frame::parse(&nested(MAX_NESTING)).unwrap().is_some());
+        assert!(Frame::parse(&nested(MAX_NESTING + 1)).is_err());
+        assert!(Frame::parse(&b"*1\r\n".repeat(200_000)).is_err());
+        // nor do many empty lines before a command
+        let mut lines = b"\r\n".repeat(200_000);
+        lines.extend_from_slice(b"PING\r\n");
+        assert_eq!(Frame::parse(&lines).unwrap(), Some((Frame::command(["PING"]), lines.len())));
+    }
+}
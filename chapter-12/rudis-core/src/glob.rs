@@ -0,0 +1,74 @@
+// Glob-style pattern matching, as used by SCAN's MATCH option: `*` matches
+// any run of bytes, `?` any single byte, `[abc]`, `[^abc]` and `[a-z]` a
+// byte out of a set, and `\` escapes the byte after it.
+
+pub(crate) fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|skip| glob_match(rest, &text[skip..])),
+        Some((b'?', rest)) => !text.is_empty() && glob_match(rest, &text[1..]),
+        Some((b'[', rest)) => match (text.split_first(), match_class(rest)) {
+            (Some((c, text)), Some((class, rest))) => class(*c) && glob_match(rest, text),
+            // an unterminated class is matched literally
+            (Some((b'[', text)), None) => glob_match(rest, text),
+            _ => false,
+        },
+        Some((b'\\', [escaped, rest @ ..])) => {
+            text.first() == Some(escaped) && glob_match(rest, &text[1..])
+        }
+        Some((c, rest)) => text.first() == Some(c) && glob_match(rest, &text[1..]),
+    }
+}
+
+// Parses the class after its opening `[`. Returns a predicate for the
+// class and what follows the closing `]`.
+fn match_class(pattern: &[u8]) -> Option<(impl Fn(u8) -> bool + '_, &[u8])> {
+    let (negate, body) = match pattern.split_first() {
+        Some((b'^', body)) => (true, body),
+        _ => (false, pattern),
+    };
+    let end = body.iter().position(|c| *c == b']')?;
+    let (class, rest) = (&body[..end], &body[end + 1..]);
+    let matches = move |c: u8| {
+        let mut i = 0;
+        while i < class.len() {
+            if class[i] == b'\\' && i + 1 < class.len() {
+                if class[i + 1] == c {
+                    return true;
+                }
+                i += 2;
+            } else if i + 2 < class.len() && class[i + 1] == b'-' {
+                let (lo, hi) = (class[i].min(class[i + 2]), class[i].max(class[i + 2]));
+                if (lo..=hi).contains(&c) {
+                    return true;
+                }
+                i += 3;
+            } else {
+                if class[i] == c {
+                    return true;
+                }
+                i += 1;
+            }
+        }
+        false
+    };
+    Some((move |c| matches(c) != negate, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob_match;
+
+    #[test]
+    fn patterns() {
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"user:*", b"user:42"));
+        assert!(!glob_match(b"user:*", b"session:42"));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[a-c]llo", b"hbllo"));
+        assert!(glob_match(b"a\\*b", b"a*b"));
+        assert!(!glob_match(b"a\\*b", b"axb"));
+    }
+}
@@ -0,0 +1,123 @@
+// The hash commands. Like lists, a hash that loses its last field is
+// removed.
+
+use crate::commands::{parse_int, Reply};
+use crate::encoding::Hash;
+use crate::engine::Engine;
+use crate::frame::Frame;
+use crate::session::Session;
+use crate::store::{wrong_type, Store, Value};
+
+fn hash_mut<'a>(store: &'a mut Store, key: &[u8]) -> Result<Option<&'a mut Hash>, Frame> {
+    match store.get_mut(key) {
+        Some(Value::Hash(hash)) => Ok(Some(hash)),
+        Some(_) => Err(wrong_type()),
+        None => Ok(None),
+    }
+}
+
+fn hash<'a>(store: &'a Store, key: &[u8]) -> Result<Option<&'a Hash>, Frame> {
+    match store.get(key) {
+        Some(Value::Hash(hash)) => Ok(Some(hash)),
+        Some(_) => Err(wrong_type()),
+        None => Ok(None),
+    }
+}
+
+fn hash_or_new<'a>(store: &'a mut Store, key: &[u8]) -> Result<&'a mut Hash, Frame> {
+    if hash_mut(store, key)?.is_none() {
+        store.set(key.to_vec(), Value::Hash(Hash::default()));
+    }
+    Ok(hash_mut(store, key)?.unwrap())
+}
+
+// HSET key field value [field value ...]
+pub(crate) fn hset(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    if !args.len().is_multiple_of(2) {
+        return Err(Frame::error("ERR wrong number of arguments for 'hset' command"));
+    }
+    let mut store = engine.store();
+    let limits = store.limits();
+    let hash = hash_or_new(&mut store, &args[1])?;
+    let added = args[2..].chunks(2)
+        .filter(|pair| hash.insert(pair[0].to_vec(), pair[1].to_vec(), &limits))
+        .count();
+    Ok(Frame::Integer(added as i64))
+}
+
+pub(crate) fn hget(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let store = engine.store();
+    Ok(hash(&store, &args[1])?
+        .and_then(|hash| hash.get(&args[2]))
+        .map_or(Frame::Null, Frame::bulk))
+}
+
+pub(crate) fn hmget(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let store = engine.store();
+    let hash = hash(&store, &args[1])?;
+    Ok(Frame::Array(args[2..].iter()
+        .map(|field| hash.and_then(|hash| hash.get(field)).map_or(Frame::Null, Frame::bulk))
+        .collect()))
+}
+
+pub(crate) fn hdel(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let mut store = engine.store();
+    let hash = match hash_mut(&mut store, &args[1])? {
+        Some(hash) => hash,
+        None => return Ok(Frame::Integer(0)),
+    };
+    let removed = args[2..].iter().filter(|field| hash.remove(field)).count();
+    if hash.is_empty() {
+        store.remove(&args[1]);
+    }
+    Ok(Frame::Integer(removed as i64))
+}
+
+pub(crate) fn hlen(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let store = engine.store();
+    Ok(Frame::Integer(hash(&store, &args[1])?.map_or(0, Hash::len) as i64))
+}
+
+pub(crate) fn hexists(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let store = engine.store();
+    let exists = hash(&store, &args[1])?.is_some_and(|hash| hash.get(&args[2]).is_some());
+    Ok(Frame::Integer(exists as i64))
+}
+
+// HGETALL, HKEYS and HVALS differ only in what they take from each field.
+fn fields(engine: &Engine, key: &[u8], pick: fn(&[u8], &[u8]) -> Vec<Frame>) -> Reply {
+    let store = engine.store();
+    let items = hash(&store, key)?
+        .map(|hash| hash.iter().flat_map(|(f, v)| pick(f, v)).collect())
+        .unwrap_or_default();
+    Ok(Frame::Array(items))
+}
+
+pub(crate) fn hgetall(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    fields(engine, &args[1], |f, v| vec![Frame::bulk(f), Frame::bulk(v)])
+}
+
+pub(crate) fn hkeys(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    fields(engine, &args[1], |f, _| vec![Frame::bulk(f)])
+}
+
+pub(crate) fn hvals(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    fields(engine, &args[1], |_, v| vec![Frame::bulk(v)])
+}
+
+// A missing field counts as 0.
+pub(crate) fn hincrby(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let by = parse_int(&args[3])?;
+    let mut store = engine.store();
+    let limits = store.limits();
+    let hash = hash_or_new(&mut store, &args[1])?;
+    let current = match hash.get(&args[2]) {
+        Some(value) => parse_int(value)
+            .map_err(|_| Frame::error("ERR hash value is not an integer"))?,
+        None => 0,
+    };
+    let value = current.checked_add(by)
+        .ok_or_else(|| Frame::error("ERR increment or decrement would overflow"))?;
+    hash.insert(args[2].to_vec(), value.to_string().into_bytes(), &limits);
+    Ok(Frame::Integer(value))
+}
@@ -0,0 +1,150 @@
+// The commands that work on keys of any type: deleting them, checking they
+// exist and managing their time to live. Expiry times are kept absolute, in
+// milliseconds since the UNIX epoch, whatever unit the command used.
+
+use crate::commands::{parse_int, unknown_subcommand, Reply};
+use crate::engine::Engine;
+use crate::frame::Frame;
+use crate::session::Session;
+use crate::store::{now_ms, Value};
+
+pub(crate) fn del(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let mut store = engine.store();
+    let removed = args[1..].iter()
+        .filter(|key| store.remove(key).is_some())
+        .count();
+    Ok(Frame::Integer(removed as i64))
+}
+
+// Like DEL, but large values are freed in the background.
+pub(crate) fn unlink(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let mut store = engine.store();
+    let removed = args[1..].iter().filter(|key| store.unlink(key)).count();
+    Ok(Frame::Integer(removed as i64))
+}
+
+// FLUSHALL [ASYNC|SYNC] and FLUSHDB [ASYNC|SYNC] are the same thing with a
+// single database.
+pub(crate) fn flushall(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let lazy = match &args[1..] {
+        [] => false,
+        [mode] if mode.eq_ignore_ascii_case(b"SYNC") => false,
+        [mode] if mode.eq_ignore_ascii_case(b"ASYNC") => true,
+        _ => return Err(Frame::error("ERR syntax error")),
+    };
+    engine.store().flush(lazy);
+    engine.clients().invalidate_all();
+    Ok(Frame::ok())
+}
+
+// A key given twice is counted twice, like in Redis.
+pub(crate) fn exists(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let store = engine.store();
+    let found = args[1..].iter().filter(|key| store.contains(key)).count();
+    Ok(Frame::Integer(found as i64))
+}
+
+pub(crate) fn key_type(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let kind = engine.store().get(&args[1]).map_or("none", Value::type_name);
+    Ok(Frame::Simple(kind.to_string()))
+}
+
+pub(crate) fn object(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let subcommand = String::from_utf8_lossy(&args[1]).to_uppercase();
+    match (subcommand.as_str(), &args[2..]) {
+        ("ENCODING", [key]) => Ok(engine.store().get(key)
+            .map_or(Frame::Null, |value| Frame::bulk(value.encoding()))),
+        _ => Err(unknown_subcommand(&subcommand)),
+    }
+}
+
+// The key and value bytes plus what the key costs in the keyspace.
+pub(crate) fn memory(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    const KEY_OVERHEAD: usize = 56;
+    let subcommand = String::from_utf8_lossy(&args[1]).to_uppercase();
+    match (subcommand.as_str(), &args[2..]) {
+        ("USAGE", [key, options @ ..]) => {
+            // values are measured whole, the sample count is ignored
+            match options {
+                [] => {}
+                [name, count] if name.eq_ignore_ascii_case(b"SAMPLES") => {
+                    parse_int(count)?;
+                }
+                _ => return Err(Frame::error("ERR syntax error")),
+            }
+            Ok(engine.store().get(key).map_or(Frame::Null, |value| {
+                Frame::Integer((KEY_OVERHEAD + key.len() + value.memory_usage()) as i64)
+            }))
+        }
+        _ => Err(unknown_subcommand(&subcommand)),
+    }
+}
+
+/// Turns the time argument of EXPIRE and friends into an absolute expiry
+/// time. `relative` is false for the *AT variants, `unit` is the number of
+/// milliseconds per unit.
+pub(crate) fn expires_at(arg: &[u8], relative: bool, unit: i64) -> Result<i64, Frame> {
+    let invalid = || Frame::error("ERR invalid expire time");
+    let at = parse_int(arg)?.checked_mul(unit).ok_or_else(invalid)?;
+    if relative {
+        return at.checked_add(now_ms() as i64).ok_or_else(invalid);
+    }
+    Ok(at)
+}
+
+// A time in the past deletes the key right away.
+fn set_expiry(engine: &Engine, key: &[u8], at: i64) -> Reply {
+    let mut store = engine.store();
+    let updated = if at <= now_ms() as i64 {
+        store.remove(key).is_some()
+    } else {
+        store.set_expiry(key, Some(at as u64))
+    };
+    Ok(Frame::Integer(updated as i64))
+}
+
+pub(crate) fn expire(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    set_expiry(engine, &args[1], expires_at(&args[2], true, 1000)?)
+}
+
+pub(crate) fn pexpire(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    set_expiry(engine, &args[1], expires_at(&args[2], true, 1)?)
+}
+
+pub(crate) fn expireat(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    set_expiry(engine, &args[1], expires_at(&args[2], false, 1000)?)
+}
+
+pub(crate) fn pexpireat(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    set_expiry(engine, &args[1], expires_at(&args[2], false, 1)?)
+}
+
+// -2 for a missing key, -1 for a key that never expires.
+fn remaining(engine: &Engine, key: &[u8], unit: u64) -> Reply {
+    let store = engine.store();
+    if !store.contains(key) {
+        return Ok(Frame::Integer(-2));
+    }
+    let left = match store.expiry(key) {
+        Some(at) => (at.saturating_sub(now_ms()) + unit / 2) / unit,
+        None => return Ok(Frame::Integer(-1)),
+    };
+    Ok(Frame::Integer(left as i64))
+}
+
+pub(crate) fn ttl(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    remaining(engine, &args[1], 1000)
+}
+
+pub(crate) fn pttl(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    remaining(engine, &args[1], 1)
+}
+
+pub(crate) fn persist(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let mut store = engine.store();
+    let had_expiry = store.expiry(&args[1]).is_some();
+    if had_expiry {
+        store.set_expiry(&args[1], None);
+    }
+    Ok(Frame::Integer(had_expiry as i64))
+}
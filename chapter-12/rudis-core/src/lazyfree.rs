@@ -0,0 +1,110 @@
+// Lazy freeing. Dropping a collection with millions of elements takes as
+// long as allocating it did, and the store lock is held meanwhile, so big
+// values unlinked from the keyspace are dropped on a background thread
+// instead. Small ones are cheaper to drop right away than to hand over.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread;
+
+use crate::frame::Frame;
+
+// Values needing more allocations than this to drop go to the background
+// thread, as in Redis.
+pub(crate) const LAZYFREE_THRESHOLD: usize = 64;
+
+type Garbage = Box<dyn Send>;
+
+/// Which implicit deletions free their values lazily. UNLINK and the ASYNC
+/// flushes always do.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LazyFreeConfig {
+    // keys evicted to free memory; rudis never evicts yet
+    pub lazy_eviction: bool,
+    // keys removed because they expired
+    pub lazy_expire: bool,
+    // values replaced or deleted as a side effect of a command, like SET
+    // over an existing key or RESTORE REPLACE
+    pub lazy_server_del: bool,
+}
+
+impl LazyFreeConfig {
+    pub const CONFIG_NAMES: &'static [&'static str] = &[
+        "lazyfree-lazy-eviction", "lazyfree-lazy-expire", "lazyfree-lazy-server-del",
+    ];
+
+    fn field(&mut self, name: &str) -> Option<&mut bool> {
+        Some(match name {
+            "lazyfree-lazy-eviction" => &mut self.lazy_eviction,
+            "lazyfree-lazy-expire" => &mut self.lazy_expire,
+            "lazyfree-lazy-server-del" => &mut self.lazy_server_del,
+            _ => return None,
+        })
+    }
+
+    pub fn get_config(&self, name: &str) -> Option<String> {
+        let mut config = *self;
+        config.field(name).map(|lazy| if *lazy { "yes" } else { "no" }.to_string())
+    }
+
+    pub fn set_config(&mut self, name: &str, value: &str) -> Result<(), Frame> {
+        let invalid = || Frame::Error(format!(
+            "ERR Invalid argument '{}' for CONFIG SET '{}'", value, name
+        ));
+        let lazy = match value.to_lowercase().as_str() {
+            "yes" => true,
+            "no" => false,
+            _ => return Err(invalid()),
+        };
+        *self.field(name).ok_or_else(invalid)? = lazy;
+        Ok(())
+    }
+}
+
+/// The handle to the background thread, started on first use.
+#[derive(Default)]
+pub(crate) struct LazyFree {
+    sender: Option<Sender<Garbage>>,
+    // handed over and not dropped yet
+    pending: Arc<AtomicUsize>,
+}
+
+impl LazyFree {
+    /// Drops `garbage` on the background thread, or right here when the
+    /// thread can't be started.
+    pub(crate) fn free(&mut self, garbage: impl Send + 'static) {
+        let garbage: Garbage = Box::new(garbage);
+        let sender = match &self.sender {
+            Some(sender) => sender,
+            None => match self.start() {
+                Some(sender) => self.sender.insert(sender),
+                None => return,
+            },
+        };
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        if let Err(mpsc::SendError(garbage)) = sender.send(garbage) {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+            drop(garbage);
+        }
+    }
+
+    fn start(&self) -> Option<Sender<Garbage>> {
+        let (sender, receiver) = mpsc::channel::<Garbage>();
+        let pending = Arc::clone(&self.pending);
+        thread::Builder::new()
+            .name("lazyfree".to_string())
+            .spawn(move || {
+                for garbage in receiver {
+                    drop(garbage);
+                    pending.fetch_sub(1, Ordering::SeqCst);
+                }
+            })
+            .ok()?;
+        Some(sender)
+    }
+
+    pub(crate) fn pending(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,43 @@
+//! The parts of rudis that do not depend on how clients reach the server:
+//! the store, the command engine and RESP handling.
+//!
+//! ```
+//! use rudis_core::{Engine, Frame};
+//!
+//! let engine = Engine::new();
+//! let (mut session, _pushes) = engine.connect("in-process").unwrap();
+//! engine.execute(&mut session, Frame::command(["SET", "key", "value"]));
+//! let reply = engine.execute(&mut session, Frame::command(["GET", "key"]));
+//! assert_eq!(reply, Frame::bulk("value"));
+//! ```
+
+mod cluster;
+#[cfg(feature = "codec")]
+mod codec;
+mod commands;
+mod diagnostics;
+mod dump;
+mod encoding;
+mod engine;
+mod frame;
+mod glob;
+mod hashes;
+mod keys;
+mod lazyfree;
+mod lists;
+mod persistence;
+mod session;
+mod sets;
+mod sort;
+mod store;
+mod tracking;
+mod zsets;
+
+pub use crate::cluster::{key_hash_slot, ClusterState, CLUSTER_SLOTS};
+#[cfg(feature = "codec")]
+pub use crate::codec::RespCodec;
+pub use crate::diagnostics::{CommandStats, LATENCY_BUCKETS};
+pub use crate::engine::{Engine, Stats, EXPIRE_INTERVAL};
+pub use crate::frame::{Frame, FrameReader};
+pub use crate::persistence::{shutting_down, SaveMode};
+pub use crate::session::{Pushes, Session};
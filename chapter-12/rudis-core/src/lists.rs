@@ -0,0 +1,118 @@
+// The list commands. A list that loses its last element is removed, so an
+// empty list never exists, like in Redis.
+
+use std::collections::VecDeque;
+
+use crate::commands::{parse_int, Reply};
+use crate::engine::Engine;
+use crate::frame::Frame;
+use crate::session::Session;
+use crate::store::{wrong_type, Store, Value};
+
+#[derive(Clone, Copy)]
+enum End {
+    Left,
+    Right,
+}
+
+fn list_mut<'a>(store: &'a mut Store, key: &[u8]) -> Result<Option<&'a mut VecDeque<Vec<u8>>>, Frame> {
+    match store.get_mut(key) {
+        Some(Value::List(list)) => Ok(Some(list)),
+        Some(_) => Err(wrong_type()),
+        None => Ok(None),
+    }
+}
+
+fn push(engine: &Engine, args: &[Vec<u8>], end: End) -> Reply {
+    let mut store = engine.store();
+    if list_mut(&mut store, &args[1])?.is_none() {
+        store.set(args[1].to_vec(), Value::List(VecDeque::new()));
+    }
+    let list = list_mut(&mut store, &args[1])?.unwrap();
+    for element in &args[2..] {
+        match end {
+            End::Left => list.push_front(element.to_vec()),
+            End::Right => list.push_back(element.to_vec()),
+        }
+    }
+    Ok(Frame::Integer(list.len() as i64))
+}
+
+pub(crate) fn lpush(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    push(engine, args, End::Left)
+}
+
+pub(crate) fn rpush(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    push(engine, args, End::Right)
+}
+
+// Without a count the reply is the element, with one it is an array.
+fn pop(engine: &Engine, args: &[Vec<u8>], end: End) -> Reply {
+    let count = match args.get(2) {
+        Some(count) => Some(parse_int(count).ok().filter(|c| *c >= 0).ok_or_else(|| {
+            Frame::error("ERR value is out of range, must be positive")
+        })? as usize),
+        None => None,
+    };
+    if args.len() > 3 {
+        return Err(Frame::error("ERR syntax error"));
+    }
+    let mut store = engine.store();
+    let list = match list_mut(&mut store, &args[1])? {
+        Some(list) => list,
+        None if count.is_some() => return Ok(Frame::NullArray),
+        None => return Ok(Frame::Null),
+    };
+    let mut popped = Vec::new();
+    for _ in 0..count.unwrap_or(1).min(list.len()) {
+        let element = match end {
+            End::Left => list.pop_front(),
+            End::Right => list.pop_back(),
+        };
+        popped.extend(element.map(Frame::Bulk));
+    }
+    if list.is_empty() {
+        store.remove(&args[1]);
+    }
+    match count {
+        Some(_) => Ok(Frame::Array(popped)),
+        None => Ok(popped.pop().unwrap_or(Frame::Null)),
+    }
+}
+
+pub(crate) fn lpop(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    pop(engine, args, End::Left)
+}
+
+pub(crate) fn rpop(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    pop(engine, args, End::Right)
+}
+
+pub(crate) fn llen(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let mut store = engine.store();
+    let len = list_mut(&mut store, &args[1])?.map_or(0, |list| list.len());
+    Ok(Frame::Integer(len as i64))
+}
+
+/// Turns inclusive `start` and `stop` indexes, negative ones counting from
+/// the end, into a range of `len` elements, empty if they don't overlap.
+pub(crate) fn index_range(start: i64, stop: i64, len: usize) -> std::ops::Range<usize> {
+    let len = len as i64;
+    let resolve = |i: i64| if i < 0 { len + i } else { i };
+    let (start, stop) = (resolve(start).max(0), resolve(stop).min(len - 1));
+    if start > stop {
+        return 0..0;
+    }
+    start as usize..stop as usize + 1
+}
+
+pub(crate) fn lrange(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let (start, stop) = (parse_int(&args[2])?, parse_int(&args[3])?);
+    let mut store = engine.store();
+    let list = match list_mut(&mut store, &args[1])? {
+        Some(list) => list,
+        None => return Ok(Frame::Array(vec![])),
+    };
+    let range = index_range(start, stop, list.len());
+    Ok(Frame::Array(list.range(range).map(|e| Frame::bulk(e.as_slice())).collect()))
+}
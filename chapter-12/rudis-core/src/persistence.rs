@@ -0,0 +1,167 @@
+// Point in time snapshots of the store. The snapshot is the whole keyspace
+// written as a single RESP array of entries, one per key, so it is
+// read back with the parser used for client requests, without the limits
+// put on clients. Each entry is an array of the key, the value and the
+// expiry time, null for none.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use crate::encoding::Limits;
+use crate::frame::Frame;
+use crate::store::{Store, Value};
+
+pub struct Persistence {
+    path: PathBuf,
+    // snapshot on shutdown unless told otherwise
+    enabled: bool,
+    // when the last snapshot was attempted and whether it was written
+    last_save: Option<(SystemTime, bool)>,
+}
+
+/// How SHUTDOWN treats the snapshot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SaveMode {
+    Default,
+    Save,
+    NoSave,
+}
+
+impl Default for Persistence {
+    fn default() -> Self {
+        Persistence::new()
+    }
+}
+
+impl Persistence {
+    pub fn new() -> Self {
+        Persistence { path: PathBuf::from("dump.resp"), enabled: false, last_save: None }
+    }
+
+    pub fn enable(&mut self, path: &str) {
+        self.path = PathBuf::from(path);
+        self.enabled = true;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn last_save(&self) -> Option<(SystemTime, bool)> {
+        self.last_save
+    }
+
+    pub fn should_save(&self, mode: SaveMode) -> bool {
+        match mode {
+            SaveMode::Default => self.enabled,
+            SaveMode::Save => true,
+            SaveMode::NoSave => false,
+        }
+    }
+
+    /// Reads the snapshot back, an absent snapshot is an empty store.
+    /// Values get the encodings `limits` call for.
+    pub fn load(&self, limits: Limits) -> io::Result<Store> {
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Store::with_limits(limits)),
+            Err(e) => return Err(e),
+        };
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "corrupt snapshot");
+        let entries = match Frame::parse_unlimited(&data)? {
+            Some((Frame::Array(entries), _)) => entries,
+            _ => return Err(invalid()),
+        };
+        // snapshots of older versions alternate keys and string values
+        if let Some(Frame::Bulk(_)) = entries.first() {
+            return load_pairs(entries, limits).ok_or_else(invalid);
+        }
+        let mut store = Store::with_limits(limits);
+        for entry in entries {
+            let (key, value, expires_at) = match entry {
+                Frame::Array(entry) => match <[Frame; 3]>::try_from(entry) {
+                    Ok([Frame::Bulk(key), value, Frame::Integer(at)]) => (key, value, Some(at as u64)),
+                    Ok([Frame::Bulk(key), value, Frame::Null]) => (key, value, None),
+                    _ => return Err(invalid()),
+                },
+                _ => return Err(invalid()),
+            };
+            let value = Value::from_frame(value, &limits).ok_or_else(invalid)?;
+            store.set_with_expiry(key, value, expires_at);
+        }
+        Ok(store)
+    }
+
+    /// Writes the snapshot to a temporary file first and renames it over the
+    /// old one, so a crash mid-write never leaves a truncated snapshot behind.
+    pub fn save(&mut self, store: &Store) -> io::Result<()> {
+        let saved = self.write(store);
+        self.last_save = Some((SystemTime::now(), saved.is_ok()));
+        saved
+    }
+
+    fn write(&self, store: &Store) -> io::Result<()> {
+        let entries = store.iter_with_expiry()
+            .map(|(key, value, expires_at)| Frame::Array(vec![
+                Frame::bulk(key.as_slice()),
+                value.to_frame(),
+                expires_at.map_or(Frame::Null, |at| Frame::Integer(at as i64)),
+            ]))
+            .collect();
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&Frame::Array(entries).to_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+fn load_pairs(items: Vec<Frame>, limits: Limits) -> Option<Store> {
+    let mut store = Store::with_limits(limits);
+    for kv in items.chunks(2) {
+        match kv {
+            [Frame::Bulk(k), Frame::Bulk(v)] => store.set(k.to_vec(), Value::Str(v.to_vec())),
+            _ => return None,
+        }
+    }
+    Some(store)
+}
+
+pub fn parse_save_mode(args: &[Vec<u8>]) -> Result<SaveMode, Frame> {
+    match args {
+        [] => Ok(SaveMode::Default),
+        [s] if s.eq_ignore_ascii_case(b"SAVE") => Ok(SaveMode::Save),
+        [s] if s.eq_ignore_ascii_case(b"NOSAVE") => Ok(SaveMode::NoSave),
+        _ => Err(Frame::error("ERR syntax error")),
+    }
+}
+
+pub fn shutting_down() -> Frame {
+    Frame::error("ERR Server is shutting down")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::MAX_ARRAY_LEN;
+
+    #[test]
+    fn snapshots_bigger_than_a_request_load() {
+        let path = std::env::temp_dir().join(format!("rudis-snapshot-{}.resp", std::process::id()));
+        let mut persistence = Persistence::new();
+        persistence.enable(path.to_str().unwrap());
+        let mut store = Store::new();
+        let list = (0..=MAX_ARRAY_LEN).map(|i| i.to_string().into_bytes()).collect();
+        store.set(b"big".to_vec(), Value::List(list));
+        persistence.save(&store).unwrap();
+
+        let loaded = persistence.load(Limits::default()).unwrap();
+        let _ = fs::remove_file(&path);
+        match loaded.get(b"big") {
+            Some(Value::List(list)) => assert_eq!(list.len(), MAX_ARRAY_LEN as usize + 1),
+            _ => panic!("the list was not loaded"),
+        }
+    }
+}
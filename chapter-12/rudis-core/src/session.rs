@@ -0,0 +1,221 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use crate::frame::Frame;
+use crate::persistence::shutting_down;
+use crate::tracking::Tracking;
+
+const DEFAULT_MAX_CLIENTS: usize = 10000;
+
+/// Frames the server sends a client on its own, like MONITOR output.
+/// The network frontend writes them to the client as they arrive.
+pub type Pushes = UnboundedReceiver<Frame>;
+
+pub(crate) struct Client {
+    push: Option<UnboundedSender<Frame>>,
+    pub(crate) monitor: bool,
+    // channels the client is subscribed to
+    pub(crate) channels: BTreeSet<Vec<u8>>,
+    // set by HELLO 3, the client understands push frames
+    pub(crate) resp3: bool,
+    // set by CLIENT TRACKING ON
+    pub(crate) tracking: Option<Tracking>,
+    // keys the client is told about when they change
+    pub(crate) tracked: HashSet<Vec<u8>>,
+}
+
+impl Client {
+    pub(crate) fn push(&self, frame: Frame) {
+        if let Some(push) = &self.push {
+            let _ = push.unbounded_send(frame);
+        }
+    }
+
+    /// Pub/sub messages are arrays in RESP2 and push frames in RESP3.
+    pub(crate) fn pubsub_frame(&self, items: Vec<Frame>) -> Frame {
+        if self.resp3 {
+            Frame::Push(items)
+        } else {
+            Frame::Array(items)
+        }
+    }
+}
+
+/// Every connected client, so that clients can reach each other.
+pub(crate) struct Clients {
+    next_id: u64,
+    pub(crate) max_clients: usize,
+    closed: bool,
+    pub(crate) clients: HashMap<u64, Client>,
+    // subscribers of each channel
+    channels: HashMap<Vec<u8>, HashSet<u64>>,
+    // clients that read each key, to be told when it changes
+    pub(crate) tracked: HashMap<Vec<u8>, HashSet<u64>>,
+    // clients tracking in BCAST mode, told about every key
+    pub(crate) bcast: HashSet<u64>,
+}
+
+impl Clients {
+    pub(crate) fn new() -> Self {
+        Clients {
+            next_id: 0,
+            max_clients: DEFAULT_MAX_CLIENTS,
+            closed: false,
+            clients: HashMap::new(),
+            channels: HashMap::new(),
+            tracked: HashMap::new(),
+            bcast: HashSet::new(),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub(crate) fn get_mut(&mut self, id: u64) -> Option<&mut Client> {
+        self.clients.get_mut(&id)
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Client> {
+        self.clients.values()
+    }
+
+    /// Subscribes the client to `channel`, returns the number of channels
+    /// the client is subscribed to afterwards.
+    pub(crate) fn subscribe(&mut self, id: u64, channel: &[u8]) -> usize {
+        let client = match self.clients.get_mut(&id) {
+            Some(client) => client,
+            None => return 0,
+        };
+        client.channels.insert(channel.to_vec());
+        self.channels.entry(channel.to_vec()).or_default().insert(id);
+        client.channels.len()
+    }
+
+    pub(crate) fn unsubscribe(&mut self, id: u64, channel: &[u8]) -> usize {
+        if let Some(subscribers) = self.channels.get_mut(channel) {
+            subscribers.remove(&id);
+            if subscribers.is_empty() {
+                self.channels.remove(channel);
+            }
+        }
+        match self.clients.get_mut(&id) {
+            Some(client) => {
+                client.channels.remove(channel);
+                client.channels.len()
+            }
+            None => 0,
+        }
+    }
+
+    /// Sends `message` to every subscriber of `channel`, returns the number
+    /// of clients that received it.
+    pub(crate) fn publish(&self, channel: &[u8], message: &[u8]) -> usize {
+        let subscribers = match self.channels.get(channel) {
+            Some(subscribers) => subscribers,
+            None => return 0,
+        };
+        let frame = vec![Frame::bulk("message"), Frame::bulk(channel), Frame::bulk(message)];
+        for id in subscribers {
+            if let Some(client) = self.clients.get(id) {
+                client.push(client.pubsub_frame(frame.clone()));
+            }
+        }
+        subscribers.len()
+    }
+
+    /// Refuses new clients and ends the push streams of the connected ones.
+    pub(crate) fn close(&mut self) {
+        self.closed = true;
+        for client in self.clients.values_mut() {
+            client.push = None;
+        }
+    }
+}
+
+/// Per connection state that outlives a single command.
+pub struct Session {
+    pub(crate) id: u64,
+    pub(crate) peer: String,
+    // set by ASKING, valid for the next command only
+    pub(crate) asking: bool,
+    // set by CLIENT CACHING, valid for the next command only
+    pub(crate) caching: Option<bool>,
+    clients: Arc<Mutex<Clients>>,
+}
+
+impl Session {
+    pub(crate) fn connect(clients: &Arc<Mutex<Clients>>, peer: String)
+        -> Result<(Session, Pushes), Frame>
+    {
+        let mut registry = clients.lock().unwrap();
+        if registry.closed {
+            return Err(shutting_down());
+        }
+        if registry.len() >= registry.max_clients {
+            return Err(Frame::error("ERR max number of clients reached"));
+        }
+        let id = registry.next_id;
+        registry.next_id += 1;
+        let (tx, rx) = mpsc::unbounded();
+        registry.clients.insert(id, Client {
+            push: Some(tx),
+            monitor: false,
+            channels: BTreeSet::new(),
+            resp3: false,
+            tracking: None,
+            tracked: HashSet::new(),
+        });
+        let session = Session {
+            id,
+            peer,
+            asking: false,
+            caching: None,
+            clients: Arc::clone(clients),
+        };
+        Ok((session, rx))
+    }
+
+    pub fn peer(&self) -> &str {
+        &self.peer
+    }
+
+    pub fn is_monitor(&self) -> bool {
+        self.clients.lock().unwrap()
+            .get_mut(self.id)
+            .map(|c| c.monitor)
+            .unwrap_or(false)
+    }
+
+    /// Whether the client is subscribed to any channel. A RESP2 client
+    /// can then only send the pub/sub commands.
+    pub fn is_subscribed(&self) -> bool {
+        self.clients.lock().unwrap()
+            .get_mut(self.id)
+            .map(|c| !c.channels.is_empty())
+            .unwrap_or(false)
+    }
+
+    pub fn is_resp3(&self) -> bool {
+        self.clients.lock().unwrap()
+            .get_mut(self.id)
+            .map(|c| c.resp3)
+            .unwrap_or(false)
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let mut clients = self.clients.lock().unwrap();
+        let channels = clients.get_mut(self.id)
+            .map(|c| c.channels.iter().cloned().collect::<Vec<_>>())
+            .unwrap_or_default();
+        for channel in channels {
+            clients.unsubscribe(self.id, &channel);
+        }
+        clients.untrack(self.id);
+        clients.clients.remove(&self.id);
+    }
+}
@@ -0,0 +1,67 @@
+// The set commands. A set that loses its last member is removed.
+
+use crate::commands::Reply;
+use crate::encoding::Set;
+use crate::engine::Engine;
+use crate::frame::Frame;
+use crate::session::Session;
+use crate::store::{wrong_type, Store, Value};
+
+fn set_mut<'a>(store: &'a mut Store, key: &[u8]) -> Result<Option<&'a mut Set>, Frame> {
+    match store.get_mut(key) {
+        Some(Value::Set(set)) => Ok(Some(set)),
+        Some(_) => Err(wrong_type()),
+        None => Ok(None),
+    }
+}
+
+fn set<'a>(store: &'a Store, key: &[u8]) -> Result<Option<&'a Set>, Frame> {
+    match store.get(key) {
+        Some(Value::Set(set)) => Ok(Some(set)),
+        Some(_) => Err(wrong_type()),
+        None => Ok(None),
+    }
+}
+
+pub(crate) fn sadd(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let mut store = engine.store();
+    let limits = store.limits();
+    if set_mut(&mut store, &args[1])?.is_none() {
+        store.set(args[1].to_vec(), Value::Set(Set::default()));
+    }
+    let set = set_mut(&mut store, &args[1])?.unwrap();
+    let added = args[2..].iter()
+        .filter(|member| set.insert(member.to_vec(), &limits))
+        .count();
+    Ok(Frame::Integer(added as i64))
+}
+
+pub(crate) fn srem(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let mut store = engine.store();
+    let set = match set_mut(&mut store, &args[1])? {
+        Some(set) => set,
+        None => return Ok(Frame::Integer(0)),
+    };
+    let removed = args[2..].iter().filter(|member| set.remove(member)).count();
+    if set.is_empty() {
+        store.remove(&args[1]);
+    }
+    Ok(Frame::Integer(removed as i64))
+}
+
+pub(crate) fn sismember(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let store = engine.store();
+    let member = set(&store, &args[1])?.is_some_and(|set| set.contains(&args[2]));
+    Ok(Frame::Integer(member as i64))
+}
+
+pub(crate) fn smembers(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let store = engine.store();
+    let members = set(&store, &args[1])?.map(Set::members).unwrap_or_default();
+    Ok(Frame::Array(members.into_iter().map(Frame::Bulk).collect()))
+}
+
+pub(crate) fn scard(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let store = engine.store();
+    Ok(Frame::Integer(set(&store, &args[1])?.map_or(0, Set::len) as i64))
+}
@@ -0,0 +1,195 @@
+// SORT and SORT_RO. The elements of a list, set or sorted set are sorted
+// by their own value or by weights looked up in other keys, and the reply
+// can pick values from other keys too.
+//
+// SORT key [BY pattern] [LIMIT offset count] [GET pattern ...] [ASC|DESC]
+//     [ALPHA] [STORE destination]
+
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+
+use crate::commands::{parse_int, Reply};
+use crate::engine::Engine;
+use crate::frame::Frame;
+use crate::session::Session;
+use crate::store::{wrong_type, Store, Value};
+
+#[derive(Default)]
+struct Options {
+    by: Option<Vec<u8>>,
+    // offset and count, a negative count takes everything after offset
+    limit: Option<(i64, i64)>,
+    get: Vec<Vec<u8>>,
+    desc: bool,
+    alpha: bool,
+    store: Option<Vec<u8>>,
+}
+
+impl Options {
+    fn parse(args: &[Vec<u8>], readonly: bool) -> Result<Options, Frame> {
+        let mut options = Options::default();
+        let mut rest = args;
+        while let Some(option) = rest.first() {
+            let value = |n: usize| rest.get(n).ok_or_else(|| Frame::error("ERR syntax error"));
+            match option.to_ascii_uppercase().as_slice() {
+                b"ASC" => options.desc = false,
+                b"DESC" => options.desc = true,
+                b"ALPHA" => options.alpha = true,
+                b"BY" => {
+                    options.by = Some(value(1)?.clone());
+                    rest = &rest[1..];
+                }
+                b"GET" => {
+                    options.get.push(value(1)?.clone());
+                    rest = &rest[1..];
+                }
+                b"LIMIT" => {
+                    options.limit = Some((parse_int(value(1)?)?, parse_int(value(2)?)?));
+                    rest = &rest[2..];
+                }
+                b"STORE" if !readonly => {
+                    options.store = Some(value(1)?.clone());
+                    rest = &rest[1..];
+                }
+                _ => return Err(Frame::error("ERR syntax error")),
+            }
+            rest = &rest[1..];
+        }
+        Ok(options)
+    }
+
+    // A BY pattern without a `*` names the same key for every element,
+    // which means do not sort at all.
+    fn sorting(&self) -> bool {
+        self.by.as_ref().is_none_or(|by| by.contains(&b'*'))
+    }
+}
+
+// Resolves a BY or GET pattern for an element: the first `*` is replaced
+// by the element and a `->field` suffix reads a hash field instead of a
+// string. `#` stands for the element itself.
+fn lookup(store: &Store, pattern: &[u8], element: &[u8]) -> Option<Vec<u8>> {
+    if pattern == b"#" {
+        return Some(element.to_vec());
+    }
+    let star = pattern.iter().position(|&b| b == b'*')?;
+    let arrow = pattern[star + 1..].windows(2)
+        .position(|w| w == b"->")
+        .map(|i| star + 1 + i)
+        .filter(|&i| i + 2 < pattern.len());
+    let (key_pattern, field) = match arrow {
+        Some(i) => (&pattern[..i], Some(&pattern[i + 2..])),
+        None => (pattern, None),
+    };
+    let mut key = key_pattern[..star].to_vec();
+    key.extend_from_slice(element);
+    key.extend_from_slice(&key_pattern[star + 1..]);
+    match (store.get(&key)?, field) {
+        (Value::Str(s), None) => Some(s.clone()),
+        (Value::Hash(hash), Some(field)) => hash.get(field).map(<[u8]>::to_vec),
+        _ => None,
+    }
+}
+
+fn elements(store: &Store, key: &[u8], desc: bool, sorting: bool) -> Result<Vec<Vec<u8>>, Frame> {
+    Ok(match store.get(key) {
+        None => vec![],
+        Some(Value::List(list)) => list.iter().cloned().collect(),
+        Some(Value::Set(set)) => set.members(),
+        // unsorted, a sorted set still honours DESC by its own order
+        Some(Value::ZSet(zset)) if !sorting && desc => {
+            zset.iter().rev().map(|(member, _)| member.to_vec()).collect()
+        }
+        Some(Value::ZSet(zset)) => zset.iter().map(|(member, _)| member.to_vec()).collect(),
+        Some(_) => return Err(wrong_type()),
+    })
+}
+
+enum Weight {
+    Number(f64),
+    Alpha(Option<Vec<u8>>),
+}
+
+fn weight(store: &Store, options: &Options, element: &[u8]) -> Result<Weight, Frame> {
+    let value = match &options.by {
+        Some(by) => lookup(store, by, element),
+        None => Some(element.to_vec()),
+    };
+    if options.alpha {
+        return Ok(Weight::Alpha(value));
+    }
+    // a missing weight key counts as 0
+    let value = match value {
+        Some(value) => value,
+        None => return Ok(Weight::Number(0.0)),
+    };
+    std::str::from_utf8(&value).ok()
+        .and_then(|v| v.trim().parse::<f64>().ok())
+        .filter(|v| !v.is_nan())
+        .map(Weight::Number)
+        .ok_or_else(|| Frame::error("ERR One or more scores can't be converted into double"))
+}
+
+fn compare(a: &Weight, b: &Weight) -> Ordering {
+    match (a, b) {
+        (Weight::Number(a), Weight::Number(b)) => a.total_cmp(b),
+        (Weight::Alpha(a), Weight::Alpha(b)) => a.cmp(b),
+        _ => Ordering::Equal,
+    }
+}
+
+fn run(engine: &Engine, session: &Session, args: &[Vec<u8>], readonly: bool) -> Reply {
+    let options = Options::parse(&args[2..], readonly)?;
+    let mut store = engine.store();
+    let sorting = options.sorting();
+    let elements = elements(&store, &args[1], options.desc, sorting)?;
+
+    let mut weighted = elements.into_iter()
+        .map(|element| Ok((weight(&store, &options, &element)?, element)))
+        .collect::<Result<Vec<_>, Frame>>()?;
+    if sorting {
+        // equal weights fall back to the elements, so the order never
+        // depends on how the collection is stored
+        weighted.sort_by(|(wa, a), (wb, b)| {
+            let order = compare(wa, wb).then_with(|| a.cmp(b));
+            if options.desc { order.reverse() } else { order }
+        });
+    }
+
+    let (offset, count) = options.limit.unwrap_or((0, -1));
+    let offset = (offset.max(0) as usize).min(weighted.len());
+    let count = if count < 0 { weighted.len() } else { count as usize };
+    let picked = weighted.iter().skip(offset).take(count);
+    let values = picked
+        .flat_map(|(_, element)| {
+            if options.get.is_empty() {
+                vec![Some(element.clone())]
+            } else {
+                options.get.iter().map(|pattern| lookup(&store, pattern, element)).collect()
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let destination = match options.store {
+        Some(destination) => destination,
+        None => return Ok(Frame::Array(values.into_iter().map(|v| v.map_or(Frame::Null, Frame::Bulk)).collect())),
+    };
+    let stored = values.len();
+    if values.is_empty() {
+        store.remove(&destination);
+    } else {
+        let list = values.into_iter().map(Option::unwrap_or_default).collect::<VecDeque<_>>();
+        store.set(destination.clone(), Value::List(list));
+    }
+    drop(store);
+    engine.clients().invalidate(&[destination.as_slice()], Some(session.id));
+    Ok(Frame::Integer(stored as i64))
+}
+
+pub(crate) fn sort(engine: &Engine, session: &mut Session, args: &[Vec<u8>]) -> Reply {
+    run(engine, session, args, false)
+}
+
+pub(crate) fn sort_ro(engine: &Engine, session: &mut Session, args: &[Vec<u8>]) -> Reply {
+    run(engine, session, args, true)
+}
@@ -0,0 +1,468 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::encoding::{format_score, parse_score, Hash, Limits, Set, ZSet};
+use crate::frame::Frame;
+use crate::lazyfree::{LazyFree, LazyFreeConfig, LAZYFREE_THRESHOLD};
+
+/// A value stored under a key.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Str(Vec<u8>),
+    List(VecDeque<Vec<u8>>),
+    Hash(Hash),
+    Set(Set),
+    ZSet(ZSet),
+}
+
+impl Value {
+    pub(crate) fn type_name(&self) -> &'static str {
+        match self {
+            Value::Str(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::ZSet(_) => "zset",
+        }
+    }
+
+    /// The encoding OBJECT ENCODING reports. Lists always use the one
+    /// encoding, reported under the name Redis uses for large lists.
+    pub(crate) fn encoding(&self) -> &'static str {
+        match self {
+            Value::Str(s) if s.len() <= 20 && std::str::from_utf8(s).ok()
+                .and_then(|s| s.parse::<i64>().ok())
+                .is_some_and(|n| n.to_string().as_bytes() == s.as_slice()) => "int",
+            Value::Str(s) if s.len() <= 44 => "embstr",
+            Value::Str(_) => "raw",
+            Value::List(_) => "quicklist",
+            Value::Hash(hash) => hash.encoding(),
+            Value::Set(set) => set.encoding(),
+            Value::ZSet(zset) => zset.encoding(),
+        }
+    }
+
+    /// An estimate of the bytes the value takes, for MEMORY USAGE. Compact
+    /// encodings cost their contents plus a couple of bytes per entry,
+    /// tables a pointer-sized bucket and an allocation per entry on top.
+    pub(crate) fn memory_usage(&self) -> usize {
+        const ALLOC: usize = 16;
+        const TABLE_ENTRY: usize = 3 * std::mem::size_of::<usize>() + 2 * ALLOC;
+        const PACKED_ENTRY: usize = 2;
+        let vec = std::mem::size_of::<Vec<u8>>();
+        match self {
+            Value::Str(s) => s.len(),
+            Value::List(items) => items.iter().map(|i| vec + i.len() + ALLOC).sum(),
+            Value::Hash(Hash::Listpack(entries)) => entries.iter()
+                .map(|(f, v)| f.len() + v.len() + 2 * PACKED_ENTRY)
+                .sum(),
+            Value::Hash(Hash::Table(entries)) => entries.iter()
+                .map(|(f, v)| f.len() + v.len() + TABLE_ENTRY)
+                .sum(),
+            Value::Set(Set::Intset(members)) => members.len() * std::mem::size_of::<i64>(),
+            Value::Set(Set::Listpack(members)) => members.iter().map(|m| m.len() + PACKED_ENTRY).sum(),
+            Value::Set(Set::Table(members)) => members.iter().map(|m| m.len() + TABLE_ENTRY).sum(),
+            Value::ZSet(ZSet::Listpack(entries)) => entries.iter()
+                .map(|(_, m)| m.len() + std::mem::size_of::<f64>() + PACKED_ENTRY)
+                .sum(),
+            // the member is stored twice, in the table and in the index
+            Value::ZSet(zset) => zset.iter()
+                .map(|(m, _)| 2 * (m.len() + std::mem::size_of::<f64>()) + 2 * TABLE_ENTRY)
+                .sum(),
+        }
+    }
+
+    /// Roughly how many allocations dropping the value frees. Compact
+    /// encodings are a single one.
+    pub(crate) fn free_effort(&self) -> usize {
+        match self {
+            Value::List(items) => items.len(),
+            Value::Hash(Hash::Table(entries)) => entries.len(),
+            Value::Set(Set::Table(members)) => members.len(),
+            Value::ZSet(zset @ ZSet::Skiplist { .. }) => zset.len(),
+            _ => 1,
+        }
+    }
+
+    /// The value as a frame tagged with its type, the way snapshots and
+    /// DUMP payloads store it. Strings are plain bulk strings.
+    pub(crate) fn to_frame(&self) -> Frame {
+        let bulk = |item: &[u8]| Frame::bulk(item);
+        let items = match self {
+            Value::Str(s) => return Frame::bulk(s.as_slice()),
+            Value::List(items) => items.iter().map(|i| bulk(i)).collect(),
+            Value::Hash(hash) => hash.iter().flat_map(|(f, v)| [bulk(f), bulk(v)]).collect(),
+            Value::Set(set) => set.members().into_iter().map(Frame::Bulk).collect(),
+            Value::ZSet(zset) => zset.iter()
+                .flat_map(|(m, score)| [bulk(m), Frame::bulk(format_score(score))])
+                .collect(),
+        };
+        Frame::Array(vec![Frame::bulk(self.type_name()), Frame::Array(items)])
+    }
+
+    /// Rebuilds a value from `to_frame`, in the encoding `limits` call for.
+    pub(crate) fn from_frame(frame: Frame, limits: &Limits) -> Option<Value> {
+        let (kind, items) = match frame {
+            Frame::Bulk(s) => return Some(Value::Str(s)),
+            Frame::Array(tagged) => match <[Frame; 2]>::try_from(tagged).ok()? {
+                [Frame::Bulk(kind), Frame::Array(items)] => (kind, items),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        let items = items.into_iter()
+            .map(|item| match item {
+                Frame::Bulk(item) => Some(item),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(match kind.as_slice() {
+            b"hash" => {
+                let mut hash = Hash::default();
+                for pair in pairs(&items)? {
+                    hash.insert(pair[0].clone(), pair[1].clone(), limits);
+                }
+                Value::Hash(hash)
+            }
+            b"zset" => {
+                let mut zset = ZSet::default();
+                for pair in pairs(&items)? {
+                    zset.insert(pair[0].clone(), parse_score(&pair[1]).ok()?, limits);
+                }
+                Value::ZSet(zset)
+            }
+            b"set" => {
+                let mut set = Set::default();
+                for member in items {
+                    set.insert(member, limits);
+                }
+                Value::Set(set)
+            }
+            b"list" => Value::List(items.into()),
+            _ => return None,
+        })
+    }
+}
+
+// Field and value or member and score pairs, none if one is left over.
+fn pairs(items: &[Vec<u8>]) -> Option<std::slice::Chunks<'_, Vec<u8>>> {
+    items.len().is_multiple_of(2).then(|| items.chunks(2))
+}
+
+/// The reply to a command run against a key holding another type.
+pub(crate) fn wrong_type() -> Frame {
+    Frame::error("WRONGTYPE Operation against a key holding the wrong kind of value")
+}
+
+/// Milliseconds since the UNIX epoch, the unit of expiry times.
+pub fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+struct Entry {
+    value: Value,
+    // absolute, in milliseconds since the UNIX epoch
+    expires_at: Option<u64>,
+    // where the key is in `Store::volatile`, set when it has an expiry
+    slot: Option<usize>,
+    // where the key is in `Store::places`
+    place: usize,
+}
+
+impl Entry {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+}
+
+/// The keyspace. An expired key is invisible right away and removed the
+/// next time a write touches it, or when `expire_some` comes across it.
+#[derive(Default)]
+pub struct Store {
+    keys: HashMap<Vec<u8>, Entry>,
+    // the keys with an expiry, in no order, for `expire_some` to go through
+    volatile: Vec<Vec<u8>>,
+    // where `expire_some` carries on
+    expire_cursor: usize,
+    // every key at a place that stays its own for as long as the key
+    // lives, for `scan` to go through in order
+    places: Vec<Option<Vec<u8>>>,
+    // the free places, the lowest taken first so the end can be trimmed
+    free_places: BinaryHeap<Reverse<usize>>,
+    limits: Limits,
+    // keys removed because they expired, for the engine to invalidate
+    expired: Vec<Vec<u8>>,
+    expired_total: u64,
+    lazyfree_config: LazyFreeConfig,
+    lazyfree: LazyFree,
+}
+
+impl Store {
+    pub fn new() -> Self {
+        Store::default()
+    }
+
+    pub fn with_limits(limits: Limits) -> Self {
+        Store { limits, ..Store::default() }
+    }
+
+    /// When to convert compact encodings.
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    pub fn limits_mut(&mut self) -> &mut Limits {
+        &mut self.limits
+    }
+
+    /// Which implicit deletions free their values in the background.
+    pub fn lazyfree_config(&self) -> LazyFreeConfig {
+        self.lazyfree_config
+    }
+
+    pub fn lazyfree_config_mut(&mut self) -> &mut LazyFreeConfig {
+        &mut self.lazyfree_config
+    }
+
+    /// Values handed to the background thread and not freed yet.
+    pub fn lazyfree_pending(&self) -> usize {
+        self.lazyfree.pending()
+    }
+
+    fn free(&mut self, value: Value, lazy: bool) {
+        if lazy && value.free_effort() > LAZYFREE_THRESHOLD {
+            self.lazyfree.free(value);
+        }
+        // anything else is dropped right here
+    }
+
+    fn live(&self, key: &[u8]) -> Option<&Entry> {
+        self.keys.get(key).filter(|e| !e.is_expired(now_ms()))
+    }
+
+    // Takes the key out of the keyspace, out of `volatile` and out of its
+    // place.
+    fn take(&mut self, key: &[u8]) -> Option<Entry> {
+        let entry = self.keys.remove(key)?;
+        if let Some(slot) = entry.slot {
+            self.unlist(slot);
+        }
+        self.vacate(entry.place);
+        Some(entry)
+    }
+
+    fn place(&mut self, key: &[u8]) -> usize {
+        while let Some(Reverse(place)) = self.free_places.pop() {
+            // places past the end were trimmed off
+            if place < self.places.len() {
+                self.places[place] = Some(key.to_vec());
+                return place;
+            }
+        }
+        self.places.push(Some(key.to_vec()));
+        self.places.len() - 1
+    }
+
+    fn vacate(&mut self, place: usize) {
+        self.places[place] = None;
+        self.free_places.push(Reverse(place));
+        while self.places.last().is_some_and(Option::is_none) {
+            self.places.pop();
+        }
+    }
+
+    fn list(&mut self, key: &[u8]) -> usize {
+        self.volatile.push(key.to_vec());
+        self.volatile.len() - 1
+    }
+
+    fn unlist(&mut self, slot: usize) {
+        self.volatile.swap_remove(slot);
+        // the last key took the place of the removed one
+        if let Some(moved) = self.volatile.get(slot) {
+            if let Some(entry) = self.keys.get_mut(moved) {
+                entry.slot = Some(slot);
+            }
+        }
+    }
+
+    // Drops the key if it expired, so that writes never see a dead value.
+    fn reap(&mut self, key: &[u8]) {
+        if self.keys.get(key).is_some_and(|e| e.is_expired(now_ms())) {
+            self.expire(key);
+        }
+    }
+
+    fn expire(&mut self, key: &[u8]) {
+        if let Some(entry) = self.take(key) {
+            self.free(entry.value, self.lazyfree_config.lazy_expire);
+        }
+        self.expired.push(key.to_vec());
+        self.expired_total += 1;
+    }
+
+    /// Looks at up to `count` keys with an expiry, carrying on where the
+    /// last call stopped, and removes those expired by `now`. Returns how
+    /// many it removed, so the caller can tell whether to go on.
+    pub fn expire_some(&mut self, now: u64, count: usize) -> usize {
+        let mut removed = 0;
+        for _ in 0..count.min(self.volatile.len()) {
+            if self.expire_cursor >= self.volatile.len() {
+                self.expire_cursor = 0;
+            }
+            let expired = self.keys.get(&self.volatile[self.expire_cursor])
+                .is_some_and(|e| e.is_expired(now));
+            if expired {
+                // the last key is moved here and looked at next
+                let key = self.volatile[self.expire_cursor].clone();
+                self.expire(&key);
+                removed += 1;
+            } else {
+                self.expire_cursor += 1;
+            }
+        }
+        removed
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&Value> {
+        self.live(key).map(|e| &e.value)
+    }
+
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<&mut Value> {
+        self.reap(key);
+        self.keys.get_mut(key).map(|e| &mut e.value)
+    }
+
+    /// Stores `value` under `key`, dropping any expiry the key had.
+    pub fn set(&mut self, key: Vec<u8>, value: Value) {
+        self.set_with_expiry(key, value, None);
+    }
+
+    pub fn set_with_expiry(&mut self, key: Vec<u8>, value: Value, expires_at: Option<u64>) {
+        // a key that is written over keeps its place
+        let place = match self.keys.get_mut(&key) {
+            Some(entry) => {
+                let replaced = std::mem::replace(&mut entry.value, value);
+                self.free(replaced, self.lazyfree_config.lazy_server_del);
+                self.change_expiry(&key, expires_at);
+                return;
+            }
+            None => self.place(&key),
+        };
+        let slot = expires_at.map(|_| self.list(&key));
+        self.keys.insert(key, Entry { value, expires_at, slot, place });
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<Value> {
+        self.reap(key);
+        self.take(key).map(|e| e.value)
+    }
+
+    /// Removes the key right away and frees a large value in the
+    /// background. Returns whether the key existed.
+    pub fn unlink(&mut self, key: &[u8]) -> bool {
+        self.reap(key);
+        match self.take(key) {
+            Some(entry) => {
+                self.free(entry.value, true);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes every key, freeing the old keyspace in the background when
+    /// `lazy` is set.
+    pub fn flush(&mut self, lazy: bool) {
+        let keys = std::mem::take(&mut self.keys);
+        self.volatile.clear();
+        self.places.clear();
+        self.free_places.clear();
+        if lazy && !keys.is_empty() {
+            self.lazyfree.free(keys);
+        }
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.live(key).is_some()
+    }
+
+    /// When the key expires, `None` for a missing key or one without expiry.
+    pub fn expiry(&self, key: &[u8]) -> Option<u64> {
+        self.live(key).and_then(|e| e.expires_at)
+    }
+
+    /// Sets or clears the expiry of a key, returns false if it does not exist.
+    pub fn set_expiry(&mut self, key: &[u8], expires_at: Option<u64>) -> bool {
+        self.reap(key);
+        if !self.keys.contains_key(key) {
+            return false;
+        }
+        self.change_expiry(key, expires_at);
+        true
+    }
+
+    // Sets or clears the expiry of a key there is, listing it in
+    // `volatile` or not to match.
+    fn change_expiry(&mut self, key: &[u8], expires_at: Option<u64>) {
+        let slot = self.keys[key].slot;
+        let slot = match (slot, expires_at) {
+            (None, Some(_)) => Some(self.list(key)),
+            (Some(slot), None) => {
+                self.unlist(slot);
+                None
+            }
+            (slot, _) => slot,
+        };
+        let entry = self.keys.get_mut(key).unwrap();
+        entry.expires_at = expires_at;
+        entry.slot = slot;
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, &Value)> {
+        self.iter_with_expiry().map(|(k, v, _)| (k, v))
+    }
+
+    pub fn iter_with_expiry(&self) -> impl Iterator<Item = (&Vec<u8>, &Value, Option<u64>)> {
+        let now = now_ms();
+        self.keys.iter()
+            .filter(move |(_, e)| !e.is_expired(now))
+            .map(|(k, e)| (k, &e.value, e.expires_at))
+    }
+
+    /// Goes through `count` places of the keyspace from `cursor` on, for
+    /// SCAN. Returns the live keys found there and the cursor to carry on
+    /// from, 0 once the end is reached. A key keeps its place for as long
+    /// as it lives, so one there for the whole scan is returned once.
+    pub fn scan(&self, cursor: usize, count: usize) -> (usize, Vec<&Vec<u8>>) {
+        let now = now_ms();
+        let end = cursor.saturating_add(count).min(self.places.len());
+        let keys = self.places.get(cursor..end).unwrap_or(&[]).iter()
+            .flatten()
+            .filter(|key| self.keys.get(*key).is_some_and(|e| !e.is_expired(now)))
+            .collect();
+        (if end >= self.places.len() { 0 } else { end }, keys)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.iter().map(|(k, _)| k)
+    }
+
+    /// The number of keys, counting expired ones not removed yet.
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// How many keys were removed because they expired.
+    pub fn expired_total(&self) -> u64 {
+        self.expired_total
+    }
+
+    /// The keys that expired since the last call.
+    pub(crate) fn take_expired(&mut self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.expired)
+    }
+}
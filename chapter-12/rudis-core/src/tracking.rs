@@ -0,0 +1,230 @@
+// Server assisted client side caching. A client with tracking on is told
+// which keys changed, so it can drop them from its cache:
+//
+// - by default the server remembers the keys each client read,
+// - in OPTIN mode only the keys read right after CLIENT CACHING YES, in
+//   OPTOUT mode all but those read right after CLIENT CACHING NO,
+// - in BCAST mode the client hears about every key matching its prefixes,
+//   read or not.
+//
+// RESP3 clients get invalidations as push frames. RESP2 clients redirect
+// them to another connection subscribed to __redis__:invalidate.
+
+use std::collections::HashMap;
+
+use crate::frame::Frame;
+use crate::session::{Client, Clients};
+
+pub(crate) const INVALIDATE_CHANNEL: &[u8] = b"__redis__:invalidate";
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Tracking {
+    redirect: Option<u64>,
+    bcast: bool,
+    prefixes: Vec<Vec<u8>>,
+    optin: bool,
+    optout: bool,
+    // don't tell the client about the keys it changed itself
+    noloop: bool,
+}
+
+impl Tracking {
+    /// Parses the options following CLIENT TRACKING ON.
+    pub(crate) fn parse(args: &[Vec<u8>]) -> Result<Tracking, Frame> {
+        let mut tracking = Tracking::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let option = String::from_utf8_lossy(arg).to_uppercase();
+            match option.as_str() {
+                "BCAST" => tracking.bcast = true,
+                "OPTIN" => tracking.optin = true,
+                "OPTOUT" => tracking.optout = true,
+                "NOLOOP" => tracking.noloop = true,
+                "REDIRECT" => {
+                    let id = args.next()
+                        .and_then(|id| std::str::from_utf8(id).ok()?.parse().ok())
+                        .ok_or_else(|| Frame::error("ERR Invalid client ID"))?;
+                    tracking.redirect = Some(id);
+                }
+                "PREFIX" => {
+                    let prefix = args.next().ok_or_else(|| Frame::error("ERR syntax error"))?;
+                    tracking.prefixes.push(prefix.to_vec());
+                }
+                _ => return Err(Frame::error("ERR syntax error")),
+            }
+        }
+        if tracking.optin && tracking.optout {
+            return Err(Frame::error(
+                "ERR You can't use both OPTIN and OPTOUT"
+            ));
+        }
+        if tracking.bcast && (tracking.optin || tracking.optout) {
+            return Err(Frame::error(
+                "ERR OPTIN and OPTOUT are not compatible with BCAST"
+            ));
+        }
+        if !tracking.bcast && !tracking.prefixes.is_empty() {
+            return Err(Frame::error(
+                "ERR PREFIX option requires BCAST mode to be enabled"
+            ));
+        }
+        Ok(tracking)
+    }
+
+    pub(crate) fn redirect(&self) -> Option<u64> {
+        self.redirect
+    }
+
+    pub(crate) fn optin(&self) -> bool {
+        self.optin
+    }
+
+    pub(crate) fn optout(&self) -> bool {
+        self.optout
+    }
+
+    // Whether a read is remembered, given the CLIENT CACHING that came
+    // right before it, if any.
+    fn tracks_read(&self, caching: Option<bool>) -> bool {
+        !self.bcast && match (self.optin, self.optout) {
+            (true, _) => caching == Some(true),
+            (_, true) => caching != Some(false),
+            _ => true,
+        }
+    }
+
+    fn wants(&self, id: u64, key: &[u8], writer: Option<u64>) -> bool {
+        if self.noloop && writer == Some(id) {
+            return false;
+        }
+        !self.bcast || self.prefixes.is_empty() || self.prefixes.iter().any(|p| key.starts_with(p))
+    }
+}
+
+impl Clients {
+    /// Turns tracking on for client `id`, or changes its options.
+    pub(crate) fn start_tracking(&mut self, id: u64, tracking: Tracking) {
+        let client = match self.clients.get_mut(&id) {
+            Some(client) => client,
+            None => return,
+        };
+        if tracking.bcast {
+            self.bcast.insert(id);
+        } else {
+            self.bcast.remove(&id);
+        }
+        client.tracking = Some(tracking);
+    }
+
+    /// Remembers that client `id` reads `keys`.
+    pub(crate) fn track(&mut self, id: u64, keys: &[&[u8]], caching: Option<bool>) {
+        let client = match self.clients.get_mut(&id) {
+            Some(client) => client,
+            None => return,
+        };
+        if !client.tracking.as_ref().is_some_and(|t| t.tracks_read(caching)) {
+            return;
+        }
+        for key in keys {
+            client.tracked.insert(key.to_vec());
+            self.tracked.entry(key.to_vec()).or_default().insert(id);
+        }
+    }
+
+    /// Turns tracking off for client `id` and forgets the keys it read,
+    /// once it asks to or goes.
+    pub(crate) fn untrack(&mut self, id: u64) {
+        self.bcast.remove(&id);
+        let client = match self.clients.get_mut(&id) {
+            Some(client) => client,
+            None => return,
+        };
+        client.tracking = None;
+        for key in std::mem::take(&mut client.tracked) {
+            if let Some(readers) = self.tracked.get_mut(&key) {
+                readers.remove(&id);
+                if readers.is_empty() {
+                    self.tracked.remove(&key);
+                }
+            }
+        }
+    }
+
+    /// Tells every client caching `keys` that they changed. `writer` is the
+    /// client that changed them, if any.
+    pub(crate) fn invalidate(&mut self, keys: &[&[u8]], writer: Option<u64>) {
+        let mut invalidated: HashMap<u64, Vec<Frame>> = HashMap::new();
+        for key in keys {
+            // a key is reported once, it has to be read again to be tracked
+            let readers = self.tracked.remove(*key).unwrap_or_default();
+            for id in &readers {
+                if let Some(client) = self.clients.get_mut(id) {
+                    client.tracked.remove(*key);
+                }
+            }
+            for id in readers.union(&self.bcast) {
+                let wants = self.clients.get(id)
+                    .and_then(|c| c.tracking.as_ref())
+                    .is_some_and(|t| t.wants(*id, key, writer));
+                if wants {
+                    invalidated.entry(*id).or_default().push(Frame::bulk(*key));
+                }
+            }
+        }
+        for (id, keys) in invalidated {
+            self.send_invalidation(id, Frame::Array(keys));
+        }
+    }
+
+    /// Tells every tracking client to drop its whole cache, after a flush.
+    pub(crate) fn invalidate_all(&mut self) {
+        self.tracked.clear();
+        let tracking = self.clients.iter_mut()
+            .filter(|(_, c)| c.tracking.is_some())
+            .map(|(id, c)| {
+                c.tracked.clear();
+                *id
+            })
+            .collect::<Vec<_>>();
+        for id in tracking {
+            self.send_invalidation(id, Frame::Null);
+        }
+    }
+
+    // `keys` is the array of changed keys, or null for all of them.
+    fn send_invalidation(&self, id: u64, keys: Frame) {
+        let client = match self.clients.get(&id) {
+            Some(client) => client,
+            None => return,
+        };
+        let redirect = client.tracking.as_ref().and_then(|t| t.redirect);
+        let target = match redirect {
+            Some(redirect) => match self.clients.get(&redirect) {
+                Some(target) => target,
+                None => {
+                    if client.resp3 {
+                        client.push(Frame::Push(vec![
+                            Frame::bulk("tracking-redir-broken"),
+                            Frame::Integer(redirect as i64),
+                        ]));
+                    }
+                    return;
+                }
+            },
+            None => client,
+        };
+        deliver(target, keys);
+    }
+}
+
+fn deliver(target: &Client, keys: Frame) {
+    if target.resp3 {
+        target.push(Frame::Push(vec![Frame::bulk("invalidate"), keys]));
+    } else if target.channels.contains(INVALIDATE_CHANNEL) {
+        target.push(Frame::Array(vec![
+            Frame::bulk("message"),
+            Frame::bulk(INVALIDATE_CHANNEL),
+            keys,
+        ]));
+    }
+}
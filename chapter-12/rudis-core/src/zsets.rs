@@ -0,0 +1,153 @@
+// The sorted set commands. A sorted set that loses its last member is
+// removed.
+
+use crate::commands::{parse_int, Reply};
+use crate::encoding::{format_score, parse_score, ZSet};
+use crate::engine::Engine;
+use crate::frame::Frame;
+use crate::lists::index_range;
+use crate::session::Session;
+use crate::store::{wrong_type, Store, Value};
+
+fn zset_mut<'a>(store: &'a mut Store, key: &[u8]) -> Result<Option<&'a mut ZSet>, Frame> {
+    match store.get_mut(key) {
+        Some(Value::ZSet(zset)) => Ok(Some(zset)),
+        Some(_) => Err(wrong_type()),
+        None => Ok(None),
+    }
+}
+
+fn zset<'a>(store: &'a Store, key: &[u8]) -> Result<Option<&'a ZSet>, Frame> {
+    match store.get(key) {
+        Some(Value::ZSet(zset)) => Ok(Some(zset)),
+        Some(_) => Err(wrong_type()),
+        None => Ok(None),
+    }
+}
+
+fn zset_or_new<'a>(store: &'a mut Store, key: &[u8]) -> Result<&'a mut ZSet, Frame> {
+    if zset_mut(store, key)?.is_none() {
+        store.set(key.to_vec(), Value::ZSet(ZSet::default()));
+    }
+    Ok(zset_mut(store, key)?.unwrap())
+}
+
+// ZADD key [NX|XX] [CH] score member [score member ...]
+pub(crate) fn zadd(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let (mut nx, mut xx, mut ch) = (false, false, false);
+    let mut rest = &args[2..];
+    while let Some(option) = rest.first() {
+        match option.to_ascii_uppercase().as_slice() {
+            b"NX" => nx = true,
+            b"XX" => xx = true,
+            b"CH" => ch = true,
+            _ => break,
+        }
+        rest = &rest[1..];
+    }
+    if nx && xx {
+        return Err(Frame::error("ERR XX and NX options at the same time are not compatible"));
+    }
+    if rest.is_empty() || !rest.len().is_multiple_of(2) {
+        return Err(Frame::error("ERR syntax error"));
+    }
+    let pairs = rest.chunks(2)
+        .map(|pair| Ok((parse_score(&pair[0])?, &pair[1])))
+        .collect::<Result<Vec<_>, Frame>>()?;
+    let mut store = engine.store();
+    let limits = store.limits();
+    let zset = zset_or_new(&mut store, &args[1])?;
+    let mut changed = 0;
+    for (score, member) in pairs {
+        let current = zset.score(member);
+        if (nx && current.is_some()) || (xx && current.is_none()) || current == Some(score) {
+            continue;
+        }
+        let added = zset.insert(member.to_vec(), score, &limits);
+        if added || ch {
+            changed += 1;
+        }
+    }
+    // XX on a missing key must not leave an empty sorted set behind
+    if zset.is_empty() {
+        store.remove(&args[1]);
+    }
+    Ok(Frame::Integer(changed))
+}
+
+pub(crate) fn zincrby(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let by = parse_score(&args[2])?;
+    let mut store = engine.store();
+    let limits = store.limits();
+    let zset = zset_or_new(&mut store, &args[1])?;
+    let score = zset.score(&args[3]).unwrap_or(0.0) + by;
+    if score.is_nan() {
+        return Err(Frame::error("ERR resulting score is not a number (NaN)"));
+    }
+    zset.insert(args[3].to_vec(), score, &limits);
+    Ok(Frame::bulk(format_score(score)))
+}
+
+pub(crate) fn zrem(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let mut store = engine.store();
+    let zset = match zset_mut(&mut store, &args[1])? {
+        Some(zset) => zset,
+        None => return Ok(Frame::Integer(0)),
+    };
+    let removed = args[2..].iter().filter(|member| zset.remove(member).is_some()).count();
+    if zset.is_empty() {
+        store.remove(&args[1]);
+    }
+    Ok(Frame::Integer(removed as i64))
+}
+
+pub(crate) fn zscore(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let store = engine.store();
+    Ok(zset(&store, &args[1])?
+        .and_then(|zset| zset.score(&args[2]))
+        .map_or(Frame::Null, |score| Frame::bulk(format_score(score))))
+}
+
+pub(crate) fn zcard(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let store = engine.store();
+    Ok(Frame::Integer(zset(&store, &args[1])?.map_or(0, ZSet::len) as i64))
+}
+
+pub(crate) fn zrank(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let store = engine.store();
+    Ok(zset(&store, &args[1])?
+        .and_then(|zset| zset.rank(&args[2]))
+        .map_or(Frame::Null, |rank| Frame::Integer(rank as i64)))
+}
+
+// ZRANGE key start stop [REV] [WITHSCORES], by rank only.
+pub(crate) fn zrange(engine: &Engine, _: &mut Session, args: &[Vec<u8>]) -> Reply {
+    let (start, stop) = (parse_int(&args[2])?, parse_int(&args[3])?);
+    let (mut rev, mut withscores) = (false, false);
+    for option in &args[4..] {
+        match option.to_ascii_uppercase().as_slice() {
+            b"REV" => rev = true,
+            b"WITHSCORES" => withscores = true,
+            _ => return Err(Frame::error("ERR syntax error")),
+        }
+    }
+    let store = engine.store();
+    let zset = match zset(&store, &args[1])? {
+        Some(zset) => zset,
+        None => return Ok(Frame::Array(vec![])),
+    };
+    let range = index_range(start, stop, zset.len());
+    let members: Box<dyn Iterator<Item = (&[u8], f64)>> = if rev {
+        Box::new(zset.iter().rev())
+    } else {
+        Box::new(zset.iter())
+    };
+    let mut reply = vec![];
+    for (member, score) in members.skip(range.start).take(range.len()) {
+        reply.push(Frame::bulk(member));
+        if withscores {
+            reply.push(Frame::bulk(format_score(score)));
+        }
+    }
+    Ok(Frame::Array(reply))
+}
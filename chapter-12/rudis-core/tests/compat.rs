@@ -0,0 +1,378 @@
+// Differential tests: random command sequences run against the engine and
+// against a small model of what Redis does, and every reply has to match.
+// A failing sequence is shrunk to the shortest one still failing, and
+// proptest keeps it in compat.proptest-regressions to replay it first next
+// time.
+//
+// Keys come from a tiny pool so that commands keep meeting the keys, and
+// the types, other commands left behind.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+
+use proptest::collection::vec;
+use proptest::prelude::*;
+use rudis_core::{Engine, Frame};
+
+#[derive(Debug, Clone)]
+enum Value {
+    Str(Vec<u8>),
+    List(VecDeque<Vec<u8>>),
+    Hash(BTreeMap<Vec<u8>, Vec<u8>>),
+    Set(BTreeSet<Vec<u8>>),
+    ZSet(BTreeMap<Vec<u8>, f64>),
+}
+
+impl Value {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::Str(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::ZSet(_) => "zset",
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match self {
+            Value::Str(_) => false,
+            Value::List(list) => list.is_empty(),
+            Value::Hash(hash) => hash.is_empty(),
+            Value::Set(set) => set.is_empty(),
+            Value::ZSet(zset) => zset.is_empty(),
+        }
+    }
+}
+
+fn wrong_type() -> Frame {
+    Frame::error("WRONGTYPE Operation against a key holding the wrong kind of value")
+}
+
+fn not_integer() -> Frame {
+    Frame::error("ERR value is not an integer or out of range")
+}
+
+fn bulks<'a>(items: impl IntoIterator<Item = &'a Vec<u8>>) -> Frame {
+    Frame::Array(items.into_iter().map(|i| Frame::bulk(i.as_slice())).collect())
+}
+
+// Redis parses integers strictly: no sign but a minus, no spaces.
+fn parse_int(arg: &[u8]) -> Result<i64, Frame> {
+    let s = std::str::from_utf8(arg).map_err(|_| not_integer())?;
+    if s.starts_with('+') || s.trim() != s {
+        return Err(not_integer());
+    }
+    s.parse().map_err(|_| not_integer())
+}
+
+fn format_score(score: f64) -> String {
+    score.to_string()
+}
+
+fn range(start: i64, stop: i64, len: usize) -> std::ops::Range<usize> {
+    let len = len as i64;
+    let start = if start < 0 { (len + start).max(0) } else { start };
+    let stop = if stop < 0 { len + stop } else { stop.min(len - 1) };
+    if start > stop || start >= len {
+        return 0..0;
+    }
+    start as usize..stop as usize + 1
+}
+
+// A key holding the wrong type fails the command, a missing key reads as
+// an empty value of the right one.
+macro_rules! typed {
+    ($model:expr, $key:expr, $variant:ident) => {
+        match $model.keys.get($key) {
+            Some(Value::$variant(value)) => Some(value),
+            Some(_) => return Err(wrong_type()),
+            None => None,
+        }
+    };
+}
+
+macro_rules! typed_mut {
+    ($model:expr, $key:expr, $variant:ident, $new:expr) => {
+        match $model.keys.entry($key.to_vec()).or_insert_with(|| Value::$variant($new)) {
+            Value::$variant(value) => value,
+            _ => return Err(wrong_type()),
+        }
+    };
+}
+
+#[derive(Default)]
+struct Model {
+    keys: HashMap<Vec<u8>, Value>,
+}
+
+impl Model {
+    fn apply(&mut self, args: &[Vec<u8>]) -> Frame {
+        let reply = self.run(args);
+        // like the engine, no command leaves an empty collection behind
+        self.keys.retain(|_, value| !value.is_empty());
+        match reply {
+            Ok(reply) | Err(reply) => reply,
+        }
+    }
+
+    fn run(&mut self, args: &[Vec<u8>]) -> Result<Frame, Frame> {
+        let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+        let key = args[1].as_slice();
+        match name.as_str() {
+            "GET" => Ok(typed!(self, key, Str).map_or(Frame::Null, |s| Frame::bulk(s.as_slice()))),
+            "SET" => {
+                self.keys.insert(key.to_vec(), Value::Str(args[2].clone()));
+                Ok(Frame::ok())
+            }
+            "INCR" => self.increment(key, 1),
+            "DECR" => self.increment(key, -1),
+            "INCRBY" => self.increment(key, parse_int(&args[2])?),
+            "DEL" | "UNLINK" => {
+                Ok(Frame::Integer(args[1..].iter().filter(|k| self.keys.remove(*k).is_some()).count() as i64))
+            }
+            "EXISTS" => Ok(Frame::Integer(args[1..].iter().filter(|k| self.keys.contains_key(*k)).count() as i64)),
+            "TYPE" => Ok(Frame::Simple(self.keys.get(key).map_or("none", Value::type_name).to_string())),
+
+            "LPUSH" | "RPUSH" => {
+                let list = typed_mut!(self, key, List, VecDeque::new());
+                for element in &args[2..] {
+                    if name == "LPUSH" {
+                        list.push_front(element.clone());
+                    } else {
+                        list.push_back(element.clone());
+                    }
+                }
+                Ok(Frame::Integer(list.len() as i64))
+            }
+            "LPOP" | "RPOP" => {
+                if typed!(self, key, List).is_none() {
+                    return Ok(Frame::Null);
+                }
+                let list = typed_mut!(self, key, List, VecDeque::new());
+                let popped = if name == "LPOP" { list.pop_front() } else { list.pop_back() };
+                Ok(popped.map_or(Frame::Null, Frame::Bulk))
+            }
+            "LLEN" => Ok(Frame::Integer(typed!(self, key, List).map_or(0, |l| l.len()) as i64)),
+            "LRANGE" => {
+                let (start, stop) = (parse_int(&args[2])?, parse_int(&args[3])?);
+                let list = typed!(self, key, List).cloned().unwrap_or_default();
+                Ok(bulks(list.range(range(start, stop, list.len()))))
+            }
+
+            "HSET" => {
+                let hash = typed_mut!(self, key, Hash, BTreeMap::new());
+                let added = args[2..].chunks(2)
+                    .filter(|pair| hash.insert(pair[0].clone(), pair[1].clone()).is_none())
+                    .count();
+                Ok(Frame::Integer(added as i64))
+            }
+            "HGET" => Ok(typed!(self, key, Hash)
+                .and_then(|h| h.get(&args[2]))
+                .map_or(Frame::Null, |v| Frame::bulk(v.as_slice()))),
+            "HDEL" => {
+                if typed!(self, key, Hash).is_none() {
+                    return Ok(Frame::Integer(0));
+                }
+                let hash = typed_mut!(self, key, Hash, BTreeMap::new());
+                Ok(Frame::Integer(args[2..].iter().filter(|f| hash.remove(*f).is_some()).count() as i64))
+            }
+            "HLEN" => Ok(Frame::Integer(typed!(self, key, Hash).map_or(0, |h| h.len()) as i64)),
+            "HEXISTS" => Ok(Frame::Integer(typed!(self, key, Hash).is_some_and(|h| h.contains_key(&args[2])) as i64)),
+            "HINCRBY" => {
+                let by = parse_int(&args[3])?;
+                let hash = typed_mut!(self, key, Hash, BTreeMap::new());
+                let current = match hash.get(&args[2]) {
+                    Some(value) => parse_int(value).map_err(|_| Frame::error("ERR hash value is not an integer"))?,
+                    None => 0,
+                };
+                let value = current.checked_add(by)
+                    .ok_or_else(|| Frame::error("ERR increment or decrement would overflow"))?;
+                hash.insert(args[2].clone(), value.to_string().into_bytes());
+                Ok(Frame::Integer(value))
+            }
+
+            "SADD" => {
+                let set = typed_mut!(self, key, Set, BTreeSet::new());
+                Ok(Frame::Integer(args[2..].iter().filter(|m| set.insert(m.to_vec())).count() as i64))
+            }
+            "SREM" => {
+                if typed!(self, key, Set).is_none() {
+                    return Ok(Frame::Integer(0));
+                }
+                let set = typed_mut!(self, key, Set, BTreeSet::new());
+                Ok(Frame::Integer(args[2..].iter().filter(|m| set.remove(*m)).count() as i64))
+            }
+            "SISMEMBER" => Ok(Frame::Integer(typed!(self, key, Set).is_some_and(|s| s.contains(&args[2])) as i64)),
+            "SCARD" => Ok(Frame::Integer(typed!(self, key, Set).map_or(0, |s| s.len()) as i64)),
+            // sorted here, the order of a set is unspecified
+            "SMEMBERS" => Ok(bulks(typed!(self, key, Set).cloned().unwrap_or_default().iter())),
+
+            "ZADD" => {
+                let zset = typed_mut!(self, key, ZSet, BTreeMap::new());
+                let added = args[2..].chunks(2)
+                    .filter(|pair| {
+                        let score = std::str::from_utf8(&pair[0]).unwrap().parse().unwrap();
+                        zset.insert(pair[1].clone(), score).is_none()
+                    })
+                    .count();
+                Ok(Frame::Integer(added as i64))
+            }
+            "ZREM" => {
+                if typed!(self, key, ZSet).is_none() {
+                    return Ok(Frame::Integer(0));
+                }
+                let zset = typed_mut!(self, key, ZSet, BTreeMap::new());
+                Ok(Frame::Integer(args[2..].iter().filter(|m| zset.remove(*m).is_some()).count() as i64))
+            }
+            "ZSCORE" => Ok(typed!(self, key, ZSet)
+                .and_then(|z| z.get(&args[2]))
+                .map_or(Frame::Null, |score| Frame::bulk(format_score(*score)))),
+            "ZCARD" => Ok(Frame::Integer(typed!(self, key, ZSet).map_or(0, |z| z.len()) as i64)),
+            "ZRANK" => Ok(typed!(self, key, ZSet)
+                .and_then(|z| sorted(z).iter().position(|(m, _)| *m == args[2]))
+                .map_or(Frame::Null, |rank| Frame::Integer(rank as i64))),
+            "ZRANGE" => {
+                let (start, stop) = (parse_int(&args[2])?, parse_int(&args[3])?);
+                let members = typed!(self, key, ZSet).map(sorted).unwrap_or_default();
+                let withscores = args.len() > 4;
+                let mut reply = vec![];
+                for (member, score) in &members[range(start, stop, members.len())] {
+                    reply.push(Frame::bulk(member.as_slice()));
+                    if withscores {
+                        reply.push(Frame::bulk(format_score(*score)));
+                    }
+                }
+                Ok(Frame::Array(reply))
+            }
+            _ => unreachable!("no model for {}", name),
+        }
+    }
+
+    fn increment(&mut self, key: &[u8], by: i64) -> Result<Frame, Frame> {
+        let current = match typed!(self, key, Str) {
+            Some(s) => parse_int(s)?,
+            None => 0,
+        };
+        let value = current.checked_add(by)
+            .ok_or_else(|| Frame::error("ERR increment or decrement would overflow"))?;
+        self.keys.insert(key.to_vec(), Value::Str(value.to_string().into_bytes()));
+        Ok(Frame::Integer(value))
+    }
+}
+
+// Members by score, then by member for equal scores.
+fn sorted(zset: &BTreeMap<Vec<u8>, f64>) -> Vec<(Vec<u8>, f64)> {
+    let mut members = zset.iter().map(|(m, s)| (m.clone(), *s)).collect::<Vec<_>>();
+    members.sort_by(|(ma, sa), (mb, sb)| sa.total_cmp(sb).then_with(|| ma.cmp(mb)));
+    members
+}
+
+fn key() -> impl Strategy<Value = String> {
+    prop::sample::select(vec!["a", "b", "c"]).prop_map(String::from)
+}
+
+// Integers, around the overflow edge too, and things that are not.
+fn value() -> impl Strategy<Value = String> {
+    prop::sample::select(vec![
+        "0", "1", "-1", "42", "9223372036854775807", "-9223372036854775808", "x", "1.5", " 1", "",
+    ])
+    .prop_map(String::from)
+}
+
+fn field() -> impl Strategy<Value = String> {
+    prop::sample::select(vec!["f", "g", "h"]).prop_map(String::from)
+}
+
+fn index() -> impl Strategy<Value = String> {
+    (-4i64..4).prop_map(|i| i.to_string())
+}
+
+fn score() -> impl Strategy<Value = String> {
+    prop::sample::select(vec!["0", "1", "-2", "1.5", "3"]).prop_map(String::from)
+}
+
+fn command() -> impl Strategy<Value = Vec<String>> {
+    let cmd = |name: &str, args: Vec<String>| {
+        std::iter::once(name.to_string()).chain(args).collect::<Vec<_>>()
+    };
+    prop_oneof![
+        key().prop_map(move |k| cmd("GET", vec![k])),
+        (key(), value()).prop_map(move |(k, v)| cmd("SET", vec![k, v])),
+        key().prop_map(move |k| cmd("INCR", vec![k])),
+        key().prop_map(move |k| cmd("DECR", vec![k])),
+        (key(), value()).prop_map(move |(k, v)| cmd("INCRBY", vec![k, v])),
+        vec(key(), 1..3).prop_map(move |ks| cmd("DEL", ks)),
+        vec(key(), 1..3).prop_map(move |ks| cmd("UNLINK", ks)),
+        vec(key(), 1..3).prop_map(move |ks| cmd("EXISTS", ks)),
+        key().prop_map(move |k| cmd("TYPE", vec![k])),
+        (key(), vec(value(), 1..3)).prop_map(move |(k, vs)| cmd("LPUSH", [vec![k], vs].concat())),
+        (key(), vec(value(), 1..3)).prop_map(move |(k, vs)| cmd("RPUSH", [vec![k], vs].concat())),
+        key().prop_map(move |k| cmd("LPOP", vec![k])),
+        key().prop_map(move |k| cmd("RPOP", vec![k])),
+        key().prop_map(move |k| cmd("LLEN", vec![k])),
+        (key(), index(), index()).prop_map(move |(k, a, b)| cmd("LRANGE", vec![k, a, b])),
+        (key(), vec((field(), value()), 1..3)).prop_map(move |(k, fvs)| {
+            cmd("HSET", std::iter::once(k).chain(fvs.into_iter().flat_map(|(f, v)| [f, v])).collect())
+        }),
+        (key(), field()).prop_map(move |(k, f)| cmd("HGET", vec![k, f])),
+        (key(), vec(field(), 1..3)).prop_map(move |(k, fs)| cmd("HDEL", [vec![k], fs].concat())),
+        key().prop_map(move |k| cmd("HLEN", vec![k])),
+        (key(), field()).prop_map(move |(k, f)| cmd("HEXISTS", vec![k, f])),
+        (key(), field(), value()).prop_map(move |(k, f, v)| cmd("HINCRBY", vec![k, f, v])),
+        (key(), vec(value(), 1..3)).prop_map(move |(k, ms)| cmd("SADD", [vec![k], ms].concat())),
+        (key(), vec(value(), 1..3)).prop_map(move |(k, ms)| cmd("SREM", [vec![k], ms].concat())),
+        (key(), value()).prop_map(move |(k, m)| cmd("SISMEMBER", vec![k, m])),
+        key().prop_map(move |k| cmd("SCARD", vec![k])),
+        key().prop_map(move |k| cmd("SMEMBERS", vec![k])),
+        (key(), vec((score(), field()), 1..3)).prop_map(move |(k, sms)| {
+            cmd("ZADD", std::iter::once(k).chain(sms.into_iter().flat_map(|(s, m)| [s, m])).collect())
+        }),
+        (key(), vec(field(), 1..3)).prop_map(move |(k, ms)| cmd("ZREM", [vec![k], ms].concat())),
+        (key(), field()).prop_map(move |(k, m)| cmd("ZSCORE", vec![k, m])),
+        key().prop_map(move |k| cmd("ZCARD", vec![k])),
+        (key(), field()).prop_map(move |(k, m)| cmd("ZRANK", vec![k, m])),
+        (key(), index(), index(), any::<bool>()).prop_map(move |(k, a, b, withscores)| {
+            let mut args = vec![k, a, b];
+            if withscores {
+                args.push("WITHSCORES".to_string());
+            }
+            cmd("ZRANGE", args)
+        }),
+    ]
+}
+
+// Set members come back in whatever order the encoding keeps them.
+fn normalize(command: &str, reply: Frame) -> Frame {
+    match (command, reply) {
+        ("SMEMBERS", Frame::Array(mut members)) => {
+            members.sort_by(|a, b| match (a, b) {
+                (Frame::Bulk(a), Frame::Bulk(b)) => a.cmp(b),
+                _ => std::cmp::Ordering::Equal,
+            });
+            Frame::Array(members)
+        }
+        (_, reply) => reply,
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(512))]
+
+    #[test]
+    fn replies_match_the_model(commands in vec(command(), 1..40)) {
+        let engine = Engine::new();
+        let (mut session, _pushes) = engine.connect("proptest").unwrap();
+        // small limits, so that the compact encodings get converted too
+        for name in ["hash-max-listpack-entries", "set-max-intset-entries", "set-max-listpack-entries",
+                     "zset-max-listpack-entries"] {
+            engine.execute(&mut session, Frame::command(["CONFIG", "SET", name, "2"]));
+        }
+        let mut model = Model::default();
+        for command in &commands {
+            let args = command.iter().map(|a| a.as_bytes().to_vec()).collect::<Vec<_>>();
+            let expected = model.apply(&args);
+            let actual = normalize(&command[0], engine.execute(&mut session, Frame::command(args)));
+            prop_assert_eq!(actual, expected, "replying to {:?}", command);
+        }
+    }
+}
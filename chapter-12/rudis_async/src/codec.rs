@@ -1,46 +0,0 @@
-use std::io;
-use bytes::BytesMut;
-use tokio_util::codec::{Decoder, Encoder};
-use resp::{Value, Decoder as RespDecoder};
-use std::io::BufReader;
-use std::str;
-
-pub struct RespCodec;
-
-impl Encoder<Vec<u8>> for RespCodec {
-    type Error = io::Error;
-
-    fn encode(&mut self, msg: Vec<u8>, buf: &mut BytesMut)
-    -> io::Result<()> 
-    {
-        buf.reserve(msg.len());
-        buf.extend(msg);
-        Ok(())
-    }
-}
-
-impl Decoder for RespCodec {
-    type Item = Value;
-    type Error = io::Error;
-
-    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Value>> {
-        let s = if let Some(n) = buf.iter().rposition(|b| *b == b'\n') {
-            let client_query = buf.split_to(n + 1);
-            match str::from_utf8(&client_query.as_ref()) {
-                Ok(s) => s.to_string(),
-                Err(_) => return Err(
-                    io::Error::new(io::ErrorKind::Other, "invalid string")
-                ),
-            }
-        } else {
-            return Ok(None);
-        };
-        if let Ok(v) = RespDecoder::new(&mut BufReader::new(s.as_bytes()))
-            .decode()
-        {
-            Ok(Some(v))
-        } else {
-            Ok(None)
-        }
-    }
-}
@@ -0,0 +1,84 @@
+// Command line configuration of the server. The option names follow the
+// redis.conf directives of the same meaning.
+//
+//   rudis_async 127.0.0.1:6378 --tls-port 6380 \
+//       --tls-cert-file rudis.crt --tls-key-file rudis.key \
+//       --tls-ca-cert-file ca.crt --unixsocket /tmp/rudis.sock --unixsocketperm 700 \
+//       --metrics-port 9121
+
+use std::env;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context, Error};
+
+pub struct TlsConfig {
+    pub port: u16,
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+    // when set, clients have to present a certificate signed by this CA
+    pub ca_cert_file: Option<PathBuf>,
+}
+
+pub struct Config {
+    pub addr: SocketAddr,
+    pub cluster: bool,
+    pub dbfilename: Option<String>,
+    pub tls: Option<TlsConfig>,
+    pub unixsocket: Option<PathBuf>,
+    pub unixsocketperm: u32,
+    // Prometheus metrics over HTTP, on the same address as the server
+    pub metrics_port: Option<u16>,
+}
+
+impl Config {
+    pub fn from_args() -> Result<Config, Error> {
+        let mut addr = "127.0.0.1:6378".to_string();
+        let mut cluster = false;
+        let mut dbfilename = None;
+        let mut tls_port = None;
+        let mut tls_cert_file = None;
+        let mut tls_key_file = None;
+        let mut tls_ca_cert_file = None;
+        let mut unixsocket = None;
+        let mut unixsocketperm = 0o700;
+        let mut metrics_port = None;
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| anyhow!("{} expects a value", arg));
+            match arg.as_str() {
+                "--cluster" => cluster = true,
+                "--dbfilename" => dbfilename = Some(value()?),
+                "--tls-port" => {
+                    tls_port = Some(value()?.parse::<u16>().context("invalid --tls-port")?)
+                }
+                "--tls-cert-file" => tls_cert_file = Some(PathBuf::from(value()?)),
+                "--tls-key-file" => tls_key_file = Some(PathBuf::from(value()?)),
+                "--tls-ca-cert-file" => tls_ca_cert_file = Some(PathBuf::from(value()?)),
+                "--unixsocket" => unixsocket = Some(PathBuf::from(value()?)),
+                "--unixsocketperm" => {
+                    unixsocketperm = u32::from_str_radix(&value()?, 8)
+                        .context("--unixsocketperm expects octal permissions")?
+                }
+                "--metrics-port" => {
+                    metrics_port = Some(value()?.parse::<u16>().context("invalid --metrics-port")?)
+                }
+                _ => addr = arg.clone(),
+            }
+        }
+        let addr = addr.parse::<SocketAddr>().context("invalid listening address")?;
+        let tls = match (tls_port, tls_cert_file, tls_key_file) {
+            (Some(port), Some(cert_file), Some(key_file)) => Some(TlsConfig {
+                port,
+                cert_file,
+                key_file,
+                ca_cert_file: tls_ca_cert_file,
+            }),
+            (None, None, None) if tls_ca_cert_file.is_none() => None,
+            _ => return Err(anyhow!(
+                "TLS needs --tls-port, --tls-cert-file and --tls-key-file"
+            )),
+        };
+        Ok(Config { addr, cluster, dbfilename, tls, unixsocket, unixsocketperm, metrics_port })
+    }
+}
@@ -0,0 +1,55 @@
+// Keeps the cluster view of this node in sync with its peers by polling
+// CLUSTER NODES from each of them once a second.
+//
+// A three node cluster on one host:
+//
+//   rudis_async 127.0.0.1:7000 --cluster
+//   rudis_async 127.0.0.1:7001 --cluster
+//   rudis_async 127.0.0.1:7002 --cluster
+//
+// then on 7000 `CLUSTER MEET 127.0.0.1 7001` and `CLUSTER MEET 127.0.0.1 7002`,
+// and `CLUSTER ADDSLOTS` a third of the slots on each node.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use rudis_core::{Engine, Frame, RespCodec};
+use tokio::net::TcpStream;
+use tokio::time;
+use tokio_util::codec::Decoder;
+
+const GOSSIP_INTERVAL: Duration = Duration::from_secs(1);
+
+// Introduces ourselves to the peer at `addr`, so that it polls us back,
+// and fetches its view of the cluster.
+async fn fetch_nodes(addr: SocketAddr, myself: SocketAddr) -> Option<String> {
+    let stream = time::timeout(GOSSIP_INTERVAL, TcpStream::connect(addr))
+        .await.ok()?.ok()?;
+    let mut framed = RespCodec.framed(stream);
+    let (ip, port) = (myself.ip().to_string(), myself.port().to_string());
+    framed.send(Frame::command(["CLUSTER", "MEET", &ip, &port])).await.ok()?;
+    framed.send(Frame::command(["CLUSTER", "NODES"])).await.ok()?;
+    let mut replies = framed.take(2).skip(1);
+    match time::timeout(GOSSIP_INTERVAL, replies.next()).await {
+        Ok(Some(Ok(Frame::Bulk(nodes)))) => String::from_utf8(nodes).ok(),
+        _ => None,
+    }
+}
+
+/// Polls every known peer for its view of the cluster, forever.
+pub async fn gossip(engine: Engine) {
+    let mut interval = time::interval(GOSSIP_INTERVAL);
+    loop {
+        interval.tick().await;
+        let (peers, myself) = {
+            let cluster = engine.cluster().read().unwrap();
+            (cluster.peers(), cluster.myself_addr())
+        };
+        for addr in peers {
+            if let Some(nodes) = fetch_nodes(addr, myself).await {
+                engine.cluster().write().unwrap().merge(addr, &nodes);
+            }
+        }
+    }
+}
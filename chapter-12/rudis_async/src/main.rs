@@ -1,53 +1,265 @@
-mod codec;
-use crate::codec::RespCodec;
+mod config;
+mod gossip;
+mod metrics;
+mod tls;
 
-use lazy_static::lazy_static;
-use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::sync::Mutex;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::sync::Arc;
+use std::time::Duration;
 use anyhow::Error;
-use tokio::net::{TcpListener, TcpStream};
+use rudis_core::{shutting_down, Engine, Frame, RespCodec, SaveMode, EXPIRE_INTERVAL};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, watch};
+use tokio_rustls::TlsAcceptor;
 use tokio_util::codec::Decoder;
 use futures::stream::StreamExt;
-use futures::{SinkExt, TryFutureExt};
-use std::env;
-
-mod commands;
-use crate::commands::process_client_request;
-
-lazy_static! {
-    static ref RUDIS_DB: Mutex<HashMap<String, String>> = 
-        Mutex::new(HashMap::new());
-}
-
-async fn handle_client(client: TcpStream) -> Result<(), Error> {
-    let (mut tx, rx) = RespCodec.framed(client).split();
-    let (input, _) = rx.into_future().await;
-    let input = input.unwrap().unwrap();
-    let reply = process_client_request(input);
-    tx.send(reply)
-        .map_err(|e| {
-            let msg = format!("Failed to process connection; error = {:?}", e);
-            Error::msg(msg)
-        })
-        .await?; 
+use futures::{Sink, SinkExt};
+
+use crate::config::Config;
+
+// A client that hasn't finished its TLS handshake by then is dropped, it
+// would otherwise hold up shutdown for as long as it likes
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// How long a last frame to a client may take to write, a client that
+// doesn't read it would otherwise hold up shutdown too
+const GOODBYE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// What every connection needs, whichever listener accepted it. `_done`
+// is dropped when the connection ends, which is how main knows that every
+// client is gone.
+#[derive(Clone)]
+struct Server {
+    engine: Engine,
+    stop: Arc<watch::Sender<bool>>,
+    _done: mpsc::Sender<()>,
+}
+
+// Removes keys that expired without anyone touching them, forever.
+async fn expire_keys(engine: Engine) {
+    let mut interval = tokio::time::interval(EXPIRE_INTERVAL);
+    loop {
+        interval.tick().await;
+        engine.expire_keys();
+    }
+}
+
+// Resolves once a shutdown has been requested.
+async fn stopping(shutdown: &mut watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|stopping| *stopping).await;
+}
+
+// Sends a last frame to the client, giving up after GOODBYE_TIMEOUT.
+async fn say_goodbye<T>(tx: &mut T, frame: Frame) -> Result<(), Error>
+    where T: Sink<Frame, Error = io::Error> + Unpin
+{
+    match tokio::time::timeout(GOODBYE_TIMEOUT, tx.send(frame)).await {
+        Ok(sent) => Ok(sent?),
+        Err(_) => Err(Error::msg("client stopped reading")),
+    }
+}
+
+async fn handle_client<S>(server: Server, client: S, peer: String) -> Result<(), Error>
+    where S: AsyncRead + AsyncWrite
+{
+    let Server { engine, stop, .. } = server;
+    let (mut tx, mut rx) = RespCodec.framed(client).split();
+    let (mut session, mut pushes) = match engine.connect(peer) {
+        Ok(session) => session,
+        Err(reply) => return say_goodbye(&mut tx, reply).await,
+    };
+    let mut shutdown = stop.subscribe();
+    loop {
+        // a reply or push waiting for a client that stopped reading is
+        // given up on when the server shuts down
+        let sent = tokio::select! {
+            input = rx.next() => {
+                let input = match input {
+                    Some(input) => input?,
+                    None => return Ok(()),
+                };
+                let reply = engine.execute(&mut session, input);
+                // the command that just finished was the last one we serve
+                if engine.shutdown_requested().is_some() {
+                    let sent = say_goodbye(&mut tx, reply).await;
+                    stop.send_replace(true);
+                    return sent;
+                }
+                tokio::select! {
+                    sent = tx.send(reply) => sent.map_err(|e| {
+                        Error::msg(format!("Failed to process connection; error = {:?}", e))
+                    }),
+                    _ = stopping(&mut shutdown) => return Ok(()),
+                }
+            }
+            Some(push) = pushes.next() => tokio::select! {
+                sent = tx.send(push) => sent.map_err(Error::from),
+                _ = stopping(&mut shutdown) => return Ok(()),
+            },
+            _ = stopping(&mut shutdown) => return say_goodbye(&mut tx, shutting_down()).await,
+        };
+        sent?;
+    }
+}
+
+// Serves TCP clients, over TLS when given an acceptor.
+async fn accept_tcp(server: Server, listener: TcpListener, tls: Option<TlsAcceptor>) {
+    let mut shutdown = server.stop.subscribe();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((client, addr)) => {
+                    println!("Client connected: {:?}", addr);
+                    // replies are small, don't hold them back waiting for more
+                    let _ = client.set_nodelay(true);
+                    let server = server.clone();
+                    let tls = tls.clone();
+                    tokio::spawn(async move {
+                        let peer = addr.to_string();
+                        let handled = match tls {
+                            Some(tls) => match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls.accept(client)).await {
+                                Ok(Ok(client)) => handle_client(server, client, peer).await,
+                                Ok(Err(e)) => Err(Error::msg(format!("TLS handshake failed: {}", e))),
+                                Err(_) => Err(Error::msg("TLS handshake timed out")),
+                            },
+                            None => handle_client(server, client, peer).await,
+                        };
+                        if let Err(e) = handled {
+                            println!("Connection from {} failed: {}", addr, e);
+                        }
+                    });
+                }
+                Err(e) => println!("Failed to accept connection: {}", e),
+            },
+            _ = stopping(&mut shutdown) => return,
+        }
+    }
+}
+
+async fn accept_unix(server: Server, listener: UnixListener) {
+    let mut shutdown = server.stop.subscribe();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((client, _)) => {
+                    println!("Client connected on the unix socket");
+                    tokio::spawn(handle_client(server.clone(), client, "unixsocket".to_string()));
+                }
+                Err(e) => println!("Failed to accept connection: {}", e),
+            },
+            _ = stopping(&mut shutdown) => return,
+        }
+    }
+}
+
+fn bind_unix(config: &Config) -> Result<Option<UnixListener>, Error> {
+    let path = match &config.unixsocket {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    // a socket left behind by a previous run would make bind fail, but
+    // anything else at the path is not ours to remove
+    match fs::symlink_metadata(path) {
+        Ok(meta) if !meta.file_type().is_socket() => {
+            return Err(Error::msg(format!("{} exists and is not a socket", path.display())));
+        }
+        Ok(_) if std::os::unix::net::UnixStream::connect(path).is_ok() => {
+            return Err(Error::msg(format!("another server is listening on {}", path.display())));
+        }
+        Ok(_) => fs::remove_file(path)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(config.unixsocketperm))?;
+    println!("rudis_async listening on: {}", path.display());
+    Ok(Some(listener))
+}
+
+async fn wait_for_signal(engine: Engine, stop: Arc<watch::Sender<bool>>)
+    -> std::io::Result<()>
+{
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = sigterm.recv() => println!("Received SIGTERM"),
+        _ = sigint.recv() => println!("Received SIGINT"),
+    }
+    engine.request_shutdown(SaveMode::Default);
+    stop.send_replace(true);
     Ok(())
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let addr = env::args()
-        .skip(1)
-        .next()
-        .unwrap_or("127.0.0.1:6378".to_string());
-    let addr = addr.parse::<SocketAddr>()?;
-
-    let listener = TcpListener::bind(&addr).await?;
+async fn main() -> Result<(), Error> {
+    let config = Config::from_args()?;
+    let engine = Engine::new();
+    if let Some(path) = &config.dbfilename {
+        engine.enable_persistence(path)?;
+    }
+
+    let listener = TcpListener::bind(&config.addr).await?;
+    // the actual address, in case port 0 asked for any free port
+    let addr = listener.local_addr()?;
     println!("rudis_async listening on: {}", addr);
+    let tls = match &config.tls {
+        Some(tls_config) => {
+            let tls_listener = TcpListener::bind((addr.ip(), tls_config.port)).await?;
+            println!("rudis_async listening for TLS on: {}", tls_listener.local_addr()?);
+            Some((tls_listener, tls::acceptor(tls_config)?))
+        }
+        None => None,
+    };
+    let unix_listener = bind_unix(&config)?;
+    let metrics_listener = match config.metrics_port {
+        Some(port) => {
+            let metrics_listener = TcpListener::bind((addr.ip(), port)).await?;
+            println!("rudis_async serving metrics on: {}", metrics_listener.local_addr()?);
+            Some(metrics_listener)
+        }
+        None => None,
+    };
+
+    if config.cluster {
+        engine.enable_cluster(addr);
+        println!("Cluster mode enabled, node id: {}", engine.cluster().read().unwrap().myid());
+        tokio::spawn(gossip::gossip(engine.clone()));
+    }
+
+    tokio::spawn(expire_keys(engine.clone()));
+    let stop = Arc::new(watch::channel(false).0);
+    tokio::spawn(wait_for_signal(engine.clone(), Arc::clone(&stop)));
+    let (done_tx, mut done_rx) = mpsc::channel(1);
+    let server = Server { engine: engine.clone(), stop: Arc::clone(&stop), _done: done_tx };
+
+    let mut listeners = vec![tokio::spawn(accept_tcp(server.clone(), listener, None))];
+    if let Some((tls_listener, acceptor)) = tls {
+        listeners.push(tokio::spawn(accept_tcp(server.clone(), tls_listener, Some(acceptor))));
+    }
+    if let Some(unix_listener) = unix_listener {
+        listeners.push(tokio::spawn(accept_unix(server.clone(), unix_listener)));
+    }
+    if let Some(metrics_listener) = metrics_listener {
+        tokio::spawn(metrics::serve(engine.clone(), metrics_listener, stop.subscribe()));
+    }
+    drop(server);
+    for listener in listeners {
+        let _ = listener.await;
+    }
+
+    println!("Shutting down, waiting for clients to finish");
+    let _ = done_rx.recv().await;
+    engine.close_clients();
+    if let Some(path) = &config.unixsocket {
+        let _ = fs::remove_file(path);
+    }
 
-    while let Ok((client, addr)) = listener.accept().await {
-        println!("Client connected: {:?}", addr);
-        tokio::spawn(handle_client(client));
+    if engine.finish_shutdown()? {
+        println!("Snapshot saved");
     }
+    println!("rudis_async is now ready to exit, bye bye...");
     Ok(())
 }
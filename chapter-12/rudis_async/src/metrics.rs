@@ -0,0 +1,156 @@
+// Prometheus metrics over HTTP. Only `GET /metrics` is served, with the
+// text exposition format, one connection per scrape, so a hand written
+// HTTP/1.1 responder is all it takes.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::Error;
+use rudis_core::{Engine, Stats, LATENCY_BUCKETS};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+
+// Scrapers send a few hundred bytes of headers at most.
+const MAX_REQUEST_LEN: usize = 8192;
+// and right away, a client that trickles them in is dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub async fn serve(engine: Engine, listener: TcpListener, mut shutdown: watch::Receiver<bool>) {
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((client, addr)) => {
+                    let engine = engine.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = respond(engine, client).await {
+                            println!("Metrics request from {} failed: {}", addr, e);
+                        }
+                    });
+                }
+                Err(e) => println!("Failed to accept metrics connection: {}", e),
+            },
+            _ = shutdown.wait_for(|stopping| *stopping) => return,
+        }
+    }
+}
+
+// The request up to the end of its headers, None if the client closed
+// the connection or sent too much before that.
+async fn read_request(client: &mut TcpStream) -> Result<Option<Vec<u8>>, Error> {
+    let mut request = Vec::new();
+    let mut chunk = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = client.read(&mut chunk).await?;
+        if n == 0 || request.len() + n > MAX_REQUEST_LEN {
+            return Ok(None);
+        }
+        request.extend_from_slice(&chunk[..n]);
+    }
+    Ok(Some(request))
+}
+
+async fn respond(engine: Engine, mut client: TcpStream) -> Result<(), Error> {
+    let request = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut client)).await {
+        Ok(request) => match request? {
+            Some(request) => request,
+            None => return Ok(()),
+        },
+        Err(_) => return Err(Error::msg("timed out reading the request")),
+    };
+    let request_line = String::from_utf8_lossy(&request);
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render(&engine.stats())),
+        (Some("GET"), _) => ("404 Not Found", "not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "only GET is supported\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body
+    );
+    client.write_all(response.as_bytes()).await?;
+    Ok(client.shutdown().await?)
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, value: impl std::fmt::Display) {
+    header(out, name, kind, help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+// The resident set size, where /proc tells it.
+fn rss_bytes() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let kb = status.lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(kb * 1024)
+}
+
+pub fn render(stats: &Stats) -> String {
+    let mut out = String::new();
+
+    header(&mut out, "rudis_commands_total", "counter", "Commands processed, by command.");
+    for (name, command) in &stats.commands {
+        let _ = writeln!(out, "rudis_commands_total{{cmd=\"{}\"}} {}", name.to_lowercase(), command.calls);
+    }
+    header(&mut out, "rudis_commands_failed_total", "counter", "Commands answered with an error, by command.");
+    for (name, command) in &stats.commands {
+        let _ = writeln!(
+            out, "rudis_commands_failed_total{{cmd=\"{}\"}} {}", name.to_lowercase(), command.failed_calls
+        );
+    }
+    header(&mut out, "rudis_command_duration_seconds", "histogram", "Time spent running commands, by command.");
+    for (name, command) in &stats.commands {
+        let name = name.to_lowercase();
+        let mut cumulative = 0;
+        for (le, count) in LATENCY_BUCKETS.iter().zip(&command.buckets) {
+            cumulative += count;
+            let _ = writeln!(
+                out, "rudis_command_duration_seconds_bucket{{cmd=\"{}\",le=\"{}\"}} {}",
+                name, *le as f64 / 1e6, cumulative
+            );
+        }
+        let _ = writeln!(
+            out, "rudis_command_duration_seconds_bucket{{cmd=\"{}\",le=\"+Inf\"}} {}", name, command.calls
+        );
+        let _ = writeln!(
+            out, "rudis_command_duration_seconds_sum{{cmd=\"{}\"}} {}", name, command.duration.as_secs_f64()
+        );
+        let _ = writeln!(out, "rudis_command_duration_seconds_count{{cmd=\"{}\"}} {}", name, command.calls);
+    }
+
+    metric(&mut out, "rudis_connected_clients", "gauge", "Clients connected.", stats.connected_clients);
+    header(&mut out, "rudis_db_keys", "gauge", "Keys per database.");
+    let _ = writeln!(out, "rudis_db_keys{{db=\"db0\"}} {}", stats.keys);
+    metric(&mut out, "rudis_expired_keys_total", "counter", "Keys removed because they expired.", stats.expired_keys);
+    metric(&mut out, "rudis_evicted_keys_total", "counter", "Keys evicted to free memory.", stats.evicted_keys);
+    metric(
+        &mut out, "rudis_lazyfree_pending_objects", "gauge",
+        "Values waiting to be freed in the background.", stats.lazyfree_pending_objects,
+    );
+    if let Some(rss) = rss_bytes() {
+        metric(&mut out, "rudis_memory_rss_bytes", "gauge", "Resident memory of the server process.", rss);
+    }
+
+    metric(
+        &mut out, "rudis_persistence_enabled", "gauge",
+        "Whether a snapshot is written on shutdown.", stats.persistence_enabled as u8,
+    );
+    if let Some((at, ok)) = stats.last_save {
+        let at = at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        metric(&mut out, "rudis_last_save_timestamp_seconds", "gauge", "When the last snapshot was attempted.", at);
+        metric(&mut out, "rudis_last_save_success", "gauge", "Whether the last snapshot was written.", ok as u8);
+    }
+    out
+}
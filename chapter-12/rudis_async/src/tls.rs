@@ -0,0 +1,54 @@
+// TLS for client connections, on top of rustls.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Error};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::TlsConfig;
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let mut reader = BufReader::new(
+        File::open(path).with_context(|| format!("can't open {}", path.display()))?
+    );
+    let certs = rustls_pemfile::certs(&mut reader)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("invalid certificate in {}", path.display()))?;
+    if certs.is_empty() {
+        return Err(anyhow!("no certificate found in {}", path.display()));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, Error> {
+    let mut reader = BufReader::new(
+        File::open(path).with_context(|| format!("can't open {}", path.display()))?
+    );
+    rustls_pemfile::private_key(&mut reader)
+        .with_context(|| format!("invalid private key in {}", path.display()))?
+        .ok_or_else(|| anyhow!("no private key found in {}", path.display()))
+}
+
+pub fn acceptor(config: &TlsConfig) -> Result<TlsAcceptor, Error> {
+    let builder = ServerConfig::builder();
+    let builder = match &config.ca_cert_file {
+        Some(ca_cert_file) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca_cert_file)? {
+                roots.add(cert)?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots)).build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let server_config = builder
+        .with_single_cert(load_certs(&config.cert_file)?, load_key(&config.key_file)?)?;
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
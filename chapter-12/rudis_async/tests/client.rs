@@ -0,0 +1,423 @@
+// Runs every command through rudis-client against a real rudis_async,
+// started on a free port for each test.
+
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use futures::StreamExt;
+use rudis_client::{Client, Error, Frame, Pipeline, Pool, SaveMode};
+
+struct Server {
+    child: Child,
+    addr: String,
+}
+
+impl Server {
+    fn start(args: &[&str]) -> Server {
+        Server::start_on("127.0.0.1:0", args)
+    }
+
+    // Waits for the server to tell where it is listening.
+    fn start_on(addr: &str, args: &[&str]) -> Server {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_rudis_async"))
+            .arg(addr)
+            .args(args)
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("failed to start rudis_async");
+        let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+        let addr = lines.by_ref()
+            .map(|line| line.unwrap())
+            .find_map(|line| line.strip_prefix("rudis_async listening on: ").map(String::from))
+            .expect("rudis_async exited before listening");
+        // keep draining the output so the server never blocks on a full pipe
+        std::thread::spawn(move || lines.for_each(drop));
+        Server { child, addr }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[tokio::test]
+async fn ping() {
+    let server = Server::start(&[]);
+    let mut client = Client::connect(&server.addr).await.unwrap();
+    assert_eq!(client.ping().await.unwrap(), "PONG");
+}
+
+#[tokio::test]
+async fn set_and_get() {
+    let server = Server::start(&[]);
+    let mut client = Client::connect(&server.addr).await.unwrap();
+    assert_eq!(client.get("missing").await.unwrap(), None);
+    client.set("key", "value").await.unwrap();
+    assert_eq!(client.get("key").await.unwrap(), Some(b"value".to_vec()));
+    client.set(b"bin\r\n", b"\0\xff").await.unwrap();
+    assert_eq!(client.get(b"bin\r\n").await.unwrap(), Some(b"\0\xff".to_vec()));
+}
+
+#[tokio::test]
+async fn counters() {
+    let server = Server::start(&[]);
+    let mut client = Client::connect(&server.addr).await.unwrap();
+    assert_eq!(client.incr("n").await.unwrap(), 1);
+    assert_eq!(client.incr_by("n", -11).await.unwrap(), -10);
+    client.set("s", "abc").await.unwrap();
+    assert!(matches!(client.incr("s").await, Err(Error::Server(_))));
+}
+
+#[tokio::test]
+async fn lists() {
+    let server = Server::start(&[]);
+    let mut client = Client::connect(&server.addr).await.unwrap();
+    assert_eq!(client.rpush("l", ["b", "c"]).await.unwrap(), 2);
+    assert_eq!(client.lpush("l", ["a"]).await.unwrap(), 3);
+    assert_eq!(client.llen("l").await.unwrap(), 3);
+    assert_eq!(client.lrange("l", 0, -1).await.unwrap(), vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+    assert_eq!(client.lpop("l").await.unwrap(), Some(b"a".to_vec()));
+    assert_eq!(client.rpop("l").await.unwrap(), Some(b"c".to_vec()));
+    assert_eq!(client.rpop("l").await.unwrap(), Some(b"b".to_vec()));
+    assert_eq!(client.rpop("l").await.unwrap(), None);
+    assert!(matches!(client.get("l").await, Ok(None)));
+    client.set("s", "abc").await.unwrap();
+    assert!(matches!(client.lpush("s", ["x"]).await, Err(Error::Server(e)) if e.starts_with("WRONGTYPE")));
+}
+
+#[tokio::test]
+async fn error_replies() {
+    let server = Server::start(&[]);
+    let mut client = Client::connect(&server.addr).await.unwrap();
+    match client.cmd(["NOPE"]).await {
+        Err(Error::Server(e)) => assert!(e.starts_with("ERR unknown command")),
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(matches!(client.cmd(["GET"]).await, Err(Error::Server(_))));
+    // the connection is still usable afterwards
+    assert_eq!(client.ping().await.unwrap(), "PONG");
+}
+
+#[tokio::test]
+async fn pipeline() {
+    let server = Server::start(&[]);
+    let mut client = Client::connect(&server.addr).await.unwrap();
+    let mut pipeline = Pipeline::new();
+    pipeline.cmd(["SET", "a", "1"]).cmd(["GET", "a"]).cmd(["NOPE"]).cmd(["GET", "b"]);
+    let replies = client.pipeline(&pipeline).await.unwrap();
+    assert_eq!(replies.len(), 4);
+    assert_eq!(replies[0], Frame::ok());
+    assert_eq!(replies[1], Frame::bulk("1"));
+    assert!(replies[2].is_error());
+    assert_eq!(replies[3], Frame::Null);
+}
+
+#[tokio::test]
+async fn config_and_slowlog() {
+    let server = Server::start(&[]);
+    let mut client = Client::connect(&server.addr).await.unwrap();
+    client.config_set("slowlog-log-slower-than", "0").await.unwrap();
+    let params = client.config_get("slowlog-log-slower-than").await.unwrap();
+    assert_eq!(params, vec![("slowlog-log-slower-than".to_string(), "0".to_string())]);
+    client.ping().await.unwrap();
+    assert!(client.slowlog_len().await.unwrap() > 0);
+    client.slowlog_reset().await.unwrap();
+    // SLOWLOG RESET itself is logged right after the reset
+    assert!(client.slowlog_len().await.unwrap() <= 1);
+    assert!(client.config_set("nope", "1").await.is_err());
+}
+
+#[tokio::test]
+async fn cluster_keyslot() {
+    let server = Server::start(&["--cluster"]);
+    let mut client = Client::connect(&server.addr).await.unwrap();
+    assert_eq!(client.cluster_keyslot("foo").await.unwrap(), 12182);
+    assert_eq!(client.cluster_keyslot("{foo}.bar").await.unwrap(), 12182);
+}
+
+#[tokio::test]
+async fn save_and_shutdown() {
+    let path = std::env::temp_dir().join(format!("rudis-client-{}.resp", std::process::id()));
+    let mut server = Server::start(&["--dbfilename", path.to_str().unwrap()]);
+    let mut client = Client::connect(&server.addr).await.unwrap();
+    client.set("persisted", "yes").await.unwrap();
+    client.save().await.unwrap();
+    assert!(path.exists());
+    client.shutdown(SaveMode::NoSave).await.unwrap();
+    assert!(server.child.wait().unwrap().success());
+    let _ = std::fs::remove_file(path);
+}
+
+#[tokio::test]
+async fn clients_that_stop_reading_do_not_hold_up_shutdown() {
+    let mut server = Server::start(&[]);
+    let mut subscriber = std::net::TcpStream::connect(&server.addr).unwrap();
+    std::io::Write::write_all(&mut subscriber, &Frame::command(["SUBSCRIBE", "news"]).to_bytes()).unwrap();
+    let mut publisher = Client::connect(&server.addr).await.unwrap();
+    // far more than the socket buffers hold, nobody reads it
+    let message = "x".repeat(1024 * 1024);
+    for _ in 0..32 {
+        publisher.publish("news", message.as_str()).await.unwrap();
+    }
+    publisher.shutdown(SaveMode::NoSave).await.unwrap();
+    let deadline = std::time::Instant::now() + Duration::from_secs(10);
+    while server.child.try_wait().unwrap().is_none() {
+        assert!(std::time::Instant::now() < deadline, "shutdown waits for the subscriber");
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn unix_socket_replaces_only_a_stale_socket() {
+    let path = std::env::temp_dir().join(format!("rudis-client-{}.sock", std::process::id()));
+    let socket = ["--unixsocket", path.to_str().unwrap()];
+    let fails = || {
+        let status = Command::new(env!("CARGO_BIN_EXE_rudis_async"))
+            .args(["127.0.0.1:0"].iter().chain(&socket))
+            .stdout(Stdio::null())
+            .status()
+            .unwrap();
+        !status.success()
+    };
+
+    std::fs::write(&path, "not a socket").unwrap();
+    assert!(fails());
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "not a socket");
+    std::fs::remove_file(&path).unwrap();
+
+    // the socket is bound after the TCP address is announced
+    let listening = || (0..50).any(|_| {
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        std::os::unix::net::UnixStream::connect(&path).is_ok()
+    });
+    let first = Server::start(&socket);
+    assert!(listening());
+    assert!(fails());
+    assert!(listening());
+    // killed, it leaves the socket behind for the next server to take over
+    drop(first);
+    assert!(path.exists());
+    let _second = Server::start(&socket);
+    assert!(listening());
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn publish_and_subscribe() {
+    let server = Server::start(&[]);
+    let mut publisher = Client::connect(&server.addr).await.unwrap();
+    let subscriber = Client::connect(&server.addr).await.unwrap();
+    let mut pubsub = subscriber.subscribe(["news", "sport"]).await.unwrap();
+    assert_eq!(publisher.publish("news", "hello").await.unwrap(), 1);
+    assert_eq!(publisher.publish("weather", "rain").await.unwrap(), 0);
+    assert_eq!(publisher.publish("sport", "goal").await.unwrap(), 1);
+    let mut messages = pubsub.messages();
+    let first = messages.next().await.unwrap().unwrap();
+    assert_eq!((first.channel.as_slice(), first.payload.as_slice()), (&b"news"[..], &b"hello"[..]));
+    let second = messages.next().await.unwrap().unwrap();
+    assert_eq!((second.channel.as_slice(), second.payload.as_slice()), (&b"sport"[..], &b"goal"[..]));
+    drop(messages);
+    pubsub.unsubscribe(["news"]).await.unwrap();
+    // the unsubscribe has been processed once the next publish is received
+    publisher.publish("sport", "sync").await.unwrap();
+    assert_eq!(pubsub.next_message().await.unwrap().payload, b"sync");
+    assert_eq!(publisher.publish("news", "again").await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn pool_shares_connections() {
+    let server = Server::start(&[]);
+    let pool = Pool::new(server.addr.clone(), 2);
+    let tasks = (0..8).map(|i| {
+        let pool = pool.clone();
+        tokio::spawn(async move {
+            let mut client = pool.get().await.unwrap();
+            let key = format!("key{}", i);
+            client.set(&key, i.to_string()).await.unwrap();
+            client.get(&key).await.unwrap()
+        })
+    });
+    for (i, task) in tasks.collect::<Vec<_>>().into_iter().enumerate() {
+        assert_eq!(task.await.unwrap(), Some(i.to_string().into_bytes()));
+    }
+    assert_eq!(pool.idle(), 2);
+}
+
+#[tokio::test]
+async fn reconnects_after_a_restart() {
+    let server = Server::start(&[]);
+    let addr = server.addr.clone();
+    let mut client = Client::connect(&addr).await.unwrap();
+    let mut pubsub = Client::connect(&addr).await.unwrap().subscribe(["news"]).await.unwrap();
+    client.set("k", "v").await.unwrap();
+    drop(server);
+    // the command running when the connection broke fails, the next one
+    // finds the restarted server
+    assert!(client.ping().await.is_err());
+    let _server = Server::start_on(&addr, &[]);
+    assert_eq!(client.ping().await.unwrap(), "PONG");
+    assert_eq!(client.get("k").await.unwrap(), None);
+    // the subscriber subscribes again on its own
+    let mut publisher = Client::connect(&addr).await.unwrap();
+    let receive = tokio::spawn(async move { pubsub.next_message().await });
+    let mut delivered = 0;
+    while delivered == 0 {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        delivered = publisher.publish("news", "back").await.unwrap();
+    }
+    assert_eq!(receive.await.unwrap().unwrap().payload, b"back");
+}
+
+#[tokio::test]
+async fn expiry() {
+    let server = Server::start(&[]);
+    let mut client = Client::connect(&server.addr).await.unwrap();
+    client.set("k", "v").await.unwrap();
+    assert_eq!(client.ttl("k").await.unwrap(), None);
+    assert!(client.expire("k", Duration::from_millis(50)).await.unwrap());
+    assert!(client.ttl("k").await.unwrap().is_some());
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!client.exists("k").await.unwrap());
+    assert_eq!(client.get("k").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn dump_and_restore() {
+    let server = Server::start(&[]);
+    let mut client = Client::connect(&server.addr).await.unwrap();
+    client.rpush("l", ["a", "b"]).await.unwrap();
+    client.expire("l", Duration::from_secs(100)).await.unwrap();
+    let payload = client.dump("l").await.unwrap().unwrap();
+    assert!(matches!(client.restore("l", None, &payload, false).await, Err(Error::Server(_))));
+    client.restore("copy", None, &payload, false).await.unwrap();
+    assert_eq!(client.lrange("copy", 0, -1).await.unwrap(), vec![b"a".to_vec(), b"b".to_vec()]);
+    assert!(client.ttl("copy").await.unwrap().unwrap() > Duration::from_secs(90));
+    let mut corrupt = payload.clone();
+    corrupt[0] ^= 1;
+    assert!(matches!(client.restore("bad", None, &corrupt, true).await, Err(Error::Server(_))));
+    assert_eq!(client.dump("missing").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn migrate_moves_keys() {
+    let source = Server::start(&[]);
+    let target = Server::start(&[]);
+    let mut client = Client::connect(&source.addr).await.unwrap();
+    let mut other = Client::connect(&target.addr).await.unwrap();
+    client.set("a", "1").await.unwrap();
+    client.rpush("b", ["x"]).await.unwrap();
+    let (host, port) = target.addr.rsplit_once(':').unwrap();
+    let port = port.parse().unwrap();
+    let timeout = Duration::from_secs(1);
+    assert!(client.migrate(host, port, ["a", "b", "c"], timeout, false).await.unwrap());
+    assert_eq!(client.del(["a", "b"]).await.unwrap(), 0);
+    assert_eq!(other.get("a").await.unwrap(), Some(b"1".to_vec()));
+    assert_eq!(other.llen("b").await.unwrap(), 1);
+    assert!(!client.migrate(host, port, ["a"], timeout, false).await.unwrap());
+    // an existing key on the target needs REPLACE
+    client.set("a", "2").await.unwrap();
+    assert!(matches!(client.migrate(host, port, ["a"], timeout, false).await, Err(Error::Server(_))));
+    assert!(client.exists("a").await.unwrap());
+    assert!(client.migrate(host, port, ["a"], timeout, true).await.unwrap());
+    assert_eq!(other.get("a").await.unwrap(), Some(b"2".to_vec()));
+}
+
+#[tokio::test]
+async fn hashes_sets_and_sorted_sets() {
+    let server = Server::start(&[]);
+    let mut client = Client::connect(&server.addr).await.unwrap();
+    assert_eq!(client.hset("h", [("a", "1"), ("b", "2")]).await.unwrap(), 2);
+    assert_eq!(client.hget("h", "b").await.unwrap(), Some(b"2".to_vec()));
+    assert_eq!(client.hgetall("h").await.unwrap().len(), 2);
+    assert_eq!(client.object_encoding("h").await.unwrap().as_deref(), Some("listpack"));
+    client.config_set("hash-max-listpack-value", "4").await.unwrap();
+    client.hset("h", [("c", "a long value")]).await.unwrap();
+    assert_eq!(client.object_encoding("h").await.unwrap().as_deref(), Some("hashtable"));
+
+    assert_eq!(client.sadd("s", ["3", "1", "3"]).await.unwrap(), 2);
+    assert_eq!(client.object_encoding("s").await.unwrap().as_deref(), Some("intset"));
+    assert_eq!(client.smembers("s").await.unwrap(), vec![b"1".to_vec(), b"3".to_vec()]);
+
+    assert_eq!(client.zadd("z", [(2.0, "b"), (1.0, "a")]).await.unwrap(), 2);
+    assert_eq!(client.zrange("z", 0, -1).await.unwrap(), vec![b"a".to_vec(), b"b".to_vec()]);
+    assert_eq!(client.object_encoding("missing").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn prometheus_metrics() {
+    use std::io::{Read, Write};
+
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let server = Server::start(&["--metrics-port", &port.to_string()]);
+    let mut client = Client::connect(&server.addr).await.unwrap();
+    client.set("k", "v").await.unwrap();
+    client.get("k").await.unwrap();
+    assert!(client.incr("k").await.is_err());
+
+    let scrape = |path: &str| {
+        let mut http = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        write!(http, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        http.read_to_string(&mut response).unwrap();
+        response
+    };
+    let metrics = scrape("/metrics");
+    assert!(metrics.starts_with("HTTP/1.1 200 OK"));
+    assert!(metrics.contains("rudis_commands_total{cmd=\"get\"} 1\n"));
+    assert!(metrics.contains("rudis_commands_failed_total{cmd=\"incrby\"} 1\n"));
+    assert!(metrics.contains("rudis_command_duration_seconds_count{cmd=\"set\"} 1\n"));
+    assert!(metrics.contains("rudis_connected_clients 1\n"));
+    assert!(metrics.contains("rudis_db_keys{db=\"db0\"} 1\n"));
+    assert!(scrape("/nope").starts_with("HTTP/1.1 404"));
+
+    // a client that never finishes its headers is dropped
+    let mut slow = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(slow, "GET /metrics HTTP/1.1\r\n").unwrap();
+    slow.set_read_timeout(Some(Duration::from_secs(30))).unwrap();
+    let mut response = Vec::new();
+    slow.read_to_end(&mut response).unwrap();
+    assert!(response.is_empty());
+}
+
+#[tokio::test]
+async fn unlink_frees_in_the_background() {
+    use std::io::{Read, Write};
+
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let server = Server::start(&["--metrics-port", &port.to_string()]);
+    let mut client = Client::connect(&server.addr).await.unwrap();
+    let pending = || {
+        let mut http = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        write!(http, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        http.read_to_string(&mut response).unwrap();
+        response.lines()
+            .find_map(|line| line.strip_prefix("rudis_lazyfree_pending_objects "))
+            .map(|n| n.parse::<usize>().unwrap())
+            .unwrap()
+    };
+
+    let keys: Vec<String> = (0..32).map(|n| format!("big{}", n)).collect();
+    let mut pipeline = Pipeline::new();
+    for key in &keys {
+        let elements = (0..10_000).map(|i| i.to_string());
+        pipeline.cmd(["RPUSH".to_string(), key.clone()].into_iter().chain(elements));
+    }
+    client.pipeline(&pipeline).await.unwrap();
+
+    // the lists are gone once UNLINK answers, but freeing them is still going on
+    assert_eq!(client.unlink(&keys).await.unwrap(), 32);
+    assert!(pending() > 0, "UNLINK waited for the lists to be freed");
+    assert!(!client.exists("big0").await.unwrap());
+    for _ in 0..100 {
+        if pending() == 0 {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("the background thread never freed the lists");
+}
@@ -0,0 +1,154 @@
+// One client connection of the event loop: a non-blocking socket with the
+// bytes read but not parsed yet and the replies not written yet.
+
+use std::io::{self, Read, Write};
+use std::task::{Context, Poll, Waker};
+
+use futures::StreamExt;
+use mio::net::TcpStream;
+use rudis_core::{Engine, Frame, Pushes, Session};
+
+// Replies queued past this stop the reading of more commands until the
+// client reads some of them
+const MAX_WRITE_BUF: usize = 1024 * 1024;
+// Pushes do not wait for the client, one that lets them pile up past this
+// is dropped, like the pubsub output limit of Redis
+const MAX_PUSH_BUF: usize = 32 * 1024 * 1024;
+// The query buffer limit of Redis, no command needs more than that
+const MAX_READ_BUF: usize = 1024 * 1024 * 1024;
+
+pub struct Connection {
+    pub stream: TcpStream,
+    session: Session,
+    pushes: Pushes,
+    // wakes the event loop when pushes come in
+    waker: Waker,
+    read_buf: Vec<u8>,
+    write_buf: Vec<u8>,
+    // the client sent all it will, what is left in read_buf still runs
+    eof: bool,
+    // no more commands are read, the connection closes once flushed
+    closing: bool,
+}
+
+/// What the event loop should do with a connection after an event.
+#[derive(Debug, PartialEq, Eq)]
+pub enum State {
+    Open,
+    Closed,
+}
+
+impl Connection {
+    pub fn new(stream: TcpStream, (session, pushes): (Session, Pushes), waker: Waker) -> Self {
+        Connection {
+            stream,
+            session,
+            pushes,
+            waker,
+            read_buf: Vec::new(),
+            write_buf: Vec::new(),
+            eof: false,
+            closing: false,
+        }
+    }
+
+    /// Queues a last reply and closes the connection once it is written.
+    pub fn close_with(&mut self, reply: Frame) -> State {
+        if !self.closing {
+            reply.encode(&mut self.write_buf);
+            self.closing = true;
+        }
+        self.flush()
+    }
+
+    /// Reads commands, runs them in the order they came in and writes
+    /// their replies for as long as the socket lets it. A client that stops
+    /// sending still gets the replies to what it sent before, one that
+    /// stops reading them stops being read from until it catches up.
+    pub fn on_ready(&mut self, engine: &Engine) -> State {
+        let mut chunk = [0; 16 * 1024];
+        loop {
+            self.run_commands(engine);
+            // anything left is an incomplete command
+            if self.eof && self.write_buf.len() < MAX_WRITE_BUF {
+                self.closing = true;
+            }
+            if self.flush() == State::Closed {
+                return State::Closed;
+            }
+            // writable comes around again once the client reads
+            if self.closing || self.write_buf.len() >= MAX_WRITE_BUF {
+                return State::Open;
+            }
+            match self.stream.read(&mut chunk) {
+                Ok(0) => self.eof = true,
+                Ok(n) => self.read_buf.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return State::Open,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(_) => return State::Closed,
+            }
+            if self.read_buf.len() > MAX_READ_BUF {
+                return self.close_with(Frame::error("ERR query buffer limit exceeded"));
+            }
+        }
+    }
+
+    // Runs the complete commands read so far, until the replies fill up
+    // the write buffer.
+    fn run_commands(&mut self, engine: &Engine) {
+        let mut used = 0;
+        while !self.closing && self.write_buf.len() < MAX_WRITE_BUF {
+            match Frame::parse(&self.read_buf[used..]) {
+                Ok(Some((frame, n))) => {
+                    used += n;
+                    engine.execute(&mut self.session, frame).encode(&mut self.write_buf);
+                    // the command that just ran was the last one served
+                    if engine.shutdown_requested().is_some() {
+                        self.closing = true;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    Frame::error(format!("ERR {}", e)).encode(&mut self.write_buf);
+                    self.closing = true;
+                }
+            }
+        }
+        self.read_buf.drain(..used);
+    }
+
+    /// Queues what the server pushed to the client since the last call,
+    /// like published messages or MONITOR lines. The waker of the
+    /// connection is called when more come in.
+    pub fn on_pushes(&mut self) -> State {
+        let mut cx = Context::from_waker(&self.waker);
+        let mut pushed = false;
+        while let Poll::Ready(Some(push)) = self.pushes.poll_next_unpin(&mut cx) {
+            push.encode(&mut self.write_buf);
+            pushed = true;
+        }
+        if self.write_buf.len() > MAX_PUSH_BUF {
+            return State::Closed;
+        }
+        if pushed { self.flush() } else { State::Open }
+    }
+
+    /// Writes as much of the pending replies as the socket takes.
+    pub fn flush(&mut self) -> State {
+        let mut written = 0;
+        while written < self.write_buf.len() {
+            match self.stream.write(&self.write_buf[written..]) {
+                Ok(0) => return State::Closed,
+                Ok(n) => written += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => return State::Closed,
+            }
+        }
+        self.write_buf.drain(..written);
+        if self.closing && self.write_buf.is_empty() {
+            return State::Closed;
+        }
+        State::Open
+    }
+}
@@ -1,47 +1,253 @@
-use lazy_static::lazy_static;
-use resp::Decoder;
-use std::collections::HashMap;
+// A single threaded rudis: one mio event loop owns the listener and every
+// client socket, so an idle client costs a few buffers instead of a thread.
+
+use mio::net::TcpListener;
+use mio::{Events, Interest, Poll, Token, Waker};
+use rudis_core::{shutting_down, Engine, SaveMode, EXPIRE_INTERVAL};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use std::collections::{HashMap, HashSet};
 use std::env;
-use std::io::{BufReader, Write};
-use std::net::{Shutdown, TcpListener, TcpStream};
-use std::sync::Mutex;
-use std::thread;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::Wake;
+use std::time::{Duration, Instant};
+
+mod connection;
+use crate::connection::{Connection, State};
 
-mod commands;
-use crate::commands::process_client_request;
+const LISTENER: Token = Token(0);
+const PUSHES: Token = Token(usize::MAX);
+// How long a poll waits at most, so that signals are noticed
+const POLL_TIMEOUT: Duration = Duration::from_millis(100);
+// How long shutting down waits for the last replies to be written
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_MAX_CLIENTS: usize = 10000;
 
-type STORE = Mutex<HashMap<String, String>>;
+struct Server {
+    engine: Engine,
+    poll: Poll,
+    listener: TcpListener,
+    connections: HashMap<Token, Connection>,
+    next_token: usize,
+    pushed: Arc<Pushed>,
+}
+
+// The connections that have pushes waiting, so that delivering them does
+// not have to look at every connection.
+struct Pushed {
+    tokens: Mutex<HashSet<Token>>,
+    waker: Waker,
+}
 
-lazy_static! {
-    static ref RUDIS_DB: STORE = Mutex::new(HashMap::new());
+// Wakes the event loop for the pushes of one connection.
+struct PushWaker {
+    token: Token,
+    pushed: Arc<Pushed>,
 }
 
-fn handle_client(stream: TcpStream) {
-    let mut stream = BufReader::new(stream);
-    let decoder = Decoder::new(&mut stream).decode();
-    match decoder {
-        Ok(v) => {
-            let reply = process_client_request(v);
-            stream.get_mut().write_all(&reply).unwrap();
+impl Wake for PushWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        let mut tokens = self.pushed.tokens.lock().unwrap();
+        // one wake up covers every token added before the poll returns
+        if tokens.is_empty() {
+            let _ = self.pushed.waker.wake();
         }
-        Err(e) => {
-            println!("Invalid command: {:?}", e);
-            let _ = stream.get_mut().shutdown(Shutdown::Both);
+        tokens.insert(self.token);
+    }
+}
+
+impl Server {
+    fn accept(&mut self) {
+        loop {
+            let (stream, peer) = match self.listener.accept() {
+                Ok(accepted) => accepted,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    // e.g. out of file descriptors, the next readiness
+                    // event tries again
+                    println!("Failed to accept connection: {}", e);
+                    return;
+                }
+            };
+            println!("New connection from {}", peer);
+            let token = Token(self.next_token);
+            self.next_token += 1;
+            let client = match self.engine.connect(peer) {
+                Ok(client) => client,
+                Err(reply) => {
+                    // a fresh socket has room for a short error
+                    let _ = (&stream).write_all(&reply.to_bytes());
+                    continue;
+                }
+            };
+            let waker = Arc::new(PushWaker { token, pushed: Arc::clone(&self.pushed) });
+            let mut connection = Connection::new(stream, client, waker.into());
+            let interest = Interest::READABLE | Interest::WRITABLE;
+            match self.poll.registry().register(&mut connection.stream, token, interest) {
+                Ok(()) => {
+                    // sets up the waker
+                    let state = connection.on_pushes();
+                    self.connections.insert(token, connection);
+                    self.update(token, state);
+                }
+                Err(e) => println!("Failed to register connection: {}", e),
+            }
         }
-    };
+    }
+
+    fn update(&mut self, token: Token, state: State) {
+        if state == State::Closed {
+            if let Some(mut connection) = self.connections.remove(&token) {
+                let _ = self.poll.registry().deregister(&mut connection.stream);
+            }
+        }
+    }
+
+    fn ready(&mut self, token: Token, readable: bool, writable: bool) {
+        let connection = match self.connections.get_mut(&token) {
+            Some(connection) => connection,
+            None => return,
+        };
+        let state = if readable || writable {
+            connection.on_ready(&self.engine)
+        } else {
+            State::Open
+        };
+        self.update(token, state);
+    }
+
+    // Commands of one client push to others, a PUBLISH or the MONITOR feed.
+    fn deliver_pushes(&mut self) {
+        let tokens = std::mem::take(&mut *self.pushed.tokens.lock().unwrap());
+        for token in tokens {
+            if let Some(connection) = self.connections.get_mut(&token) {
+                let state = connection.on_pushes();
+                self.update(token, state);
+            }
+        }
+    }
+
+    fn run(&mut self, signalled: &AtomicBool) -> io::Result<()> {
+        let mut events = Events::with_capacity(1024);
+        let mut expired_at = Instant::now();
+        while self.engine.shutdown_requested().is_none() {
+            if signalled.load(Ordering::SeqCst) {
+                println!("Received a termination signal");
+                self.engine.request_shutdown(SaveMode::Default);
+                break;
+            }
+            match self.poll.poll(&mut events, Some(POLL_TIMEOUT)) {
+                Ok(()) => (),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+            for event in &events {
+                match event.token() {
+                    LISTENER => self.accept(),
+                    PUSHES => self.deliver_pushes(),
+                    token => self.ready(
+                        token,
+                        event.is_readable() || event.is_read_closed(),
+                        event.is_writable() || event.is_error(),
+                    ),
+                }
+            }
+            // the poll timeout makes sure this comes around often enough
+            if expired_at.elapsed() >= EXPIRE_INTERVAL {
+                self.engine.expire_keys();
+                expired_at = Instant::now();
+            }
+        }
+        Ok(())
+    }
+
+    // Tells the clients that did not ask for the shutdown to go away and
+    // gives the last replies some time to be written.
+    fn close(&mut self) -> io::Result<()> {
+        self.poll.registry().deregister(&mut self.listener)?;
+        let tokens = self.connections.keys().copied().collect::<Vec<_>>();
+        for token in tokens {
+            let state = self.connections.get_mut(&token).unwrap().close_with(shutting_down());
+            self.update(token, state);
+        }
+        let deadline = Instant::now() + FLUSH_TIMEOUT;
+        let mut events = Events::with_capacity(1024);
+        while !self.connections.is_empty() {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                break;
+            }
+            match self.poll.poll(&mut events, Some(left.min(POLL_TIMEOUT))) {
+                Ok(()) => (),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+            for event in &events {
+                if let Some(connection) = self.connections.get_mut(&event.token()) {
+                    let state = connection.flush();
+                    self.update(event.token(), state);
+                }
+            }
+        }
+        self.connections.clear();
+        Ok(())
+    }
 }
 
-fn main() {
-    let addr = env::args()
-        .skip(1)
-        .next()
-        .unwrap_or("127.0.0.1:6378".to_owned());
-    let listener = TcpListener::bind(&addr).unwrap();
+fn main() -> io::Result<()> {
+    let engine = Engine::new();
+    let mut addr = "127.0.0.1:6378".to_owned();
+    let mut max_clients = DEFAULT_MAX_CLIENTS;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidInput, format!("{} expects a value", arg)
+        ));
+        let number = |v: String| v.parse::<usize>().map_err(|e| io::Error::new(
+            io::ErrorKind::InvalidInput, format!("{}: {}", v, e)
+        ));
+        match arg.as_str() {
+            "--dbfilename" => engine.enable_persistence(&value()?)?,
+            "--maxclients" => max_clients = number(value()?)?,
+            _ => addr = arg.clone(),
+        }
+    }
+    engine.set_max_clients(max_clients);
+
+    let signalled = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGINT, Arc::clone(&signalled))?;
+    signal_hook::flag::register(SIGTERM, Arc::clone(&signalled))?;
+
+    let listener = std::net::TcpListener::bind(&addr)?;
+    listener.set_nonblocking(true)?;
+    let mut listener = TcpListener::from_std(listener);
+    let poll = Poll::new()?;
+    poll.registry().register(&mut listener, LISTENER, Interest::READABLE)?;
+    let waker = Waker::new(poll.registry(), PUSHES)?;
     println!("rudis_sync linstening on {} ...", addr);
 
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        println!("New connection from {:?}", stream);
-        thread::spawn(|| handle_client(stream));
+    let mut server = Server {
+        engine: engine.clone(),
+        poll,
+        listener,
+        connections: HashMap::new(),
+        next_token: LISTENER.0 + 1,
+        pushed: Arc::new(Pushed { tokens: Mutex::new(HashSet::new()), waker }),
+    };
+    server.run(&signalled)?;
+
+    println!("Shutting down, waiting for clients to finish");
+    server.close()?;
+    engine.close_clients();
+
+    if engine.finish_shutdown()? {
+        println!("Snapshot saved");
     }
+    println!("rudis_sync is now ready to exit, bye bye...");
+    Ok(())
 }
@@ -0,0 +1,137 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+
+use rudis_core::Frame;
+
+struct Server {
+    child: Child,
+    addr: String,
+}
+
+impl Server {
+    // Waits for the server to say it is listening.
+    fn start() -> Server {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let addr = format!("127.0.0.1:{}", port);
+        let mut child = Command::new(env!("CARGO_BIN_EXE_rudis_sync"))
+            .arg(&addr)
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("failed to start rudis_sync");
+        let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+        lines.by_ref()
+            .map(|line| line.unwrap())
+            .find(|line| line.starts_with("rudis_sync linstening on"))
+            .expect("rudis_sync exited before listening");
+        // keep draining the output so the server never blocks on a full pipe
+        std::thread::spawn(move || lines.for_each(drop));
+        Server { child, addr }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// Sends everything at once, closes the sending half and reads until the
+// server closes too.
+fn pipeline(addr: &str, commands: &[Frame]) -> Vec<Frame> {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let request = commands.iter().flat_map(Frame::to_bytes).collect::<Vec<_>>();
+    stream.write_all(&request).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+
+    let mut replies = Vec::new();
+    let mut rest = &response[..];
+    while let Some((frame, used)) = Frame::parse(rest).unwrap() {
+        replies.push(frame);
+        rest = &rest[used..];
+    }
+    assert!(rest.is_empty(), "{} bytes left after the last reply", rest.len());
+    replies
+}
+
+#[test]
+fn half_closed_clients_get_every_reply() {
+    let server = Server::start();
+    let replies = pipeline(&server.addr, &[
+        Frame::command(["SET", "key", "value"]),
+        Frame::command(["GET", "key"]),
+        Frame::command(["PING"]),
+    ]);
+    assert_eq!(replies, [Frame::ok(), Frame::bulk("value"), Frame::Simple("PONG".to_string())]);
+}
+
+#[test]
+fn half_closed_clients_get_replies_bigger_than_the_socket_buffer() {
+    let server = Server::start();
+    let value = "x".repeat(64 * 1024);
+    let mut commands = vec![Frame::command(["SET", "big", value.as_str()])];
+    commands.extend((0..100).map(|_| Frame::command(["GET", "big"])));
+    let replies = pipeline(&server.addr, &commands);
+    assert_eq!(replies.len(), commands.len());
+    assert!(replies[1..].iter().all(|reply| *reply == Frame::bulk(value.as_str())));
+}
+
+// Reads one frame, assuming nothing else comes after it.
+fn read_frame(stream: &mut TcpStream) -> Frame {
+    let mut response = Vec::new();
+    let mut chunk = [0; 1024];
+    loop {
+        let n = stream.read(&mut chunk).unwrap();
+        assert!(n > 0, "connection closed before the reply");
+        response.extend_from_slice(&chunk[..n]);
+        if let Some((frame, _)) = Frame::parse(&response).unwrap() {
+            return frame;
+        }
+    }
+}
+
+fn request(stream: &mut TcpStream, command: Frame) -> Frame {
+    stream.write_all(&command.to_bytes()).unwrap();
+    read_frame(stream)
+}
+
+#[test]
+fn clients_that_stop_reading_stop_being_read() {
+    let server = Server::start();
+    let mut other = TcpStream::connect(&server.addr).unwrap();
+    let value = "x".repeat(1024);
+    assert_eq!(request(&mut other, Frame::command(["SET", "key", value.as_str()])), Frame::ok());
+
+    // far more than the socket buffers hold, the writer blocks once the
+    // server stops reading
+    let stream = TcpStream::connect(&server.addr).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let (done, finished) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let get = Frame::command(["GET", "key"]).to_bytes();
+        let request = get.repeat(64 * 1024 * 1024 / get.len());
+        let _ = writer.write_all(&request);
+        let _ = done.send(());
+    });
+    let waited = finished.recv_timeout(std::time::Duration::from_secs(1));
+    assert_eq!(waited, Err(std::sync::mpsc::RecvTimeoutError::Timeout));
+
+    assert_eq!(request(&mut other, Frame::command(["PING"])), Frame::Simple("PONG".to_string()));
+    stream.shutdown(Shutdown::Both).unwrap();
+}
+
+#[test]
+fn subscribers_get_what_is_published() {
+    let server = Server::start();
+    let mut subscriber = TcpStream::connect(&server.addr).unwrap();
+    let mut publisher = TcpStream::connect(&server.addr).unwrap();
+    request(&mut subscriber, Frame::command(["SUBSCRIBE", "news"]));
+    assert_eq!(request(&mut publisher, Frame::command(["PUBLISH", "news", "hello"])), Frame::Integer(1));
+
+    assert_eq!(read_frame(&mut subscriber), Frame::Array(vec![
+        Frame::bulk("message"), Frame::bulk("news"), Frame::bulk("hello"),
+    ]));
+}
@@ -0,0 +1,256 @@
+// Who follows links. Every redirect is recorded as a click, handed to a
+// background task that writes clicks to the store in batches, so a slow
+// store never holds up a redirect. The store only keeps counts of the
+// clicks, by hour, referrer and visitor, not the clicks themselves.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use log::{error, warn};
+use serde_derive::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use crate::store::LinkStore;
+
+// Clicks waiting to be written, any more are dropped
+const QUEUE_LENGTH: usize = 10_000;
+const BATCH_SIZE: usize = 500;
+// The longest a click waits for its batch to fill up
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const TOP_REFERRERS: usize = 10;
+// Referrers and visitors counted per link, so that made up referrers or
+// many visitors can't grow the counts without end. Clicks past these still
+// count, by hour.
+pub(crate) const MAX_REFERRERS: usize = 1000;
+pub(crate) const MAX_VISITORS: usize = 100_000;
+
+/// One redirect.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Click {
+    /// Seconds since the Unix epoch
+    pub(crate) at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) referrer: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) user_agent: Option<String>,
+    /// Stands for the client's address without giving it away, see `visitor`
+    pub(crate) visitor: String,
+}
+
+/// The client's address cut down to its network, a /24 for IPv4 and a /48
+/// for IPv6, and hashed. Clicks from one place count as one visitor, and the
+/// address itself is never stored.
+pub(crate) fn visitor(ip: IpAddr) -> String {
+    let network = match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            format!("{}.{}.{}.0/24", a, b, c)
+        }
+        IpAddr::V6(ip) => {
+            let s = ip.segments();
+            format!("{:x}:{:x}:{:x}::/48", s[0], s[1], s[2])
+        }
+    };
+    let mut sha = Sha256::new();
+    sha.input_str(&network);
+    let mut digest = sha.result_str();
+    digest.truncate(16);
+    digest
+}
+
+/// Hands clicks over to the task that writes them.
+#[derive(Clone)]
+pub(crate) struct Recorder {
+    sender: mpsc::Sender<(String, Click)>,
+}
+
+impl Recorder {
+    pub(crate) fn start(store: Arc<dyn LinkStore>) -> Self {
+        let (sender, receiver) = mpsc::channel(QUEUE_LENGTH);
+        tokio::spawn(write_clicks(store, receiver));
+        Recorder { sender }
+    }
+
+    /// Never waits: when the writer can't keep up the click is lost.
+    pub(crate) fn record(&self, code: &str, click: Click) {
+        if self.sender.try_send((code.to_owned(), click)).is_err() {
+            warn!("click queue is full, dropped a click on {}", code);
+        }
+    }
+}
+
+async fn write_clicks(store: Arc<dyn LinkStore>, mut receiver: mpsc::Receiver<(String, Click)>) {
+    while let Some(click) = receiver.recv().await {
+        let mut batch = vec![click];
+        let deadline = Instant::now() + FLUSH_INTERVAL;
+        while batch.len() < BATCH_SIZE {
+            match tokio::time::timeout_at(deadline, receiver.recv()).await {
+                Ok(Some(click)) => batch.push(click),
+                _ => break,
+            }
+        }
+        let count = batch.len();
+        if let Err(e) = store.record(batch).await {
+            error!("lost {} clicks, writing them failed: {}", count, e);
+        }
+    }
+}
+
+/// What is kept of the clicks on a link.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ClickCounts {
+    /// Clicks per hour, by when the hour starts
+    pub(crate) hours: BTreeMap<u64, u64>,
+    pub(crate) referrers: HashMap<String, u64>,
+    pub(crate) visitors: HashSet<String>,
+}
+
+impl ClickCounts {
+    pub(crate) fn add(&mut self, click: &Click) {
+        *self.hours.entry(hour(click.at)).or_insert(0) += 1;
+        if let Some(referrer) = &click.referrer {
+            if self.referrers.contains_key(referrer) || self.referrers.len() < MAX_REFERRERS {
+                *self.referrers.entry(referrer.clone()).or_insert(0) += 1;
+            }
+        }
+        if self.visitors.len() < MAX_VISITORS {
+            self.visitors.insert(click.visitor.clone());
+        }
+    }
+}
+
+/// When the hour `at` falls in starts.
+pub(crate) fn hour(at: u64) -> u64 {
+    at - at % Bucket::Hour.seconds()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Bucket {
+    Hour,
+    Day,
+}
+
+impl std::str::FromStr for Bucket {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hour" => Ok(Bucket::Hour),
+            "day" => Ok(Bucket::Day),
+            _ => Err(format!("unknown bucket '{}', expected hour or day", s)),
+        }
+    }
+}
+
+impl Bucket {
+    fn seconds(self) -> u64 {
+        match self {
+            Bucket::Hour => 60 * 60,
+            Bucket::Day => 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct Count {
+    /// When the hour or day starts, in seconds since the Unix epoch
+    pub(crate) start: u64,
+    pub(crate) clicks: usize,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct Referrer {
+    pub(crate) referrer: String,
+    pub(crate) clicks: usize,
+}
+
+/// The answer to GET /{code}/stats.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct Stats {
+    pub(crate) total_clicks: usize,
+    pub(crate) unique_visitors: usize,
+    /// Only hours or days that had clicks, oldest first
+    pub(crate) clicks: Vec<Count>,
+    /// Most clicks first, clicks without a referrer left out
+    pub(crate) top_referrers: Vec<Referrer>,
+}
+
+pub(crate) fn stats(counts: &ClickCounts, bucket: Bucket) -> Stats {
+    let mut buckets = BTreeMap::new();
+    for (hour, clicks) in &counts.hours {
+        *buckets.entry(hour - hour % bucket.seconds()).or_insert(0) += *clicks as usize;
+    }
+    let mut top_referrers: Vec<_> = counts.referrers.iter()
+        .map(|(referrer, clicks)| Referrer { referrer: referrer.clone(), clicks: *clicks as usize })
+        .collect();
+    top_referrers.sort_by(|a, b| b.clicks.cmp(&a.clicks).then_with(|| a.referrer.cmp(&b.referrer)));
+    top_referrers.truncate(TOP_REFERRERS);
+    Stats {
+        total_clicks: buckets.values().sum(),
+        unique_visitors: counts.visitors.len(),
+        clicks: buckets.into_iter().map(|(start, clicks)| Count { start, clicks }).collect(),
+        top_referrers,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn click(at: u64, referrer: Option<&str>, ip: &str) -> Click {
+        Click {
+            at,
+            referrer: referrer.map(String::from),
+            user_agent: None,
+            visitor: visitor(ip.parse().unwrap()),
+        }
+    }
+
+    #[test]
+    fn visitors_are_networks_not_addresses() {
+        assert_eq!(visitor("10.1.2.3".parse().unwrap()), visitor("10.1.2.200".parse().unwrap()));
+        assert_ne!(visitor("10.1.2.3".parse().unwrap()), visitor("10.1.3.3".parse().unwrap()));
+        assert_eq!(visitor("2001:db8:1:2::1".parse().unwrap()), visitor("2001:db8:1:ffff::2".parse().unwrap()));
+        assert!(!visitor("10.1.2.3".parse().unwrap()).contains("10.1"));
+    }
+
+    #[test]
+    fn stats_count_by_hour_visitor_and_referrer() {
+        let mut counts = ClickCounts::default();
+        for click in [
+            click(3600, Some("https://news.example"), "10.0.0.1"),
+            click(3700, Some("https://news.example"), "10.0.0.2"),
+            click(7300, Some("https://chat.example"), "10.0.1.1"),
+            click(7400, None, "10.0.1.1"),
+        ] {
+            counts.add(&click);
+        }
+        assert_eq!(stats(&counts, Bucket::Hour), Stats {
+            total_clicks: 4,
+            unique_visitors: 2,
+            clicks: vec![Count { start: 3600, clicks: 2 }, Count { start: 7200, clicks: 2 }],
+            top_referrers: vec![
+                Referrer { referrer: "https://news.example".to_owned(), clicks: 2 },
+                Referrer { referrer: "https://chat.example".to_owned(), clicks: 1 },
+            ],
+        });
+        assert_eq!(stats(&counts, Bucket::Day).clicks, vec![Count { start: 0, clicks: 4 }]);
+    }
+
+    #[test]
+    fn counts_stop_growing_with_new_referrers() {
+        let mut counts = ClickCounts::default();
+        for n in 0..MAX_REFERRERS + 10 {
+            counts.add(&click(0, Some(&format!("https://{}.example", n)), "10.0.0.1"));
+        }
+        counts.add(&click(0, Some("https://0.example"), "10.0.0.1"));
+        assert_eq!(counts.referrers.len(), MAX_REFERRERS);
+        assert_eq!(counts.referrers["https://0.example"], 2);
+        assert_eq!(stats(&counts, Bucket::Day).total_clicks, MAX_REFERRERS + 11);
+    }
+}
@@ -0,0 +1,104 @@
+// What gets shortened. Only http and https URLs are taken, and each is
+// written one way, so that two spellings of a URL get the same code: the
+// scheme and host in lower case, internationalized hosts in punycode, no
+// default port, and without the query parameters that only track where a
+// click came from.
+
+use url::Url;
+
+/// Tracking parameters removed unless configured otherwise. A trailing `*`
+/// matches any parameter starting with what comes before it.
+pub(crate) const DEFAULT_TRACKING_PARAMS: &[&str] = &["utm_*", "fbclid", "gclid", "mc_eid"];
+
+/// Longer than any browser or server takes.
+pub(crate) const MAX_URL_LENGTH: usize = 8192;
+
+/// Checks a pattern for tracking parameters, like `utm_*` or `fbclid`.
+pub(crate) fn validate_tracking_param(pattern: &str) -> Result<(), String> {
+    let name = pattern.strip_suffix('*').unwrap_or(pattern);
+    if name.is_empty() || name.contains(['*', '&', '=']) {
+        return Err(format!("'{}' is not a parameter name, optionally ending with *", pattern));
+    }
+    Ok(())
+}
+
+fn is_tracking(name: &str, patterns: &[String]) -> bool {
+    patterns.iter().any(|pattern| match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => name == pattern,
+    })
+}
+
+/// The one way of writing `input`, dropping query parameters that match
+/// `tracking`.
+pub(crate) fn canonicalize(input: &str, tracking: &[String]) -> Result<String, String> {
+    if input.len() > MAX_URL_LENGTH {
+        return Err(format!("the URL is longer than {} bytes", MAX_URL_LENGTH));
+    }
+    let mut url = Url::parse(input.trim()).map_err(|e| format!("not a valid URL: {}", e))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(format!("only http and https URLs can be shortened, not {}", url.scheme()));
+    }
+    if url.host_str().is_none_or(str::is_empty) {
+        return Err("the URL has no host".to_string());
+    }
+    if let Some(query) = url.query() {
+        // the parameters kept are left exactly as they were written
+        let kept: Vec<&str> = query.split('&')
+            .filter(|param| !param.is_empty())
+            .filter(|param| !is_tracking(param.split('=').next().unwrap_or_default(), tracking))
+            .collect();
+        let query = (!kept.is_empty()).then(|| kept.join("&"));
+        url.set_query(query.as_deref());
+    }
+    Ok(url.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn canonical(input: &str) -> Result<String, String> {
+        let tracking: Vec<String> = DEFAULT_TRACKING_PARAMS.iter().map(|p| p.to_string()).collect();
+        canonicalize(input, &tracking)
+    }
+
+    #[test]
+    fn spellings_of_a_url_come_out_the_same() {
+        for spelling in [
+            "https://example.com/docs?page=2",
+            "HTTPS://Example.COM:443/docs?page=2",
+            "  https://example.com/docs?utm_source=mail&page=2&utm_medium=email\n",
+            "https://example.com/docs?page=2&fbclid=abc",
+        ] {
+            assert_eq!(canonical(spelling).unwrap(), "https://example.com/docs?page=2", "{}", spelling);
+        }
+        assert_eq!(canonical("http://example.com:80").unwrap(), "http://example.com/");
+        assert_eq!(canonical("http://example.com:8080/?utm_id=1").unwrap(), "http://example.com:8080/");
+        assert_eq!(canonical("https://example.com/?flag&q=a+b%2B&utm_x").unwrap(), "https://example.com/?flag&q=a+b%2B");
+    }
+
+    #[test]
+    fn internationalized_hosts_become_punycode() {
+        assert_eq!(canonical("https://Bücher.example/straße").unwrap(), "https://xn--bcher-kva.example/stra%C3%9Fe");
+    }
+
+    #[test]
+    fn only_web_urls_are_taken() {
+        assert!(canonical("not a url").is_err());
+        assert!(canonical("ftp://example.com/file").is_err());
+        assert!(canonical("javascript:alert(1)").is_err());
+        assert!(canonical("mailto:someone@example.com").is_err());
+        assert!(canonical("https://").is_err());
+    }
+
+    #[test]
+    fn tracking_params_are_configurable() {
+        let tracking = vec!["ref".to_owned()];
+        assert_eq!(canonicalize("https://example.com/?ref=x&utm_source=y", &tracking).unwrap(),
+                   "https://example.com/?utm_source=y");
+        assert!(validate_tracking_param("utm_*").is_ok());
+        assert!(validate_tracking_param("*").is_err());
+        assert!(validate_tracking_param("a*b").is_err());
+    }
+}
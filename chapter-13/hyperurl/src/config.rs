@@ -0,0 +1,232 @@
+// How the server is set up. Every setting can come from a TOML file given
+// with --config, from an environment variable, or from a command line flag,
+// each overriding the one before:
+//
+//   bind = "0.0.0.0:8080"               HYPERURL_BIND           --bind
+//   base_url = "https://go.example/"    HYPERURL_BASE_URL       --base-url
+//   code_strategy = "random"            HYPERURL_CODE_STRATEGY  --code-strategy
+//   code_length = 6                     HYPERURL_CODE_LENGTH    --code-length
+//   store = "file:/var/lib/links.json"  HYPERURL_STORE          --store
+//   log = "hyperurl=debug"              RUST_LOG                --log
+//   tracking_params = ["utm_*", "ref"]  HYPERURL_TRACKING_PARAMS  --tracking-params utm_*,ref
+
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use clap::Parser;
+use serde_derive::Deserialize;
+use url::Url;
+
+use crate::canonical::{validate_tracking_param, DEFAULT_TRACKING_PARAMS};
+use crate::shortener::{Strategy, DEFAULT_CODE_LENGTH, MAX_CODE_LENGTH};
+use crate::store::Backend;
+
+const DEFAULT_BIND: &str = "127.0.0.1:3002";
+const DEFAULT_BASE_URL: &str = "https://u.rl/";
+const DEFAULT_LOG: &str = "hyperurl=info";
+
+/// A URL shortening service
+#[derive(Debug, Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// A TOML file with settings, overridden by the environment and flags
+    #[arg(long, env = "HYPERURL_CONFIG")]
+    config: Option<PathBuf>,
+    #[command(flatten)]
+    settings: Settings,
+}
+
+/// Settings as given, before they are checked. One left out leaves the
+/// setting to the layer below.
+#[derive(Debug, Default, Deserialize, clap::Args)]
+#[serde(deny_unknown_fields)]
+struct Settings {
+    /// The address to listen on [default: 127.0.0.1:3002]
+    #[arg(long, env = "HYPERURL_BIND")]
+    bind: Option<String>,
+    /// Where short codes are served from, short URLs start with it
+    /// [default: https://u.rl/]
+    #[arg(long, env = "HYPERURL_BASE_URL")]
+    base_url: Option<String>,
+    /// How codes are made: counter, random or hash [default: hash]
+    #[arg(long, env = "HYPERURL_CODE_STRATEGY")]
+    code_strategy: Option<String>,
+    /// How long generated codes are, at most 32 [default: 5]
+    #[arg(long, env = "HYPERURL_CODE_LENGTH")]
+    code_length: Option<usize>,
+    /// Where links are kept: memory, file:<path> or resp://<host>:<port>
+    /// [default: memory]
+    #[arg(long, env = "HYPERURL_STORE")]
+    store: Option<String>,
+    /// Log filter in env_logger syntax [default: hyperurl=info]
+    #[arg(long, env = "RUST_LOG")]
+    log: Option<String>,
+    /// Query parameters removed from URLs before shortening, comma
+    /// separated, `utm_*` for all starting with utm_ [default: utm_*,fbclid,gclid,mc_eid]
+    #[arg(long, env = "HYPERURL_TRACKING_PARAMS", value_delimiter = ',')]
+    tracking_params: Option<Vec<String>>,
+}
+
+impl Settings {
+    fn or(self, below: Settings) -> Settings {
+        Settings {
+            bind: self.bind.or(below.bind),
+            base_url: self.base_url.or(below.base_url),
+            code_strategy: self.code_strategy.or(below.code_strategy),
+            code_length: self.code_length.or(below.code_length),
+            store: self.store.or(below.store),
+            log: self.log.or(below.log),
+            tracking_params: self.tracking_params.or(below.tracking_params),
+        }
+    }
+}
+
+/// The settings the server runs with, all of them checked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Config {
+    pub(crate) bind: SocketAddr,
+    /// Always ends with a `/`
+    pub(crate) base_url: String,
+    pub(crate) code_strategy: Strategy,
+    pub(crate) code_length: usize,
+    pub(crate) store: Backend,
+    pub(crate) log: String,
+    pub(crate) tracking_params: Vec<String>,
+}
+
+impl Config {
+    /// The config from the file, environment and command line, or
+    /// everything that is wrong with it.
+    pub(crate) fn load() -> Result<Config, Vec<String>> {
+        let cli = Cli::parse();
+        let file = match &cli.config {
+            Some(path) => read_file(path).map_err(|e| vec![e])?,
+            None => Settings::default(),
+        };
+        Config::check(cli.settings.or(file))
+    }
+
+    fn check(settings: Settings) -> Result<Config, Vec<String>> {
+        let mut errors = vec![];
+
+        let bind = settings.bind.unwrap_or_else(|| DEFAULT_BIND.to_owned());
+        let bind = checked(&mut errors, bind.parse().map_err(|_| format!("bind: '{}' is not an address like {}", bind, DEFAULT_BIND)));
+        let base_url = checked(&mut errors, base_url(settings.base_url.as_deref().unwrap_or(DEFAULT_BASE_URL)));
+        let code_strategy = checked(&mut errors, match &settings.code_strategy {
+            Some(strategy) => strategy.parse().map_err(|e| format!("code_strategy: {}", e)),
+            None => Ok(Strategy::Hash),
+        });
+        let code_length = checked(&mut errors, match settings.code_length.unwrap_or(DEFAULT_CODE_LENGTH) {
+            length @ 1..=MAX_CODE_LENGTH => Ok(length),
+            _ => Err(format!("code_length: must be 1 to {}", MAX_CODE_LENGTH)),
+        });
+        let store = checked(&mut errors, match &settings.store {
+            Some(store) => store.parse().map_err(|e| format!("store: {}", e)),
+            None => Ok(Backend::Memory),
+        });
+        let log = settings.log.unwrap_or_else(|| DEFAULT_LOG.to_owned());
+        // an empty list, like --tracking-params "", keeps every parameter
+        let tracking_params: Vec<String> = match settings.tracking_params {
+            Some(params) => params.into_iter().filter(|param| !param.is_empty()).collect(),
+            None => DEFAULT_TRACKING_PARAMS.iter().map(|param| param.to_string()).collect(),
+        };
+        for param in &tracking_params {
+            checked(&mut errors, validate_tracking_param(param).map_err(|e| format!("tracking_params: {}", e)));
+        }
+
+        match (bind, base_url, code_strategy, code_length, store) {
+            (Some(bind), Some(base_url), Some(code_strategy), Some(code_length), Some(store)) if errors.is_empty() => {
+                Ok(Config { bind, base_url, code_strategy, code_length, store, log, tracking_params })
+            }
+            _ => Err(errors),
+        }
+    }
+}
+
+// The value, or None with its error kept for later.
+fn checked<T>(errors: &mut Vec<String>, result: Result<T, String>) -> Option<T> {
+    result.map_err(|e| errors.push(e)).ok()
+}
+
+fn read_file(path: &PathBuf) -> Result<Settings, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("can't read config file {}: {}", path.display(), e))?;
+    toml::from_str(&text).map_err(|e| format!("config file {}: {}", path.display(), e))
+}
+
+// An http or https URL that short codes can be put at the end of.
+fn base_url(s: &str) -> Result<String, String> {
+    let url = Url::parse(s).map_err(|e| format!("base_url: '{}' is not a URL: {}", s, e))?;
+    if !matches!(url.scheme(), "http" | "https") || !url.has_host() {
+        return Err(format!("base_url: '{}' must be an http or https URL with a host", s));
+    }
+    if url.query().is_some() || url.fragment().is_some() {
+        return Err(format!("base_url: '{}' can't have a query or fragment", s));
+    }
+    let mut base_url = url.to_string();
+    if !base_url.ends_with('/') {
+        base_url.push('/');
+    }
+    Ok(base_url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upper_layers_win_and_gaps_fall_through() {
+        let flags = Settings { bind: Some("0.0.0.0:80".to_owned()), ..Settings::default() };
+        let env = Settings {
+            bind: Some("0.0.0.0:81".to_owned()),
+            code_length: Some(7),
+            ..Settings::default()
+        };
+        let file: Settings = toml::from_str(r#"
+            bind = "0.0.0.0:82"
+            code_length = 8
+            store = "memory"
+            tracking_params = ["ref"]
+        "#).unwrap();
+        let settings = flags.or(env.or(file));
+        assert_eq!(settings.bind.as_deref(), Some("0.0.0.0:80"));
+        assert_eq!(settings.code_length, Some(7));
+        assert_eq!(settings.store.as_deref(), Some("memory"));
+        assert_eq!(settings.tracking_params, Some(vec!["ref".to_owned()]));
+        assert_eq!(settings.base_url, None);
+    }
+
+    #[test]
+    fn defaults_fill_in_what_is_not_set() {
+        let config = Config::check(Settings {
+            base_url: Some("https://go.example/app".to_owned()),
+            ..Settings::default()
+        }).unwrap();
+        assert_eq!(config, Config {
+            bind: DEFAULT_BIND.parse().unwrap(),
+            base_url: "https://go.example/app/".to_owned(),
+            code_strategy: Strategy::Hash,
+            code_length: DEFAULT_CODE_LENGTH,
+            store: Backend::Memory,
+            log: DEFAULT_LOG.to_owned(),
+            tracking_params: DEFAULT_TRACKING_PARAMS.iter().map(|p| p.to_string()).collect(),
+        });
+    }
+
+    #[test]
+    fn every_error_is_reported() {
+        let errors = Config::check(Settings {
+            bind: Some("localhost".to_owned()),
+            base_url: Some("ftp://go.example/".to_owned()),
+            code_strategy: Some("sequential".to_owned()),
+            code_length: Some(MAX_CODE_LENGTH + 1),
+            store: Some("sqlite:links.db".to_owned()),
+            log: None,
+            tracking_params: Some(vec!["utm_*".to_owned(), "a*b".to_owned()]),
+        }).unwrap_err();
+        let fields: Vec<&str> = errors.iter().map(|e| e.split(':').next().unwrap()).collect();
+        assert_eq!(fields, ["bind", "base_url", "code_strategy", "code_length", "store", "tracking_params"]);
+        assert!(Config::check(Settings { code_length: Some(0), ..Settings::default() }).is_err());
+    }
+}
@@ -1,24 +1,52 @@
 use log::{info, error};
-use std::env;
 use std::convert::Infallible;
+use std::process;
+use std::sync::Arc;
 use hyper::Server;
+use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
 
+mod analytics;
+mod canonical;
+mod config;
 mod shortener;
 mod service;
-use crate::service::url_service;
+mod store;
+use crate::config::Config;
+use crate::service::{sweep_expired, url_service, UrlService};
+use crate::shortener::code_generator;
 
 #[tokio::main]
 async fn main() {
-    env::set_var("RUST_LOG", "hyperurl=info");
-    env_logger::init();
+    let config = Config::load().unwrap_or_else(|errors| {
+        for e in errors {
+            eprintln!("hyperurl: {}", e);
+        }
+        process::exit(1);
+    });
+    env_logger::Builder::new().parse_filters(&config.log).init();
+
+    let store = store::open(&config.store).await.unwrap_or_else(|e| {
+        error!("can't open link store {:?}: {}", config.store, e);
+        process::exit(1);
+    });
+    let codes = code_generator(config.code_strategy, config.code_length, Arc::clone(&store));
+    let links = Arc::new(UrlService::new(store, codes, config.base_url, config.tracking_params));
+    tokio::spawn(sweep_expired(Arc::clone(&links)));
 
-    let addr = "127.0.0.1:3002".parse().unwrap();
-    let make_service = make_service_fn(|_| async {
-        Ok::<_, Infallible>(service_fn(url_service))
+    let make_service = make_service_fn(move |conn: &AddrStream| {
+        let service = Arc::clone(&links);
+        let remote = conn.remote_addr();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| url_service(Arc::clone(&service), remote, req)))
+        }
+    });
+    let server = Server::try_bind(&config.bind).unwrap_or_else(|e| {
+        error!("can't listen on {}: {}", config.bind, e);
+        process::exit(1);
     });
-    let server = Server::bind(&addr).serve(make_service);
-    info!("URL shortner listening on http://{}", addr);
+    let server = server.serve(make_service);
+    info!("URL shortner listening on http://{}", config.bind);
     if let Err(e) = server.await {
         error!("server error: {}", e);
     }
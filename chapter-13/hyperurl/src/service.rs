@@ -1,43 +1,390 @@
-use std::sync::{Arc, RwLock};
-use std::collections::HashMap;
-use hyper::{Request, Body, Response};
-use lazy_static::lazy_static;
-use futures::TryStreamExt;
-
-use crate::shortener::shorten_url;
-
-type UrlDb = Arc<RwLock<HashMap<String, String>>>;
-
-lazy_static! {
-    static ref SHORT_URLS: UrlDb = Arc::new(RwLock::new(HashMap::new()));
-}
-
-// to_bytes version
-// use hyper::body::to_bytes;
-//
-// pub(crate) async fn url_service(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
-//     let body = to_bytes(req.into_body()).await?;
-//     let c = body.iter().cloned().collect::<Vec<u8>>();
-//     let url_to_shorten = std::str::from_utf8(&c).unwrap();
-//     let shortened_url = shorten_url(url_to_shorten);
-//     SHORT_URLS.write().unwrap().insert(shortened_url, url_to_shorten.to_string());
-//     let a = &*SHORT_URLS.read().unwrap();
-//     Ok(Response::new(Body::from(format!("{:#?}", a))))
-// }
-
-pub(crate) async fn url_service(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
-    let reply = req
-        .into_body()
-        .map_ok(|chunk| {
-            let c = chunk.iter().map(|byte| byte.to_owned()).collect::<Vec<u8>>();
-            let url_to_shorten = std::str::from_utf8(&c).unwrap();
-            let shortened_url = {
-                let mut urls = SHORT_URLS.write().unwrap();
-                urls.entry(url_to_shorten.to_owned())
-                    .or_insert(shorten_url(url_to_shorten))
-                    .clone()
-            };
-            shortened_url
-        });
-    Ok(Response::new(Body::wrap_stream(reply)))
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use hyper::{Request, Body, Response, Method, StatusCode};
+use hyper::body::HttpBody;
+use hyper::header::{CONTENT_LENGTH, CONTENT_TYPE, LOCATION, REFERER, USER_AGENT};
+use log::{error, info};
+
+use crate::analytics::{self, Bucket, Click, Recorder};
+use crate::canonical::{canonicalize, MAX_URL_LENGTH};
+use crate::shortener::{is_reserved, validate_alias, CodeGenerator};
+use crate::store::{now, Inserted, Link, LinkStore, Visit};
+
+// How often expired links are removed from the store
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+// Generated codes tried for one link, a few lengths' worth of random ones
+const MAX_CODE_ATTEMPTS: usize = 64;
+
+pub(crate) struct UrlService {
+    store: Arc<dyn LinkStore>,
+    codes: Box<dyn CodeGenerator>,
+    clicks: Recorder,
+    /// Short URLs are this followed by the code
+    base_url: String,
+    /// Query parameters taken off URLs before they are shortened
+    tracking_params: Vec<String>,
+}
+
+impl UrlService {
+    /// Starts recording clicks into `store` as well.
+    pub(crate) fn new(
+        store: Arc<dyn LinkStore>,
+        codes: Box<dyn CodeGenerator>,
+        base_url: String,
+        tracking_params: Vec<String>,
+    ) -> Self {
+        let clicks = Recorder::start(Arc::clone(&store));
+        UrlService { store, codes, clicks, base_url, tracking_params }
+    }
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+    let reason = code.canonical_reason().unwrap_or_default();
+    error(code, reason)
+}
+
+// Errors are JSON like {"error": "alias 'api' is reserved"}
+fn error(code: StatusCode, message: &str) -> Response<Body> {
+    Response::builder()
+        .status(code)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::json!({ "error": message }).to_string()))
+        .unwrap()
+}
+
+// A query parameter of the request, "" for a bare `name` without a value.
+fn query_param<'a>(req: &'a Request<Body>, name: &str) -> Option<&'a str> {
+    req.uri().query()?
+        .split('&')
+        .find_map(|param| match param.split_once('=') {
+            Some((key, value)) if key == name => Some(value),
+            None if param == name => Some(""),
+            _ => None,
+        })
+}
+
+// The link asked for by POST /shorten, without its URL yet.
+// `expires_at` is a Unix timestamp in seconds, `max_clicks` the redirects it
+// lasts for, and `once` is short for `max_clicks=1`.
+fn requested_link(req: &Request<Body>) -> Result<Link, String> {
+    let number = |name: &str| -> Result<Option<u64>, String> {
+        query_param(req, name)
+            .map(|value| value.parse::<u64>().map_err(|_| format!("{} must be a whole number", name)))
+            .transpose()
+    };
+    let mut link = Link::new("");
+    link.expires_at = number("expires_at")?;
+    link.max_clicks = number("max_clicks")?;
+    if query_param(req, "once").is_some() {
+        if link.max_clicks.is_some() {
+            return Err("once and max_clicks can't be used together".to_string());
+        }
+        link.max_clicks = Some(1);
+    }
+    if link.expires_at.is_some_and(|at| at <= now()) {
+        return Err("expires_at is in the past".to_string());
+    }
+    if link.max_clicks == Some(0) {
+        return Err("max_clicks must be at least 1".to_string());
+    }
+    Ok(link)
+}
+
+// The store failing is our problem, not the caller's
+fn store_error(e: std::io::Error) -> Response<Body> {
+    error!("link store error: {}", e);
+    status(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+// The body of POST /shorten, or None as soon as it is longer than any URL,
+// so a client can't make us hold on to more.
+async fn read_url(req: Request<Body>) -> Result<Option<Vec<u8>>, hyper::Error> {
+    let length = req.headers().get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse::<usize>().ok());
+    if length.is_some_and(|length| length > MAX_URL_LENGTH) {
+        return Ok(None);
+    }
+    let mut body = req.into_body();
+    let mut url = vec![];
+    while let Some(chunk) = body.data().await {
+        url.extend_from_slice(&chunk?);
+        if url.len() > MAX_URL_LENGTH {
+            return Ok(None);
+        }
+    }
+    Ok(Some(url))
+}
+
+// Whether `alias` now points to `link`, false if another link has it.
+async fn claim_alias(service: &UrlService, alias: &str, link: &Link) -> std::io::Result<bool> {
+    if service.store.insert(alias, link.clone()).await? {
+        return Ok(true);
+    }
+    Ok(matches!(service.store.get(alias).await?, Some(taken) if taken.same_as(link)))
+}
+
+// The code the link's URL already has, or a new one, trying the
+// generator's codes until the store takes one. Links that expire always
+// get a code of their own.
+async fn code_for(service: &UrlService, link: &Link) -> std::io::Result<String> {
+    if !link.expires() {
+        if let Some(code) = service.store.code_for(&link.url).await? {
+            return Ok(code);
+        }
+    }
+    for attempt in 0..MAX_CODE_ATTEMPTS {
+        let code = service.codes.generate(&link.url, attempt).await?;
+        if is_reserved(&code) {
+            continue;
+        }
+        match service.store.code_or_insert(&code, link.clone()).await? {
+            Inserted::Stored => return Ok(code),
+            Inserted::Known(known) => return Ok(known),
+            Inserted::Taken => (),
+        }
+    }
+    Err(std::io::Error::other(format!("no free code after {} attempts", MAX_CODE_ATTEMPTS)))
+}
+
+// POST /shorten with the URL as the body, answers with the short URL. The
+// URL is canonicalized first, so its spellings all get the same code.
+// `?alias=team-standup` asks for that code instead of a generated one, and
+// `?expires_at=`, `?max_clicks=` or `?once` for a link that expires.
+async fn shorten(service: &UrlService, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let alias = query_param(&req, "alias").map(String::from);
+    if let Some(Err(e)) = alias.as_deref().map(validate_alias) {
+        return Ok(error(StatusCode::BAD_REQUEST, &e));
+    }
+    let mut link = match requested_link(&req) {
+        Ok(link) => link,
+        Err(e) => return Ok(error(StatusCode::BAD_REQUEST, &e)),
+    };
+    let body = match read_url(req).await? {
+        Some(body) => body,
+        None => {
+            let e = format!("the URL is longer than {} bytes", MAX_URL_LENGTH);
+            return Ok(error(StatusCode::PAYLOAD_TOO_LARGE, &e));
+        }
+    };
+    let url = match std::str::from_utf8(&body) {
+        Ok(url) => canonicalize(url, &service.tracking_params),
+        Err(_) => Err("the URL must be UTF-8".to_string()),
+    };
+    link.url = match url {
+        Ok(url) => url,
+        Err(e) => return Ok(error(StatusCode::BAD_REQUEST, &e)),
+    };
+    let code = match alias {
+        Some(alias) => match claim_alias(service, &alias, &link).await {
+            Ok(true) => alias,
+            Ok(false) => {
+                return Ok(error(StatusCode::CONFLICT, &format!("alias '{}' is already taken", alias)));
+            }
+            Err(e) => return Ok(store_error(e)),
+        },
+        None => match code_for(service, &link).await {
+            Ok(code) => code,
+            Err(e) => return Ok(store_error(e)),
+        },
+    };
+    let short_url = format!("{}{}", service.base_url, code);
+    Ok(Response::builder()
+        .status(StatusCode::CREATED)
+        .header(LOCATION, &short_url)
+        .body(Body::from(short_url))
+        .unwrap())
+}
+
+// GET /{code}. A 302 rather than a 301: browsers cache permanent
+// redirects and would stop coming back to us for the code, and expiring
+// links need every click to go through us. Expired links are 410 Gone until
+// the sweeper removes them.
+async fn redirect(service: &UrlService, code: &str, req: &Request<Body>, remote: SocketAddr) -> Response<Body> {
+    let at = now();
+    match service.store.visit(code, at).await {
+        Ok(Visit::Redirect(link)) => {
+            let header = |name| req.headers().get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(String::from);
+            service.clicks.record(code, Click {
+                at,
+                referrer: header(REFERER),
+                user_agent: header(USER_AGENT),
+                visitor: analytics::visitor(remote.ip()),
+            });
+            Response::builder()
+                .status(StatusCode::FOUND)
+                .header(LOCATION, link.url)
+                .body(Body::empty())
+                .unwrap()
+        }
+        Ok(Visit::Gone) => status(StatusCode::GONE),
+        Ok(Visit::Missing) => status(StatusCode::NOT_FOUND),
+        Err(e) => store_error(e),
+    }
+}
+
+// GET /{code}/stats, clicks counted by day, or by hour with `?bucket=hour`.
+// Clicks show up once their batch is written, a second or so late.
+async fn stats(service: &UrlService, code: &str, req: &Request<Body>) -> Response<Body> {
+    let bucket = match query_param(req, "bucket").map(str::parse).unwrap_or(Ok(Bucket::Day)) {
+        Ok(bucket) => bucket,
+        Err(e) => return error(StatusCode::BAD_REQUEST, &e),
+    };
+    match service.store.get(code).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return status(StatusCode::NOT_FOUND),
+        Err(e) => return store_error(e),
+    }
+    match service.store.clicks(code).await {
+        Ok(clicks) => Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_string(&analytics::stats(&clicks, bucket)).unwrap()))
+            .unwrap(),
+        Err(e) => store_error(e),
+    }
+}
+
+/// Removes expired links from the store every SWEEP_INTERVAL, for as long
+/// as the server runs.
+pub(crate) async fn sweep_expired(service: Arc<UrlService>) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        match service.store.remove_expired(now()).await {
+            Ok(0) => {}
+            Ok(removed) => info!("removed {} expired links", removed),
+            Err(e) => error!("sweeping expired links failed: {}", e),
+        }
+    }
+}
+
+pub(crate) async fn url_service(
+    service: Arc<UrlService>,
+    remote: SocketAddr,
+    req: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+    let path = req.uri().path().to_owned();
+    match (req.method(), path.as_str()) {
+        (&Method::POST, "/shorten") => shorten(&service, req).await,
+        (_, "/shorten") => Ok(status(StatusCode::METHOD_NOT_ALLOWED)),
+        (&Method::GET, path) => match path[1..].strip_suffix("/stats") {
+            Some(code) => Ok(stats(&service, code, &req).await),
+            None => Ok(redirect(&service, &path[1..], &req, remote).await),
+        },
+        _ => Ok(status(StatusCode::NOT_FOUND)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shortener::HashCode;
+    use crate::store::MemoryStore;
+
+    fn service() -> Arc<UrlService> {
+        let codes = Box::new(HashCode::new(5));
+        Arc::new(UrlService::new(Arc::new(MemoryStore::default()), codes, "https://u.rl/".to_string(), vec![]))
+    }
+
+    async fn send(service: &Arc<UrlService>, method: Method, uri: &str, body: &str) -> Response<Body> {
+        let req = Request::builder().method(method).uri(uri).body(Body::from(body.to_owned())).unwrap();
+        let remote = "127.0.0.1:40000".parse().unwrap();
+        url_service(Arc::clone(service), remote, req).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn shortened_urls_redirect() {
+        let service = service();
+        let res = send(&service, Method::POST, "/shorten", "https://example.com/docs").await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let short_url = res.headers()[LOCATION].to_str().unwrap().to_owned();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(body, short_url);
+        let code = short_url.strip_prefix("https://u.rl/").unwrap();
+
+        let res = send(&service, Method::GET, &format!("/{}", code), "").await;
+        assert_eq!(res.status(), StatusCode::FOUND);
+        assert_eq!(res.headers()[LOCATION], "https://example.com/docs");
+    }
+
+    #[tokio::test]
+    async fn aliases_are_claimed_once() {
+        let service = service();
+        let res = send(&service, Method::POST, "/shorten?alias=standup", "https://example.com/standup").await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(res.headers()[LOCATION], "https://u.rl/standup");
+        // asking for the same link again is fine
+        let res = send(&service, Method::POST, "/shorten?alias=standup", "https://example.com/standup").await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        // another link under it is not
+        let res = send(&service, Method::POST, "/shorten?alias=standup", "https://example.com/other").await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(body, r#"{"error":"alias 'standup' is already taken"}"#);
+        let res = send(&service, Method::POST, "/shorten?alias=standup&once", "https://example.com/standup").await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        for uri in ["/shorten?alias=Admin", "/shorten?alias=no"] {
+            let res = send(&service, Method::POST, uri, "https://example.com/").await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", uri);
+        }
+    }
+
+    // Always comes up with the same code.
+    struct OneCode;
+
+    impl crate::shortener::CodeGenerator for OneCode {
+        fn generate<'a>(&'a self, _url: &'a str, _attempt: usize) -> futures::future::BoxFuture<'a, std::io::Result<String>> {
+            Box::pin(async { Ok("abcde".to_owned()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn running_out_of_codes_is_an_error() {
+        let service = Arc::new(UrlService::new(
+            Arc::new(MemoryStore::default()), Box::new(OneCode), "https://u.rl/".to_string(), vec![],
+        ));
+        let res = send(&service, Method::POST, "/shorten", "https://example.com/first").await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        // the same URL finds its code, another one can't get any
+        let res = send(&service, Method::POST, "/shorten", "https://example.com/first").await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let res = send(&service, Method::POST, "/shorten", "https://example.com/second").await;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn urls_too_long_are_refused_unread() {
+        let service = service();
+        let long = format!("https://example.com/{}", "a".repeat(MAX_URL_LENGTH));
+        let res = send(&service, Method::POST, "/shorten", &long).await;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        // a length too long is enough
+        let req = Request::post("/shorten")
+            .header(CONTENT_LENGTH, MAX_URL_LENGTH + 1)
+            .body(Body::from("https://example.com/"))
+            .unwrap();
+        let res = url_service(Arc::clone(&service), "127.0.0.1:40000".parse().unwrap(), req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // without a length up front, reading stops past the limit
+        let (mut sender, body) = Body::channel();
+        let req = Request::post("/shorten").body(body).unwrap();
+        let res = tokio::spawn(url_service(Arc::clone(&service), "127.0.0.1:40000".parse().unwrap(), req));
+        let chunk = hyper::body::Bytes::from(vec![b'a'; 1024]);
+        while sender.send_data(chunk.clone()).await.is_ok() {}
+        assert_eq!(res.await.unwrap().unwrap().status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn unknown_routes_and_methods() {
+        let service = service();
+        assert_eq!(send(&service, Method::GET, "/nope1", "").await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(send(&service, Method::DELETE, "/nope1", "").await.status(), StatusCode::NOT_FOUND);
+        for method in [Method::GET, Method::PUT, Method::DELETE] {
+            assert_eq!(send(&service, method, "/shorten", "").await.status(), StatusCode::METHOD_NOT_ALLOWED);
+        }
+    }
 }
@@ -1,10 +1,249 @@
+use std::io;
+use std::str::FromStr;
+use std::sync::Arc;
+
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
+use futures::future::{self, BoxFuture, FutureExt};
+use rand::Rng;
+
+use crate::store::LinkStore;
+
+pub(crate) const DEFAULT_CODE_LENGTH: usize = 5;
+// Past this a short code isn't short
+pub(crate) const MAX_CODE_LENGTH: usize = 32;
+
+const BASE62: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+// A SHA-256 digest in hex
+const MAX_HASH_LENGTH: usize = 64;
+// Random codes that hit this many taken codes in a row get longer
+const RANDOM_ATTEMPTS: usize = 8;
+const ALIAS_LENGTH: std::ops::RangeInclusive<usize> = 3..=64;
+// Paths the service routes itself, they can't be codes
+const RESERVED: &[&str] = &["shorten", "api", "admin", "health", "metrics", "stats", "static"];
+
+pub(crate) fn is_reserved(code: &str) -> bool {
+    RESERVED.iter().any(|word| word.eq_ignore_ascii_case(code))
+}
+
+/// Checks a code asked for by the caller, like `team-standup`: letters,
+/// digits, `-` and `_` only, and not one of the service's own paths.
+pub(crate) fn validate_alias(alias: &str) -> Result<(), String> {
+    if !ALIAS_LENGTH.contains(&alias.len()) {
+        return Err(format!(
+            "alias must be {} to {} characters long", ALIAS_LENGTH.start(), ALIAS_LENGTH.end()
+        ));
+    }
+    if !alias.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_') {
+        return Err("alias may only contain letters, digits, '-' and '_'".to_string());
+    }
+    if is_reserved(alias) {
+        return Err(format!("alias '{}' is reserved", alias));
+    }
+    Ok(())
+}
+
+/// Makes up the short code for a URL. The store decides whether a code is
+/// free, so when it is taken the caller asks again with the next `attempt`
+/// and gets another, possibly longer, code.
+pub(crate) trait CodeGenerator: Send + Sync {
+    fn generate<'a>(&'a self, url: &'a str, attempt: usize) -> BoxFuture<'a, io::Result<String>>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Strategy {
+    Counter,
+    Random,
+    Hash,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "counter" => Ok(Strategy::Counter),
+            "random" => Ok(Strategy::Random),
+            "hash" => Ok(Strategy::Hash),
+            _ => Err(format!("unknown code strategy '{}', expected counter, random or hash", s)),
+        }
+    }
+}
+
+/// Counters count in `store`, so that a restart doesn't start over.
+pub(crate) fn code_generator(strategy: Strategy, length: usize, store: Arc<dyn LinkStore>) -> Box<dyn CodeGenerator> {
+    match strategy {
+        Strategy::Counter => Box::new(Counter::new(length, store)),
+        Strategy::Random => Box::new(RandomCode::new(length)),
+        Strategy::Hash => Box::new(HashCode::new(length)),
+    }
+}
+
+fn base62(mut n: u64) -> String {
+    let mut digits = vec![];
+    loop {
+        digits.push(BASE62[(n % 62) as usize]);
+        n /= 62;
+        if n == 0 {
+            break;
+        }
+    }
+    digits.reverse();
+    String::from_utf8(digits).unwrap()
+}
+
+/// Counts up and writes the count in base62, padded to the code length.
+/// Codes only get longer once the length is used up. The count is kept by
+/// the store, shared by every shortener using it.
+pub(crate) struct Counter {
+    store: Arc<dyn LinkStore>,
+    length: usize,
+}
+
+impl Counter {
+    pub(crate) fn new(length: usize, store: Arc<dyn LinkStore>) -> Self {
+        Counter { store, length }
+    }
+}
+
+impl CodeGenerator for Counter {
+    fn generate<'a>(&'a self, _url: &'a str, _attempt: usize) -> BoxFuture<'a, io::Result<String>> {
+        async move {
+            let n = self.store.next_count().await?;
+            Ok(format!("{:0>width$}", base62(n), width = self.length))
+        }
+        .boxed()
+    }
+}
+
+/// Picks characters of the base62 alphabet at random.
+pub(crate) struct RandomCode {
+    length: usize,
+}
+
+impl RandomCode {
+    pub(crate) fn new(length: usize) -> Self {
+        RandomCode { length }
+    }
+}
+
+impl CodeGenerator for RandomCode {
+    fn generate<'a>(&'a self, _url: &'a str, attempt: usize) -> BoxFuture<'a, io::Result<String>> {
+        let mut rng = rand::thread_rng();
+        let length = self.length + attempt / RANDOM_ATTEMPTS;
+        let code = (0..length)
+            .map(|_| BASE62[rng.gen_range(0..BASE62.len())] as char)
+            .collect();
+        future::ready(Ok(code)).boxed()
+    }
+}
+
+/// The start of the URL's SHA-256 digest, so that the same URL always gets
+/// the same code. When another URL already has that code, the code takes
+/// one more character of the digest until it is free.
+pub(crate) struct HashCode {
+    length: usize,
+}
+
+impl HashCode {
+    pub(crate) fn new(length: usize) -> Self {
+        HashCode { length: length.min(MAX_HASH_LENGTH) }
+    }
+}
+
+impl CodeGenerator for HashCode {
+    fn generate<'a>(&'a self, url: &'a str, attempt: usize) -> BoxFuture<'a, io::Result<String>> {
+        // a whole digest taken is next to impossible, salting the URL
+        // gives another digest to try
+        let lengths = MAX_HASH_LENGTH - self.length + 1;
+        let (salt, extra) = (attempt / lengths, attempt % lengths);
+        let mut sha = Sha256::new();
+        sha.input_str(url);
+        if salt > 0 {
+            sha.input_str(&salt.to_string());
+        }
+        let mut digest = sha.result_str();
+        digest.truncate(self.length + extra);
+        future::ready(Ok(digest)).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{FileStore, MemoryStore};
+
+    #[test]
+    fn aliases_are_checked() {
+        assert!(validate_alias("team-standup_2").is_ok());
+        assert!(validate_alias("abc").is_ok());
+        assert!(validate_alias(&"a".repeat(64)).is_ok());
+        assert!(validate_alias("ab").is_err());
+        assert!(validate_alias(&"a".repeat(65)).is_err());
+        for alias in ["with space", "slash/es", "dots.dot", "ümlaut"] {
+            assert!(validate_alias(alias).is_err(), "{}", alias);
+        }
+        for alias in ["shorten", "API", "Stats"] {
+            assert_eq!(validate_alias(alias), Err(format!("alias '{}' is reserved", alias)));
+        }
+    }
+
+    #[test]
+    fn base62_counts_in_the_alphabet() {
+        assert_eq!(base62(0), "0");
+        assert_eq!(base62(61), "z");
+        assert_eq!(base62(62), "10");
+        assert_eq!(base62(62 * 62), "100");
+    }
+
+    #[tokio::test]
+    async fn counter_pads_codes_until_the_length_is_used_up() {
+        let store = Arc::new(MemoryStore::default());
+        let counter = Counter::new(2, store.clone());
+        assert_eq!(counter.generate("", 0).await.unwrap(), "00");
+        assert_eq!(counter.generate("", 0).await.unwrap(), "01");
+        for _ in 2..62 * 62 {
+            store.next_count().await.unwrap();
+        }
+        assert_eq!(counter.generate("", 0).await.unwrap(), "100");
+    }
+
+    #[tokio::test]
+    async fn counter_carries_on_after_a_restart() {
+        let path = std::env::temp_dir().join(format!("hyperurl-counter-{}.json", std::process::id()));
+        let open = || async { Arc::new(FileStore::open(path.clone()).await.unwrap()) };
+        let counter = Counter::new(5, open().await);
+        assert_eq!(counter.generate("", 0).await.unwrap(), "00000");
+        assert_eq!(counter.generate("", 0).await.unwrap(), "00001");
+        let counter = Counter::new(5, open().await);
+        assert_eq!(counter.generate("", 0).await.unwrap(), "00002");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn random_codes_get_longer_after_collisions() {
+        let codes = RandomCode::new(5);
+        for (attempt, length) in [(0, 5), (RANDOM_ATTEMPTS - 1, 5), (RANDOM_ATTEMPTS, 6), (2 * RANDOM_ATTEMPTS, 7)] {
+            let code = codes.generate("", attempt).await.unwrap();
+            assert_eq!(code.len(), length, "attempt {}", attempt);
+            assert!(code.bytes().all(|b| BASE62.contains(&b)));
+        }
+    }
 
-pub(crate) fn shorten_url(url: &str) -> String {
-    let mut sha = Sha256::new();
-    sha.input_str(url);
-    let mut s = sha.result_str();
-    s.truncate(5);
-    format!("https://u.rl/{}", s)
+    #[tokio::test]
+    async fn hash_codes_take_more_of_the_digest_on_conflict() {
+        let codes = HashCode::new(5);
+        let url = "https://example.com/";
+        let first = codes.generate(url, 0).await.unwrap();
+        assert_eq!(first.len(), 5);
+        assert_eq!(codes.generate(url, 0).await.unwrap(), first);
+        let second = codes.generate(url, 1).await.unwrap();
+        assert_eq!(second.len(), 6);
+        assert!(second.starts_with(&first));
+        assert_ne!(codes.generate("https://example.org/", 0).await.unwrap(), first);
+        // the whole digest taken, a salted one starts over at the length
+        let salted = codes.generate(url, MAX_HASH_LENGTH - 5 + 1).await.unwrap();
+        assert_eq!(salted.len(), 5);
+        assert_ne!(salted, first);
+    }
 }
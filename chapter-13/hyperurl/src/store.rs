@@ -0,0 +1,340 @@
+// Where links live. The backend is picked at startup: an in-memory map
+// that forgets everything on restart, a file on disk, or a rudis or Redis
+// server spoken to over RESP.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::future::{self, BoxFuture, FutureExt};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::analytics::{Click, ClickCounts};
+
+mod file;
+mod resp;
+pub(crate) use self::file::FileStore;
+pub(crate) use self::resp::RespStore;
+
+/// What a short code points to. A link may expire at a time, given in
+/// seconds since the Unix epoch, or after a number of redirects. Links that
+/// expire are never shared: shortening the same URL again makes a new code.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Link {
+    pub(crate) url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) expires_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) max_clicks: Option<u64>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub(crate) clicks: u64,
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+impl Link {
+    pub(crate) fn new(url: &str) -> Self {
+        Link { url: url.to_owned(), expires_at: None, max_clicks: None, clicks: 0 }
+    }
+
+    pub(crate) fn expires(&self) -> bool {
+        self.expires_at.is_some() || self.max_clicks.is_some()
+    }
+
+    pub(crate) fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|at| now >= at)
+            || self.max_clicks.is_some_and(|max| self.clicks >= max)
+    }
+
+    /// Whether `other` asks for the same link, whatever its clicks.
+    pub(crate) fn same_as(&self, other: &Link) -> bool {
+        self.url == other.url && self.expires_at == other.expires_at && self.max_clicks == other.max_clicks
+    }
+}
+
+/// What storing a new link under a code comes to.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Inserted {
+    Stored,
+    /// The code is taken, nothing was stored
+    Taken,
+    /// The URL already has this code, nothing was stored
+    Known(String),
+}
+
+/// What following a code comes to.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Visit {
+    Redirect(Link),
+    /// The link expired and is waiting for the sweeper
+    Gone,
+    Missing,
+}
+
+/// Seconds since the Unix epoch, the clock links expire by.
+pub(crate) fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// A store of links, looked up both ways: a code resolves to its link, and
+/// shortening a URL again gives back the code it already has.
+pub(crate) trait LinkStore: Send + Sync {
+    fn get<'a>(&'a self, code: &'a str) -> BoxFuture<'a, io::Result<Option<Link>>>;
+
+    /// The first code stored for `url`, if any, leaving out links that
+    /// expire.
+    fn code_for<'a>(&'a self, url: &'a str) -> BoxFuture<'a, io::Result<Option<String>>>;
+
+    /// Stores `link` under `code` unless the code is taken, in which case
+    /// it returns false. Checking and storing is one step, so two callers
+    /// never get the same code.
+    fn insert<'a>(&'a self, code: &'a str, link: Link) -> BoxFuture<'a, io::Result<bool>>;
+
+    /// Like `insert`, except that a link that doesn't expire for a URL
+    /// that has a code already is not stored, the code is given back
+    /// instead. Looking up and storing is one step, so two callers
+    /// shortening the same URL get the same code.
+    fn code_or_insert<'a>(&'a self, code: &'a str, link: Link) -> BoxFuture<'a, io::Result<Inserted>>;
+
+    /// Follows `code` at `now`, counting the click if it leads anywhere.
+    fn visit<'a>(&'a self, code: &'a str, now: u64) -> BoxFuture<'a, io::Result<Visit>>;
+
+    /// Removes the links expired by `now`, and their click counts, and
+    /// says how many there were.
+    fn remove_expired(&self, now: u64) -> BoxFuture<'_, io::Result<usize>>;
+
+    /// Counts clicks on the links they were made on.
+    fn record(&self, clicks: Vec<(String, Click)>) -> BoxFuture<'_, io::Result<()>>;
+
+    /// What was counted of the clicks on `code`.
+    fn clicks<'a>(&'a self, code: &'a str) -> BoxFuture<'a, io::Result<ClickCounts>>;
+
+    /// The next number of a count starting at 0, for codes made by
+    /// counting. The count carries on after a restart where the links do.
+    fn next_count(&self) -> BoxFuture<'_, io::Result<u64>>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Backend {
+    Memory,
+    File(PathBuf),
+    /// The address of the server, like 127.0.0.1:6379
+    Resp(String),
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    /// `memory`, `file:<path>` or `resp://<host>:<port>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "memory" {
+            Ok(Backend::Memory)
+        } else if let Some(path) = s.strip_prefix("file:").filter(|p| !p.is_empty()) {
+            Ok(Backend::File(PathBuf::from(path)))
+        } else if let Some(addr) = s.strip_prefix("resp://").or_else(|| s.strip_prefix("redis://")) {
+            Ok(Backend::Resp(addr.trim_end_matches('/').to_owned()))
+        } else {
+            Err(format!("unknown store '{}', expected memory, file:<path> or resp://<host>:<port>", s))
+        }
+    }
+}
+
+pub(crate) async fn open(backend: &Backend) -> io::Result<Arc<dyn LinkStore>> {
+    Ok(match backend {
+        Backend::Memory => Arc::new(MemoryStore::default()),
+        Backend::File(path) => Arc::new(FileStore::open(path.clone()).await?),
+        Backend::Resp(addr) => Arc::new(RespStore::connect(addr).await?),
+    })
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Links {
+    by_code: HashMap<String, Link>,
+    by_url: HashMap<String, String>,
+    #[serde(default)]
+    count: u64,
+}
+
+impl Links {
+    fn get(&self, code: &str) -> Option<Link> {
+        self.by_code.get(code).cloned()
+    }
+
+    fn code_for(&self, url: &str) -> Option<String> {
+        self.by_url.get(url).cloned()
+    }
+
+    fn insert(&mut self, code: &str, link: Link) -> bool {
+        if self.by_code.contains_key(code) {
+            return false;
+        }
+        if !link.expires() {
+            self.by_url.entry(link.url.clone()).or_insert_with(|| code.to_owned());
+        }
+        self.by_code.insert(code.to_owned(), link);
+        true
+    }
+
+    fn code_or_insert(&mut self, code: &str, link: Link) -> Inserted {
+        if !link.expires() {
+            if let Some(known) = self.code_for(&link.url) {
+                return Inserted::Known(known);
+            }
+        }
+        if self.insert(code, link) { Inserted::Stored } else { Inserted::Taken }
+    }
+
+    fn visit(&mut self, code: &str, now: u64) -> Visit {
+        match self.by_code.get_mut(code) {
+            Some(link) if link.is_expired(now) => Visit::Gone,
+            Some(link) => {
+                link.clicks += 1;
+                Visit::Redirect(link.clone())
+            }
+            None => Visit::Missing,
+        }
+    }
+
+    /// Undoes an insert of `code`.
+    fn remove(&mut self, code: &str) {
+        if let Some(link) = self.by_code.remove(code) {
+            if self.by_url.get(&link.url).is_some_and(|c| c == code) {
+                self.by_url.remove(&link.url);
+            }
+        }
+    }
+
+    /// Removes the links expired by `now` and their clicks in `clicks`.
+    fn remove_expired(&mut self, now: u64, clicks: &mut Clicks) -> usize {
+        let before = self.by_code.len();
+        self.by_code.retain(|_, link| !link.is_expired(now));
+        let by_code = &self.by_code;
+        clicks.retain(|code, _| by_code.contains_key(code));
+        before - self.by_code.len()
+    }
+
+    /// Counts in `counts` the clicks made on links there are.
+    fn record(&self, clicks: Vec<(String, Click)>, counts: &mut Clicks) {
+        for (code, click) in clicks {
+            if self.by_code.contains_key(&code) {
+                counts.entry(code).or_default().add(&click);
+            }
+        }
+    }
+
+    fn next_count(&mut self) -> u64 {
+        self.count += 1;
+        self.count - 1
+    }
+}
+
+/// The click counts of every link, by code.
+pub(crate) type Clicks = HashMap<String, ClickCounts>;
+
+/// Everything is lost when the server stops.
+#[derive(Default)]
+pub(crate) struct MemoryStore {
+    links: RwLock<Links>,
+    clicks: RwLock<Clicks>,
+}
+
+impl LinkStore for MemoryStore {
+    fn get<'a>(&'a self, code: &'a str) -> BoxFuture<'a, io::Result<Option<Link>>> {
+        future::ready(Ok(self.links.read().unwrap().get(code))).boxed()
+    }
+
+    fn code_for<'a>(&'a self, url: &'a str) -> BoxFuture<'a, io::Result<Option<String>>> {
+        future::ready(Ok(self.links.read().unwrap().code_for(url))).boxed()
+    }
+
+    fn insert<'a>(&'a self, code: &'a str, link: Link) -> BoxFuture<'a, io::Result<bool>> {
+        future::ready(Ok(self.links.write().unwrap().insert(code, link))).boxed()
+    }
+
+    fn code_or_insert<'a>(&'a self, code: &'a str, link: Link) -> BoxFuture<'a, io::Result<Inserted>> {
+        future::ready(Ok(self.links.write().unwrap().code_or_insert(code, link))).boxed()
+    }
+
+    fn visit<'a>(&'a self, code: &'a str, now: u64) -> BoxFuture<'a, io::Result<Visit>> {
+        future::ready(Ok(self.links.write().unwrap().visit(code, now))).boxed()
+    }
+
+    fn remove_expired(&self, now: u64) -> BoxFuture<'_, io::Result<usize>> {
+        let removed = self.links.write().unwrap().remove_expired(now, &mut self.clicks.write().unwrap());
+        future::ready(Ok(removed)).boxed()
+    }
+
+    fn record(&self, clicks: Vec<(String, Click)>) -> BoxFuture<'_, io::Result<()>> {
+        self.links.read().unwrap().record(clicks, &mut self.clicks.write().unwrap());
+        future::ready(Ok(())).boxed()
+    }
+
+    fn clicks<'a>(&'a self, code: &'a str) -> BoxFuture<'a, io::Result<ClickCounts>> {
+        future::ready(Ok(self.clicks.read().unwrap().get(code).cloned().unwrap_or_default())).boxed()
+    }
+
+    fn next_count(&self) -> BoxFuture<'_, io::Result<u64>> {
+        future::ready(Ok(self.links.write().unwrap().next_count())).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn click(at: u64) -> Click {
+        Click { at, referrer: None, user_agent: None, visitor: "v".to_owned() }
+    }
+
+    #[tokio::test]
+    async fn memory_store_looks_links_up_both_ways() {
+        let store = MemoryStore::default();
+        assert_eq!(store.get("a").await.unwrap(), None);
+        assert!(store.insert("a", Link::new("https://example.com")).await.unwrap());
+        assert!(!store.insert("a", Link::new("https://other.example")).await.unwrap());
+        assert!(store.insert("b", Link::new("https://example.com")).await.unwrap());
+        assert_eq!(store.get("a").await.unwrap(), Some(Link::new("https://example.com")));
+        // the first code stays the URL's code
+        assert_eq!(store.code_for("https://example.com").await.unwrap(), Some("a".to_owned()));
+        assert_eq!(store.code_for("https://other.example").await.unwrap(), None);
+
+        let link = Link::new("https://example.com");
+        assert_eq!(store.code_or_insert("c", link).await.unwrap(), Inserted::Known("a".to_owned()));
+        assert_eq!(store.get("c").await.unwrap(), None);
+        let link = Link::new("https://other.example");
+        assert_eq!(store.code_or_insert("a", link.clone()).await.unwrap(), Inserted::Taken);
+        assert_eq!(store.code_or_insert("c", link).await.unwrap(), Inserted::Stored);
+    }
+
+    #[tokio::test]
+    async fn memory_store_expires_links() {
+        let store = MemoryStore::default();
+        let mut once = Link::new("https://example.com/reset");
+        once.max_clicks = Some(1);
+        let mut dated = Link::new("https://example.com/sale");
+        dated.expires_at = Some(100);
+        assert!(store.insert("once", once).await.unwrap());
+        assert!(store.insert("dated", dated).await.unwrap());
+        assert_eq!(store.code_for("https://example.com/reset").await.unwrap(), None);
+
+        assert!(matches!(store.visit("once", 0).await.unwrap(), Visit::Redirect(link) if link.clicks == 1));
+        assert_eq!(store.visit("once", 0).await.unwrap(), Visit::Gone);
+        assert!(matches!(store.visit("dated", 99).await.unwrap(), Visit::Redirect(_)));
+        assert_eq!(store.visit("dated", 100).await.unwrap(), Visit::Gone);
+        assert_eq!(store.visit("missing", 0).await.unwrap(), Visit::Missing);
+
+        store.record(vec![("dated".to_owned(), click(1)), ("missing".to_owned(), click(2))]).await.unwrap();
+        assert_eq!(store.clicks("dated").await.unwrap().hours, [(0, 1)].into());
+        assert_eq!(store.clicks("missing").await.unwrap(), ClickCounts::default());
+
+        assert_eq!(store.remove_expired(99).await.unwrap(), 1);
+        assert_eq!(store.visit("once", 0).await.unwrap(), Visit::Missing);
+        assert_eq!(store.remove_expired(100).await.unwrap(), 1);
+        assert_eq!(store.clicks("dated").await.unwrap(), ClickCounts::default());
+    }
+}
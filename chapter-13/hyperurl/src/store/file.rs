@@ -0,0 +1,227 @@
+// Links kept in memory and written out to a JSON file after every change.
+// The file is replaced by renaming a fully written copy over it, so a crash
+// leaves either the old links or the new ones, never half a file.
+//
+// Click counts change far more often than links, they go to a file of their
+// own next to it, `{path}.clicks`, so that shortening never rewrites them.
+
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use futures::future::{BoxFuture, FutureExt};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+use super::{Clicks, Inserted, Link, LinkStore, Links, Visit};
+use crate::analytics::{Click, ClickCounts};
+
+pub(crate) struct FileStore {
+    path: PathBuf,
+    clicks_path: PathBuf,
+    // always locked before clicks
+    links: Mutex<Links>,
+    clicks: Mutex<Clicks>,
+}
+
+// What was saved at `path`, or nothing if there is no file yet.
+async fn load<T: DeserializeOwned + Default>(path: &Path) -> io::Result<T> {
+    match fs::read(path).await {
+        Ok(data) => serde_json::from_slice(&data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(e),
+    }
+}
+
+async fn save<T: Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    let data = serde_json::to_vec(value)?;
+    let mut tmp = OsString::from(path);
+    tmp.push(".tmp");
+    let mut file = fs::File::create(&tmp).await?;
+    file.write_all(&data).await?;
+    file.sync_all().await?;
+    fs::rename(&tmp, path).await?;
+    // the rename itself only lasts once the directory is on disk
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::File::open(dir).await?.sync_all().await
+}
+
+impl FileStore {
+    /// Loads the links saved at `path`, or starts empty if there is no file.
+    pub(crate) async fn open(path: PathBuf) -> io::Result<Self> {
+        let mut clicks_path = OsString::from(&path);
+        clicks_path.push(".clicks");
+        let clicks_path = PathBuf::from(clicks_path);
+        let links = load(&path).await?;
+        let clicks = load(&clicks_path).await?;
+        Ok(FileStore { path, clicks_path, links: Mutex::new(links), clicks: Mutex::new(clicks) })
+    }
+
+    // Saves the links with the one just stored under `code`, which is only
+    // kept once it is safely on disk.
+    async fn keep(&self, links: &mut Links, code: &str) -> io::Result<()> {
+        if let Err(e) = save(&self.path, &*links).await {
+            links.remove(code);
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+impl LinkStore for FileStore {
+    fn get<'a>(&'a self, code: &'a str) -> BoxFuture<'a, io::Result<Option<Link>>> {
+        async move { Ok(self.links.lock().await.get(code)) }.boxed()
+    }
+
+    fn code_for<'a>(&'a self, url: &'a str) -> BoxFuture<'a, io::Result<Option<String>>> {
+        async move { Ok(self.links.lock().await.code_for(url)) }.boxed()
+    }
+
+    fn insert<'a>(&'a self, code: &'a str, link: Link) -> BoxFuture<'a, io::Result<bool>> {
+        async move {
+            let mut links = self.links.lock().await;
+            if !links.insert(code, link) {
+                return Ok(false);
+            }
+            self.keep(&mut links, code).await?;
+            Ok(true)
+        }
+        .boxed()
+    }
+
+    fn code_or_insert<'a>(&'a self, code: &'a str, link: Link) -> BoxFuture<'a, io::Result<Inserted>> {
+        async move {
+            let mut links = self.links.lock().await;
+            let inserted = links.code_or_insert(code, link);
+            if inserted == Inserted::Stored {
+                self.keep(&mut links, code).await?;
+            }
+            Ok(inserted)
+        }
+        .boxed()
+    }
+
+    fn visit<'a>(&'a self, code: &'a str, now: u64) -> BoxFuture<'a, io::Result<Visit>> {
+        async move {
+            let mut links = self.links.lock().await;
+            let visit = links.visit(code, now);
+            // clicks only need to last for links they use up, a one-time
+            // link must stay used after a restart. A failed save keeps the
+            // click, better a link used up early than used twice.
+            if let Visit::Redirect(Link { max_clicks: Some(_), .. }) = visit {
+                save(&self.path, &*links).await?;
+            }
+            Ok(visit)
+        }
+        .boxed()
+    }
+
+    fn remove_expired(&self, now: u64) -> BoxFuture<'_, io::Result<usize>> {
+        async move {
+            let mut links = self.links.lock().await;
+            let mut clicks = self.clicks.lock().await;
+            let removed = links.remove_expired(now, &mut clicks);
+            if removed > 0 {
+                save(&self.path, &*links).await?;
+                save(&self.clicks_path, &*clicks).await?;
+            }
+            Ok(removed)
+        }
+        .boxed()
+    }
+
+    fn record(&self, clicks: Vec<(String, Click)>) -> BoxFuture<'_, io::Result<()>> {
+        async move {
+            let links = self.links.lock().await;
+            let mut counts = self.clicks.lock().await;
+            links.record(clicks, &mut counts);
+            drop(links);
+            save(&self.clicks_path, &*counts).await
+        }
+        .boxed()
+    }
+
+    fn clicks<'a>(&'a self, code: &'a str) -> BoxFuture<'a, io::Result<ClickCounts>> {
+        async move { Ok(self.clicks.lock().await.get(code).cloned().unwrap_or_default()) }.boxed()
+    }
+
+    fn next_count(&self) -> BoxFuture<'_, io::Result<u64>> {
+        async move {
+            let mut links = self.links.lock().await;
+            // a failed save skips a number, which is never handed out twice
+            let count = links.next_count();
+            save(&self.path, &*links).await?;
+            Ok(count)
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> TempFile {
+            TempFile(std::env::temp_dir().join(format!("hyperurl-{}-{}.json", name, std::process::id())))
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+            let mut clicks = self.0.clone().into_os_string();
+            clicks.push(".clicks");
+            let _ = std::fs::remove_file(clicks);
+        }
+    }
+
+    #[tokio::test]
+    async fn links_survive_a_restart() {
+        let file = TempFile::new("restart");
+        let store = FileStore::open(file.0.clone()).await.unwrap();
+        let mut once = Link::new("https://example.com/reset");
+        once.max_clicks = Some(1);
+        assert!(store.insert("a", Link::new("https://example.com")).await.unwrap());
+        assert!(!store.insert("a", Link::new("https://other.example")).await.unwrap());
+        assert!(store.insert("once", once).await.unwrap());
+        assert!(matches!(store.visit("once", 0).await.unwrap(), Visit::Redirect(_)));
+        let click = Click { at: 1, referrer: None, user_agent: None, visitor: "v".to_owned() };
+        // clicks leave the links file alone
+        let saved = std::fs::read(&file.0).unwrap();
+        store.record(vec![("a".to_owned(), click.clone())]).await.unwrap();
+        assert_eq!(std::fs::read(&file.0).unwrap(), saved);
+
+        let store = FileStore::open(file.0.clone()).await.unwrap();
+        assert_eq!(store.get("a").await.unwrap(), Some(Link::new("https://example.com")));
+        assert_eq!(store.code_for("https://example.com").await.unwrap(), Some("a".to_owned()));
+        // used up stays used up
+        assert_eq!(store.visit("once", 0).await.unwrap(), Visit::Gone);
+        let mut counts = ClickCounts::default();
+        counts.add(&click);
+        assert_eq!(store.clicks("a").await.unwrap(), counts);
+        assert_eq!(store.remove_expired(0).await.unwrap(), 1);
+
+        let store = FileStore::open(file.0.clone()).await.unwrap();
+        assert_eq!(store.visit("once", 0).await.unwrap(), Visit::Missing);
+    }
+
+    #[tokio::test]
+    async fn a_missing_file_is_an_empty_store_and_a_bad_one_an_error() {
+        let file = TempFile::new("missing");
+        let store = FileStore::open(file.0.clone()).await.unwrap();
+        assert_eq!(store.get("a").await.unwrap(), None);
+        std::fs::write(&file.0, "not json").unwrap();
+        let e = FileStore::open(file.0.clone()).await.err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+}
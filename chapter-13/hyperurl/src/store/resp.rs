@@ -0,0 +1,679 @@
+// Links kept in a rudis or Redis server, spoken to over RESP. A code is
+// stored under `hyperurl:code:{code}` as the JSON of its link, and a URL
+// under `hyperurl:url:{url}` as its first code. Both are written with
+// SET NX, so the server settles which of two shorteners gets a code.
+//
+// rudis has no transactions, so the two keys are written one after the
+// other, the URL first. A URL whose code does not lead back to it, left by
+// a crash in between or by a code someone else got first, counts as having
+// no code and is replaced by the next one stored. A URL whose code is not
+// stored at all may be another shortener halfway through: whoever comes
+// across it stores the link under that code for it.
+//
+// Links that expire count their clicks in `hyperurl:clicks:{code}`, and
+// wait in the sorted set `hyperurl:expiring`, scored by when they expire,
+// for the sweeper to remove them.
+//
+// The clicks on a link are counted by hour in the hash `hyperurl:hours:{code}`
+// and by referrer in the hash `hyperurl:referrers:{code}`, and its visitors
+// are the set `hyperurl:visitors:{code}`.
+//
+// Codes made by counting take their numbers from `hyperurl:counter`.
+
+use std::io;
+
+use futures::future::{BoxFuture, FutureExt};
+use log::warn;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+use super::{Inserted, Link, LinkStore, Visit};
+use crate::analytics::{Click, ClickCounts, MAX_REFERRERS, MAX_VISITORS};
+
+const CODE_PREFIX: &str = "hyperurl:code:";
+const URL_PREFIX: &str = "hyperurl:url:";
+const CLICKS_PREFIX: &str = "hyperurl:clicks:";
+const HOURS_PREFIX: &str = "hyperurl:hours:";
+const REFERRERS_PREFIX: &str = "hyperurl:referrers:";
+const VISITORS_PREFIX: &str = "hyperurl:visitors:";
+const EXPIRING: &str = "hyperurl:expiring";
+const COUNTER: &str = "hyperurl:counter";
+// Commands that may have run before the connection dropped, and must not
+// run a second time
+const NOT_RETRIED: &[&[u8]] = &[b"INCR", b"HINCRBY"];
+// Expiring links looked at per round trip by the sweeper
+const SWEEP_BATCH: usize = 100;
+
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Status(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Reply>>),
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+async fn read_line(stream: &mut BufStream<TcpStream>) -> io::Result<String> {
+    let mut line = String::new();
+    if stream.read_line(&mut line).await? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    match line.strip_suffix("\r\n") {
+        Some(line) => Ok(line.to_owned()),
+        None => Err(invalid("reply line without CRLF")),
+    }
+}
+
+fn read_reply(stream: &mut BufStream<TcpStream>) -> BoxFuture<'_, io::Result<Reply>> {
+    async move {
+        let line = read_line(stream).await?;
+        let (kind, rest) = line.split_at(line.len().min(1));
+        let length = || rest.parse::<i64>().map_err(|_| invalid(format!("bad length in '{}'", line)));
+        match kind {
+            "+" => Ok(Reply::Status(rest.to_owned())),
+            "-" => Err(io::Error::other(rest.to_owned())),
+            ":" => Ok(Reply::Integer(length()?)),
+            "$" => match length()? {
+                n if n < 0 => Ok(Reply::Bulk(None)),
+                n => {
+                    let mut data = vec![0; n as usize + 2];
+                    stream.read_exact(&mut data).await?;
+                    data.truncate(n as usize);
+                    Ok(Reply::Bulk(Some(data)))
+                }
+            },
+            "*" => match length()? {
+                n if n < 0 => Ok(Reply::Array(None)),
+                n => {
+                    let mut items = Vec::with_capacity(n as usize);
+                    for _ in 0..n {
+                        items.push(read_reply(stream).await?);
+                    }
+                    Ok(Reply::Array(Some(items)))
+                }
+            },
+            _ => Err(invalid(format!("unexpected reply '{}'", line))),
+        }
+    }
+    .boxed()
+}
+
+fn encode(args: &[&[u8]]) -> Vec<u8> {
+    let mut buf = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        buf.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        buf.extend_from_slice(arg);
+        buf.extend_from_slice(b"\r\n");
+    }
+    buf
+}
+
+pub(crate) struct RespStore {
+    addr: String,
+    // one connection, commands take turns on it
+    conn: Mutex<Option<BufStream<TcpStream>>>,
+}
+
+impl RespStore {
+    /// Connects right away so that a wrong address shows up at startup.
+    pub(crate) async fn connect(addr: &str) -> io::Result<Self> {
+        let store = RespStore { addr: addr.to_owned(), conn: Mutex::new(None) };
+        store.command(&[b"PING"]).await?;
+        Ok(store)
+    }
+
+    // Sends one command and waits for its reply. The connection is dropped
+    // on any error, even an error reply, which may be the server saying
+    // goodbye rather than answering. A command that failed on a connection
+    // left over from before is tried once more on a new one, unless running
+    // it twice would count or append twice.
+    async fn command(&self, args: &[&[u8]]) -> io::Result<Reply> {
+        let mut conn = self.conn.lock().await;
+        let mut retry = conn.is_some() && !NOT_RETRIED.contains(&args[0]);
+        loop {
+            if conn.is_none() {
+                *conn = Some(BufStream::new(TcpStream::connect(&self.addr).await?));
+            }
+            let stream = conn.as_mut().unwrap();
+            let result = async {
+                stream.write_all(&encode(args)).await?;
+                stream.flush().await?;
+                read_reply(stream).await
+            }
+            .await;
+            match result {
+                Err(e) => {
+                    *conn = None;
+                    if !retry {
+                        return Err(e);
+                    }
+                    warn!("lost connection to {}: {}", self.addr, e);
+                    retry = false;
+                }
+                reply => return reply,
+            }
+        }
+    }
+
+    async fn get_bulk(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        match self.command(&[b"GET", key.as_bytes()]).await? {
+            Reply::Bulk(value) => Ok(value),
+            reply => Err(invalid(format!("unexpected reply to GET: {:?}", reply))),
+        }
+    }
+
+    async fn set_nx(&self, key: &str, value: &[u8]) -> io::Result<bool> {
+        self.set(&[b"SET", key.as_bytes(), value, b"NX"]).await
+    }
+
+    async fn set(&self, args: &[&[u8]]) -> io::Result<bool> {
+        match self.command(args).await? {
+            Reply::Status(_) => Ok(true),
+            Reply::Bulk(None) => Ok(false),
+            reply => Err(invalid(format!("unexpected reply to SET: {:?}", reply))),
+        }
+    }
+
+    // Makes `code` the URL's code, unless it has one that leads back to it.
+    async fn index_url(&self, url: &str, code: &str) -> io::Result<()> {
+        let key = format!("{}{}", URL_PREFIX, url);
+        if !self.set_nx(&key, code.as_bytes()).await? && self.code_for(url).await?.is_none() {
+            self.set(&[b"SET", key.as_bytes(), code.as_bytes()]).await?;
+        }
+        Ok(())
+    }
+
+    // Makes `code` the URL's code unless it has one already, which is
+    // returned instead.
+    async fn claim_url(&self, url: &str, code: &str) -> io::Result<Option<String>> {
+        let key = format!("{}{}", URL_PREFIX, url);
+        if self.set_nx(&key, code.as_bytes()).await? {
+            return Ok(None);
+        }
+        let known = match self.get_bulk(&key).await? {
+            Some(known) => String::from_utf8(known).map_err(|e| invalid(e.to_string()))?,
+            None => return self.set(&[b"SET", key.as_bytes(), code.as_bytes()]).await.map(|_| None),
+        };
+        let json = serde_json::to_vec(&Link::new(url))?;
+        let finished = self.set_nx(&format!("{}{}", CODE_PREFIX, known), &json).await?;
+        if finished || self.code_for(url).await?.as_ref() == Some(&known) {
+            return Ok(Some(known));
+        }
+        self.set(&[b"SET", key.as_bytes(), code.as_bytes()]).await?;
+        Ok(None)
+    }
+
+    async fn integer(&self, args: &[&[u8]]) -> io::Result<i64> {
+        match self.command(args).await? {
+            Reply::Integer(n) => Ok(n),
+            reply => Err(invalid(format!("expected an integer, got {:?}", reply))),
+        }
+    }
+
+    async fn expire_at(&self, code: &str, at: u64) -> io::Result<()> {
+        self.integer(&[b"ZADD", EXPIRING.as_bytes(), at.to_string().as_bytes(), code.as_bytes()]).await?;
+        Ok(())
+    }
+
+    // The items of an array reply, like the fields and values of HGETALL.
+    async fn strings(&self, args: &[&[u8]]) -> io::Result<Vec<String>> {
+        let command = String::from_utf8_lossy(args[0]);
+        let items = match self.command(args).await? {
+            Reply::Array(Some(items)) => items,
+            reply => return Err(invalid(format!("unexpected reply to {}: {:?}", command, reply))),
+        };
+        items.into_iter()
+            .map(|item| match item {
+                Reply::Bulk(Some(data)) => String::from_utf8(data).map_err(|e| invalid(e.to_string())),
+                reply => Err(invalid(format!("unexpected item in {} reply: {:?}", command, reply))),
+            })
+            .collect()
+    }
+
+    // The fields of a hash with their values as numbers.
+    async fn counts(&self, key: &str) -> io::Result<Vec<(String, u64)>> {
+        let items = self.strings(&[b"HGETALL", key.as_bytes()]).await?;
+        items.chunks(2)
+            .map(|pair| {
+                let count = pair[1].parse().map_err(|_| invalid("bad count in HGETALL reply"))?;
+                Ok((pair[0].clone(), count))
+            })
+            .collect()
+    }
+
+    // The first expiring links with when they expire, soonest first.
+    async fn expiring(&self) -> io::Result<Vec<(String, f64)>> {
+        let stop = (SWEEP_BATCH - 1).to_string();
+        let items = self.strings(&[b"ZRANGE", EXPIRING.as_bytes(), b"0", stop.as_bytes(), b"WITHSCORES"]).await?;
+        items.chunks(2)
+            .map(|pair| {
+                let score = pair[1].parse().map_err(|_| invalid("bad score in ZRANGE reply"))?;
+                Ok((pair[0].clone(), score))
+            })
+            .collect()
+    }
+
+    // Adds the counts of a batch of clicks on `code`. New referrers and
+    // visitors are left out once there are enough, give or take a batch.
+    async fn add_counts(&self, code: &str, counts: ClickCounts) -> io::Result<()> {
+        let key = format!("{}{}", HOURS_PREFIX, code);
+        for (hour, clicks) in counts.hours {
+            let (hour, clicks) = (hour.to_string(), clicks.to_string());
+            self.integer(&[b"HINCRBY", key.as_bytes(), hour.as_bytes(), clicks.as_bytes()]).await?;
+        }
+        let key = format!("{}{}", REFERRERS_PREFIX, code);
+        let mut known = self.integer(&[b"HLEN", key.as_bytes()]).await? as usize;
+        for (referrer, clicks) in counts.referrers {
+            if known >= MAX_REFERRERS && self.integer(&[b"HEXISTS", key.as_bytes(), referrer.as_bytes()]).await? == 0 {
+                continue;
+            }
+            let by = clicks.to_string();
+            let total = self.integer(&[b"HINCRBY", key.as_bytes(), referrer.as_bytes(), by.as_bytes()]).await?;
+            // a referrer seen for the first time
+            if total as u64 == clicks {
+                known += 1;
+            }
+        }
+        let key = format!("{}{}", VISITORS_PREFIX, code);
+        if (self.integer(&[b"SCARD", key.as_bytes()]).await? as usize) < MAX_VISITORS {
+            let mut args: Vec<&[u8]> = vec![b"SADD", key.as_bytes()];
+            args.extend(counts.visitors.iter().map(String::as_bytes));
+            self.integer(&args).await?;
+        }
+        Ok(())
+    }
+}
+
+impl LinkStore for RespStore {
+    fn get<'a>(&'a self, code: &'a str) -> BoxFuture<'a, io::Result<Option<Link>>> {
+        async move {
+            match self.get_bulk(&format!("{}{}", CODE_PREFIX, code)).await? {
+                Some(json) => serde_json::from_slice(&json).map(Some).map_err(invalid_json),
+                None => Ok(None),
+            }
+        }
+        .boxed()
+    }
+
+    fn code_for<'a>(&'a self, url: &'a str) -> BoxFuture<'a, io::Result<Option<String>>> {
+        async move {
+            let code = match self.get_bulk(&format!("{}{}", URL_PREFIX, url)).await? {
+                Some(code) => String::from_utf8(code).map_err(|e| invalid(e.to_string()))?,
+                None => return Ok(None),
+            };
+            Ok(match self.get(&code).await? {
+                Some(link) if link.url == url && !link.expires() => Some(code),
+                _ => None,
+            })
+        }
+        .boxed()
+    }
+
+    fn insert<'a>(&'a self, code: &'a str, link: Link) -> BoxFuture<'a, io::Result<bool>> {
+        async move {
+            let json = serde_json::to_vec(&link)?;
+            if !link.expires() {
+                self.index_url(&link.url, code).await?;
+            }
+            if !self.set_nx(&format!("{}{}", CODE_PREFIX, code), &json).await? {
+                return Ok(false);
+            }
+            // used up links join the set as they run out of clicks
+            if let Some(at) = link.expires_at {
+                self.expire_at(code, at).await?;
+            }
+            Ok(true)
+        }
+        .boxed()
+    }
+
+    fn code_or_insert<'a>(&'a self, code: &'a str, link: Link) -> BoxFuture<'a, io::Result<Inserted>> {
+        async move {
+            if link.expires() {
+                let stored = self.insert(code, link).await?;
+                return Ok(if stored { Inserted::Stored } else { Inserted::Taken });
+            }
+            if let Some(known) = self.claim_url(&link.url, code).await? {
+                return Ok(Inserted::Known(known));
+            }
+            let json = serde_json::to_vec(&link)?;
+            if !self.set_nx(&format!("{}{}", CODE_PREFIX, code), &json).await? {
+                return Ok(Inserted::Taken);
+            }
+            Ok(Inserted::Stored)
+        }
+        .boxed()
+    }
+
+    fn visit<'a>(&'a self, code: &'a str, now: u64) -> BoxFuture<'a, io::Result<Visit>> {
+        async move {
+            let mut link = match self.get(code).await? {
+                Some(link) => link,
+                None => return Ok(Visit::Missing),
+            };
+            if link.is_expired(now) {
+                return Ok(Visit::Gone);
+            }
+            // only links that run out of clicks count them
+            if let Some(max) = link.max_clicks {
+                let key = format!("{}{}", CLICKS_PREFIX, code);
+                link.clicks = self.integer(&[b"INCR", key.as_bytes()]).await? as u64;
+                if link.clicks > max {
+                    return Ok(Visit::Gone);
+                }
+                if link.clicks == max {
+                    self.expire_at(code, now).await?;
+                }
+            }
+            Ok(Visit::Redirect(link))
+        }
+        .boxed()
+    }
+
+    fn remove_expired(&self, now: u64) -> BoxFuture<'_, io::Result<usize>> {
+        async move {
+            let mut removed = 0;
+            loop {
+                let batch = self.expiring().await?;
+                let full = batch.len() == SWEEP_BATCH;
+                for (code, at) in &batch {
+                    if *at > now as f64 {
+                        return Ok(removed);
+                    }
+                    let prefixes = [CODE_PREFIX, CLICKS_PREFIX, HOURS_PREFIX, REFERRERS_PREFIX, VISITORS_PREFIX];
+                    let keys = prefixes.map(|prefix| format!("{}{}", prefix, code));
+                    let mut args: Vec<&[u8]> = vec![b"DEL"];
+                    args.extend(keys.iter().map(String::as_bytes));
+                    self.integer(&args).await?;
+                    self.integer(&[b"ZREM", EXPIRING.as_bytes(), code.as_bytes()]).await?;
+                    removed += 1;
+                }
+                if !full {
+                    return Ok(removed);
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn record(&self, clicks: Vec<(String, Click)>) -> BoxFuture<'_, io::Result<()>> {
+        async move {
+            // the batch is counted first, a few commands per link
+            let mut by_code: Vec<(String, ClickCounts)> = vec![];
+            for (code, click) in clicks {
+                match by_code.iter_mut().find(|(c, _)| *c == code) {
+                    Some((_, counts)) => counts.add(&click),
+                    None => {
+                        let mut counts = ClickCounts::default();
+                        counts.add(&click);
+                        by_code.push((code, counts));
+                    }
+                }
+            }
+            for (code, counts) in by_code {
+                self.add_counts(&code, counts).await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn clicks<'a>(&'a self, code: &'a str) -> BoxFuture<'a, io::Result<ClickCounts>> {
+        async move {
+            let hours = self.counts(&format!("{}{}", HOURS_PREFIX, code)).await?.into_iter()
+                .map(|(hour, clicks)| Ok((hour.parse().map_err(|_| invalid("bad hour in store"))?, clicks)))
+                .collect::<io::Result<_>>()?;
+            let referrers = self.counts(&format!("{}{}", REFERRERS_PREFIX, code)).await?.into_iter().collect();
+            let key = format!("{}{}", VISITORS_PREFIX, code);
+            let visitors = self.strings(&[b"SMEMBERS", key.as_bytes()]).await?.into_iter().collect();
+            Ok(ClickCounts { hours, referrers, visitors })
+        }
+        .boxed()
+    }
+
+    fn next_count(&self) -> BoxFuture<'_, io::Result<u64>> {
+        async move { Ok(self.integer(&[b"INCR", COUNTER.as_bytes()]).await? as u64 - 1) }.boxed()
+    }
+}
+
+fn invalid_json(e: serde_json::Error) -> io::Error {
+    invalid(format!("bad link in store: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::process::{Child, Command, Stdio};
+
+    use super::*;
+    use crate::store::now;
+
+    // A rudis_sync of its own for each test, or the server in RUDIS_SERVER.
+    struct Server {
+        addr: String,
+        child: Option<Child>,
+    }
+
+    impl Server {
+        fn start() -> Server {
+            if let Ok(addr) = std::env::var("RUDIS_SERVER") {
+                return Server { addr, child: None };
+            }
+            let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+            Server::start_at(&format!("127.0.0.1:{}", port))
+        }
+
+        fn start_at(addr: &str) -> Server {
+            let manifest = concat!(env!("CARGO_MANIFEST_DIR"), "/../../chapter-12/rudis_sync/Cargo.toml");
+            let mut child = Command::new(env!("CARGO"))
+                .args(["run", "--quiet", "--offline", "--manifest-path", manifest, "--", addr])
+                .stdout(Stdio::piped())
+                .spawn()
+                .expect("failed to start rudis_sync");
+            // it says so once it is listening on the address
+            let mut stdout = BufReader::new(child.stdout.take().unwrap());
+            let mut line = String::new();
+            while !line.contains(addr) {
+                line.clear();
+                if stdout.read_line(&mut line).unwrap() == 0 {
+                    panic!("rudis_sync exited before listening");
+                }
+            }
+            // it dies of a broken pipe if nobody reads what it prints
+            std::thread::spawn(move || std::io::copy(&mut stdout, &mut std::io::sink()));
+            Server { addr: addr.to_owned(), child: Some(child) }
+        }
+    }
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            if let Some(child) = self.child.as_mut() {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+        }
+    }
+
+    // Keys of their own, so tests can share a server given in RUDIS_SERVER
+    fn unique(name: &str) -> String {
+        format!("{}-{}-{}", name, std::process::id(), rand::random::<u32>())
+    }
+
+    #[tokio::test]
+    async fn stores_links_both_ways() {
+        let server = Server::start();
+        let store = RespStore::connect(&server.addr).await.unwrap();
+        let (code, url) = (unique("code"), format!("https://example.com/{}", unique("page")));
+
+        assert_eq!(store.get(&code).await.unwrap(), None);
+        assert_eq!(store.code_for(&url).await.unwrap(), None);
+        assert!(store.insert(&code, Link::new(&url)).await.unwrap());
+        assert_eq!(store.get(&code).await.unwrap(), Some(Link::new(&url)));
+        assert_eq!(store.code_for(&url).await.unwrap(), Some(code.clone()));
+
+        // a second code for the same URL leaves its first code in place
+        let other = unique("other");
+        assert!(store.insert(&other, Link::new(&url)).await.unwrap());
+        assert_eq!(store.code_for(&url).await.unwrap(), Some(code));
+    }
+
+    #[tokio::test]
+    async fn taken_codes_are_not_overwritten() {
+        let server = Server::start();
+        let store = RespStore::connect(&server.addr).await.unwrap();
+        let code = unique("code");
+
+        assert!(store.insert(&code, Link::new("https://first.example")).await.unwrap());
+        assert!(!store.insert(&code, Link::new("https://second.example")).await.unwrap());
+        assert_eq!(store.get(&code).await.unwrap(), Some(Link::new("https://first.example")));
+    }
+
+    #[tokio::test]
+    async fn urls_whose_code_went_elsewhere_get_the_next_code() {
+        let server = Server::start();
+        let store = RespStore::connect(&server.addr).await.unwrap();
+        let (code, other) = (unique("code"), unique("other"));
+        let url = format!("https://example.com/{}", unique("page"));
+
+        assert!(store.insert(&code, Link::new("https://first.example")).await.unwrap());
+        // the URL is claimed before the code turns out to be taken
+        assert!(!store.insert(&code, Link::new(&url)).await.unwrap());
+        assert_eq!(store.code_for(&url).await.unwrap(), None);
+        assert!(store.insert(&other, Link::new(&url)).await.unwrap());
+        assert_eq!(store.code_for(&url).await.unwrap(), Some(other));
+    }
+
+    #[tokio::test]
+    async fn urls_get_one_code_whoever_shortens_them() {
+        let server = Server::start();
+        let store = RespStore::connect(&server.addr).await.unwrap();
+        let (code, other, third) = (unique("code"), unique("other"), unique("third"));
+        let url = format!("https://example.com/{}", unique("page"));
+
+        // a shortener that claimed the URL but has not stored the link yet
+        store.set_nx(&format!("{}{}", URL_PREFIX, url), code.as_bytes()).await.unwrap();
+        assert_eq!(store.code_or_insert(&other, Link::new(&url)).await.unwrap(), Inserted::Known(code.clone()));
+        assert_eq!(store.get(&code).await.unwrap(), Some(Link::new(&url)));
+        assert_eq!(store.get(&other).await.unwrap(), None);
+        // and it finds its code taken, by its own link
+        assert_eq!(store.code_or_insert(&code, Link::new(&url)).await.unwrap(), Inserted::Known(code.clone()));
+        assert_eq!(store.code_or_insert(&third, Link::new(&url)).await.unwrap(), Inserted::Known(code));
+    }
+
+    #[tokio::test]
+    async fn expired_links_are_gone_until_swept() {
+        let server = Server::start();
+        let store = RespStore::connect(&server.addr).await.unwrap();
+        let (once, dated) = (unique("once"), unique("dated"));
+        let now = now();
+
+        let mut link = Link::new("https://example.com/reset");
+        link.max_clicks = Some(1);
+        assert!(store.insert(&once, link).await.unwrap());
+        let mut link = Link::new("https://example.com/reset");
+        link.expires_at = Some(now + 60);
+        assert!(store.insert(&dated, link).await.unwrap());
+        // neither is handed out for the URL
+        assert_eq!(store.code_for("https://example.com/reset").await.unwrap(), None);
+
+        assert!(matches!(store.visit(&once, now).await.unwrap(), Visit::Redirect(link) if link.clicks == 1));
+        assert_eq!(store.visit(&once, now).await.unwrap(), Visit::Gone);
+        assert!(matches!(store.visit(&dated, now).await.unwrap(), Visit::Redirect(_)));
+        assert_eq!(store.visit(&dated, now + 60).await.unwrap(), Visit::Gone);
+
+        assert!(store.remove_expired(now).await.unwrap() >= 1);
+        assert_eq!(store.visit(&once, now).await.unwrap(), Visit::Missing);
+        assert!(matches!(store.visit(&dated, now).await.unwrap(), Visit::Redirect(_)));
+        store.remove_expired(now + 60).await.unwrap();
+        assert_eq!(store.visit(&dated, now).await.unwrap(), Visit::Missing);
+    }
+
+    #[tokio::test]
+    async fn clicks_are_kept_until_their_link_is_swept() {
+        let server = Server::start();
+        let store = RespStore::connect(&server.addr).await.unwrap();
+        let (code, other) = (unique("code"), unique("other"));
+        let now = now();
+        let mut link = Link::new("https://example.com");
+        link.expires_at = Some(now + 60);
+        store.insert(&code, link).await.unwrap();
+        let click = |at: u64| Click {
+            at,
+            referrer: Some(format!("https://{}.example", at % 2)),
+            user_agent: Some("curl".to_owned()),
+            visitor: format!("v{}", at % 2),
+        };
+
+        let counts = |clicks: &[Click]| {
+            let mut counts = ClickCounts::default();
+            clicks.iter().for_each(|click| counts.add(click));
+            counts
+        };
+
+        store.record(vec![(code.clone(), click(1)), (other.clone(), click(2)), (code.clone(), click(3))]).await.unwrap();
+        store.record(vec![(code.clone(), click(4))]).await.unwrap();
+        assert_eq!(store.clicks(&code).await.unwrap(), counts(&[click(1), click(3), click(4)]));
+        assert_eq!(store.clicks(&other).await.unwrap(), counts(&[click(2)]));
+
+        store.remove_expired(now + 60).await.unwrap();
+        assert_eq!(store.clicks(&code).await.unwrap(), ClickCounts::default());
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_losing_the_server() {
+        let mut server = Server::start();
+        if server.child.is_none() {
+            // can't restart a server we didn't start
+            return;
+        }
+        let store = RespStore::connect(&server.addr).await.unwrap();
+        let code = unique("code");
+        assert!(store.insert(&code, Link::new("https://example.com")).await.unwrap());
+
+        let addr = server.addr.clone();
+        drop(server);
+        assert!(store.get(&code).await.is_err());
+
+        server = Server::start_at(&addr);
+        assert_eq!(store.get(&code).await.unwrap(), None);
+        assert!(store.insert(&code, Link::new("https://example.com")).await.unwrap());
+        drop(server);
+    }
+
+    #[tokio::test]
+    async fn the_count_is_shared_by_every_store() {
+        let server = Server::start();
+        let first = RespStore::connect(&server.addr).await.unwrap();
+        let second = RespStore::connect(&server.addr).await.unwrap();
+        let count = first.next_count().await.unwrap();
+        assert!(second.next_count().await.unwrap() > count);
+    }
+
+    #[tokio::test]
+    async fn counting_is_not_retried_on_a_new_connection() {
+        let server = Server::start();
+        if server.child.is_none() {
+            // can't restart a server we didn't start
+            return;
+        }
+        let store = RespStore::connect(&server.addr).await.unwrap();
+        assert_eq!(store.next_count().await.unwrap(), 0);
+
+        let addr = server.addr.clone();
+        drop(server);
+        let _server = Server::start_at(&addr);
+        // it may have counted before the old connection went
+        assert!(store.next_count().await.is_err());
+        assert_eq!(store.next_count().await.unwrap(), 0);
+    }
+
+    #[test]
+    fn encodes_commands_as_arrays_of_bulk_strings() {
+        assert_eq!(encode(&[b"GET", b"key"]), b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n");
+    }
+}
@@ -11,6 +11,18 @@ struct Cli {
     /// The url to shorten
     #[arg(short, long)]
     url: String,
+    /// Ask for this code instead of a generated one, like team-standup
+    #[arg(short, long)]
+    alias: Option<String>,
+    /// Expire the link at this Unix timestamp, in seconds
+    #[arg(long)]
+    expires_at: Option<u64>,
+    /// Expire the link after this many redirects
+    #[arg(long, conflicts_with = "once")]
+    max_clicks: Option<u64>,
+    /// Expire the link after its first redirect
+    #[arg(long)]
+    once: bool,
     /// Setting logging for this CLI tool
     #[command(flatten)]
     verbosity: Verbosity,
@@ -20,8 +32,22 @@ fn main() -> Result<(), reqwest::Error> {
     let args = Cli::parse();
     println!("Shortening: {}", args.url);
     let client = reqwest::blocking::Client::new();
+    let mut query = vec![];
+    if let Some(alias) = args.alias {
+        query.push(("alias", alias));
+    }
+    if let Some(expires_at) = args.expires_at {
+        query.push(("expires_at", expires_at.to_string()));
+    }
+    if let Some(max_clicks) = args.max_clicks {
+        query.push(("max_clicks", max_clicks.to_string()));
+    }
+    if args.once {
+        query.push(("once", String::new()));
+    }
     let res = client
-        .post(&format!("http://{}/shorten", CONN_ADDR))
+        .post(format!("http://{}/shorten", CONN_ADDR))
+        .query(&query)
         .body(args.url)
         .send()?;
     let a: String = res.text().unwrap();